@@ -16,6 +16,14 @@ struct Session;
 #[derive(Debug)]
 struct ServerError;
 
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Server error")
+    }
+}
+
+impl std::error::Error for ServerError {}
+
 impl From<()> for ServerError {
     fn from(_: ()) -> Self {
         ServerError
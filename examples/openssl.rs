@@ -60,6 +60,9 @@ async fn main() -> std::io::Result<()> {
     builder.set_certificate_chain_file("./tests/cert.pem").unwrap();
     let acceptor = builder.build();
 
+    // limit number of concurrent tls handshakes per worker
+    ntex_tls::max_concurrent_ssl_accept(64);
+
     ntex::server::Server::build()
         .bind("mqtt", "127.0.0.1:8883", move |_| {
             pipeline_factory(Acceptor::new(acceptor.clone()))
@@ -72,6 +72,9 @@ async fn main() -> std::io::Result<()> {
 
     let tls_acceptor = Arc::new(tls_config);
 
+    // limit number of concurrent tls handshakes per worker
+    ntex_tls::max_concurrent_ssl_accept(64);
+
     ntex::server::Server::build()
         .bind("mqtt", "127.0.0.1:8883", move |_| {
             pipeline_factory(Acceptor::new(tls_acceptor.clone()))
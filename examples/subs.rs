@@ -85,6 +85,7 @@ fn control_service_factory() -> impl ServiceFactory<
             v5::ControlMessage::ProtocolError(e) => Ready::Ok(e.ack()),
             v5::ControlMessage::Ping(p) => Ready::Ok(p.ack()),
             v5::ControlMessage::Disconnect(d) => Ready::Ok(d.ack()),
+            v5::ControlMessage::WillPublish(w) => Ready::Ok(w.ack()),
             v5::ControlMessage::Subscribe(mut s) => {
                 // store subscribed topics in session, publish service uses this list for echos
                 s.iter_mut().for_each(|mut s| {
@@ -97,6 +98,8 @@ fn control_service_factory() -> impl ServiceFactory<
             v5::ControlMessage::Unsubscribe(s) => Ready::Ok(s.ack()),
             v5::ControlMessage::Closed(c) => Ready::Ok(c.ack()),
             v5::ControlMessage::PeerGone(c) => Ready::Ok(c.ack()),
+            v5::ControlMessage::Timeout(t) => Ready::Ok(t.ack()),
+            v5::ControlMessage::RateLimited(r) => Ready::Ok(r.ack()),
         }))
     })
 }
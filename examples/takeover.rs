@@ -0,0 +1,61 @@
+use ntex::util::Ready;
+use ntex_mqtt::{v3, MqttServer};
+
+#[derive(Debug)]
+struct MySession {
+    // session is unregistered once connection is closed
+    _registration: v3::Registration<v3::MqttSink>,
+}
+
+#[derive(Debug)]
+struct MyServerError;
+
+impl From<()> for MyServerError {
+    fn from(_: ()) -> Self {
+        MyServerError
+    }
+}
+
+async fn handshake(
+    handshake: v3::Handshake,
+    registry: v3::SessionRegistry<v3::MqttSink>,
+) -> Result<v3::HandshakeAck<MySession>, MyServerError> {
+    log::info!("new connection: {:?}", handshake);
+
+    // register new connection and close connection with the same client id
+    let client_id = handshake.packet().client_id.clone();
+    let (registration, prev) = registry.register(client_id.clone(), handshake.sink());
+    registration.on_evicted(move |session: MySession| {
+        // release resources of evicted session here
+        log::info!("session {:?} is evicted", client_id);
+        drop(session);
+    });
+    let session_present = if let Some(prev) = prev {
+        log::info!("take over session of {:?}", registration.client_id());
+        prev.close();
+        // restore session state here
+        !handshake.packet().clean_session
+    } else {
+        false
+    };
+
+    Ok(handshake.ack(MySession { _registration: registration }, session_present))
+}
+
+#[ntex::main]
+async fn main() -> std::io::Result<()> {
+    std::env::set_var("RUST_LOG", "ntex=trace,ntex_mqtt=trace,takeover=trace");
+    env_logger::init();
+
+    ntex::server::Server::build()
+        .bind("mqtt", "127.0.0.1:1883", |_| {
+            // registry is per worker
+            let registry = v3::SessionRegistry::new();
+            MqttServer::new()
+                .v3(v3::MqttServer::new(move |con| handshake(con, registry.clone()))
+                    .publish(|_: v3::Publish| Ready::Ok::<_, MyServerError>(())))
+        })?
+        .workers(1)
+        .run()
+        .await
+}
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::{net::IpAddr, time::Duration, time::Instant};
+
+use ntex::io::{types::PeerAddr, IoRef};
+use ntex::time::Seconds;
+
+/// List of temporarily banned clients
+///
+/// List is consulted during handshake, `connect` packet of banned client is
+/// rejected before handshake service is called.
+pub trait BanList {
+    /// Check if client with `client_id` connected from `addr` is banned
+    fn is_banned(&self, client_id: &str, addr: Option<IpAddr>) -> bool;
+
+    /// Ban client for `duration`
+    fn ban(&self, client_id: &str, addr: Option<IpAddr>, duration: Seconds);
+}
+
+/// In-memory ban list
+///
+/// Clones share the same entries, so one list could be used by all workers.
+/// Entries are removed once ban duration is elapsed.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBanList {
+    entries: Arc<Mutex<BanEntries>>,
+    by_addr: bool,
+}
+
+#[derive(Debug, Default)]
+struct BanEntries {
+    // ban deadline and banned address of the client
+    clients: HashMap<String, (Instant, Option<IpAddr>)>,
+    addrs: HashMap<IpAddr, Instant>,
+}
+
+impl MemoryBanList {
+    /// Create empty ban list, clients are banned by client id
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ban peer ip address along with client id.
+    ///
+    /// By default only client id is banned.
+    pub fn by_addr(mut self, val: bool) -> Self {
+        self.by_addr = val;
+        self
+    }
+
+    /// Remove client id and ip address it is banned with from the list
+    pub fn unban(&self, client_id: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some((_, Some(addr))) = entries.clients.remove(client_id) {
+            entries.addrs.remove(&addr);
+        }
+    }
+}
+
+impl BanList for MemoryBanList {
+    fn is_banned(&self, client_id: &str, addr: Option<IpAddr>) -> bool {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        // stale entries of looked up keys are removed, rest is purged by `ban()`
+        if let Some((until, _)) = entries.clients.get(client_id) {
+            if *until > now {
+                return true;
+            }
+            entries.clients.remove(client_id);
+        }
+        if let (true, Some(addr)) = (self.by_addr, addr) {
+            if let Some(until) = entries.addrs.get(&addr) {
+                if *until > now {
+                    return true;
+                }
+                entries.addrs.remove(&addr);
+            }
+        }
+        false
+    }
+
+    fn ban(&self, client_id: &str, addr: Option<IpAddr>, duration: Seconds) {
+        let now = Instant::now();
+        let until = now + Duration::from_secs(duration.0 as u64);
+        let addr = if self.by_addr { addr } else { None };
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.purge(now);
+        entries.clients.insert(client_id.to_string(), (until, addr));
+        if let Some(addr) = addr {
+            entries.addrs.insert(addr, until);
+        }
+    }
+}
+
+impl BanEntries {
+    fn purge(&mut self, now: Instant) {
+        self.clients.retain(|_, (until, _)| *until > now);
+        self.addrs.retain(|_, until| *until > now);
+    }
+}
+
+/// Ip address of connected peer
+pub(crate) fn peer_ip(io: &IoRef) -> Option<IpAddr> {
+    io.query::<PeerAddr>().get().map(|addr| addr.0.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_ban_list() {
+        let addr = Some(IpAddr::from([127, 0, 0, 1]));
+        let bans = MemoryBanList::new();
+        bans.ban("client", addr, Seconds(60));
+        assert!(bans.is_banned("client", None));
+        assert!(!bans.is_banned("other", addr));
+        bans.unban("client");
+        assert!(!bans.is_banned("client", addr));
+
+        let bans = MemoryBanList::new().by_addr(true);
+        bans.ban("client", addr, Seconds(60));
+        assert!(bans.is_banned("other", addr));
+        bans.unban("client");
+        assert!(!bans.is_banned("client", addr));
+        assert!(!bans.is_banned("other", addr));
+        bans.ban("client", addr, Seconds(60));
+
+        // expired entries
+        bans.ban("expired", None, Seconds::ZERO);
+        assert!(!bans.is_banned("expired", None));
+        assert_eq!(bans.entries.lock().unwrap().clients.len(), 1);
+        bans.ban("expired", None, Seconds::ZERO);
+        bans.ban("other", None, Seconds(60));
+        assert_eq!(bans.entries.lock().unwrap().clients.len(), 2);
+    }
+}
@@ -0,0 +1,95 @@
+use std::{cell::Cell, cell::RefCell, mem, rc::Rc};
+
+use ntex::time::{sleep, Millis};
+
+/// Batch of ack packets
+///
+/// Queued packets are written together once batch is full
+/// or max delay is elapsed, packets order is preserved.
+pub(crate) struct AckBatch<P> {
+    max_delay: Millis,
+    max_count: usize,
+    generation: Cell<usize>,
+    queue: RefCell<Vec<P>>,
+    flush: Box<dyn Fn(Vec<P>)>,
+}
+
+impl<P: 'static> AckBatch<P> {
+    pub(crate) fn new<F>(max_delay: Millis, max_count: usize, flush: F) -> Rc<Self>
+    where
+        F: Fn(Vec<P>) + 'static,
+    {
+        Rc::new(AckBatch {
+            max_delay,
+            max_count,
+            generation: Cell::new(0),
+            queue: RefCell::new(Vec::new()),
+            flush: Box::new(flush),
+        })
+    }
+
+    /// Queue packet
+    pub(crate) fn push(self: &Rc<Self>, pkt: P) {
+        let len = {
+            let mut queue = self.queue.borrow_mut();
+            queue.push(pkt);
+            queue.len()
+        };
+
+        if len >= self.max_count {
+            self.flush();
+        } else if len == 1 {
+            // flush batch after delay, unless it is flushed earlier
+            let batch = self.clone();
+            let generation = self.generation.get();
+            ntex::rt::spawn(async move {
+                sleep(batch.max_delay).await;
+                if batch.generation.get() == generation {
+                    batch.flush();
+                }
+            });
+        }
+    }
+
+    /// Write all queued packets
+    pub(crate) fn flush(&self) {
+        self.generation.set(self.generation.get().wrapping_add(1));
+        let pkts = mem::take(&mut *self.queue.borrow_mut());
+        if !pkts.is_empty() {
+            (self.flush)(pkts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ntex::test]
+    async fn test_ack_batch() {
+        let flushed = Rc::new(RefCell::new(Vec::new()));
+        let flushed2 = flushed.clone();
+        let batch = AckBatch::new(Millis(100), 3, move |pkts| flushed2.borrow_mut().push(pkts));
+
+        // flush on max count
+        batch.push(1);
+        batch.push(2);
+        assert!(flushed.borrow().is_empty());
+        batch.push(3);
+        assert_eq!(&*flushed.borrow(), &[vec![1, 2, 3]]);
+
+        // stale timer does not flush next batch
+        batch.push(4);
+        sleep(Millis(50)).await;
+        batch.push(5);
+        batch.push(6);
+        batch.push(7);
+        assert_eq!(flushed.borrow().len(), 2);
+        sleep(Millis(70)).await;
+        assert_eq!(flushed.borrow().len(), 2);
+
+        // flush on delay
+        sleep(Millis(80)).await;
+        assert_eq!(&flushed.borrow()[2], &vec![7]);
+    }
+}
@@ -0,0 +1,194 @@
+use std::task::{Context, Poll};
+use std::{cell::Cell, cell::RefCell, rc::Rc};
+
+use ntex::time::{Interval, Millis, Seconds, Sleep};
+use ntex::util::HashMap;
+
+/// Interval of inflight exchanges checks during shutdown
+const CHECK_INTERVAL: Millis = Millis(50);
+
+/// Connection that could be drained on server shutdown
+pub(crate) trait DrainConnection {
+    /// Check if connection has inflight exchanges
+    fn is_busy(&self) -> bool;
+
+    /// Notify peer where protocol allows and close connection
+    fn disconnect(&self);
+
+    /// Close connection without waiting for inflight exchanges
+    fn force_close(&self);
+}
+
+/// Drains server connections on shutdown
+///
+/// Drain is shared by all services created by the same server factory.
+/// Once shutdown starts, idle connections get disconnected, busy ones are
+/// disconnected as soon as their inflight exchanges settle. Remaining
+/// connections are force closed after grace period.
+#[derive(Clone, Default)]
+pub(crate) struct Drain(Rc<DrainInner>);
+
+#[derive(Default)]
+struct DrainInner {
+    grace_period: Cell<Seconds>,
+    next_id: Cell<usize>,
+    conns: RefCell<HashMap<usize, Rc<DrainEntry>>>,
+    state: RefCell<Option<(Sleep, Interval)>>,
+}
+
+struct DrainEntry {
+    conn: Rc<dyn DrainConnection>,
+    closing: Cell<bool>,
+}
+
+impl Drain {
+    /// Set shutdown grace period, `0` disables draining
+    pub(crate) fn set_grace_period(&self, period: Seconds) {
+        self.0.grace_period.set(period);
+    }
+
+    /// Check if shutdown is started
+    pub(crate) fn is_draining(&self) -> bool {
+        self.0.state.borrow().is_some()
+    }
+
+    /// Register connection, connection is unregistered once guard is dropped
+    pub(crate) fn register(&self, conn: Rc<dyn DrainConnection>) -> Option<DrainGuard> {
+        if self.0.grace_period.get().is_zero() {
+            return None;
+        }
+
+        let id = self.0.next_id.get();
+        self.0.next_id.set(id.wrapping_add(1));
+        self.0
+            .conns
+            .borrow_mut()
+            .insert(id, Rc::new(DrainEntry { conn, closing: Cell::new(false) }));
+        Some(DrainGuard { drain: self.0.clone(), id })
+    }
+
+    /// Drain connections.
+    ///
+    /// Returns `Ready` once all connections are closed or grace period is elapsed.
+    pub(crate) fn poll_shutdown(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let grace_period = self.0.grace_period.get();
+        if grace_period.is_zero() {
+            return Poll::Ready(());
+        }
+
+        let mut state = self.0.state.borrow_mut();
+        let (deadline, interval) = state.get_or_insert_with(|| {
+            log::trace!(
+                "Start draining {} connections, grace period: {:?}",
+                self.0.conns.borrow().len(),
+                grace_period
+            );
+            (Sleep::new(grace_period.into()), Interval::new(CHECK_INTERVAL))
+        });
+
+        // connections could be unregistered while closing
+        let entries: Vec<_> = self.0.conns.borrow().values().cloned().collect();
+        if deadline.poll_elapsed(cx).is_ready() {
+            if !entries.is_empty() {
+                log::trace!("Grace period is elapsed, closing {} connections", entries.len());
+            }
+            for entry in entries {
+                entry.conn.force_close();
+            }
+            return Poll::Ready(());
+        }
+
+        for entry in entries {
+            if !entry.closing.get() && !entry.conn.is_busy() {
+                entry.closing.set(true);
+                entry.conn.disconnect();
+            }
+        }
+
+        if self.0.conns.borrow().is_empty() {
+            Poll::Ready(())
+        } else {
+            // inflight exchanges do not notify drain, check them periodically
+            while interval.poll_tick(cx).is_ready() {}
+            Poll::Pending
+        }
+    }
+}
+
+/// Registered connection, unregistered on drop
+pub(crate) struct DrainGuard {
+    drain: Rc<DrainInner>,
+    id: usize,
+}
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        self.drain.conns.borrow_mut().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex::util::lazy;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Conn {
+        busy: Cell<bool>,
+        disconnected: Cell<bool>,
+        closed: Cell<bool>,
+    }
+
+    impl DrainConnection for Conn {
+        fn is_busy(&self) -> bool {
+            self.busy.get()
+        }
+
+        fn disconnect(&self) {
+            self.disconnected.set(true);
+        }
+
+        fn force_close(&self) {
+            self.closed.set(true);
+        }
+    }
+
+    #[ntex::test]
+    async fn test_drain() {
+        // draining is disabled
+        let drain = Drain::default();
+        assert!(drain.register(Rc::new(Conn::default())).is_none());
+        assert!(lazy(|cx| drain.poll_shutdown(cx)).await.is_ready());
+
+        let drain = Drain::default();
+        drain.set_grace_period(Seconds(1));
+        let idle = Rc::new(Conn::default());
+        let busy = Rc::new(Conn { busy: Cell::new(true), ..Default::default() });
+        let g1 = drain.register(idle.clone()).unwrap();
+        let g2 = drain.register(busy.clone()).unwrap();
+
+        assert!(lazy(|cx| drain.poll_shutdown(cx)).await.is_pending());
+        assert!(drain.is_draining());
+        assert!(idle.disconnected.get());
+        assert!(!busy.disconnected.get());
+        drop(g1);
+
+        busy.busy.set(false);
+        assert!(lazy(|cx| drain.poll_shutdown(cx)).await.is_pending());
+        assert!(busy.disconnected.get());
+        drop(g2);
+        assert!(lazy(|cx| drain.poll_shutdown(cx)).await.is_ready());
+
+        // grace period is elapsed
+        let drain = Drain::default();
+        drain.set_grace_period(Seconds(1));
+        let busy = Rc::new(Conn { busy: Cell::new(true), ..Default::default() });
+        let _g = drain.register(busy.clone()).unwrap();
+        assert!(lazy(|cx| drain.poll_shutdown(cx)).await.is_pending());
+        ntex::time::sleep(Millis(1100)).await;
+        assert!(lazy(|cx| drain.poll_shutdown(cx)).await.is_ready());
+        assert!(busy.closed.get());
+        assert!(!busy.disconnected.get());
+    }
+}
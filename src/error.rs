@@ -1,37 +1,52 @@
-use std::{error, fmt, io};
+use std::{error, io};
 
 use derive_more::{Display, From};
 use ntex::util::Either;
 
 /// Errors which can occur when attempting to handle mqtt connection.
+///
+/// Underlying service, protocol or io error is available via `Error::source()`.
 #[derive(Debug, Display)]
 pub enum MqttError<E> {
     /// Publish handler service error
     #[display(fmt = "Service error")]
     Service(E),
     /// Protocol error
-    #[display(fmt = "Mqtt protocol error: {}", _0)]
+    #[display(fmt = "Mqtt protocol error")]
     Protocol(ProtocolError),
     /// Handshake timeout
+    #[display(fmt = "Handshake timeout")]
     HandshakeTimeout,
     /// Peer disconnect
-    #[display(fmt = "Peer is disconnected, error: {:?}", _0)]
+    #[display(fmt = "Peer is disconnected")]
     Disconnected(Option<io::Error>),
     /// Server error
     #[display(fmt = "Server error: {}", _0)]
     ServerError(&'static str),
 }
 
-impl<E: fmt::Debug> error::Error for MqttError<E> {}
+impl<E: error::Error + 'static> error::Error for MqttError<E> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            MqttError::Service(err) => Some(err),
+            MqttError::Protocol(err) => Some(err),
+            MqttError::Disconnected(Some(err)) => Some(err),
+            MqttError::HandshakeTimeout
+            | MqttError::Disconnected(None)
+            | MqttError::ServerError(_) => None,
+        }
+    }
+}
 
 /// Protocol level errors
-#[derive(Debug, Display, From)]
+#[derive(Clone, Debug, Display, From)]
 pub enum ProtocolError {
     /// Mqtt parse error
-    #[display(fmt = "Decode error: {:?}", _0)]
+    #[display(fmt = "Decode error")]
+    #[from(ignore)]
     Decode(DecodeError),
     /// Mqtt encode error
-    #[display(fmt = "Encode error: {:?}", _0)]
+    #[display(fmt = "Encode error")]
     Encode(EncodeError),
     /// Unexpected packet
     #[display(fmt = "Unexpected packet {:?}, {}", _0, _1)]
@@ -51,9 +66,67 @@ pub enum ProtocolError {
     /// Keep alive timeout
     #[display(fmt = "Keep alive timeout")]
     KeepAliveTimeout,
+    /// Packet is not received completely within read timeout
+    #[display(fmt = "Read timeout")]
+    ReadTimeout,
+    /// Write buffer is not flushed within write timeout
+    #[display(fmt = "Write timeout")]
+    WriteTimeout,
+    /// PUBCOMP is not received within timeout after PUBREL retransmission
+    #[display(fmt = "PUBCOMP timeout")]
+    PubcompTimeout,
+    /// Publish QoS is lower than server's minimum QoS
+    #[display(fmt = "Publish QoS is not supported")]
+    QosNotSupported,
+    /// Inbound publish rate limit exceeded
+    #[display(fmt = "Inbound publish rate limit exceeded")]
+    RateLimitExceeded,
+    /// Retained publish is received, but server does not support retain (mqtt v5 only)
+    #[display(fmt = "Retain is not supported")]
+    RetainNotSupported,
+    /// Packet type is not allowed for the connection
+    #[display(fmt = "Packet type {:#04X} is not allowed", _0)]
+    PacketNotAllowed(u8),
+    /// Packet size exceeds max size, packet is rejected before its payload is received.
+    ///
+    /// `size` is lower bound if remaining length is not received completely
+    #[display(fmt = "Packet size {} exceeds max size {}", size, limit)]
+    #[from(ignore)]
+    MaxSizeExceeded { size: u32, limit: u32 },
+}
+
+impl error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ProtocolError::Decode(err) => Some(err),
+            ProtocolError::Encode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl ProtocolError {
+    /// Read, write or PUBCOMP timeout reported by io dispatcher as io error
+    pub(crate) fn from_io_timeout(err: &io::Error) -> Option<ProtocolError> {
+        match err.get_ref().and_then(|e| e.downcast_ref::<ProtocolError>()) {
+            Some(ProtocolError::ReadTimeout) => Some(ProtocolError::ReadTimeout),
+            Some(ProtocolError::WriteTimeout) => Some(ProtocolError::WriteTimeout),
+            Some(ProtocolError::PubcompTimeout) => Some(ProtocolError::PubcompTimeout),
+            _ => None,
+        }
+    }
 }
 
-impl error::Error for ProtocolError {}
+impl From<DecodeError> for ProtocolError {
+    fn from(err: DecodeError) -> Self {
+        match err {
+            DecodeError::MaxSizeExceeded { size, limit } => {
+                ProtocolError::MaxSizeExceeded { size, limit }
+            }
+            err => ProtocolError::Decode(err),
+        }
+    }
+}
 
 impl<E> From<ProtocolError> for MqttError<E> {
     fn from(err: ProtocolError) -> Self {
@@ -76,7 +149,7 @@ impl<E> From<Either<io::Error, io::Error>> for MqttError<E> {
 impl<E> From<Either<DecodeError, io::Error>> for MqttError<E> {
     fn from(err: Either<DecodeError, io::Error>) -> Self {
         match err {
-            Either::Left(err) => MqttError::Protocol(ProtocolError::Decode(err)),
+            Either::Left(err) => MqttError::Protocol(err.into()),
             Either::Right(err) => MqttError::Disconnected(Some(err)),
         }
     }
@@ -91,7 +164,7 @@ impl<E> From<Either<EncodeError, io::Error>> for MqttError<E> {
     }
 }
 
-#[derive(Debug, Display, From)]
+#[derive(Clone, Debug, Display, From)]
 pub enum DecodeError {
     InvalidProtocol,
     InvalidLength,
@@ -103,7 +176,12 @@ pub enum DecodeError {
     UnsupportedPacketType,
     // MQTT v3 only
     PacketIdRequired,
-    MaxSizeExceeded,
+    #[display(fmt = "MaxSizeExceeded({}, {})", size, limit)]
+    #[from(ignore)]
+    MaxSizeExceeded {
+        size: u32,
+        limit: u32,
+    },
     Utf8Error,
 }
 
@@ -132,7 +210,10 @@ impl PartialEq for DecodeError {
             (DecodeError::InvalidClientId, DecodeError::InvalidClientId) => true,
             (DecodeError::UnsupportedPacketType, DecodeError::UnsupportedPacketType) => true,
             (DecodeError::PacketIdRequired, DecodeError::PacketIdRequired) => true,
-            (DecodeError::MaxSizeExceeded, DecodeError::MaxSizeExceeded) => true,
+            (
+                DecodeError::MaxSizeExceeded { size, limit },
+                DecodeError::MaxSizeExceeded { size: size2, limit: limit2 },
+            ) => size == size2 && limit == limit2,
             (DecodeError::MalformedPacket, DecodeError::MalformedPacket) => true,
             (DecodeError::Utf8Error, DecodeError::Utf8Error) => true,
             _ => false,
@@ -150,6 +231,43 @@ pub enum SendPacketError {
     /// Peer disconnected
     #[display(fmt = "Peer disconnected")]
     Disconnected,
+    /// Size of write buffer exceeds the limit (mqtt v3 only)
+    #[display(fmt = "Write buffer is full")]
+    WriteBufferFull,
+    /// Packet could not be sent by this side of connection
+    #[display(fmt = "Packet is not allowed")]
+    NotAllowed,
 }
 
 impl error::Error for SendPacketError {}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+
+    #[test]
+    fn test_source() {
+        let err = MqttError::<io::Error>::Protocol(DecodeError::MalformedPacket.into());
+        assert_eq!(err.to_string(), "Mqtt protocol error");
+        let src = err.source().unwrap();
+        assert_eq!(src.to_string(), "Decode error");
+        assert_eq!(src.source().unwrap().to_string(), "MalformedPacket");
+
+        let err =
+            MqttError::<io::Error>::Service(io::Error::new(io::ErrorKind::Other, "service"));
+        assert_eq!(err.source().unwrap().to_string(), "service");
+
+        let err = MqttError::<io::Error>::Disconnected(Some(io::Error::new(
+            io::ErrorKind::Other,
+            "reset",
+        )));
+        assert_eq!(err.to_string(), "Peer is disconnected");
+        assert_eq!(err.source().unwrap().to_string(), "reset");
+
+        assert!(MqttError::<io::Error>::Disconnected(None).source().is_none());
+        assert!(MqttError::<io::Error>::ServerError("error").source().is_none());
+        assert!(ProtocolError::ReadTimeout.source().is_none());
+    }
+}
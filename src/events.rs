@@ -0,0 +1,230 @@
+use std::task::{Context, Poll};
+use std::{
+    cell::Cell, cell::RefCell, collections::VecDeque, pin::Pin, rc::Rc, time::SystemTime,
+};
+
+use ntex::io::IoRef;
+use ntex::task::LocalWaker;
+use ntex::util::{ByteString, Stream};
+
+use crate::types::QoS;
+
+/// Max number of buffered lifecycle events
+const CAPACITY: usize = 1024;
+
+/// Connection lifecycle event
+#[derive(Debug, Clone)]
+pub struct LifecycleEvent {
+    client_id: ByteString,
+    timestamp: SystemTime,
+    kind: LifecycleEventKind,
+}
+
+/// Kind of connection lifecycle event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LifecycleEventKind {
+    /// Connection handshake succeeded
+    Connected,
+    /// Connection is closed
+    Disconnected,
+    /// Subscription is confirmed with specified qos
+    SubscriptionAdded { topic: ByteString, qos: QoS },
+    /// Subscription is removed
+    SubscriptionRemoved { topic: ByteString },
+}
+
+impl LifecycleEvent {
+    #[inline]
+    /// Client id of the connection
+    pub fn client_id(&self) -> &ByteString {
+        &self.client_id
+    }
+
+    #[inline]
+    /// Time of the event
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    #[inline]
+    /// Kind of the event
+    pub fn kind(&self) -> &LifecycleEventKind {
+        &self.kind
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct LifecycleChannel {
+    queue: RefCell<VecDeque<LifecycleEvent>>,
+    receivers: RefCell<Vec<Rc<LocalWaker>>>,
+    dropped: Cell<usize>,
+}
+
+impl LifecycleChannel {
+    /// Create receiver for the channel
+    pub(crate) fn receiver(self: &Rc<Self>) -> LifecycleEvents {
+        let waker = Rc::new(LocalWaker::new());
+        self.receivers.borrow_mut().push(waker.clone());
+        LifecycleEvents { chan: self.clone(), waker }
+    }
+
+    fn has_receivers(&self) -> bool {
+        !self.receivers.borrow().is_empty()
+    }
+
+    /// Start emitting events for new connection
+    ///
+    /// Emits `Connected` event and `Disconnected` event once io is closed.
+    pub(crate) fn connected(
+        self: &Rc<Self>,
+        io: &IoRef,
+        client_id: ByteString,
+    ) -> Option<LifecycleEmitter> {
+        if !self.has_receivers() {
+            return None;
+        }
+
+        let emitter = LifecycleEmitter { chan: self.clone(), client_id };
+        emitter.emit(LifecycleEventKind::Connected);
+
+        let on_disconnect = io.on_disconnect();
+        let disconnected = emitter.clone();
+        ntex::rt::spawn(async move {
+            on_disconnect.await;
+            disconnected.emit(LifecycleEventKind::Disconnected);
+        });
+        Some(emitter)
+    }
+
+    fn push(&self, event: LifecycleEvent) {
+        let mut queue = self.queue.borrow_mut();
+        if queue.len() >= CAPACITY {
+            queue.pop_front();
+            self.dropped.set(self.dropped.get() + 1);
+        }
+        queue.push_back(event);
+
+        // any of receivers could take the event
+        for waker in self.receivers.borrow().iter() {
+            waker.wake();
+        }
+    }
+}
+
+/// Per-connection events emitter
+#[derive(Clone)]
+pub(crate) struct LifecycleEmitter {
+    chan: Rc<LifecycleChannel>,
+    client_id: ByteString,
+}
+
+impl LifecycleEmitter {
+    pub(crate) fn emit(&self, kind: LifecycleEventKind) {
+        if self.chan.has_receivers() {
+            self.chan.push(LifecycleEvent {
+                kind,
+                client_id: self.client_id.clone(),
+                timestamp: SystemTime::now(),
+            });
+        }
+    }
+}
+
+/// Stream of connection lifecycle events.
+///
+/// Stream is backed by bounded queue of 1024 events, if consumer
+/// does not keep up, oldest events get dropped. Number of dropped
+/// events is available via `LifecycleEvents::dropped()` method.
+///
+/// All receivers created by the same server share one queue,
+/// each event is delivered to only one of them.
+pub struct LifecycleEvents {
+    chan: Rc<LifecycleChannel>,
+    waker: Rc<LocalWaker>,
+}
+
+impl LifecycleEvents {
+    /// Number of events dropped because of queue overflow
+    pub fn dropped(&self) -> usize {
+        self.chan.dropped.get()
+    }
+}
+
+impl Drop for LifecycleEvents {
+    fn drop(&mut self) {
+        self.chan.receivers.borrow_mut().retain(|w| !Rc::ptr_eq(w, &self.waker));
+    }
+}
+
+impl Stream for LifecycleEvents {
+    type Item = LifecycleEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<LifecycleEvent>> {
+        if let Some(event) = self.chan.queue.borrow_mut().pop_front() {
+            Poll::Ready(Some(event))
+        } else {
+            self.waker.register(cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ntex::test]
+    async fn test_overflow() {
+        let chan = Rc::new(LifecycleChannel::default());
+        let mut events = chan.receiver();
+
+        let emitter =
+            LifecycleEmitter { chan: chan.clone(), client_id: ByteString::from_static("id") };
+        for _ in 0..CAPACITY + 2 {
+            emitter.emit(LifecycleEventKind::Connected);
+        }
+        emitter.emit(LifecycleEventKind::Disconnected);
+        assert_eq!(events.dropped(), 3);
+
+        let mut count = 0;
+        while let Some(ev) = chan.queue.borrow_mut().pop_front() {
+            count += 1;
+            if count == CAPACITY {
+                assert_eq!(ev.kind(), &LifecycleEventKind::Disconnected);
+            }
+        }
+        assert_eq!(count, CAPACITY);
+
+        emitter.emit(LifecycleEventKind::Disconnected);
+        let ev = ntex::util::stream_recv(&mut events).await.unwrap();
+        assert_eq!(ev.client_id(), "id");
+    }
+
+    #[ntex::test]
+    async fn test_multiple_receivers() {
+        let chan = Rc::new(LifecycleChannel::default());
+        let mut events1 = chan.receiver();
+        let mut events2 = chan.receiver();
+
+        let received = Rc::new(Cell::new(false));
+        let received2 = received.clone();
+        ntex::rt::spawn(async move {
+            if ntex::util::stream_recv(&mut events1).await.is_some() {
+                received2.set(true);
+            }
+        });
+        ntex::time::sleep(ntex::time::Millis(10)).await;
+
+        // second receiver waits for events too, then goes away
+        let res = ntex::util::lazy(|cx| Pin::new(&mut events2).poll_next(cx)).await;
+        assert!(res.is_pending());
+        drop(events2);
+        assert_eq!(chan.receivers.borrow().len(), 1);
+
+        let emitter =
+            LifecycleEmitter { chan: chan.clone(), client_id: ByteString::from_static("id") };
+        emitter.emit(LifecycleEventKind::Connected);
+        ntex::time::sleep(ntex::time::Millis(10)).await;
+        assert!(received.get());
+    }
+}
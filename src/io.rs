@@ -1,17 +1,51 @@
 //! Framed transport dispatcher
 use std::task::{Context, Poll};
 use std::{
-    cell::Cell, cell::RefCell, collections::VecDeque, future::Future, pin::Pin, rc::Rc, time,
+    cell::Cell, cell::RefCell, collections::VecDeque, future::Future, io, pin::Pin, rc::Rc,
+    time,
 };
 
 use ntex::codec::{Decoder, Encoder};
 use ntex::io::{DispatchItem, IoBoxed, IoRef, IoStatusUpdate, RecvError};
 use ntex::service::{IntoService, Service};
-use ntex::time::Seconds;
+use ntex::time::{Seconds, Sleep};
 use ntex::util::{ready, Pool};
 
+use crate::error::ProtocolError;
+
 type Response<U> = <U as Encoder>::Item;
 
+/// Keep-alive timeout updates and io timeouts
+pub(crate) trait KeepAlive {
+    /// Take updated keep-alive timeout
+    fn take_keepalive(&self) -> Option<Seconds> {
+        None
+    }
+
+    /// Max time to read single frame, `0` disables timeout
+    fn read_timeout(&self) -> Seconds {
+        Seconds::ZERO
+    }
+
+    /// Max time without write progress, `0` disables timeout
+    fn write_timeout(&self) -> Seconds {
+        Seconds::ZERO
+    }
+
+    /// Connection id, used in log records
+    fn connection_id(&self) -> u64 {
+        0
+    }
+
+    /// Check if tasks wait for write buffer capacity
+    fn has_write_waiters(&self) -> bool {
+        false
+    }
+
+    /// Write buffer is flushed, wake up waiting tasks
+    fn wake_write_waiters(&self) {}
+}
+
 pin_project_lite::pin_project! {
     /// Dispatcher for mqtt protocol
     pub(crate) struct Dispatcher<S, U>
@@ -46,6 +80,8 @@ bitflags::bitflags! {
 struct DispatcherInner {
     io: IoBoxed,
     keepalive_timeout: Cell<time::Duration>,
+    read_timer: RefCell<Option<Sleep>>,
+    write_timer: RefCell<Option<(Sleep, usize)>>,
 }
 
 struct DispatcherState<S: Service<DispatchItem<U>>, U: Encoder + Decoder> {
@@ -77,7 +113,6 @@ enum IoDispatcherState {
 }
 
 pub(crate) enum IoDispatcherError<S, U> {
-    KeepAlive,
     Encoder(U),
     Service(S),
 }
@@ -97,7 +132,7 @@ fn insert_flags(flags: &mut Cell<Flags>, f: Flags) {
 impl<S, U> Dispatcher<S, U>
 where
     S: Service<DispatchItem<U>, Response = Option<Response<U>>> + 'static,
-    U: Decoder + Encoder + Clone + 'static,
+    U: Decoder + Encoder + KeepAlive + Clone + 'static,
     <U as Encoder>::Item: 'static,
 {
     /// Construct new `Dispatcher` instance with outgoing messages stream.
@@ -127,7 +162,12 @@ where
             response: None,
             response_idx: 0,
             flags: Cell::new(Flags::empty()),
-            inner: DispatcherInner { io, keepalive_timeout },
+            inner: DispatcherInner {
+                io,
+                keepalive_timeout,
+                read_timer: RefCell::new(None),
+                write_timer: RefCell::new(None),
+            },
         }
     }
 
@@ -168,6 +208,66 @@ impl DispatcherInner {
         self.io.remove_keepalive_timer();
         self.keepalive_timeout.set(time::Duration::ZERO);
     }
+
+    /// Check read and write timeouts.
+    ///
+    /// Read timer starts once read buffer contains incomplete frame, write timer
+    /// starts once write buffer is not empty and restarts on write progress.
+    fn poll_timeouts<U: KeepAlive>(
+        &self,
+        codec: &U,
+        cx: &mut Context<'_>,
+    ) -> Option<io::Error> {
+        let timeout = codec.read_timeout();
+        if !timeout.is_zero() {
+            let mut timer = self.read_timer.borrow_mut();
+            if self.io.with_read_buf(|buf| buf.is_empty()) {
+                *timer = None;
+            } else if timer
+                .get_or_insert_with(|| Sleep::new(timeout.into()))
+                .poll_elapsed(cx)
+                .is_ready()
+            {
+                log::trace!(
+                    "{}: Frame is not received within read timeout {:?}",
+                    codec.connection_id(),
+                    timeout
+                );
+                return Some(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    ProtocolError::ReadTimeout,
+                ));
+            }
+        }
+
+        let timeout = codec.write_timeout();
+        if !timeout.is_zero() {
+            let mut timer = self.write_timer.borrow_mut();
+            let len = self.io.with_write_buf(|buf| buf.len()).unwrap_or(0);
+            if len == 0 {
+                *timer = None;
+            } else {
+                let (sleep, pending) =
+                    timer.get_or_insert_with(|| (Sleep::new(timeout.into()), len));
+                if len < *pending {
+                    sleep.reset(timeout);
+                    *pending = len;
+                }
+                if sleep.poll_elapsed(cx).is_ready() {
+                    log::trace!(
+                        "{}: Write buffer is not flushed within timeout {:?}",
+                        codec.connection_id(),
+                        timeout
+                    );
+                    return Some(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        ProtocolError::WriteTimeout,
+                    ));
+                }
+            }
+        }
+        None
+    }
 }
 
 impl<S, U> DispatcherState<S, U>
@@ -231,7 +331,7 @@ where
 impl<S, U> Future for Dispatcher<S, U>
 where
     S: Service<DispatchItem<U>, Response = Option<Response<U>>> + 'static,
-    U: Decoder + Encoder + Clone + 'static,
+    U: Decoder + Encoder + KeepAlive + Clone + 'static,
     <U as Encoder>::Item: 'static,
 {
     type Output = Result<(), S::Error>;
@@ -259,6 +359,11 @@ where
             }
         }
 
+        // wake up tasks waiting for write buffer capacity
+        if this.codec.has_write_waiters() && io.poll_flush(cx, true).is_ready() {
+            this.codec.wake_write_waiters();
+        }
+
         // handle memory pool pressure
         if this.pool.poll_ready(cx).is_pending() {
             io.pause();
@@ -271,27 +376,40 @@ where
                     // println!("IO-DISP state :{:?}:", io.flags());
                     match this.service.poll_ready(cx) {
                         Poll::Ready(Ok(_)) => {
+                            // read and write timeouts are reported as peer disconnect
+                            let item = match this.inner.poll_timeouts(this.codec, cx) {
+                                Some(err) => Poll::Ready(Err(RecvError::PeerGone(Some(err)))),
+                                None => io.poll_recv(this.codec, cx),
+                            };
+
                             // decode incoming bytes stream
-                            let item = match ready!(io.poll_recv(this.codec, cx)) {
+                            let item = match ready!(item) {
                                 Ok(el) => {
+                                    // frame is received completely
+                                    *this.inner.read_timer.borrow_mut() = None;
+
                                     // update keep-alive timer
+                                    if let Some(timeout) = this.codec.take_keepalive() {
+                                        this.inner.keepalive_timeout.set(timeout.into());
+                                    }
                                     this.inner.update_keepalive();
 
                                     Some(DispatchItem::Item(el))
                                 }
                                 Err(RecvError::Stop) => {
-                                    log::trace!("dispatcher is instructed to stop");
+                                    log::trace!(
+                                        "{}: dispatcher is instructed to stop",
+                                        this.codec.connection_id()
+                                    );
                                     *this.st = IoDispatcherState::Stop;
                                     None
                                 }
                                 Err(RecvError::KeepAlive) => {
-                                    // check keepalive timeout
-                                    log::trace!("keepalive timeout");
-                                    *this.st = IoDispatcherState::Stop;
-                                    let mut inner = this.state.borrow_mut();
-                                    if inner.error.is_none() {
-                                        inner.error = Some(IoDispatcherError::KeepAlive);
-                                    }
+                                    // service closes connection or extends idle period
+                                    log::trace!(
+                                        "{}: keepalive timeout",
+                                        this.codec.connection_id()
+                                    );
                                     Some(DispatchItem::KeepAliveTimeout)
                                 }
                                 Err(RecvError::WriteBackpressure) => {
@@ -376,12 +494,18 @@ where
                         }
                         Poll::Pending => {
                             // pause io read task
-                            log::trace!("service is not ready, pause read task");
+                            log::trace!(
+                                "{}: service is not ready, pause read task",
+                                this.codec.connection_id()
+                            );
                             io.pause();
                             return Poll::Pending;
                         }
                         Poll::Ready(Err(err)) => {
-                            log::trace!("service readiness check failed, stopping");
+                            log::trace!(
+                                "{}: service readiness check failed, stopping",
+                                this.codec.connection_id()
+                            );
                             // service readiness error
                             *this.st = IoDispatcherState::Stop;
                             this.state.borrow_mut().error =
@@ -401,7 +525,10 @@ where
 
                     if this.state.borrow().queue.is_empty() {
                         if io.poll_shutdown(cx).is_ready() {
-                            log::trace!("io shutdown completed");
+                            log::trace!(
+                                "{}: io shutdown completed",
+                                this.codec.connection_id()
+                            );
                             *this.st = IoDispatcherState::Shutdown;
                             continue;
                         }
@@ -428,7 +555,10 @@ where
                     let is_err = this.state.borrow().error.is_some();
 
                     return if this.service.poll_shutdown(cx, is_err).is_ready() {
-                        log::trace!("service shutdown is completed, stop");
+                        log::trace!(
+                            "{}: service shutdown is completed, stop",
+                            this.codec.connection_id()
+                        );
 
                         Poll::Ready(
                             if let Some(IoDispatcherError::Service(err)) =
@@ -461,6 +591,8 @@ mod tests {
 
     use super::*;
 
+    impl KeepAlive for BytesCodec {}
+
     impl<S, U> Dispatcher<S, U>
     where
         S: Service<DispatchItem<U>, Response = Option<Response<U>>>,
@@ -496,7 +628,12 @@ mod tests {
                     response_idx: 0,
                     pool: io.memory_pool().pool(),
                     flags: Cell::new(Flags::empty()),
-                    inner: DispatcherInner { keepalive_timeout, io: IoBoxed::from(io) },
+                    inner: DispatcherInner {
+                        keepalive_timeout,
+                        io: IoBoxed::from(io),
+                        read_timer: RefCell::new(None),
+                        write_timer: RefCell::new(None),
+                    },
                 },
                 rio,
             )
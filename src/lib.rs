@@ -12,17 +12,29 @@ pub mod error;
 pub mod v3;
 pub mod v5;
 
+mod ban;
+mod coalesce;
+mod drain;
+mod events;
 mod inflight;
 mod io;
+mod limiter;
+mod registry;
+mod reject;
+mod selector;
 mod server;
 mod service;
 mod session;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod types;
 mod version;
+pub mod ws;
 
 pub use self::error::MqttError;
+pub use self::events::{LifecycleEvent, LifecycleEventKind, LifecycleEvents};
 pub use self::server::MqttServer;
-pub use self::session::Session;
+pub use self::session::{ConnectionDiagnostics, DisconnectReason, NegotiatedConfig, Session};
 pub use self::topic::{Level as TopicLevel, Topic};
 
 // http://www.iana.org/assignments/service-names-port-numbers/service-names-port-numbers.xhtml
@@ -0,0 +1,504 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::{cell::Cell, cell::RefCell, collections::VecDeque, future::Future, pin::Pin, rc::Rc};
+use std::{mem, time::Duration, time::Instant};
+
+use ntex::task::LocalWaker;
+use ntex::time::{now, Seconds};
+
+/// Default number of concurrently running handshake service calls
+pub(crate) const DEFAULT_MAX_HANDSHAKES: usize = 256;
+
+/// Inbound publish rate limiter
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RateLimiter {
+    /// Inbound publish rate is not limited
+    Disabled,
+    /// Number of publish packets received within last `window` is limited by `count`
+    SlidingWindow { count: u32, window: Seconds },
+    /// Publish packets are delayed to `rate` packets per second with bursts of
+    /// `burst` packets, control service is notified once connection is throttled
+    /// continuously for `notify` period
+    TokenBucket { rate: u32, burst: u32, notify: Seconds },
+}
+
+#[allow(clippy::derivable_impls)]
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::Disabled
+    }
+}
+
+/// Per-connection sliding window state
+pub(crate) struct SlidingWindow {
+    count: usize,
+    window: Duration,
+    stamps: VecDeque<Instant>,
+}
+
+impl SlidingWindow {
+    pub(crate) fn new(limiter: RateLimiter) -> Self {
+        match limiter {
+            RateLimiter::SlidingWindow { count, window } if !window.is_zero() => {
+                SlidingWindow {
+                    count: count as usize,
+                    window: Duration::from_secs(window.0 as u64),
+                    // buffer grows with number of received messages
+                    stamps: VecDeque::new(),
+                }
+            }
+            _ => SlidingWindow { count: 0, window: Duration::ZERO, stamps: VecDeque::new() },
+        }
+    }
+
+    /// Register inbound message, returns `false` if limit is exceeded
+    pub(crate) fn check(&mut self) -> bool {
+        if self.window.is_zero() {
+            return true;
+        }
+
+        let now = now();
+        while let Some(stamp) = self.stamps.front() {
+            if now.duration_since(*stamp) >= self.window {
+                self.stamps.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if self.stamps.len() >= self.count {
+            false
+        } else {
+            self.stamps.push_back(now);
+            true
+        }
+    }
+}
+
+/// Per-connection token bucket state
+pub(crate) struct TokenBucket {
+    interval: Duration,
+    tolerance: Duration,
+    notify: Duration,
+    tat: Option<Instant>,
+    throttled: Option<Instant>,
+    notified: bool,
+    waited: bool,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limiter: RateLimiter) -> Self {
+        let (interval, tolerance, notify) = match limiter {
+            RateLimiter::TokenBucket { rate, burst, notify } if rate != 0 => {
+                let interval = Duration::from_secs(1) / rate;
+                (interval, interval * burst.saturating_sub(1), notify.into())
+            }
+            _ => (Duration::ZERO, Duration::ZERO, Duration::ZERO),
+        };
+        TokenBucket {
+            interval,
+            tolerance,
+            notify,
+            tat: None,
+            throttled: None,
+            notified: false,
+            waited: false,
+        }
+    }
+
+    /// Time to wait for the next token, message is not registered
+    ///
+    /// Connection stays throttled until message is acquired without waiting.
+    pub(crate) fn wait(&mut self) -> Duration {
+        if self.interval.is_zero() {
+            return Duration::ZERO;
+        }
+
+        let now = now();
+        let delay = self
+            .tat
+            .map(|tat| tat.saturating_duration_since(now))
+            .unwrap_or_default()
+            .saturating_sub(self.tolerance);
+
+        if !delay.is_zero() {
+            self.waited = true;
+            if self.throttled.is_none() {
+                self.throttled = Some(now);
+            }
+        }
+        delay
+    }
+
+    /// Acquire token for inbound message, returns time to wait for the token
+    pub(crate) fn acquire(&mut self) -> Duration {
+        if self.interval.is_zero() {
+            return Duration::ZERO;
+        }
+
+        let now = now();
+        let tat = self.tat.map(|tat| tat.max(now)).unwrap_or(now);
+        let delay = tat.duration_since(now).saturating_sub(self.tolerance);
+        self.tat = Some(tat + self.interval);
+
+        if delay.is_zero() {
+            if !mem::take(&mut self.waited) {
+                self.throttled = None;
+                self.notified = false;
+            }
+        } else if self.throttled.is_none() {
+            self.throttled = Some(now);
+        }
+        delay
+    }
+
+    /// Returns throttling period once it exceeds notify period
+    ///
+    /// Period is reported once for each continuous throttling.
+    pub(crate) fn take_notify(&mut self) -> Option<Duration> {
+        if self.notify.is_zero() || self.notified {
+            return None;
+        }
+
+        let throttled = now().duration_since(self.throttled?);
+        if throttled >= self.notify {
+            self.notified = true;
+            Some(throttled)
+        } else {
+            None
+        }
+    }
+}
+
+/// Limits number of concurrently running handshake service calls
+///
+/// Limit is shared by all clones. Waiters acquire permits in fifo order.
+#[derive(Clone)]
+pub(crate) struct HandshakeLimit(Rc<HandshakeLimitInner>);
+
+struct HandshakeLimitInner {
+    capacity: Cell<usize>,
+    count: Cell<usize>,
+    waiters: RefCell<VecDeque<Rc<Waiter>>>,
+}
+
+#[derive(Default)]
+struct Waiter {
+    notified: Cell<bool>,
+    waker: LocalWaker,
+}
+
+impl Default for HandshakeLimit {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_HANDSHAKES)
+    }
+}
+
+impl HandshakeLimit {
+    /// Create limit, `0` means unlimited
+    pub(crate) fn new(capacity: usize) -> Self {
+        HandshakeLimit(Rc::new(HandshakeLimitInner {
+            capacity: Cell::new(capacity),
+            count: Cell::new(0),
+            waiters: RefCell::new(VecDeque::new()),
+        }))
+    }
+
+    /// Change limit, `0` means unlimited
+    pub(crate) fn set_capacity(&self, capacity: usize) {
+        self.0.capacity.set(capacity);
+        while self.0.has_capacity() && self.0.notify() {
+            self.0.count.set(self.0.count.get() + 1);
+        }
+    }
+
+    /// Wait for a free slot
+    pub(crate) fn acquire(&self) -> HandshakeAcquire {
+        HandshakeAcquire { limit: self.0.clone(), waiter: None }
+    }
+}
+
+impl HandshakeLimitInner {
+    fn has_capacity(&self) -> bool {
+        let capacity = self.capacity.get();
+        capacity == 0 || self.count.get() < capacity
+    }
+
+    /// Pass permit to next waiter
+    fn notify(&self) -> bool {
+        if let Some(waiter) = self.waiters.borrow_mut().pop_front() {
+            waiter.notified.set(true);
+            waiter.waker.wake();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn release(&self) {
+        let capacity = self.capacity.get();
+        if (capacity != 0 && self.count.get() > capacity) || !self.notify() {
+            self.count.set(self.count.get() - 1);
+        }
+    }
+}
+
+pub(crate) struct HandshakeAcquire {
+    limit: Rc<HandshakeLimitInner>,
+    waiter: Option<Rc<Waiter>>,
+}
+
+impl Future for HandshakeAcquire {
+    type Output = HandshakePermit;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.as_mut().get_mut();
+
+        if let Some(ref waiter) = this.waiter {
+            if waiter.notified.get() {
+                this.waiter = None;
+                return Poll::Ready(HandshakePermit(this.limit.clone()));
+            }
+            waiter.waker.register(cx.waker());
+        } else if this.limit.has_capacity() {
+            this.limit.count.set(this.limit.count.get() + 1);
+            return Poll::Ready(HandshakePermit(this.limit.clone()));
+        } else {
+            let waiter = Rc::new(Waiter::default());
+            waiter.waker.register(cx.waker());
+            this.limit.waiters.borrow_mut().push_back(waiter.clone());
+            this.waiter = Some(waiter);
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for HandshakeAcquire {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            if waiter.notified.get() {
+                self.limit.release();
+            } else {
+                self.limit.waiters.borrow_mut().retain(|w| !Rc::ptr_eq(w, &waiter));
+            }
+        }
+    }
+}
+
+/// Handshake slot, released on drop
+pub(crate) struct HandshakePermit(Rc<HandshakeLimitInner>);
+
+impl Drop for HandshakePermit {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// Limits number of inbound QoS 2 publish packets processed concurrently
+/// by all connections that share the limit.
+///
+/// Limit is thread-safe, create it once and pass clones to server
+/// factories of all workers.
+#[derive(Clone, Debug)]
+pub struct Qos2InflightLimit(Arc<Qos2InflightInner>);
+
+#[derive(Debug)]
+struct Qos2InflightInner {
+    max: usize,
+    count: AtomicUsize,
+    waiters: Mutex<Vec<Waker>>,
+}
+
+impl Qos2InflightLimit {
+    /// Create limit for `max` concurrently processed QoS 2 publish packets,
+    /// `0` means unlimited
+    pub fn new(max: usize) -> Self {
+        Qos2InflightLimit(Arc::new(Qos2InflightInner {
+            max,
+            count: AtomicUsize::new(0),
+            waiters: Mutex::new(Vec::new()),
+        }))
+    }
+
+    /// Number of QoS 2 publish packets that are currently processed
+    pub fn inflight(&self) -> usize {
+        self.0.count.load(Ordering::Acquire)
+    }
+
+    /// Take slot if limit is not reached
+    pub(crate) fn try_acquire(&self) -> Option<Qos2Permit> {
+        let mut count = self.0.count.load(Ordering::Acquire);
+        loop {
+            if self.0.max != 0 && count >= self.0.max {
+                return None;
+            }
+            match self.0.count.compare_exchange_weak(
+                count,
+                count + 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(Qos2Permit(self.0.clone())),
+                Err(val) => count = val,
+            }
+        }
+    }
+
+    /// Wait for free slot
+    pub(crate) fn poll_acquire(&self, cx: &mut Context<'_>) -> Poll<Qos2Permit> {
+        if let Some(permit) = self.try_acquire() {
+            return Poll::Ready(permit);
+        }
+        {
+            // waiter is polled repeatedly, keep one waker per waiter
+            let mut waiters = self.0.waiters.lock().unwrap();
+            if !waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                waiters.push(cx.waker().clone());
+            }
+        }
+
+        // slot could be released before waker is registered
+        match self.try_acquire() {
+            Some(permit) => Poll::Ready(permit),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// QoS 2 slot, released on drop
+pub(crate) struct Qos2Permit(Arc<Qos2InflightInner>);
+
+impl Drop for Qos2Permit {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::AcqRel);
+        let waiters = mem::take(&mut *self.0.waiters.lock().unwrap());
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ntex::test]
+    async fn test_sliding_window() {
+        let mut limiter = SlidingWindow::new(RateLimiter::Disabled);
+        for _ in 0..100 {
+            assert!(limiter.check());
+        }
+
+        let mut limiter =
+            SlidingWindow::new(RateLimiter::SlidingWindow { count: 2, window: Seconds(60) });
+        assert!(limiter.check());
+        assert!(limiter.check());
+        assert!(!limiter.check());
+    }
+
+    #[ntex::test]
+    async fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(RateLimiter::Disabled);
+        for _ in 0..100 {
+            assert!(bucket.acquire().is_zero());
+        }
+
+        let mut bucket = TokenBucket::new(RateLimiter::TokenBucket {
+            rate: 10,
+            burst: 2,
+            notify: Seconds::ZERO,
+        });
+        assert!(bucket.acquire().is_zero());
+        assert!(bucket.acquire().is_zero());
+        let delay = bucket.acquire();
+        assert!(delay > Duration::from_millis(50) && delay <= Duration::from_millis(100));
+        let delay = bucket.acquire();
+        assert!(delay > Duration::from_millis(150) && delay <= Duration::from_millis(200));
+        assert_eq!(bucket.take_notify(), None);
+
+        let mut bucket = TokenBucket::new(RateLimiter::TokenBucket {
+            rate: 1,
+            burst: 1,
+            notify: Seconds(1),
+        });
+        assert!(bucket.acquire().is_zero());
+        assert!(!bucket.acquire().is_zero());
+        assert_eq!(bucket.take_notify(), None);
+        ntex::time::sleep(Duration::from_millis(1100)).await;
+        assert!(!bucket.acquire().is_zero());
+        assert!(bucket.take_notify().is_some());
+        assert_eq!(bucket.take_notify(), None);
+
+        // connection stays throttled while messages wait for tokens
+        let mut bucket = TokenBucket::new(RateLimiter::TokenBucket {
+            rate: 10,
+            burst: 1,
+            notify: Seconds::ZERO,
+        });
+        assert!(bucket.wait().is_zero());
+        assert!(bucket.acquire().is_zero());
+        assert!(!bucket.wait().is_zero());
+        ntex::time::sleep(Duration::from_millis(110)).await;
+        assert!(bucket.wait().is_zero());
+        assert!(bucket.acquire().is_zero());
+        assert!(bucket.throttled.is_some());
+        ntex::time::sleep(Duration::from_millis(110)).await;
+        assert!(bucket.wait().is_zero());
+        assert!(bucket.acquire().is_zero());
+        assert!(bucket.throttled.is_none());
+    }
+
+    #[ntex::test]
+    async fn test_handshake_limit() {
+        let limit = HandshakeLimit::new(1);
+        let p1 = limit.acquire().await;
+
+        let mut w1 = Box::pin(limit.acquire());
+        let mut w2 = Box::pin(limit.acquire());
+        let w3 = limit.acquire();
+        assert!(ntex::util::lazy(|cx| w1.as_mut().poll(cx)).await.is_pending());
+        assert!(ntex::util::lazy(|cx| w2.as_mut().poll(cx)).await.is_pending());
+        drop(w3);
+        assert_eq!(limit.0.waiters.borrow().len(), 2);
+
+        // cancelled waiter returns permit to next waiter
+        drop(p1);
+        drop(w1);
+        let p2 = w2.await;
+        assert_eq!(limit.0.count.get(), 1);
+        drop(p2);
+        assert_eq!(limit.0.count.get(), 0);
+
+        // unlimited
+        let limit = HandshakeLimit::new(0);
+        let _p = (limit.acquire().await, limit.acquire().await);
+        assert_eq!(limit.0.count.get(), 2);
+    }
+
+    #[ntex::test]
+    async fn test_qos2_inflight_limit() {
+        let limit = Qos2InflightLimit::new(1);
+        let p1 = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        assert_eq!(limit.inflight(), 1);
+
+        let limit2 = limit.clone();
+        assert!(ntex::util::lazy(|cx| limit2.poll_acquire(cx)).await.is_pending());
+        assert!(ntex::util::lazy(|cx| limit2.poll_acquire(cx)).await.is_pending());
+        assert_eq!(limit.0.waiters.lock().unwrap().len(), 1);
+
+        drop(p1);
+        assert!(limit.0.waiters.lock().unwrap().is_empty());
+        let p2 = ntex::util::lazy(|cx| limit2.poll_acquire(cx)).await;
+        assert!(p2.is_ready());
+        assert_eq!(limit.inflight(), 1);
+        drop(p2);
+        assert_eq!(limit.inflight(), 0);
+
+        // unlimited
+        let limit = Qos2InflightLimit::new(0);
+        let _p = (limit.try_acquire().unwrap(), limit.try_acquire().unwrap());
+        assert_eq!(limit.inflight(), 2);
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::{cell::Cell, cell::RefCell, fmt, rc::Rc};
+
+use ntex::util::ByteString;
+
+use crate::session::SessionSink;
+
+/// Registry of connected sessions keyed by client id
+///
+/// Registry could be used for session takeover, new connection registers its
+/// sink during handshake and closes connection that used the same client id.
+/// Registration is synchronous, so if several connections with the same client
+/// id register concurrently, only the last one stays registered. Handshake that
+/// awaits after registration could check `Registration::is_active()` before ack.
+///
+/// State of evicted session is handed back to `Registration::on_evicted()`
+/// callback once evicted connection is closed and its session is released.
+///
+/// Registry is not thread safe, each server worker uses its own registry. Clones
+/// share the same entries.
+///
+/// ```rust,ignore
+/// async fn handshake(
+///     handshake: v3::Handshake,
+///     registry: SessionRegistry<v3::MqttSink>,
+/// ) -> Result<v3::HandshakeAck<MySession>, MyServerError> {
+///     let client_id = handshake.packet().client_id.clone();
+///     let (registration, prev) = registry.register(client_id, handshake.sink());
+///     let session_present = if let Some(prev) = prev {
+///         prev.close();
+///         true
+///     } else {
+///         false
+///     };
+///     Ok(handshake.ack(MySession { registration }, session_present))
+/// }
+/// ```
+pub struct SessionRegistry<T>(Rc<RegistryInner<T>>);
+
+struct RegistryInner<T> {
+    next_id: Cell<u64>,
+    entries: RefCell<HashMap<ByteString, Entry<T>>>,
+}
+
+struct Entry<T> {
+    id: u64,
+    session: T,
+    on_evicted: Option<Box<dyn FnOnce(&T)>>,
+}
+
+impl<T> SessionRegistry<T> {
+    /// Create empty registry
+    pub fn new() -> Self {
+        SessionRegistry(Rc::new(RegistryInner {
+            next_id: Cell::new(0),
+            entries: RefCell::new(HashMap::new()),
+        }))
+    }
+
+    /// Register session for client id.
+    ///
+    /// Returns registration guard and previously registered session. Session
+    /// is unregistered once guard is dropped, unless another session is
+    /// registered for the same client id. Eviction callback of previously
+    /// registered session is passed to its connection before return.
+    pub fn register(&self, client_id: ByteString, session: T) -> (Registration<T>, Option<T>) {
+        let id = self.0.next_id.get();
+        self.0.next_id.set(id + 1);
+
+        let prev = self
+            .0
+            .entries
+            .borrow_mut()
+            .insert(client_id.clone(), Entry { id, session, on_evicted: None });
+        let prev = prev.map(|entry| {
+            if let Some(f) = entry.on_evicted {
+                f(&entry.session);
+            }
+            entry.session
+        });
+        (Registration { registry: self.0.clone(), client_id, id }, prev)
+    }
+
+    /// Check if session is registered for client id
+    pub fn contains(&self, client_id: &str) -> bool {
+        self.0.entries.borrow().contains_key(client_id)
+    }
+
+    /// Number of registered sessions
+    pub fn len(&self) -> usize {
+        self.0.entries.borrow().len()
+    }
+
+    /// Check if registry is empty
+    pub fn is_empty(&self) -> bool {
+        self.0.entries.borrow().is_empty()
+    }
+}
+
+impl<T: Clone> SessionRegistry<T> {
+    /// Get session registered for client id
+    pub fn get(&self, client_id: &str) -> Option<T> {
+        self.0.entries.borrow().get(client_id).map(|entry| entry.session.clone())
+    }
+}
+
+impl<T> Default for SessionRegistry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for SessionRegistry<T> {
+    fn clone(&self) -> Self {
+        SessionRegistry(self.0.clone())
+    }
+}
+
+impl<T> fmt::Debug for SessionRegistry<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionRegistry").field("len", &self.len()).finish()
+    }
+}
+
+/// Session registration, session is unregistered on drop
+pub struct Registration<T> {
+    registry: Rc<RegistryInner<T>>,
+    client_id: ByteString,
+    id: u64,
+}
+
+impl<T> Registration<T> {
+    /// Client id of registered session
+    pub fn client_id(&self) -> &ByteString {
+        &self.client_id
+    }
+
+    /// Check if session is still registered.
+    ///
+    /// Returns `false` if another session is registered for the same client id.
+    pub fn is_active(&self) -> bool {
+        self.registry
+            .entries
+            .borrow()
+            .get(&self.client_id)
+            .map(|entry| entry.id == self.id)
+            .unwrap_or(false)
+    }
+
+    /// Set callback that receives state of the session once session is
+    /// evicted by another session with the same client id.
+    ///
+    /// Evicted connection surrenders its state after connection is closed
+    /// and all references to the session are dropped. `St` must be state
+    /// type of the registered connection, otherwise callback is not called.
+    /// Callback is not called if session is already evicted or if
+    /// registration is dropped.
+    pub fn on_evicted<St, F>(&self, f: F)
+    where
+        T: SessionSink,
+        St: 'static,
+        F: FnOnce(St) + 'static,
+    {
+        if let Some(entry) = self.registry.entries.borrow_mut().get_mut(&self.client_id) {
+            if entry.id == self.id {
+                entry.on_evicted =
+                    Some(Box::new(move |sink: &T| sink.set_evicted(Box::new(f))));
+            }
+        }
+    }
+}
+
+impl<T> Drop for Registration<T> {
+    fn drop(&mut self) {
+        if self.is_active() {
+            let _entry = self.registry.entries.borrow_mut().remove(&self.client_id);
+        }
+    }
+}
+
+impl<T> fmt::Debug for Registration<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Registration")
+            .field("client_id", &self.client_id)
+            .field("active", &self.is_active())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let registry = SessionRegistry::new();
+        let client_id = ByteString::from_static("client");
+
+        let (r1, prev) = registry.register(client_id.clone(), 1);
+        assert_eq!(prev, None);
+        assert!(r1.is_active());
+        assert_eq!(registry.get("client"), Some(1));
+
+        // takeover
+        let (r2, prev) = registry.register(client_id.clone(), 2);
+        assert_eq!(prev, Some(1));
+        assert!(!r1.is_active());
+        assert!(r2.is_active());
+
+        // stale registration does not remove new session
+        drop(r1);
+        assert_eq!(registry.get("client"), Some(2));
+        assert_eq!(registry.len(), 1);
+
+        drop(r2);
+        assert!(!registry.contains("client"));
+        assert!(registry.is_empty());
+    }
+
+    #[derive(Clone, Default)]
+    struct TestSink(Rc<RefCell<Option<Box<dyn std::any::Any>>>>);
+
+    impl SessionSink for TestSink {
+        fn set_evicted<St: 'static>(&self, f: Box<dyn FnOnce(St)>) {
+            *self.0.borrow_mut() = Some(Box::new(f));
+        }
+
+        fn take_evicted<St: 'static>(&self) -> Option<Box<dyn FnOnce(St)>> {
+            let f = self.0.borrow_mut().take()?;
+            f.downcast::<Box<dyn FnOnce(St)>>().ok().map(|f| *f)
+        }
+    }
+
+    #[test]
+    fn test_on_evicted() {
+        let registry = SessionRegistry::new();
+        let client_id = ByteString::from_static("client");
+        let evicted = Rc::new(Cell::new(0));
+
+        let sink1 = TestSink::default();
+        let (r1, _) = registry.register(client_id.clone(), sink1.clone());
+        let evicted2 = evicted.clone();
+        r1.on_evicted(move |st: usize| evicted2.set(evicted2.get() + st));
+        assert!(sink1.0.borrow().is_none());
+
+        // callback is passed to evicted session
+        let (r2, _) = registry.register(client_id.clone(), TestSink::default());
+        assert!(sink1.0.borrow().is_some());
+        sink1.take_evicted::<usize>().unwrap()(1);
+        assert_eq!(evicted.get(), 1);
+
+        // callback for different state type is dropped
+        let sink2 = registry.get("client").unwrap();
+        r2.on_evicted(move |st: usize| evicted.set(st));
+        let (_r3, _) = registry.register(client_id.clone(), TestSink::default());
+        assert!(sink2.take_evicted::<String>().is_none());
+        assert!(sink2.0.borrow().is_none());
+
+        // evicted registration can not set callback
+        r1.on_evicted(|_: usize| panic!());
+        let (_r4, _) = registry.register(client_id.clone(), TestSink::default());
+        assert!(sink1.0.borrow().is_none());
+
+        // dropped registration does not pass callback
+        let sink5 = TestSink::default();
+        let (r5, _) = registry.register(ByteString::from_static("other"), sink5.clone());
+        r5.on_evicted(|_: usize| panic!());
+        drop(r5);
+        let _ = registry.register(ByteString::from_static("other"), TestSink::default());
+        assert!(sink5.0.borrow().is_none());
+    }
+}
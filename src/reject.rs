@@ -0,0 +1,66 @@
+use std::cell::Cell;
+
+/// Reason of inbound publish rejection
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RejectReason {
+    /// Inbound publish rate limit exceeded
+    RateLimitExceeded,
+    /// Publish QoS is lower than server's minimum QoS
+    QosNotSupported,
+    /// Number of in-flight messages exceeded
+    ReceiveMaximumExceeded,
+    /// Publish service failed or returned failure reason code
+    Service,
+    /// Global QoS 2 in-flight limit is reached (mqtt v5 only)
+    QuotaExceeded,
+    /// Number of distinct publish topics is reached (mqtt v5 only)
+    TopicCardinalityExceeded,
+    /// Retained publish is received, but retain is not available (mqtt v5 only)
+    RetainNotSupported,
+}
+
+/// Reports every n-th rejected publish packet
+pub(crate) struct RejectSampler<P> {
+    rate: u32,
+    count: Cell<u32>,
+    hook: Box<dyn Fn(&P, RejectReason)>,
+}
+
+impl<P> RejectSampler<P> {
+    pub(crate) fn new<F>(rate: u32, f: F) -> Self
+    where
+        F: Fn(&P, RejectReason) + 'static,
+    {
+        RejectSampler { rate: rate.max(1), count: Cell::new(0), hook: Box::new(f) }
+    }
+
+    /// Register rejected publish packet
+    pub(crate) fn rejected(&self, pkt: &P, reason: RejectReason) {
+        let count = self.count.get();
+        self.count.set(if count + 1 >= self.rate { 0 } else { count + 1 });
+        if count == 0 {
+            (self.hook)(pkt, reason)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_sampling() {
+        let reported = Rc::new(Cell::new(0));
+        let reported2 = reported.clone();
+        let sampler =
+            RejectSampler::new(3, move |_: &(), _| reported2.set(reported2.get() + 1));
+        for _ in 0..7 {
+            sampler.rejected(&(), RejectReason::Service);
+        }
+        assert_eq!(reported.get(), 3);
+
+        let sampler = RejectSampler::new(0, |_: &(), _| ());
+        assert_eq!(sampler.rate, 1);
+    }
+}
@@ -0,0 +1,274 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::{cmp, future::Future, net::SocketAddr, pin::Pin, rc::Rc};
+
+use ntex::io::{types, IoBoxed};
+use ntex::time::Deadline;
+use ntex::util::{select, Bytes, Either, Extensions};
+
+use crate::error::{MqttError, ProtocolError};
+use crate::utils::decode_remaining_length;
+
+/// Selector variants statistics
+///
+/// Handle keeps number of connections matched by each selector's variant.
+/// Counters are indexed in order variants were added to the selector.
+#[derive(Clone, Debug, Default)]
+pub struct SelectorStats(Arc<RwLock<Vec<AtomicUsize>>>);
+
+impl SelectorStats {
+    /// Number of connections matched by variant with index `idx`
+    pub fn get(&self, idx: usize) -> Option<usize> {
+        self.0.read().unwrap().get(idx).map(|cnt| cnt.load(Ordering::Relaxed))
+    }
+
+    /// Number of connections matched by each variant
+    pub fn counts(&self) -> Vec<usize> {
+        self.0.read().unwrap().iter().map(|cnt| cnt.load(Ordering::Relaxed)).collect()
+    }
+
+    /// Add counter for new variant, returns index of the variant
+    pub(crate) fn add_variant(&self) -> usize {
+        let mut counts = self.0.write().unwrap();
+        counts.push(AtomicUsize::new(0));
+        counts.len() - 1
+    }
+
+    pub(crate) fn matched(&self, idx: usize) {
+        if let Some(cnt) = self.0.read().unwrap().get(idx) {
+            cnt.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Transport properties of connection, available to selector's variant checks
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SelectContext {
+    peer_addr: Option<SocketAddr>,
+    is_tls: bool,
+}
+
+impl SelectContext {
+    pub(crate) fn new(io: &IoBoxed) -> Self {
+        SelectContext {
+            peer_addr: io.query::<types::PeerAddr>().get().map(|addr| addr.0),
+            // tls filters report negotiated application protocol
+            is_tls: io.query::<types::HttpProtocol>().get().is_some(),
+        }
+    }
+
+    #[inline]
+    /// Remote address of connection, if transport provides it
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    #[inline]
+    /// Check if connection is secured by tls
+    pub fn is_tls(&self) -> bool {
+        self.is_tls
+    }
+
+    /// Override remote address, i.e. with address reported by pre-connect hook
+    pub(crate) fn set_peer_addr(&mut self, addr: Option<SocketAddr>) {
+        if addr.is_some() {
+            self.peer_addr = addr;
+        }
+    }
+}
+
+/// Result of selector's variant check
+///
+/// Check selects variant by returning `true` or `Some(data)`, data is
+/// available to handshake service via `Handshake::data()`.
+pub trait VariantMatch {
+    /// Convert to handshake data, `None` if variant is not selected
+    fn into_selected(self) -> Option<Extensions>;
+}
+
+impl VariantMatch for bool {
+    fn into_selected(self) -> Option<Extensions> {
+        if self {
+            Some(Extensions::new())
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: 'static> VariantMatch for Option<T> {
+    fn into_selected(self) -> Option<Extensions> {
+        self.map(|data| {
+            let mut ext = Extensions::new();
+            ext.insert(data);
+            ext
+        })
+    }
+}
+
+/// Outcome of variant check, `Some` selects variant with data for handshake
+pub(crate) type Selected = Option<Extensions>;
+
+/// Count match of variant as soon as its check selects connection
+pub(crate) async fn count_match<E, R>(stats: SelectorStats, idx: usize, check: R) -> R::Output
+where
+    R: Future<Output = Result<Selected, E>>,
+{
+    let selected = check.await?;
+    if selected.is_some() {
+        stats.matched(idx);
+    }
+    Ok(selected)
+}
+
+pub(crate) type ProtocolErrorHook =
+    Rc<dyn Fn(&ProtocolError, Option<SocketAddr>, &PacketHeader)>;
+
+/// Max size of fixed header, packet type byte and 4 bytes of remaining length
+const MAX_HEADER_SIZE: usize = 5;
+
+/// Fixed header of the first packet of connection, passed to protocol error hook
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PacketHeader {
+    header: Bytes,
+    remaining: Option<u32>,
+}
+
+impl PacketHeader {
+    /// Raw bytes of fixed header, packet type byte and remaining length
+    ///
+    /// If remaining length is malformed, contains up to 5 received bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.header
+    }
+
+    /// Packet type byte, including flags
+    pub fn packet_type(&self) -> Option<u8> {
+        self.header.first().copied()
+    }
+
+    /// Length of the packet including fixed header
+    ///
+    /// Returns `None` if remaining length is malformed or not complete.
+    pub fn packet_len(&self) -> Option<usize> {
+        self.remaining.map(|len| self.header.len() + len as usize)
+    }
+
+    fn from_buf(buf: &[u8]) -> Option<Self> {
+        if buf.is_empty() {
+            return None;
+        }
+        let buf = &buf[..cmp::min(buf.len(), MAX_HEADER_SIZE)];
+        match decode_remaining_length(&buf[1..], 0) {
+            Ok(Some((len, consumed))) => Some(PacketHeader {
+                header: Bytes::copy_from_slice(&buf[..consumed + 1]),
+                remaining: Some(len),
+            }),
+            Ok(None) => None,
+            Err(_) => {
+                Some(PacketHeader { header: Bytes::copy_from_slice(buf), remaining: None })
+            }
+        }
+    }
+}
+
+/// Wait for complete fixed header of the first packet and copy it from read buffer
+pub(crate) async fn peek_header(io: &IoBoxed) -> PacketHeader {
+    loop {
+        if let Some(header) = io.with_read_buf(|buf| PacketHeader::from_buf(buf)) {
+            return header;
+        }
+        if let Ok(Some(_)) = io.read_ready().await {
+            continue;
+        }
+        // connection is closed, keep incomplete header
+        return io.with_read_buf(|buf| PacketHeader {
+            header: Bytes::copy_from_slice(&buf[..cmp::min(buf.len(), MAX_HEADER_SIZE)]),
+            remaining: None,
+        });
+    }
+}
+
+/// Report protocol error of connection handshake to the hook
+pub(crate) fn protocol_error<E>(
+    hook: &Option<ProtocolErrorHook>,
+    io: &IoBoxed,
+    header: Option<&PacketHeader>,
+    err: MqttError<E>,
+) -> MqttError<E> {
+    if let (Some(hook), Some(header), MqttError::Protocol(ref e)) = (hook, header, &err) {
+        (*hook)(e, io.query::<types::PeerAddr>().get().map(|addr| addr.0), header);
+    }
+    err
+}
+
+pub(crate) type PreConnectHook<E> =
+    Rc<dyn Fn(IoBoxed) -> Pin<Box<dyn Future<Output = Result<PreConnect, E>>>>>;
+
+/// Io and remote address of the client, returned by pre-connect hook
+pub(crate) type PreConnect = (IoBoxed, Option<SocketAddr>);
+
+/// Call pre-connect hook, hook's running time counts towards handshake timeout
+pub(crate) async fn pre_connect<E>(
+    io: IoBoxed,
+    timeout: &mut Deadline,
+    hook: &PreConnectHook<E>,
+) -> Result<PreConnect, MqttError<E>> {
+    match select(&mut *timeout, (*hook)(io)).await {
+        Either::Left(_) => Err(MqttError::HandshakeTimeout),
+        Either::Right(res) => res.map_err(MqttError::Service),
+    }
+}
+
+/// Result of inspecting first bytes of connection
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SniffResult {
+    /// Continue with mqtt handshake
+    Mqtt,
+    /// Not enough data, wait for more bytes
+    NeedMore,
+    /// Pass connection to `IoFallback` service, buffered bytes are preserved
+    Fallback,
+    /// Close connection
+    Reject,
+}
+
+/// Selector's fallback service for connections passed by sniff hook
+///
+/// Service receives io with all buffered bytes, see `Selector::fallback()`.
+pub struct IoFallback<S>(S);
+
+impl<S> IoFallback<S> {
+    /// Create fallback for io service factory
+    pub fn new(service: S) -> Self {
+        IoFallback(service)
+    }
+
+    pub(crate) fn into_inner(self) -> S {
+        self.0
+    }
+}
+
+/// Call sniff hook on buffered bytes until it makes a decision
+pub(crate) async fn sniff<E>(
+    io: &IoBoxed,
+    timeout: &mut Deadline,
+    hook: &dyn Fn(&[u8]) -> SniffResult,
+) -> Result<SniffResult, MqttError<E>> {
+    loop {
+        let result =
+            io.with_read_buf(
+                |buf| if buf.is_empty() { SniffResult::NeedMore } else { hook(buf) },
+            );
+        if result != SniffResult::NeedMore {
+            return Ok(result);
+        }
+
+        match select(&mut *timeout, io.read_ready()).await {
+            Either::Left(_) => return Err(MqttError::HandshakeTimeout),
+            Either::Right(Ok(Some(_))) => (),
+            Either::Right(Ok(None)) => return Err(MqttError::Disconnected(None)),
+            Either::Right(Err(err)) => return Err(MqttError::Disconnected(Some(err))),
+        }
+    }
+}
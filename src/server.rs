@@ -10,6 +10,17 @@ use crate::version::{ProtocolVersion, VersionCodec};
 use crate::{error::MqttError, v3, v5};
 
 /// Mqtt Server
+///
+/// Tcp keep-alive (`SO_KEEPALIVE`) is a transport level setting and it is not
+/// related to mqtt keep-alive. Io objects do not expose socket options, so tcp
+/// keep-alive must be configured on the listener socket, accepted connections
+/// inherit it. Configure `std::net::TcpListener` and register it with
+/// `ntex::server::Server::build().listen()`.
+///
+/// Tls handshakes are performed by tls acceptor before mqtt server receives
+/// connection. Number of concurrent tls handshakes is limited per worker
+/// with `ntex_tls::max_concurrent_ssl_accept()`, excess connections are queued
+/// by acceptor. Limit does not affect plaintext connections.
 pub struct MqttServer<V3, V5, Err, InitErr> {
     v3: V3,
     v5: V5,
@@ -7,7 +7,7 @@ use ntex::service::{Service, ServiceFactory};
 use ntex::time::{Deadline, Seconds};
 use ntex::util::{select, Either};
 
-use crate::io::Dispatcher;
+use crate::io::{Dispatcher, KeepAlive};
 
 type ResponseItem<U> = Option<<U as Encoder>::Item>;
 
@@ -59,7 +59,7 @@ where
             Error = C::Error,
             InitError = C::Error,
         > + 'static,
-    Codec: Decoder + Encoder + Clone + 'static,
+    Codec: Decoder + Encoder + KeepAlive + Clone + 'static,
 {
     type Response = ();
     type Error = C::Error;
@@ -85,7 +85,7 @@ where
             Error = C::Error,
             InitError = C::Error,
         > + 'static,
-    Codec: Decoder + Encoder + Clone + 'static,
+    Codec: Decoder + Encoder + KeepAlive + Clone + 'static,
 {
     type Response = ();
     type Error = C::Error;
@@ -110,7 +110,7 @@ where
             Error = C::Error,
             InitError = C::Error,
         > + 'static,
-    Codec: Decoder + Encoder + Clone + 'static,
+    Codec: Decoder + Encoder + KeepAlive + Clone + 'static,
 {
     type Response = ();
     type Error = C::Error;
@@ -142,7 +142,7 @@ where
             Error = C::Error,
             InitError = C::Error,
         > + 'static,
-    Codec: Decoder + Encoder + Clone + 'static,
+    Codec: Decoder + Encoder + KeepAlive + Clone + 'static,
 {
     type Response = ();
     type Error = C::Error;
@@ -169,10 +169,13 @@ where
                 log::trace!("Connection handshake failed: {:?}", e);
                 e
             })?;
-            log::trace!("Connection handshake succeeded");
+            log::trace!("{}: Connection handshake succeeded", codec.connection_id());
 
             let handler = handler.new_service(session).await?;
-            log::trace!("Connection handler is created, starting dispatcher");
+            log::trace!(
+                "{}: Connection handler is created, starting dispatcher",
+                codec.connection_id()
+            );
 
             Dispatcher::new(io, codec, handler)
                 .keepalive_timeout(keepalive)
@@ -195,7 +198,7 @@ where
             Error = C::Error,
             InitError = C::Error,
         > + 'static,
-    Codec: Decoder + Encoder + Clone + 'static,
+    Codec: Decoder + Encoder + KeepAlive + Clone + 'static,
 {
     type Response = ();
     type Error = C::Error;
@@ -229,7 +232,7 @@ where
             Error = C::Error,
             InitError = C::Error,
         > + 'static,
-    Codec: Decoder + Encoder + Clone + 'static,
+    Codec: Decoder + Encoder + KeepAlive + Clone + 'static,
 {
     type Response = ();
     type Error = C::Error;
@@ -260,10 +263,16 @@ where
                             log::trace!("Connection handshake failed: {:?}", e);
                             e
                         })?;
-                        log::trace!("Connection handshake succeeded");
+                        log::trace!(
+                            "{}: Connection handshake succeeded",
+                            codec.connection_id()
+                        );
 
                         let handler = handler.new_service(st).await?;
-                        log::trace!("Connection handler is created, starting dispatcher");
+                        log::trace!(
+                            "{}: Connection handler is created, starting dispatcher",
+                            codec.connection_id()
+                        );
 
                         Ok::<_, C::Error>((io, codec, ka, handler))
                     }),
@@ -1,14 +1,142 @@
-use std::ops::Deref;
-use std::rc::Rc;
+use std::{
+    collections::BTreeMap, mem::ManuallyDrop, ops::Deref, rc::Rc, rc::Weak, time::Instant,
+};
+
+use ntex::time::Seconds;
+use ntex::util::ByteString;
+
+use crate::error::ProtocolError;
+use crate::types::{PacketMask, QoS};
 
 /// Mqtt connection session
+///
+/// Session state is shared by publish and control services of the connection,
+/// services get references to the state, so state can not be replaced during
+/// connection lifetime. Values that change, for example client role after
+/// re-authentication, must use interior mutability (`Cell`, `RefCell`) within state.
+///
+/// Session is the single handle services need, it dereferences to the state
+/// and gives access to the connection's sink.
+///
+/// ```rust,ignore
+/// struct St {
+///     role: RefCell<Role>,
+/// }
+///
+/// fn_factory_with_config(|session: Session<St>| async move {
+///     Ok(fn_service(move |publish: Publish| {
+///         let session = session.clone();
+///         async move {
+///             if *session.role.borrow() == Role::Echo {
+///                 let topic = publish.packet().topic.clone();
+///                 session.sink().publish(topic, publish.payload().clone()).send_at_most_once()?;
+///             }
+///             Ok(publish.ack())
+///         }
+///     }))
+/// })
+/// ```
 pub struct Session<T, St>(Rc<SessionInner<T, St>>);
 
+/// Session reference that does not keep session alive
+pub(crate) struct WeakSession<T, St>(Weak<SessionInner<T, St>>);
+
 struct SessionInner<T, St> {
-    st: St,
+    st: ManuallyDrop<St>,
     sink: T,
-    max_receive: u16,
-    max_topic_alias: u16,
+    negotiated: NegotiatedConfig,
+    // hands state back to eviction callback of the session
+    release: fn(&T, St),
+}
+
+/// Sink that keeps eviction callback of the session, see `Registration::on_evicted()`
+pub trait SessionSink {
+    /// Set callback, state is passed to callback once session is released
+    fn set_evicted<St: 'static>(&self, f: Box<dyn FnOnce(St)>);
+
+    /// Take callback, callback is dropped if it expects different state type
+    fn take_evicted<St: 'static>(&self) -> Option<Box<dyn FnOnce(St)>>;
+}
+
+fn release<T: SessionSink, St: 'static>(sink: &T, st: St) {
+    if let Some(f) = sink.take_evicted::<St>() {
+        f(st)
+    }
+}
+
+impl<T, St> Drop for SessionInner<T, St> {
+    fn drop(&mut self) {
+        // safety: state is not used after drop
+        let st = unsafe { ManuallyDrop::take(&mut self.st) };
+        (self.release)(&self.sink, st)
+    }
+}
+
+/// Connection parameters negotiated during handshake
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct NegotiatedConfig {
+    /// Protocol level, `4` for mqtt v3.1.1 and `5` for mqtt v5
+    pub protocol_level: u8,
+    /// Client id, id assigned by server for mqtt v5
+    pub client_id: ByteString,
+    /// Keep-alive timeout
+    #[serde(serialize_with = "serialize_seconds")]
+    pub keepalive: Seconds,
+    /// Max inbound packet size, `0` means unlimited
+    pub max_inbound_size: u32,
+    /// Max outbound packet size requested by client, `0` means unlimited
+    pub max_outbound_size: u32,
+    /// Server receive maximum, `0` means unlimited (mqtt v5 only)
+    pub receive_max: u16,
+    /// Client receive maximum (mqtt v5 only)
+    pub peer_receive_max: u16,
+    /// Server topic alias maximum (mqtt v5 only)
+    pub topic_alias_max: u16,
+    /// Session expiry interval in seconds (mqtt v5 only)
+    pub session_expiry: u32,
+    /// Clean session (clean start for mqtt v5) flag
+    pub clean_start: bool,
+    /// Max QoS supported by server (mqtt v5 only)
+    pub max_qos: Option<QoS>,
+}
+
+fn serialize_seconds<S: serde::Serializer>(val: &Seconds, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u16(val.0)
+}
+
+/// Reason of connection termination, see `MqttServer::on_disconnect()`
+#[derive(Debug, Clone)]
+pub enum DisconnectReason {
+    /// Client sent DISCONNECT packet
+    Clean,
+    /// Client did not send any packet within keep-alive timeout
+    KeepaliveTimeout,
+    /// Protocol error, including read and write timeouts
+    Protocol(ProtocolError),
+    /// Peer closed connection without DISCONNECT packet or io error occurred
+    Io,
+    /// Connection is closed by server, i.e. by application, max connection
+    /// lifetime or server shutdown
+    ServerEvicted,
+}
+
+/// Serializable snapshot of connection state
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ConnectionDiagnostics {
+    /// Connection id, see `Session::connection_id()`
+    pub connection_id: u64,
+    /// Connection parameters negotiated during handshake
+    pub negotiated: NegotiatedConfig,
+    /// Number of outbound packets waiting for acknowledgement
+    pub inflight: usize,
+    /// Number of outbound packets that could be sent before in-flight limit is reached
+    pub credit: usize,
+    /// Size of write buffer in bytes, not yet flushed to the peer
+    pub pending_write_bytes: usize,
+    /// Seconds elapsed since last PINGREQ packet
+    pub last_ping_secs: Option<u64>,
+    /// Topic filters confirmed by control service
+    pub subscriptions: BTreeMap<ByteString, QoS>,
 }
 
 impl<T, St> Clone for Session<T, St> {
@@ -19,26 +147,189 @@ impl<T, St> Clone for Session<T, St> {
 }
 
 impl<T, St> Session<T, St> {
-    pub(crate) fn new(st: St, sink: T) -> Self {
-        Session(Rc::new(SessionInner { st, sink, max_receive: 0, max_topic_alias: 0 }))
-    }
-
-    pub(crate) fn new_v5(st: St, sink: T, max_receive: u16, max_topic_alias: u16) -> Self {
-        Session(Rc::new(SessionInner { st, sink, max_receive, max_topic_alias }))
+    pub(crate) fn new(st: St, sink: T, negotiated: NegotiatedConfig) -> Self
+    where
+        T: SessionSink,
+        St: 'static,
+    {
+        Session(Rc::new(SessionInner {
+            st: ManuallyDrop::new(st),
+            sink,
+            negotiated,
+            release: release::<T, St>,
+        }))
     }
 
     #[inline]
+    /// Returns sink of the connection, sink could be used to publish
+    /// messages to the client
     pub fn sink(&self) -> &T {
         &self.0.sink
     }
 
     #[inline]
+    /// Returns session state, same state is also accessible via `Deref`
     pub fn state(&self) -> &St {
         &self.0.st
     }
 
+    /// Snapshot of connection parameters negotiated during handshake.
+    ///
+    /// Parameters changed after handshake, i.e. keep-alive timeout,
+    /// are not reflected, see `keepalive()` for current keep-alive timeout.
+    #[inline]
+    pub fn negotiated(&self) -> &NegotiatedConfig {
+        &self.0.negotiated
+    }
+
     pub(crate) fn params(&self) -> (u16, u16) {
-        (self.0.max_receive, self.0.max_topic_alias)
+        (self.0.negotiated.receive_max, self.0.negotiated.topic_alias_max)
+    }
+
+    pub(crate) fn downgrade(&self) -> WeakSession<T, St> {
+        WeakSession(Rc::downgrade(&self.0))
+    }
+}
+
+impl<T, St> WeakSession<T, St> {
+    pub(crate) fn upgrade(&self) -> Option<Session<T, St>> {
+        self.0.upgrade().map(Session)
+    }
+}
+
+impl<St> Session<crate::v3::MqttSink, St> {
+    /// Connection id.
+    ///
+    /// Id is assigned once connection is accepted, ids are increasing and
+    /// unique within process. Internal log records of connection are prefixed
+    /// with the id.
+    pub fn connection_id(&self) -> u64 {
+        self.0.sink.connection_id()
+    }
+
+    /// Ban client for `duration` in server's ban list.
+    ///
+    /// Client id and peer address are passed to the ban list, new connections
+    /// of the client are rejected until ban expires. Current connection is
+    /// not closed. Returns `false` if server does not use ban list.
+    pub fn quarantine(&self, duration: Seconds) -> bool {
+        self.0.sink.quarantine(&self.0.negotiated.client_id, duration)
+    }
+
+    /// Time of last PINGREQ packet received from the client
+    pub fn last_ping_at(&self) -> Option<Instant> {
+        self.0.sink.last_ping_at()
+    }
+
+    /// Will message from CONNECT packet.
+    ///
+    /// Returns `None` if client did not set will or will is discarded.
+    pub fn will(&self) -> Option<crate::v3::codec::LastWill> {
+        self.0.sink.will()
+    }
+
+    /// Serializable snapshot of connection state
+    pub fn diagnostics(&self) -> ConnectionDiagnostics {
+        let sink = &self.0.sink;
+        ConnectionDiagnostics {
+            connection_id: sink.connection_id(),
+            negotiated: self.0.negotiated.clone(),
+            inflight: sink.inflight_count(),
+            credit: sink.credit(),
+            pending_write_bytes: sink.pending_write_bytes(),
+            last_ping_secs: sink.last_ping_at().map(|at| at.elapsed().as_secs()),
+            subscriptions: sink.subscriptions(),
+        }
+    }
+
+    /// Effective keep-alive timeout of the connection.
+    ///
+    /// Returns timeout negotiated during handshake, server applies grace
+    /// period to client's keep-alive, or timeout set with `set_keepalive()`.
+    pub fn keepalive(&self) -> Seconds {
+        self.0.sink.keepalive_timeout().unwrap_or(self.0.negotiated.keepalive)
+    }
+
+    /// Set keep-alive timeout for the connection.
+    ///
+    /// Running keep-alive timer is restarted with new timeout.
+    pub fn set_keepalive(&self, timeout: Seconds) {
+        self.0.sink.set_keepalive(timeout)
+    }
+
+    /// Set packet types peer is allowed to send.
+    ///
+    /// Disallowed packet is handled as protocol error.
+    pub fn set_allowed_packets(&self, mask: PacketMask) {
+        self.0.sink.set_allowed_packets(mask)
+    }
+}
+
+impl<St> Session<crate::v5::MqttSink, St> {
+    /// Connection id.
+    ///
+    /// Id is assigned once connection is accepted, ids are increasing and
+    /// unique within process. Internal log records of connection are prefixed
+    /// with the id.
+    pub fn connection_id(&self) -> u64 {
+        self.0.sink.connection_id()
+    }
+
+    /// Ban client for `duration` in server's ban list.
+    ///
+    /// Client id and peer address are passed to the ban list, new connections
+    /// of the client are rejected until ban expires. Current connection is
+    /// not closed. Returns `false` if server does not use ban list.
+    pub fn quarantine(&self, duration: Seconds) -> bool {
+        self.0.sink.quarantine(&self.0.negotiated.client_id, duration)
+    }
+
+    /// Time of last PINGREQ packet received from the client
+    pub fn last_ping_at(&self) -> Option<Instant> {
+        self.0.sink.last_ping_at()
+    }
+
+    /// Will message from CONNECT packet.
+    ///
+    /// Returns `None` if client did not set will or will is discarded.
+    pub fn will(&self) -> Option<crate::v5::codec::LastWill> {
+        self.0.sink.will()
+    }
+
+    /// Serializable snapshot of connection state
+    pub fn diagnostics(&self) -> ConnectionDiagnostics {
+        let sink = &self.0.sink;
+        ConnectionDiagnostics {
+            connection_id: sink.connection_id(),
+            negotiated: self.0.negotiated.clone(),
+            inflight: sink.inflight_count(),
+            credit: sink.credit(),
+            pending_write_bytes: sink.pending_write_bytes(),
+            last_ping_secs: sink.last_ping_at().map(|at| at.elapsed().as_secs()),
+            subscriptions: sink.subscriptions(),
+        }
+    }
+
+    /// Effective keep-alive timeout of the connection.
+    ///
+    /// Returns timeout negotiated during handshake, server applies grace
+    /// period to client's keep-alive, or timeout set with `set_keepalive()`.
+    pub fn keepalive(&self) -> Seconds {
+        self.0.sink.keepalive_timeout().unwrap_or(self.0.negotiated.keepalive)
+    }
+
+    /// Set keep-alive timeout for the connection.
+    ///
+    /// Running keep-alive timer is restarted with new timeout.
+    pub fn set_keepalive(&self, timeout: Seconds) {
+        self.0.sink.set_keepalive(timeout)
+    }
+
+    /// Set packet types peer is allowed to send.
+    ///
+    /// Disallowed packet is handled as protocol error.
+    pub fn set_allowed_packets(&self, mask: PacketMask) {
+        self.0.sink.set_allowed_packets(mask)
     }
 }
 
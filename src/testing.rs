@@ -0,0 +1,194 @@
+//! Utilities for testing mqtt servers without network
+//!
+//! Module is available with `testing` feature.
+use std::fmt;
+
+use ntex::codec::{Decoder, Encoder};
+use ntex::io::{Io, IoBoxed};
+use ntex::service::{Service, ServiceFactory};
+use ntex::testing::Io as IoTest;
+use ntex::time::{sleep, Millis};
+use ntex::util::{Bytes, BytesMut};
+
+use crate::{v3, v5};
+
+/// Read budget of the client side, budget is refilled before each read
+const READ_BUF_CAP: usize = 64 * 1024;
+
+/// Step of the scripted client session
+#[derive(Debug, Clone)]
+pub enum PacketScript<P> {
+    /// Encode and send packet to the server
+    Packet(P),
+    /// Send raw bytes to the server, could be used for malformed packets
+    Raw(Bytes),
+    /// Wait before next step
+    Delay(Millis),
+}
+
+/// Packet type that could be replayed against server
+pub trait ReplayPacket: Sized {
+    #[doc(hidden)]
+    type Codec: Encoder<Item = Self> + Decoder<Item = Self> + Default;
+}
+
+impl ReplayPacket for v3::codec::Packet {
+    type Codec = v3::codec::Codec;
+}
+
+impl ReplayPacket for v5::codec::Packet {
+    type Codec = v5::codec::Codec;
+}
+
+/// Feed scripted packets to the server and collect its responses
+///
+/// Server runs on in-memory io, script steps get executed in order. Once
+/// script is completed, responses are collected until server reads all
+/// packets and flushes its write buffer, or until server closes connection.
+/// Responses of service calls that are still pending at that point are not
+/// collected, `PacketScript::Delay` could be used to wait for slow services.
+/// Bytes that could not be decoded are ignored.
+///
+/// ```rust,ignore
+/// let responses = replay(
+///     v3::MqttServer::new(handshake).publish(publish).finish(),
+///     &[PacketScript::Packet(connect), PacketScript::Packet(publish)],
+/// )
+/// .await;
+/// ```
+pub async fn replay<S, P>(server: S, script: &[PacketScript<P>]) -> Vec<P>
+where
+    S: ServiceFactory<IoBoxed, Response = ()>,
+    S::Error: fmt::Debug,
+    S::InitError: fmt::Debug,
+    S::Service: 'static,
+    P: ReplayPacket + Clone,
+    <P::Codec as Encoder>::Error: fmt::Debug,
+{
+    let srv = server.new_service(()).await.expect("Cannot create server service");
+    let (client, server_io) = IoTest::create();
+    client.remote_buffer_cap(READ_BUF_CAP);
+    let server_io = Io::new(server_io);
+    let state = server_io.get_ref();
+    ntex::rt::spawn(async move {
+        if let Err(e) = srv.call(IoBoxed::from(server_io)).await {
+            log::trace!("Replayed connection is terminated with error: {:?}", e);
+        }
+    });
+
+    let codec = P::Codec::default();
+    for step in script {
+        match step {
+            PacketScript::Packet(pkt) => {
+                let mut buf = BytesMut::new();
+                codec.encode(pkt.clone(), &mut buf).expect("Cannot encode packet");
+                client.write(buf);
+            }
+            PacketScript::Raw(data) => client.write(data),
+            PacketScript::Delay(delay) => sleep(*delay).await,
+        }
+    }
+
+    let mut buf = BytesMut::new();
+    loop {
+        client.remote_buffer_cap(READ_BUF_CAP);
+        let data = client.read_any();
+        let flushed = data.is_empty()
+            && state.with_write_buf(|buf| buf.is_empty()).unwrap_or(true)
+            && (state.is_closed()
+                || (client.remote_buffer(|buf| buf.is_empty())
+                    && state.with_read_buf(|buf| buf.is_empty())));
+        if flushed {
+            break;
+        }
+        buf.extend_from_slice(&data);
+        sleep(Millis(1)).await;
+    }
+    client.close().await;
+
+    let mut responses = Vec::new();
+    while let Ok(Some(pkt)) = codec.decode(&mut buf) {
+        responses.push(pkt);
+    }
+    responses
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex::util::{ByteString, Ready};
+
+    use super::*;
+    use crate::v3::{codec, Handshake, MqttServer, PreConnackPublishPolicy, Publish};
+
+    fn connect() -> codec::Packet {
+        codec::Packet::Connect(Box::new(
+            codec::Connect::default().client_id(ByteString::from_static("user")),
+        ))
+    }
+
+    #[ntex::test]
+    async fn test_replay() {
+        let server = || {
+            MqttServer::new(|con: Handshake| Ready::Ok::<_, ()>(con.ack((), false)))
+                // script pipelines publish packets right after connect
+                .pre_connack_publish_policy(PreConnackPublishPolicy::Buffer)
+                .publish(|_: Publish| Ready::Ok::<_, ()>(()))
+                .finish()
+        };
+
+        let responses = replay(
+            server(),
+            &[
+                PacketScript::Packet(connect()),
+                PacketScript::Delay(Millis(10)),
+                PacketScript::Packet(codec::Packet::Publish(codec::Publish {
+                    dup: false,
+                    retain: false,
+                    qos: codec::QoS::AtLeastOnce,
+                    topic: ByteString::from_static("test"),
+                    packet_id: std::num::NonZeroU16::new(1),
+                    payload: Bytes::new(),
+                })),
+                PacketScript::Packet(codec::Packet::PingRequest),
+            ],
+        )
+        .await;
+        assert_eq!(
+            responses,
+            vec![
+                codec::Packet::ConnectAck {
+                    session_present: false,
+                    return_code: codec::ConnectAckReason::ConnectionAccepted
+                },
+                codec::Packet::PublishAck { packet_id: std::num::NonZeroU16::new(1).unwrap() },
+                codec::Packet::PingResponse,
+            ]
+        );
+
+        // responses are larger than single read
+        let mut script = vec![PacketScript::Packet(connect())];
+        for idx in 1..=512 {
+            script.push(PacketScript::Packet(codec::Packet::Publish(codec::Publish {
+                dup: false,
+                retain: false,
+                qos: codec::QoS::AtLeastOnce,
+                topic: ByteString::from_static("test"),
+                packet_id: std::num::NonZeroU16::new(idx),
+                payload: Bytes::new(),
+            })));
+        }
+        let responses = replay(server(), &script).await;
+        assert_eq!(responses.len(), 513);
+
+        // malformed packet closes connection
+        let responses = replay(
+            server(),
+            &[
+                PacketScript::Packet(connect()),
+                PacketScript::Raw(Bytes::from_static(b"\xff\xff\xff\xff\xff")),
+            ],
+        )
+        .await;
+        assert_eq!(responses.len(), 1);
+    }
+}
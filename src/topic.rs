@@ -139,6 +139,53 @@ impl Topic {
     pub fn matches_str<S: AsRef<str> + ?Sized>(&self, topic: &S) -> bool {
         matches!(self, topic.as_ref().split('/'))
     }
+
+    /// Match topic name, returns byte ranges of levels matched by wildcards.
+    ///
+    /// Multi-level wildcard captures all remaining levels, range is empty
+    /// if it matches parent level.
+    pub(crate) fn match_segments(&self, topic: &str) -> Option<Vec<(usize, usize)>> {
+        let mut segments = Vec::new();
+        let mut pos = Some(0);
+
+        for (idx, level) in self.0.iter().enumerate() {
+            // wildcards at first level do not match topics starting with '$'
+            if idx == 0 && is_metadata(topic) && !level.is_metadata() {
+                return None;
+            }
+            if let Level::MultiWildcard = level {
+                let start = pos.unwrap_or(topic.len());
+                segments.push((start, topic.len()));
+                return Some(segments);
+            }
+
+            let start = pos?;
+            let end = topic[start..].find('/').map(|i| start + i);
+            pos = end.map(|i| i + 1);
+            let value = &topic[start..end.unwrap_or(topic.len())];
+
+            match level {
+                Level::SingleWildcard => segments.push((start, start + value.len())),
+                Level::Blank => {
+                    if !value.is_empty() {
+                        return None;
+                    }
+                }
+                Level::Normal(ref s) | Level::Metadata(ref s) => {
+                    if s != value {
+                        return None;
+                    }
+                }
+                Level::MultiWildcard => unreachable!(),
+            }
+        }
+
+        if pos.is_none() {
+            Some(segments)
+        } else {
+            None
+        }
+    }
 }
 
 impl<'a> From<&'a [Level]> for Topic {
@@ -488,4 +535,27 @@ mod tests {
         assert!(Topic::from_str(&"$SYS/#").unwrap().matches_str("$SYS/"));
         assert!(Topic::from_str("$SYS/monitor/+").unwrap().matches_str("$SYS/monitor/Clients"));
     }
+
+    #[test]
+    fn test_match_segments() {
+        let t: Topic = "sensors/+/temp".parse().unwrap();
+        assert_eq!(t.match_segments("sensors/kitchen/temp"), Some(vec![(8, 15)]));
+        assert_eq!(t.match_segments("sensors//temp"), Some(vec![(8, 8)]));
+        assert_eq!(t.match_segments("sensors/kitchen"), None);
+        assert_eq!(t.match_segments("sensors/kitchen/temp/max"), None);
+
+        let t: Topic = "sensors/+/#".parse().unwrap();
+        assert_eq!(t.match_segments("sensors/a/b/c"), Some(vec![(8, 9), (10, 13)]));
+        assert_eq!(t.match_segments("sensors/a"), Some(vec![(8, 9), (9, 9)]));
+
+        let t: Topic = "#".parse().unwrap();
+        assert_eq!(t.match_segments("a/b"), Some(vec![(0, 3)]));
+        assert_eq!(t.match_segments("$SYS/a"), None);
+        assert_eq!(Topic::from_str("+/a").unwrap().match_segments("$SYS/a"), None);
+        assert_eq!(
+            Topic::from_str("$SYS/+").unwrap().match_segments("$SYS/a"),
+            Some(vec![(5, 6)])
+        );
+        assert_eq!(Topic::from_str("/+").unwrap().match_segments("/a"), Some(vec![(1, 2)]));
+    }
 }
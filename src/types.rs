@@ -1,3 +1,10 @@
+use std::sync::{atomic::AtomicU32, atomic::Ordering, Arc};
+use std::{borrow::Cow, convert::TryFrom, fmt, rc::Rc, time::Duration};
+
+use ntex::{io::IoRef, time::Seconds, util::ByteString, util::Bytes};
+
+use crate::error::{DecodeError, ProtocolError};
+
 pub const MQTT: &[u8] = b"MQTT";
 pub const MQTT_LEVEL_3: u8 = 4;
 pub const MQTT_LEVEL_5: u8 = 5;
@@ -8,7 +15,7 @@ pub const MAX_PACKET_SIZE: u32 = 0xF_FF_FF_FF;
 
 prim_enum! {
     /// Quality of Service
-    #[derive(serde::Serialize, serde::Deserialize)]
+    #[derive(serde::Serialize, serde::Deserialize, PartialOrd, Ord)]
     pub enum QoS {
         /// At most once delivery
         ///
@@ -48,6 +55,274 @@ bitflags::bitflags! {
     }
 }
 
+bitflags::bitflags! {
+    /// Set of packet types peer is allowed to send
+    pub struct PacketMask: u16 {
+        const PUBLISH     = 1 << 3;
+        const PUBACK      = 1 << 4;
+        const PUBREC      = 1 << 5;
+        const PUBREL      = 1 << 6;
+        const PUBCOMP     = 1 << 7;
+        const SUBSCRIBE   = 1 << 8;
+        const UNSUBSCRIBE = 1 << 10;
+        const PINGREQ     = 1 << 12;
+        const DISCONNECT  = 1 << 14;
+        const AUTH        = 1 << 15;
+    }
+}
+
+impl PacketMask {
+    /// Check if packet type is allowed, packet types that are not part
+    /// of the mask are always allowed
+    pub(crate) fn is_allowed(self, packet_type: u8) -> bool {
+        let bit = PacketMask::from_bits_truncate(1 << (packet_type >> 4));
+        bit.is_empty() || self.contains(bit)
+    }
+}
+
+/// Handling of `publish` packets received before `connect-ack` is sent
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PreConnackPublishPolicy {
+    /// Process publish packets after `connect-ack` is sent
+    Buffer,
+    /// Close connection with protocol error
+    Reject,
+}
+
+#[allow(clippy::derivable_impls)]
+impl Default for PreConnackPublishPolicy {
+    fn default() -> Self {
+        PreConnackPublishPolicy::Reject
+    }
+}
+
+impl PreConnackPublishPolicy {
+    /// Check packets in read buffer for publish packet that must be rejected
+    ///
+    /// Fixed header of each buffered packet is decoded, check stops at first
+    /// incomplete packet.
+    pub(crate) fn check(self, io: &IoRef) -> Result<(), ProtocolError> {
+        if self == PreConnackPublishPolicy::Buffer {
+            return Ok(());
+        }
+        let found = io.with_read_buf(|buf| {
+            let mut src = &buf[..];
+            while let Some(first_byte) = src.first() {
+                let (len, consumed) = match crate::utils::decode_variable_length(&src[1..]) {
+                    Ok(Some(val)) => val,
+                    _ => return false,
+                };
+                if first_byte & 0xF0 == packet_type::PUBLISH_START {
+                    return true;
+                }
+                src = &src[(1 + consumed + len as usize).min(src.len())..];
+            }
+            false
+        });
+
+        if found {
+            log::trace!("Publish packet is received before connect-ack");
+            Err(ProtocolError::Unexpected(
+                packet_type::PUBLISH_START,
+                "Publish packet is received before connect-ack",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Action for idle connection, once keep-alive timeout is expired
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IdleAction {
+    /// Close connection with keep-alive timeout error
+    Disconnect,
+    /// Keep connection open, keep-alive timer is re-armed with specified timeout
+    Extend(Seconds),
+}
+
+/// Decoding of client identifiers that are not valid utf-8
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ClientIdEncoding {
+    /// Reject connection with `ClientIdentifierNotValid` reason
+    Strict,
+    /// Replace invalid sequences with `U+FFFD`
+    Lossy,
+}
+
+#[allow(clippy::derivable_impls)]
+impl Default for ClientIdEncoding {
+    fn default() -> Self {
+        ClientIdEncoding::Strict
+    }
+}
+
+impl ClientIdEncoding {
+    pub(crate) fn decode(self, raw: &Bytes) -> Result<ByteString, DecodeError> {
+        match ByteString::try_from(raw.clone()) {
+            Ok(client_id) => Ok(client_id),
+            Err(_) if self == ClientIdEncoding::Lossy => {
+                Ok(ByteString::from(String::from_utf8_lossy(raw).into_owned()))
+            }
+            Err(_) => Err(DecodeError::InvalidClientId),
+        }
+    }
+}
+
+/// Codec operation
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Inbound packet decoding
+    Decode,
+    /// Outbound packet encoding
+    Encode,
+}
+
+/// Codec timing callback
+#[derive(Clone)]
+pub(crate) struct CodecTiming(Rc<dyn Fn(Direction, u8, Duration)>);
+
+impl CodecTiming {
+    pub(crate) fn new<F>(f: F) -> Self
+    where
+        F: Fn(Direction, u8, Duration) + 'static,
+    {
+        CodecTiming(Rc::new(f))
+    }
+
+    /// Report codec operation, packet type is taken from first byte of fixed header
+    pub(crate) fn report(&self, dir: Direction, first_byte: u8, elapsed: Duration) {
+        (self.0)(dir, first_byte >> 4, elapsed)
+    }
+}
+
+impl fmt::Debug for CodecTiming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CodecTiming").finish()
+    }
+}
+
+/// Connection level metrics observer
+///
+/// Observer is shared by all connections of the server. Packet type is
+/// control packet type (`1` for `connect` ... `15` for `auth`). All methods
+/// are no-op by default, so observer could implement only required ones.
+pub trait Metrics {
+    /// Packet is decoded
+    fn on_packet_in(&self, _packet_type: u8) {}
+
+    /// Packet is encoded to write buffer
+    fn on_packet_out(&self, _packet_type: u8) {}
+
+    /// Size of decoded or encoded packet, fixed header is included
+    fn on_bytes(&self, _dir: Direction, _n: usize) {}
+
+    /// Protocol error is handled by dispatcher
+    fn on_error(&self, _err: &ProtocolError) {}
+}
+
+/// Metrics observer of the connection
+#[derive(Clone)]
+pub(crate) struct MetricsHandle(Rc<dyn Metrics>);
+
+impl MetricsHandle {
+    pub(crate) fn new<M: Metrics + 'static>(metrics: M) -> Self {
+        MetricsHandle(Rc::new(metrics))
+    }
+
+    /// Report decoded or encoded packet, packet type is taken from first byte of fixed header
+    pub(crate) fn packet(&self, dir: Direction, first_byte: u8, size: usize) {
+        match dir {
+            Direction::Decode => self.0.on_packet_in(first_byte >> 4),
+            Direction::Encode => self.0.on_packet_out(first_byte >> 4),
+        }
+        self.0.on_bytes(dir, size);
+    }
+
+    /// Report decoded packet, size of fixed header is calculated from remaining length
+    pub(crate) fn decoded(&self, fixed: FixedHeader) {
+        let len = fixed.remaining_length as usize;
+        let header = match len {
+            0..=127 => 2,
+            128..=16_383 => 3,
+            16_384..=2_097_151 => 4,
+            _ => 5,
+        };
+        self.packet(Direction::Decode, fixed.first_byte, header + len);
+    }
+
+    pub(crate) fn error(&self, err: &ProtocolError) {
+        self.0.on_error(err)
+    }
+}
+
+impl fmt::Debug for MetricsHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MetricsHandle").finish()
+    }
+}
+
+/// Topic rewrite hook
+#[derive(Clone)]
+pub(crate) struct TopicRewrite(Rc<dyn Fn(&str) -> Cow<'_, str>>);
+
+impl TopicRewrite {
+    pub(crate) fn new<F>(f: F) -> Self
+    where
+        F: Fn(&str) -> Cow<'_, str> + 'static,
+    {
+        TopicRewrite(Rc::new(f))
+    }
+
+    /// Rewrite topic in place, topic is not re-allocated if hook returns it unchanged
+    pub(crate) fn apply(&self, topic: &mut ByteString) {
+        if topic.is_empty() {
+            return;
+        }
+        let new = match (self.0)(topic) {
+            Cow::Borrowed(s) if s.as_ptr() == topic.as_ptr() && s.len() == topic.len() => {
+                return
+            }
+            Cow::Borrowed(s) => ByteString::from(s),
+            Cow::Owned(s) => ByteString::from(s),
+        };
+        *topic = new;
+    }
+}
+
+impl fmt::Debug for TopicRewrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TopicRewrite").finish()
+    }
+}
+
+/// Shared max inbound frame size.
+///
+/// Handle is thread-safe, all clones refer to the same value. Mqtt v3 codec
+/// reads the value for every decoded packet, so changes apply to new and existing
+/// connections. Mqtt v5 connections read the value once, when connection is accepted,
+/// because max size is advertised to the client. If max size is set to `0`, size
+/// is unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct MaxSizeHandle(Arc<AtomicU32>);
+
+impl MaxSizeHandle {
+    /// Create handle with initial max size
+    pub fn new(size: u32) -> Self {
+        MaxSizeHandle(Arc::new(AtomicU32::new(size)))
+    }
+
+    /// Current max size
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Change max size
+    pub fn set(&self, size: u32) {
+        self.0.store(size, Ordering::Relaxed)
+    }
+}
+
 pub(super) mod packet_type {
     pub(crate) const CONNECT: u8 = 0b0001_0000;
     pub(crate) const CONNACK: u8 = 0b0010_0000;
@@ -67,6 +342,41 @@ pub(super) mod packet_type {
     pub(crate) const AUTH: u8 = 0b1111_0000;
 }
 
+/// Connect packet summary
+///
+/// Summary does not contain credentials, `Display` impl is suitable
+/// for one-line connection logs.
+#[derive(Debug, Clone)]
+pub struct ConnectSummary {
+    /// Client identifier
+    pub client_id: ByteString,
+    /// Protocol level, `4` for mqtt v3.1.1 and `5` for mqtt v5
+    pub protocol_level: u8,
+    /// Clean session (clean start for mqtt v5) flag
+    pub clean: bool,
+    /// Keep-alive in seconds
+    pub keep_alive: u16,
+    /// Connect packet contains last will
+    pub has_will: bool,
+    /// Connect packet contains user name
+    pub has_username: bool,
+}
+
+impl fmt::Display for ConnectSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "client-id: {:?}, protocol-level: {}, clean: {}, keep-alive: {}, will: {}, username: {}",
+            self.client_id,
+            self.protocol_level,
+            self.clean,
+            self.keep_alive,
+            self.has_will,
+            if self.has_username { "<REDACTED>" } else { "<NONE>" }
+        )
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub(crate) struct FixedHeader {
     /// Fixed Header byte
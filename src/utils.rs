@@ -1,12 +1,22 @@
+use std::collections::VecDeque;
 use std::num::{NonZeroU16, NonZeroU32};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::task::{Context, Poll};
 use std::{convert::TryFrom, future::Future, io::Cursor, marker::PhantomData, pin::Pin};
 
+use ntex::channel::pool;
 use ntex::service::Service;
-use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
+use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut, HashMap};
 
 use crate::error::{DecodeError, EncodeError};
 
+static CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Allocate id for new connection, ids are unique within process
+pub(crate) fn next_connection_id() -> u64 {
+    CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 macro_rules! ensure {
     ($cond:expr, $e:expr) => {
         if !($cond) {
@@ -145,6 +155,44 @@ pub(crate) fn decode_variable_length(src: &[u8]) -> Result<Option<(u32, usize)>,
     }
 }
 
+/// Decode packet's remaining length.
+///
+/// Remaining length is checked against `max_size` while decoding, so oversized
+/// length prefix is rejected before whole prefix is received.
+/// If `max_size` is set to `0`, size is unlimited.
+#[allow(clippy::cast_lossless)] // safe: allow cast through `as` because it is type-safe
+pub(crate) fn decode_remaining_length(
+    src: &[u8],
+    max_size: u32,
+) -> Result<Option<(u32, usize)>, DecodeError> {
+    let mut shift: u32 = 0;
+    let mut len: u32 = 0;
+    for (idx, val) in src.iter().enumerate() {
+        len += ((val & 0b0111_1111u8) as u32) << shift;
+        if max_size != 0 && len > max_size {
+            log::debug!("MaxSizeExceeded max-size: {}, remaining: {}", max_size, len);
+            return Err(DecodeError::MaxSizeExceeded { size: len, limit: max_size });
+        }
+        if val & 0b1000_0000 == 0 {
+            return Ok(Some((len, idx + 1)));
+        }
+        ensure!(shift < 21, DecodeError::InvalidLength);
+        shift += 7;
+    }
+    Ok(None)
+}
+
+/// Check if buffer contains complete packet including fixed header
+pub(crate) fn is_complete_packet(src: &[u8], max_size: u32) -> Result<bool, DecodeError> {
+    if src.len() < 2 {
+        return Ok(false);
+    }
+    match decode_remaining_length(&src[1..], max_size)? {
+        Some((len, consumed)) => Ok(src.len() > consumed + len as usize),
+        None => Ok(false),
+    }
+}
+
 #[allow(clippy::cast_lossless)] // safe: allow cast through `as` because it is type-safe
 pub(crate) fn decode_variable_length_cursor<B: Buf>(src: &mut B) -> Result<u32, DecodeError> {
     let mut shift: u32 = 0;
@@ -304,6 +352,36 @@ pub(crate) fn write_variable_length(len: u32, dst: &mut BytesMut) {
     }
 }
 
+/// Remove in-flight packet that could not be encoded.
+///
+/// Wakes up one request that waits for in-flight slot and idle waiters
+/// if no packets are in-flight. Returns removed in-flight entry.
+pub(crate) fn cancel_inflight<T>(
+    idx: u16,
+    inflight: &mut HashMap<u16, T>,
+    inflight_order: &mut VecDeque<u16>,
+    waiters: &mut VecDeque<pool::Sender<()>>,
+    idle_waiters: &mut Vec<pool::Sender<()>>,
+) -> Option<T> {
+    let entry = inflight.remove(&idx)?;
+    inflight_order.retain(|i| *i != idx);
+
+    // wake up queued request (receive max limit)
+    while let Some(tx) = waiters.pop_front() {
+        if tx.send(()).is_ok() {
+            break;
+        }
+    }
+
+    // notify idle waiters, all in-flight packets are acked
+    if inflight.is_empty() {
+        for tx in idle_waiters.drain(..) {
+            let _ = tx.send(());
+        }
+    }
+    Some(entry)
+}
+
 /// Check service readiness
 pub(crate) fn ready<S, R>(service: &S) -> Ready<'_, S, R> {
     Ready(service, PhantomData)
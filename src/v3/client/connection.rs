@@ -266,7 +266,7 @@ async fn keepalive(sink: MqttSink, timeout: Seconds) {
     loop {
         sleep(keepalive).await;
 
-        if !sink.ping() {
+        if !sink.keepalive_ping() {
             // connection is closed
             log::debug!("mqtt client connection is closed, stopping keep-alive task");
             break;
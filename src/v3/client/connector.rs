@@ -230,6 +230,7 @@ where
                 })?;
 
             let shared = Rc::new(MqttShared::new(io.get_ref(), codec, max_send, pool));
+            shared.client.set(true);
 
             match packet {
                 codec::Packet::ConnectAck { session_present, return_code } => {
@@ -140,9 +140,27 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            DispatchItem::Item(codec::Packet::PublishReceived { packet_id }) => {
+                if let Err(e) = self.sink.pkt_ack(Ack::Receive(packet_id)) {
+                    Either::Right(Either::Left(Ready::Err(MqttError::Protocol(e))))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishComplete { packet_id }) => {
+                if let Err(e) = self.sink.pkt_ack(Ack::Complete(packet_id)) {
+                    Either::Right(Either::Left(Ready::Err(MqttError::Protocol(e))))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
             DispatchItem::Item(codec::Packet::PingRequest) => {
                 Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PingResponse))))
             }
+            DispatchItem::Item(codec::Packet::PingResponse) => {
+                self.sink.ping_response();
+                Either::Right(Either::Left(Ready::Ok(None)))
+            }
             DispatchItem::Item(codec::Packet::Disconnect) => Either::Right(Either::Right(
                 ControlResponse::new(ControlMessage::dis(), &self.inner),
             )),
@@ -188,12 +206,9 @@ where
                     &self.inner,
                 )))
             }
-            DispatchItem::DecoderError(err) => {
-                Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::Decode(err)),
-                    &self.inner,
-                )))
-            }
+            DispatchItem::DecoderError(err) => Either::Right(Either::Right(
+                ControlResponse::new(ControlMessage::proto_error(err.into()), &self.inner),
+            )),
             DispatchItem::Disconnect(err) => Either::Right(Either::Right(
                 ControlResponse::new(ControlMessage::peer_gone(err), &self.inner),
             )),
@@ -309,7 +324,9 @@ where
                     this.inner.sink.close();
                     Some(codec::Packet::Disconnect)
                 }
-                ControlResultKind::Closed | ControlResultKind::Nothing => None,
+                ControlResultKind::Closed
+                | ControlResultKind::Nothing
+                | ControlResultKind::Extend(_) => None,
             },
             Poll::Pending => return Poll::Pending,
         };
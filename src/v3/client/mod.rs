@@ -3,10 +3,12 @@ mod connection;
 mod connector;
 pub mod control;
 mod dispatcher;
+mod reconnect;
 
 pub use self::connection::{Client, ClientRouter};
 pub use self::connector::MqttConnector;
 pub use self::control::{ControlMessage, ControlResult};
+pub use self::reconnect::{Backoff, OfflinePolicy, ReconnectingClient, ReconnectingSink};
 
 pub use crate::topic::Topic;
 pub use crate::types::QoS;
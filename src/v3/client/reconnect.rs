@@ -0,0 +1,442 @@
+use std::collections::{hash_map::RandomState, BTreeMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::task::Poll;
+use std::{cell::Cell, cell::RefCell, fmt, future::Future, rc::Rc};
+
+use ntex::channel::oneshot;
+use ntex::service::Service;
+use ntex::task::LocalWaker;
+use ntex::time::{sleep, Millis};
+use ntex::util::{join, poll_fn, select, ByteString, Bytes, Either, Ready};
+
+use super::{control::ControlMessage, Client};
+use crate::v3::{codec, error::SendPacketError, sink::MqttSink, ControlResult};
+
+/// Reconnect delay configuration
+///
+/// Delay grows exponentially from `base` up to `max`, random jitter
+/// is added to spread reconnects of many clients.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Backoff {
+    base: Millis,
+    max: Millis,
+    jitter: f32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(Millis(100), Millis(30_000))
+    }
+}
+
+impl Backoff {
+    /// Create backoff with initial and max delays
+    pub fn new(base: Millis, max: Millis) -> Self {
+        Backoff { base, max, jitter: 0.1 }
+    }
+
+    /// Set random jitter as a fraction of the delay.
+    ///
+    /// Value is clamped to `0.0..=1.0`. By default jitter is set to `0.1`.
+    pub fn jitter(mut self, jitter: f32) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Delay before reconnect attempt, attempts are counted from `0`
+    pub fn delay(&self, attempt: u32) -> Millis {
+        let base = u64::from(self.base.0);
+        let max = u64::from(self.max.0).max(base);
+        let delay = base.saturating_mul(1u64 << attempt.min(32)).min(max);
+        let jitter = if self.jitter > 0.0 {
+            let rnd = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+            (delay as f64 * f64::from(self.jitter) * rnd) as u64
+        } else {
+            0
+        };
+        Millis(delay.saturating_add(jitter).min(u64::from(u32::MAX)) as u32)
+    }
+}
+
+/// Publish behavior while client is disconnected
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OfflinePolicy {
+    /// Publish fails with `SendPacketError::Disconnected` error
+    Error,
+    /// Up to specified number of publishes get buffered and sent once
+    /// connection is established, publish fails with
+    /// `SendPacketError::WriteBufferFull` error if buffer is full
+    Buffer(usize),
+}
+
+#[allow(clippy::derivable_impls)]
+impl Default for OfflinePolicy {
+    fn default() -> Self {
+        OfflinePolicy::Error
+    }
+}
+
+/// Mqtt client that reconnects to the server once connection is lost
+///
+/// Subscriptions made via `ReconnectingSink` are stored and get restored
+/// on each new connection before publishes are sent.
+///
+/// ```rust,ignore
+/// let client = ReconnectingClient::new(move || connector.connect())
+///     .backoff(Backoff::new(Millis(100), Millis(10_000)))
+///     .offline_policy(OfflinePolicy::Buffer(256));
+/// let sink = client.sink();
+/// ntex::rt::spawn(client.start(|| fn_service(control)));
+/// ```
+pub struct ReconnectingClient<F> {
+    connector: F,
+    backoff: Backoff,
+    inner: Rc<Inner>,
+}
+
+type Buffered = (codec::Publish, oneshot::Sender<Result<(), SendPacketError>>);
+
+struct Inner {
+    sink: RefCell<Option<MqttSink>>,
+    subscriptions: RefCell<BTreeMap<ByteString, codec::QoS>>,
+    buffer: RefCell<VecDeque<Buffered>>,
+    policy: Cell<OfflinePolicy>,
+    closed: Cell<bool>,
+    waker: LocalWaker,
+}
+
+impl<F> fmt::Debug for ReconnectingClient<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("v3::ReconnectingClient")
+            .field("backoff", &self.backoff)
+            .field("policy", &self.inner.policy.get())
+            .finish()
+    }
+}
+
+impl<F, Fut, E> ReconnectingClient<F>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<Client, E>>,
+    E: fmt::Debug,
+{
+    /// Create reconnecting client, `connector` is called for each connection attempt
+    pub fn new(connector: F) -> Self {
+        ReconnectingClient {
+            connector,
+            backoff: Backoff::default(),
+            inner: Rc::new(Inner {
+                sink: RefCell::new(None),
+                subscriptions: RefCell::new(BTreeMap::new()),
+                buffer: RefCell::new(VecDeque::new()),
+                policy: Cell::new(OfflinePolicy::Error),
+                closed: Cell::new(false),
+                waker: LocalWaker::new(),
+            }),
+        }
+    }
+
+    /// Set reconnect delay configuration
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Set publish behavior while client is disconnected
+    ///
+    /// By default publish fails with `SendPacketError::Disconnected` error.
+    pub fn offline_policy(self, policy: OfflinePolicy) -> Self {
+        self.inner.policy.set(policy);
+        self
+    }
+
+    /// Get sink that survives reconnects
+    pub fn sink(&self) -> ReconnectingSink {
+        ReconnectingSink(self.inner.clone())
+    }
+
+    /// Run client until `ReconnectingSink::close()` is called
+    ///
+    /// `service` is called for each connection and creates control messages handler.
+    pub async fn start<S, T, Err>(self, service: S)
+    where
+        S: Fn() -> T,
+        T: Service<ControlMessage<Err>, Response = ControlResult, Error = Err> + 'static,
+        Err: fmt::Debug + 'static,
+    {
+        let mut attempt = 0;
+
+        while !self.inner.closed.get() {
+            match (self.connector)().await {
+                Ok(client) => {
+                    let sink = client.sink();
+                    let inner = self.inner.clone();
+                    let setup = async move {
+                        match inner.resubscribe(&sink).await {
+                            Ok(_) if !inner.closed.get() => {
+                                inner.connected(sink);
+                                true
+                            }
+                            Ok(_) => {
+                                sink.close();
+                                false
+                            }
+                            Err(e) => {
+                                log::debug!("Cannot restore subscriptions: {:?}", e);
+                                sink.close();
+                                false
+                            }
+                        }
+                    };
+
+                    let (res, restored) = join(client.start(service()), setup).await;
+                    self.inner.sink.borrow_mut().take();
+                    if let Err(e) = res {
+                        log::debug!("Mqtt connection is terminated with error: {:?}", e);
+                    }
+                    if restored {
+                        attempt = 0;
+                    }
+                }
+                Err(e) => log::debug!("Cannot connect to mqtt server: {:?}", e),
+            }
+
+            if !self.inner.closed.get() {
+                let delay = self.backoff.delay(attempt);
+                log::trace!("Reconnect in {:?}", delay);
+                attempt = attempt.saturating_add(1);
+                let _ = select(sleep(delay), self.inner.on_close()).await;
+            }
+        }
+        self.inner.buffer.borrow_mut().clear();
+    }
+}
+
+impl Inner {
+    /// Subscribe new connection to stored topic filters
+    ///
+    /// Subscriptions could be changed while subscribe is in progress,
+    /// repeat until connection state matches stored set.
+    async fn resubscribe(&self, sink: &MqttSink) -> Result<(), SendPacketError> {
+        let mut subscribed = BTreeMap::new();
+        loop {
+            let (subscribe, unsubscribe): (Vec<_>, Vec<_>) = {
+                let subs = self.subscriptions.borrow();
+                (
+                    subs.iter()
+                        .filter(|(filter, qos)| subscribed.get(*filter) != Some(*qos))
+                        .map(|(filter, qos)| (filter.clone(), *qos))
+                        .collect(),
+                    subscribed.keys().filter(|f| !subs.contains_key(*f)).cloned().collect(),
+                )
+            };
+
+            if !subscribe.is_empty() {
+                let mut builder = sink.subscribe();
+                for (filter, qos) in subscribe {
+                    subscribed.insert(filter.clone(), qos);
+                    builder = builder.topic_filter(filter, qos);
+                }
+                builder.send().await?;
+            } else if !unsubscribe.is_empty() {
+                let mut builder = sink.unsubscribe();
+                for filter in unsubscribe {
+                    subscribed.remove(&filter);
+                    builder = builder.topic_filter(filter);
+                }
+                builder.send().await?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Connection is ready, send buffered publishes
+    fn connected(&self, sink: MqttSink) {
+        for (pkt, tx) in self.buffer.borrow_mut().drain(..) {
+            let fut = publish(&sink, pkt);
+            ntex::rt::spawn(async move {
+                let _ = tx.send(fut.await);
+            });
+        }
+        *self.sink.borrow_mut() = Some(sink);
+    }
+
+    fn on_close(&self) -> impl Future<Output = ()> + '_ {
+        poll_fn(move |cx| {
+            if self.closed.get() {
+                Poll::Ready(())
+            } else {
+                self.waker.register(cx.waker());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+fn publish(
+    sink: &MqttSink,
+    pkt: codec::Publish,
+) -> impl Future<Output = Result<(), SendPacketError>> {
+    match pkt.qos {
+        codec::QoS::AtMostOnce => {
+            Either::Left(Ready::from(sink.publish_pkt(pkt).send_at_most_once()))
+        }
+        codec::QoS::AtLeastOnce => {
+            Either::Right(Either::Left(sink.publish_pkt(pkt).send_at_least_once()))
+        }
+        codec::QoS::ExactlyOnce => {
+            Either::Right(Either::Right(sink.publish_pkt(pkt).send_exactly_once()))
+        }
+    }
+}
+
+/// Sink of `ReconnectingClient`, stays valid across reconnects
+#[derive(Clone)]
+pub struct ReconnectingSink(Rc<Inner>);
+
+impl ReconnectingSink {
+    #[inline]
+    /// Check if client is connected and subscriptions are restored
+    pub fn is_connected(&self) -> bool {
+        self.0.sink.borrow().is_some()
+    }
+
+    #[inline]
+    /// Sink of current connection
+    pub fn sink(&self) -> Option<MqttSink> {
+        self.0.sink.borrow().clone()
+    }
+
+    /// Number of publishes buffered while client is disconnected
+    pub fn buffered(&self) -> usize {
+        self.0.buffer.borrow().len()
+    }
+
+    /// Publish message with specified qos
+    pub fn publish(
+        &self,
+        topic: ByteString,
+        payload: Bytes,
+        qos: codec::QoS,
+    ) -> impl Future<Output = Result<(), SendPacketError>> {
+        self.publish_pkt(codec::Publish {
+            topic,
+            payload,
+            qos,
+            dup: false,
+            retain: false,
+            packet_id: None,
+        })
+    }
+
+    /// Publish packet, behavior while disconnected is defined by `OfflinePolicy`
+    ///
+    /// Publish that is sent before connection is lost fails with
+    /// `SendPacketError::Disconnected` error, it is not re-sent after reconnect.
+    /// Stored subscriptions are restored on the new connection regardless.
+    pub fn publish_pkt(
+        &self,
+        pkt: codec::Publish,
+    ) -> impl Future<Output = Result<(), SendPacketError>> {
+        if let Some(ref sink) = *self.0.sink.borrow() {
+            return Either::Left(publish(sink, pkt));
+        }
+
+        match self.0.policy.get() {
+            OfflinePolicy::Buffer(max) if !self.0.closed.get() => {
+                let mut buffer = self.0.buffer.borrow_mut();
+                if buffer.len() < max {
+                    let (tx, rx) = oneshot::channel();
+                    buffer.push_back((pkt, tx));
+                    Either::Right(Either::Left(async move {
+                        rx.await.unwrap_or(Err(SendPacketError::Disconnected))
+                    }))
+                } else {
+                    Either::Right(Either::Right(Ready::Err(SendPacketError::WriteBufferFull)))
+                }
+            }
+            _ => Either::Right(Either::Right(Ready::Err(SendPacketError::Disconnected))),
+        }
+    }
+
+    /// Add topic filter to the stored subscriptions
+    ///
+    /// If client is connected, subscribe packet is sent immediately,
+    /// otherwise filter is subscribed once connection is established.
+    ///
+    /// If connection is lost before subscribe is acknowledged, method returns
+    /// `SendPacketError::Disconnected` error, but filter stays stored and is
+    /// restored on the next connection.
+    pub async fn subscribe(
+        &self,
+        filter: ByteString,
+        qos: codec::QoS,
+    ) -> Result<(), SendPacketError> {
+        self.0.subscriptions.borrow_mut().insert(filter.clone(), qos);
+        let sink = self.sink();
+        if let Some(sink) = sink {
+            sink.subscribe().topic_filter(filter, qos).send().await?;
+        }
+        Ok(())
+    }
+
+    /// Remove topic filter from the stored subscriptions
+    pub async fn unsubscribe(&self, filter: ByteString) -> Result<(), SendPacketError> {
+        if self.0.subscriptions.borrow_mut().remove(&filter).is_some() {
+            if let Some(sink) = self.sink() {
+                sink.unsubscribe().topic_filter(filter).send().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stored topic filters
+    pub fn subscriptions(&self) -> Vec<(ByteString, codec::QoS)> {
+        self.0.subscriptions.borrow().iter().map(|(f, q)| (f.clone(), *q)).collect()
+    }
+
+    /// Close current connection and stop reconnecting
+    ///
+    /// Buffered publishes fail with `SendPacketError::Disconnected` error.
+    pub fn close(&self) {
+        self.0.closed.set(true);
+        self.0.waker.wake();
+        self.0.buffer.borrow_mut().clear();
+        if let Some(sink) = self.0.sink.borrow_mut().take() {
+            sink.close();
+        }
+    }
+}
+
+impl fmt::Debug for ReconnectingSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("v3::ReconnectingSink")
+            .field("connected", &self.is_connected())
+            .field("buffered", &self.buffered())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff::new(Millis(100), Millis(1000)).jitter(0.0);
+        assert_eq!(backoff.delay(0), Millis(100));
+        assert_eq!(backoff.delay(1), Millis(200));
+        assert_eq!(backoff.delay(3), Millis(800));
+        assert_eq!(backoff.delay(4), Millis(1000));
+        assert_eq!(backoff.delay(u32::MAX), Millis(1000));
+
+        let backoff = backoff.jitter(0.5);
+        for attempt in 0..10 {
+            let delay = backoff.delay(attempt).0;
+            let base = Backoff::new(Millis(100), Millis(1000)).jitter(0.0).delay(attempt).0;
+            assert!(delay >= base && delay <= base + base / 2);
+        }
+        assert_eq!(Backoff::new(Millis(0), Millis(0)).jitter(2.0).delay(5), Millis(0));
+    }
+}
@@ -1,18 +1,28 @@
-use std::cell::Cell;
+use std::{cell::Cell, cell::RefCell, time::Instant};
 
 use ntex::codec::{Decoder, Encoder};
-use ntex::util::{Buf, BytesMut};
+use ntex::util::{Buf, Bytes, BytesMut};
 
 use super::{decode, encode, Packet, Publish};
-use crate::error::{DecodeError, EncodeError};
-use crate::types::{FixedHeader, QoS};
-use crate::utils::decode_variable_length;
+use crate::error::{DecodeError, EncodeError, ProtocolError};
+use crate::types::{packet_type, QoS};
+use crate::types::{ClientIdEncoding, CodecTiming, Direction, FixedHeader};
+use crate::types::{MaxSizeHandle, MetricsHandle};
+use crate::utils::{decode_remaining_length, is_complete_packet};
 
 #[derive(Debug)]
 /// Mqtt v3.1.1 protocol codec
 pub struct Codec {
     state: Cell<DecodeState>,
     max_size: Cell<u32>,
+    max_size_handle: RefCell<Option<MaxSizeHandle>>,
+    keep_connect: Cell<bool>,
+    lenient_protocol: Cell<bool>,
+    client_id_encoding: Cell<ClientIdEncoding>,
+    connect_bytes: RefCell<Option<Bytes>>,
+    client_id_bytes: RefCell<Option<Bytes>>,
+    timing: RefCell<Option<CodecTiming>>,
+    metrics: RefCell<Option<MetricsHandle>>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -24,7 +34,18 @@ enum DecodeState {
 impl Codec {
     /// Create `Codec` instance
     pub fn new() -> Self {
-        Codec { state: Cell::new(DecodeState::FrameHeader), max_size: Cell::new(0) }
+        Codec {
+            state: Cell::new(DecodeState::FrameHeader),
+            max_size: Cell::new(0),
+            max_size_handle: RefCell::new(None),
+            keep_connect: Cell::new(false),
+            lenient_protocol: Cell::new(false),
+            client_id_encoding: Cell::new(ClientIdEncoding::Strict),
+            connect_bytes: RefCell::new(None),
+            client_id_bytes: RefCell::new(None),
+            timing: RefCell::new(None),
+            metrics: RefCell::new(None),
+        }
     }
 
     /// Set max inbound frame size.
@@ -43,6 +64,138 @@ impl Codec {
     pub fn set_max_size(&self, size: u32) {
         self.max_size.set(size);
     }
+
+    /// Read max inbound frame size from shared handle.
+    ///
+    /// Handle overrides max size that is set with `set_max_size()`.
+    pub fn set_max_size_handle(&self, handle: Option<MaxSizeHandle>) {
+        *self.max_size_handle.borrow_mut() = handle;
+    }
+
+    /// Effective max inbound frame size
+    pub(crate) fn inbound_max_size(&self) -> u32 {
+        match *self.max_size_handle.borrow() {
+            Some(ref handle) => handle.get(),
+            None => self.max_size.get(),
+        }
+    }
+
+    /// Keep raw bytes of decoded `Connect` packet.
+    ///
+    /// By default raw bytes are not kept.
+    pub(crate) fn keep_connect_bytes(self, val: bool) -> Self {
+        self.keep_connect.set(val);
+        self
+    }
+
+    /// Accept case-insensitive protocol name in `Connect` packet.
+    ///
+    /// By default protocol name must be exactly `MQTT`.
+    pub(crate) fn lenient_protocol_name(self, val: bool) -> Self {
+        self.lenient_protocol.set(val);
+        self
+    }
+
+    /// Set decoding of client ids that are not valid utf-8.
+    ///
+    /// By default such client ids are rejected.
+    pub(crate) fn client_id_encoding(self, val: ClientIdEncoding) -> Self {
+        self.client_id_encoding.set(val);
+        self
+    }
+
+    /// Report time spent in decoding and encoding of each packet.
+    pub(crate) fn codec_timing(self, timing: Option<CodecTiming>) -> Self {
+        self.set_codec_timing(timing);
+        self
+    }
+
+    /// Report time spent in decoding and encoding of each packet.
+    pub(crate) fn set_codec_timing(&self, timing: Option<CodecTiming>) {
+        *self.timing.borrow_mut() = timing;
+    }
+
+    /// Report decoded and encoded packets and protocol errors to metrics observer.
+    pub(crate) fn metrics(self, metrics: Option<MetricsHandle>) -> Self {
+        self.set_metrics(metrics);
+        self
+    }
+
+    /// Report decoded and encoded packets and protocol errors to metrics observer.
+    pub(crate) fn set_metrics(&self, metrics: Option<MetricsHandle>) {
+        *self.metrics.borrow_mut() = metrics;
+    }
+
+    /// Report protocol error to metrics observer
+    pub(crate) fn report_error(&self, err: &ProtocolError) {
+        if let Some(ref metrics) = *self.metrics.borrow() {
+            metrics.error(err);
+        }
+    }
+
+    /// Take raw bytes of decoded `Connect` packet, fixed header is not included
+    pub(crate) fn take_connect_bytes(&self) -> Bytes {
+        self.connect_bytes.borrow_mut().take().unwrap_or_default()
+    }
+
+    /// Take raw bytes of client id of decoded `Connect` packet
+    pub(crate) fn take_client_id_bytes(&self) -> Option<Bytes> {
+        self.client_id_bytes.borrow_mut().take()
+    }
+
+    /// Encode packet to the buffer.
+    ///
+    /// Buffer is left unchanged if packet could not be encoded.
+    pub fn encode_packet(&self, pkt: &Packet, dst: &mut BytesMut) -> Result<(), EncodeError> {
+        if let Packet::Publish(Publish { qos, packet_id, .. }) = pkt {
+            if (*qos == QoS::AtLeastOnce || *qos == QoS::ExactlyOnce) && packet_id.is_none() {
+                return Err(EncodeError::PacketIdRequired);
+            }
+        }
+        let timing = self.timing.borrow();
+        let started = timing.as_ref().map(|t| (t, Instant::now(), dst.len()));
+        let content_size = encode::get_encoded_size(pkt);
+        let pos = dst.len();
+        dst.reserve(content_size + 5);
+        if let Err(err) = encode::encode(pkt, dst, content_size as u32) {
+            // drop partially encoded packet
+            dst.truncate(pos);
+            return Err(err);
+        }
+        if let Some((timing, started, pos)) = started {
+            timing.report(Direction::Encode, dst[pos], started.elapsed());
+        }
+        if let Some(ref metrics) = *self.metrics.borrow() {
+            metrics.packet(Direction::Encode, dst[pos], dst.len() - pos);
+        }
+        Ok(())
+    }
+
+    /// Check if next packet in the read buffer is publish packet
+    pub(crate) fn is_publish_next(&self, src: &[u8]) -> bool {
+        let first_byte = match self.state.get() {
+            DecodeState::Frame(fixed) => fixed.first_byte,
+            DecodeState::FrameHeader => match src.first() {
+                Some(b) => *b,
+                None => return false,
+            },
+        };
+        first_byte & 0xF0 == packet_type::PUBLISH_START
+    }
+
+    /// Decode packet from the buffer.
+    ///
+    /// Returns `Ok(None)` and leaves buffer unchanged if buffer does not contain
+    /// complete packet. Packet that exceeds max inbound size is rejected with
+    /// `DecodeError::MaxSizeExceeded` error before its payload is received.
+    pub fn decode_packet(&self, src: &mut BytesMut) -> Result<Option<Packet>, DecodeError> {
+        if let DecodeState::FrameHeader = self.state.get() {
+            if !is_complete_packet(src, self.inbound_max_size())? {
+                return Ok(None);
+            }
+        }
+        self.decode(src)
+    }
 }
 
 impl Default for Codec {
@@ -64,19 +217,14 @@ impl Decoder for Codec {
                     }
                     let src_slice = src.as_ref();
                     let first_byte = src_slice[0];
-                    match decode_variable_length(&src_slice[1..])? {
+                    // remaining length is checked against max message size
+                    match decode_remaining_length(&src_slice[1..], self.inbound_max_size())? {
                         Some((remaining_length, consumed)) => {
-                            // check max message size
-                            let max_size = self.max_size.get();
-                            if max_size != 0 && max_size < remaining_length {
-                                return Err(DecodeError::MaxSizeExceeded);
-                            }
                             src.advance(consumed + 1);
                             self.state.set(DecodeState::Frame(FixedHeader {
                                 first_byte,
                                 remaining_length,
                             }));
-                            // todo: validate remaining_length against max frame size config
                             let remaining_length = remaining_length as usize;
                             if src.len() < remaining_length {
                                 // todo: subtract?
@@ -93,8 +241,29 @@ impl Decoder for Codec {
                     if src.len() < fixed.remaining_length as usize {
                         return Ok(None);
                     }
-                    let packet_buf = src.split_to(fixed.remaining_length as usize);
-                    let packet = decode::decode_packet(packet_buf.freeze(), fixed.first_byte)?;
+                    let mut packet_buf = src.split_to(fixed.remaining_length as usize).freeze();
+                    let timing = self.timing.borrow();
+                    let started = timing.as_ref().map(|t| (t, Instant::now()));
+                    let packet = if fixed.first_byte == packet_type::CONNECT {
+                        if self.keep_connect.get() {
+                            *self.connect_bytes.borrow_mut() = Some(packet_buf.clone());
+                        }
+                        let (packet, raw_client_id) = decode::decode_connect_packet_with(
+                            &mut packet_buf,
+                            self.lenient_protocol.get(),
+                            self.client_id_encoding.get(),
+                        )?;
+                        *self.client_id_bytes.borrow_mut() = Some(raw_client_id);
+                        packet
+                    } else {
+                        decode::decode_packet(packet_buf, fixed.first_byte)?
+                    };
+                    if let Some((timing, started)) = started {
+                        timing.report(Direction::Decode, fixed.first_byte, started.elapsed());
+                    }
+                    if let Some(ref metrics) = *self.metrics.borrow() {
+                        metrics.decoded(fixed);
+                    }
                     self.state.set(DecodeState::FrameHeader);
                     src.reserve(2);
                     return Ok(Some(packet));
@@ -109,15 +278,7 @@ impl Encoder for Codec {
     type Error = EncodeError;
 
     fn encode(&self, item: Self::Item, dst: &mut BytesMut) -> Result<(), EncodeError> {
-        if let Packet::Publish(Publish { qos, packet_id, .. }) = item {
-            if (qos == QoS::AtLeastOnce || qos == QoS::ExactlyOnce) && packet_id.is_none() {
-                return Err(EncodeError::PacketIdRequired);
-            }
-        }
-        let content_size = encode::get_encoded_size(&item);
-        dst.reserve(content_size + 5);
-        encode::encode(&item, dst, content_size as u32)?;
-        Ok(())
+        self.encode_packet(&item, dst)
     }
 }
 
@@ -125,6 +286,7 @@ impl Encoder for Codec {
 mod tests {
     use super::*;
     use ntex::util::{ByteString, Bytes};
+    use std::num::NonZeroU16;
 
     #[test]
     fn test_max_size() {
@@ -132,7 +294,120 @@ mod tests {
 
         let mut buf = BytesMut::new();
         buf.extend_from_slice(b"\0\x09");
-        assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
+        assert_eq!(
+            codec.decode(&mut buf),
+            Err(DecodeError::MaxSizeExceeded { size: 9, limit: 5 })
+        );
+    }
+
+    #[test]
+    fn test_max_size_handle() {
+        let handle = MaxSizeHandle::new(0);
+        let codec = Codec::new().max_size(5);
+        codec.set_max_size_handle(Some(handle.clone()));
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\xc0\x00");
+        assert_eq!(codec.decode(&mut buf), Ok(Some(Packet::PingRequest)));
+
+        handle.set(5);
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\0\x09");
+        assert_eq!(
+            codec.decode(&mut buf),
+            Err(DecodeError::MaxSizeExceeded { size: 9, limit: 5 })
+        );
+    }
+
+    #[test]
+    fn test_max_size_length_prefix() {
+        let codec = Codec::new().max_size(1024);
+
+        // incomplete remaining length prefix already exceeds max size
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x30\xff\xff\xff");
+        assert_eq!(
+            codec.decode(&mut buf),
+            Err(DecodeError::MaxSizeExceeded { size: 16383, limit: 1024 })
+        );
+
+        // remaining length prefix is within limits
+        let codec = Codec::new().max_size(1024);
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x30\xff");
+        assert_eq!(codec.decode(&mut buf), Ok(None));
+    }
+
+    #[test]
+    fn test_encode_error_recovery() {
+        let codec = Codec::new();
+        let publish = Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtMostOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: None,
+            payload: Bytes::from_static(b"data"),
+        };
+
+        // packet id must not be set for QoS 0, packet is dropped
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\xc0\x00");
+        let res = codec.encode(
+            Packet::Publish(Publish { packet_id: NonZeroU16::new(1), ..publish.clone() }),
+            &mut buf,
+        );
+        assert_eq!(res, Err(EncodeError::MalformedPacket));
+        assert_eq!(&buf[..], b"\xc0\x00");
+
+        codec.encode(Packet::Publish(publish.clone()), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf), Ok(Some(Packet::PingRequest)));
+        assert_eq!(codec.decode(&mut buf), Ok(Some(Packet::Publish(publish))));
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let codec = Codec::new().max_size(16);
+        let pkt = Packet::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(ByteString::from_static("a/+"), QoS::AtLeastOnce)],
+        };
+
+        let mut buf = BytesMut::new();
+        codec.encode_packet(&pkt, &mut buf).unwrap();
+        let data = buf.clone();
+
+        // partial packet does not modify buffer
+        let mut partial = BytesMut::from(&data[..data.len() - 1]);
+        assert_eq!(codec.decode_packet(&mut partial), Ok(None));
+        assert_eq!(partial.len(), data.len() - 1);
+        partial.extend_from_slice(&data[data.len() - 1..]);
+        assert_eq!(codec.decode_packet(&mut partial), Ok(Some(pkt.clone())));
+        assert_eq!(codec.decode_packet(&mut buf), Ok(Some(pkt)));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"\x30\x11"[..]);
+        assert_eq!(
+            codec.decode_packet(&mut buf),
+            Err(DecodeError::MaxSizeExceeded { size: 17, limit: 16 })
+        );
+    }
+
+    #[test]
+    fn test_keep_connect_bytes() {
+        let raw = b"\x00\x04MQTT\x04\xC0\x00\x3C\x00\x0512345\x00\x04user\x00\x04pass";
+        let mut data = vec![0x10, raw.len() as u8];
+        data.extend_from_slice(raw);
+
+        let codec = Codec::new();
+        let mut buf = BytesMut::from(&data[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        assert!(codec.take_connect_bytes().is_empty());
+
+        let codec = Codec::new().keep_connect_bytes(true);
+        let mut buf = BytesMut::from(&data[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        assert_eq!(codec.take_connect_bytes(), &raw[..]);
     }
 
     #[test]
@@ -157,4 +432,30 @@ mod tests {
         };
         assert_eq!(pkt, pkt2);
     }
+
+    #[test]
+    fn test_codec_timing() {
+        let reports = std::rc::Rc::new(RefCell::new(Vec::new()));
+        let reports2 = reports.clone();
+        let codec = Codec::new().codec_timing(Some(CodecTiming::new(move |dir, tp, _| {
+            reports2.borrow_mut().push((dir, tp))
+        })));
+
+        let mut buf = BytesMut::new();
+        codec.encode(Packet::PingRequest, &mut buf).unwrap();
+        codec
+            .encode(
+                Packet::Subscribe {
+                    packet_id: NonZeroU16::new(1).unwrap(),
+                    topic_filters: vec![(ByteString::from_static("#"), QoS::AtLeastOnce)],
+                },
+                &mut buf,
+            )
+            .unwrap();
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        assert_eq!(
+            &reports.borrow()[..],
+            &[(Direction::Encode, 12), (Direction::Encode, 8), (Direction::Decode, 12)]
+        );
+    }
 }
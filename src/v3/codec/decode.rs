@@ -3,7 +3,7 @@ use std::{convert::TryFrom, convert::TryInto, num::NonZeroU16};
 use ntex::util::{Buf, ByteString, Bytes};
 
 use crate::error::DecodeError;
-use crate::types::{packet_type, QoS, MQTT, MQTT_LEVEL_3, WILL_QOS_SHIFT};
+use crate::types::{packet_type, ClientIdEncoding, QoS, MQTT, MQTT_LEVEL_3, WILL_QOS_SHIFT};
 use crate::utils::Decode;
 
 use super::packet::{Connect, LastWill, Packet, Publish, SubscribeReturnCode};
@@ -11,7 +11,7 @@ use super::{ConnectAckFlags, ConnectFlags};
 
 pub(crate) fn decode_packet(mut src: Bytes, first_byte: u8) -> Result<Packet, DecodeError> {
     match first_byte {
-        packet_type::CONNECT => decode_connect_packet(&mut src),
+        packet_type::CONNECT => decode_connect_packet(&mut src, false),
         packet_type::CONNACK => decode_connect_ack_packet(&mut src),
         packet_type::PUBLISH_START..=packet_type::PUBLISH_END => {
             decode_publish_packet(&mut src, first_byte & 0b0000_1111)
@@ -46,11 +46,29 @@ fn decode_ack(mut src: Bytes, f: impl Fn(NonZeroU16) -> Packet) -> Result<Packet
     Ok(f(packet_id))
 }
 
-fn decode_connect_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
+/// Decode `Connect` packet, `lenient` allows case-insensitive protocol name
+pub(super) fn decode_connect_packet(
+    src: &mut Bytes,
+    lenient: bool,
+) -> Result<Packet, DecodeError> {
+    decode_connect_packet_with(src, lenient, ClientIdEncoding::Strict).map(|(pkt, _)| pkt)
+}
+
+/// Decode `Connect` packet, returns packet and raw bytes of client id
+pub(super) fn decode_connect_packet_with(
+    src: &mut Bytes,
+    lenient: bool,
+    encoding: ClientIdEncoding,
+) -> Result<(Packet, Bytes), DecodeError> {
     ensure!(src.remaining() >= 10, DecodeError::InvalidLength);
     let len = src.get_u16();
 
-    ensure!(len == 4 && &src.as_ref()[0..4] == MQTT, DecodeError::InvalidProtocol);
+    ensure!(len == 4, DecodeError::InvalidProtocol);
+    let name = &src.as_ref()[0..4];
+    ensure!(
+        name == MQTT || (lenient && name.eq_ignore_ascii_case(MQTT)),
+        DecodeError::InvalidProtocol
+    );
     src.advance(4);
 
     let level = src.get_u8();
@@ -60,7 +78,8 @@ fn decode_connect_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
         ConnectFlags::from_bits(src.get_u8()).ok_or(DecodeError::ConnectReservedFlagSet)?;
 
     let keep_alive = u16::decode(src)?;
-    let client_id = ByteString::decode(src)?;
+    let raw_client_id = Bytes::decode(src)?;
+    let client_id = encoding.decode(&raw_client_id)?;
 
     ensure!(
         !client_id.is_empty() || flags.contains(ConnectFlags::CLEAN_START),
@@ -86,15 +105,15 @@ fn decode_connect_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
     };
     let password =
         if flags.contains(ConnectFlags::PASSWORD) { Some(Bytes::decode(src)?) } else { None };
-    Ok(Connect {
+    let pkt = Connect {
         clean_session: flags.contains(ConnectFlags::CLEAN_START),
         keep_alive,
         client_id,
         last_will,
         username,
         password,
-    }
-    .into())
+    };
+    Ok((pkt.into(), raw_client_id))
 }
 
 fn decode_connect_ack_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
@@ -185,9 +204,12 @@ mod tests {
     #[test]
     fn test_decode_connect_packets() {
         assert_eq!(
-            decode_connect_packet(&mut Bytes::from_static(
-                b"\x00\x04MQTT\x04\xC0\x00\x3C\x00\x0512345\x00\x04user\x00\x04pass"
-            )),
+            decode_connect_packet(
+                &mut Bytes::from_static(
+                    b"\x00\x04MQTT\x04\xC0\x00\x3C\x00\x0512345\x00\x04user\x00\x04pass"
+                ),
+                false
+            ),
             Ok(Packet::Connect(Box::new(Connect {
                 clean_session: false,
                 keep_alive: 60,
@@ -199,9 +221,12 @@ mod tests {
         );
 
         assert_eq!(
-            decode_connect_packet(&mut Bytes::from_static(
-                b"\x00\x04MQTT\x04\x14\x00\x3C\x00\x0512345\x00\x05topic\x00\x07message"
-            )),
+            decode_connect_packet(
+                &mut Bytes::from_static(
+                    b"\x00\x04MQTT\x04\x14\x00\x3C\x00\x0512345\x00\x05topic\x00\x07message"
+                ),
+                false
+            ),
             Ok(Packet::Connect(Box::new(Connect {
                 clean_session: false,
                 keep_alive: 60,
@@ -218,27 +243,38 @@ mod tests {
         );
 
         assert_eq!(
-            decode_connect_packet(&mut Bytes::from_static(b"\x00\x02MQ00000000000000000000")),
+            decode_connect_packet(
+                &mut Bytes::from_static(b"\x00\x02MQ00000000000000000000"),
+                false
+            ),
             Err(DecodeError::InvalidProtocol),
         );
         assert_eq!(
-            decode_connect_packet(&mut Bytes::from_static(b"\x00\x10MQ00000000000000000000")),
+            decode_connect_packet(
+                &mut Bytes::from_static(b"\x00\x10MQ00000000000000000000"),
+                false
+            ),
             Err(DecodeError::InvalidProtocol),
         );
         assert_eq!(
-            decode_connect_packet(&mut Bytes::from_static(b"\x00\x04MQAA00000000000000000000")),
+            decode_connect_packet(
+                &mut Bytes::from_static(b"\x00\x04MQAA00000000000000000000"),
+                false
+            ),
             Err(DecodeError::InvalidProtocol),
         );
         assert_eq!(
-            decode_connect_packet(&mut Bytes::from_static(
-                b"\x00\x04MQTT\x0300000000000000000000"
-            )),
+            decode_connect_packet(
+                &mut Bytes::from_static(b"\x00\x04MQTT\x0300000000000000000000"),
+                false
+            ),
             Err(DecodeError::UnsupportedProtocolLevel),
         );
         assert_eq!(
-            decode_connect_packet(&mut Bytes::from_static(
-                b"\x00\x04MQTT\x04\xff00000000000000000000"
-            )),
+            decode_connect_packet(
+                &mut Bytes::from_static(b"\x00\x04MQTT\x04\xff00000000000000000000"),
+                false
+            ),
             Err(DecodeError::ConnectReservedFlagSet)
         );
 
@@ -266,6 +302,48 @@ mod tests {
         assert_decode_packet!(b"\xe0\x00", Packet::Disconnect);
     }
 
+    #[test]
+    fn test_decode_connect_protocol_name() {
+        let exact = b"\x00\x04MQTT\x04\x02\x00\x3C\x00\x0512345";
+        let wrong_case = b"\x00\x04mqTT\x04\x02\x00\x3C\x00\x0512345";
+        let garbage = b"\x00\x04MQXT\x04\x02\x00\x3C\x00\x0512345";
+
+        for lenient in [false, true] {
+            assert!(decode_connect_packet(&mut Bytes::from_static(exact), lenient).is_ok());
+            assert_eq!(
+                decode_connect_packet(&mut Bytes::from_static(garbage), lenient),
+                Err(DecodeError::InvalidProtocol)
+            );
+        }
+        assert_eq!(
+            decode_connect_packet(&mut Bytes::from_static(wrong_case), false),
+            Err(DecodeError::InvalidProtocol)
+        );
+        assert!(decode_connect_packet(&mut Bytes::from_static(wrong_case), true).is_ok());
+    }
+
+    #[test]
+    fn test_decode_connect_client_id() {
+        let invalid = b"\x00\x04MQTT\x04\x02\x00\x3C\x00\x03a\xffb";
+        assert_eq!(
+            decode_connect_packet(&mut Bytes::from_static(invalid), false),
+            Err(DecodeError::InvalidClientId)
+        );
+
+        let (pkt, raw) = decode_connect_packet_with(
+            &mut Bytes::from_static(invalid),
+            false,
+            ClientIdEncoding::Lossy,
+        )
+        .unwrap();
+        if let Packet::Connect(pkt) = pkt {
+            assert_eq!(pkt.client_id, "a\u{FFFD}b");
+        } else {
+            panic!("Unexpected packet: {:?}", pkt);
+        }
+        assert_eq!(&raw[..], b"a\xffb");
+    }
+
     #[test]
     fn test_decode_publish_packets() {
         //assert_eq!(
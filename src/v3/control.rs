@@ -1,5 +1,5 @@
-use ntex::util::ByteString;
-use std::{io, marker::PhantomData, num::NonZeroU16};
+use ntex::{time::Seconds, util::ByteString};
+use std::{io, marker::PhantomData, num::NonZeroU16, time::Duration};
 
 use super::codec;
 use crate::{error, types::QoS};
@@ -22,6 +22,10 @@ pub enum ControlMessage<E> {
     ProtocolError(ProtocolError),
     /// Peer is gone
     PeerGone(PeerGone),
+    /// Keep-alive timeout is expired
+    Timeout(Timeout),
+    /// Inbound publishes are throttled by rate limit
+    RateLimited(RateLimited),
 }
 
 #[derive(Debug)]
@@ -38,6 +42,7 @@ pub(crate) enum ControlResultKind {
     Subscribe(SubscribeResult),
     Unsubscribe(UnsubscribeResult),
     Closed,
+    Extend(Seconds),
 }
 
 impl<E> ControlMessage<E> {
@@ -65,8 +70,12 @@ impl<E> ControlMessage<E> {
         ControlMessage::Disconnect(Disconnect)
     }
 
-    pub(super) fn closed(is_error: bool) -> Self {
-        ControlMessage::Closed(Closed::new(is_error))
+    pub(super) fn closed(
+        is_error: bool,
+        is_clean: bool,
+        will: Option<codec::LastWill>,
+    ) -> Self {
+        ControlMessage::Closed(Closed { is_error, is_clean, will })
     }
 
     pub(super) fn error(err: E) -> Self {
@@ -77,6 +86,14 @@ impl<E> ControlMessage<E> {
         ControlMessage::ProtocolError(ProtocolError::new(err))
     }
 
+    pub(super) fn timeout(idle: Duration) -> Self {
+        ControlMessage::Timeout(Timeout { idle })
+    }
+
+    pub(super) fn rate_limited(throttled: Duration) -> Self {
+        ControlMessage::RateLimited(RateLimited { throttled })
+    }
+
     /// Create a new `ControlMessage` from DISCONNECT packet.
     pub(super) fn peer_gone(err: Option<io::Error>) -> Self {
         ControlMessage::PeerGone(PeerGone(err))
@@ -166,6 +183,63 @@ impl ProtocolError {
     }
 }
 
+/// Keep-alive timeout, no packets are received from the client
+///
+/// Connection is closed, unless idle period is extended with `Timeout::extend()`.
+#[derive(Debug)]
+pub struct Timeout {
+    idle: Duration,
+}
+
+impl Timeout {
+    #[inline]
+    /// Time elapsed since last packet received from the client
+    pub fn idle(&self) -> Duration {
+        self.idle
+    }
+
+    #[inline]
+    /// Ack timeout and close connection
+    pub fn ack(self) -> ControlResult {
+        ControlResult { result: ControlResultKind::Disconnect }
+    }
+
+    #[inline]
+    /// Keep connection open and re-arm keep-alive timer with specified timeout.
+    ///
+    /// Regular keep-alive timeout is restored once a packet is received from
+    /// the client. Zero timeout closes connection.
+    pub fn extend(self, timeout: Seconds) -> ControlResult {
+        if timeout.is_zero() {
+            self.ack()
+        } else {
+            ControlResult { result: ControlResultKind::Extend(timeout) }
+        }
+    }
+}
+
+/// Inbound publishes are throttled by `MqttServer::publish_rate_limit()`
+///
+/// Connection could be closed with `ControlMessage::disconnect()`.
+#[derive(Debug)]
+pub struct RateLimited {
+    throttled: Duration,
+}
+
+impl RateLimited {
+    #[inline]
+    /// Time elapsed since connection is throttled
+    pub fn throttled(&self) -> Duration {
+        self.throttled
+    }
+
+    #[inline]
+    /// Ack message and keep throttling connection
+    pub fn ack(self) -> ControlResult {
+        ControlResult { result: ControlResultKind::Nothing }
+    }
+}
+
 /// Subscribe message
 #[derive(Debug)]
 pub struct Subscribe {
@@ -178,6 +252,7 @@ pub struct Subscribe {
 #[derive(Debug)]
 pub(crate) struct SubscribeResult {
     pub(crate) codes: Vec<codec::SubscribeReturnCode>,
+    pub(crate) topics: Vec<ByteString>,
     pub(crate) packet_id: NonZeroU16,
 }
 
@@ -204,6 +279,7 @@ impl Subscribe {
         ControlResult {
             result: ControlResultKind::Subscribe(SubscribeResult {
                 codes: self.codes,
+                topics: self.topics.into_iter().map(|(topic, _)| topic).collect(),
                 packet_id: self.packet_id,
             }),
         }
@@ -304,6 +380,7 @@ pub struct Unsubscribe {
 /// Result of a unsubscribe message
 #[derive(Debug)]
 pub(crate) struct UnsubscribeResult {
+    pub(crate) topics: Vec<ByteString>,
     pub(crate) packet_id: NonZeroU16,
 }
 
@@ -325,6 +402,7 @@ impl Unsubscribe {
     pub fn ack(self) -> ControlResult {
         ControlResult {
             result: ControlResultKind::Unsubscribe(UnsubscribeResult {
+                topics: self.topics,
                 packet_id: self.packet_id,
             }),
         }
@@ -335,11 +413,13 @@ impl Unsubscribe {
 #[derive(Debug)]
 pub struct Closed {
     is_error: bool,
+    is_clean: bool,
+    will: Option<codec::LastWill>,
 }
 
 impl Closed {
     pub(crate) fn new(is_error: bool) -> Self {
-        Self { is_error }
+        Self { is_error, is_clean: false, will: None }
     }
 
     /// Returns error state on connection close
@@ -347,6 +427,24 @@ impl Closed {
         self.is_error
     }
 
+    /// Returns `true` if connection is closed after DISCONNECT packet
+    pub fn is_clean(&self) -> bool {
+        self.is_clean
+    }
+
+    /// Will message that must be published.
+    ///
+    /// Will is set only if connection is closed without DISCONNECT packet
+    /// and will is not discarded with `MqttSink::discard_will()`.
+    pub fn will(&self) -> Option<&codec::LastWill> {
+        self.will.as_ref()
+    }
+
+    /// Take will message
+    pub fn take_will(&mut self) -> Option<codec::LastWill> {
+        self.will.take()
+    }
+
     #[inline]
     /// convert packet to a result
     pub fn ack(self) -> ControlResult {
@@ -86,6 +86,8 @@ impl<S, E: fmt::Debug> Service<ControlMessage<E>> for DefaultControlService<S, E
             ControlMessage::Ping(ping) => ping.ack(),
             ControlMessage::Disconnect(disc) => disc.ack(),
             ControlMessage::Closed(msg) => msg.ack(),
+            ControlMessage::Timeout(msg) => msg.ack(),
+            ControlMessage::RateLimited(msg) => msg.ack(),
             _ => {
                 log::warn!("MQTT3 Control service is not configured, pkt: {:?}", pkt);
                 ControlResult { result: ControlResultKind::Disconnect }
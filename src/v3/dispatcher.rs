@@ -1,14 +1,24 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use std::{future::Future, marker::PhantomData, num::NonZeroU16, pin::Pin, rc::Rc};
 
 use ntex::io::DispatchItem;
 use ntex::service::{fn_factory_with_config, Service, ServiceFactory};
+use ntex::time::{now, Deadline, Millis, Seconds, Sleep};
 use ntex::util::{
     buffer::BufferService, inflight::InFlightService, join, Either, HashSet, Ready,
 };
 
+use crate::coalesce::AckBatch;
+use crate::drain::{Drain, DrainConnection, DrainGuard};
 use crate::error::{MqttError, ProtocolError};
+use crate::events::LifecycleEventKind;
+use crate::limiter::{Qos2InflightLimit, Qos2Permit, RateLimiter};
+use crate::limiter::{SlidingWindow, TokenBucket};
+use crate::reject::{RejectReason, RejectSampler};
+use crate::session::DisconnectReason;
+use crate::types::{packet_type, IdleAction, QoS, TopicRewrite};
 
 use super::control::{
     ControlMessage, ControlResult, ControlResultKind, Subscribe, Unsubscribe,
@@ -16,12 +26,32 @@ use super::control::{
 use super::shared::MqttShared;
 use super::{codec, publish::Publish, shared::Ack, sink::MqttSink, Session};
 
+/// Dispatcher settings, built by `MqttServer`
+pub(super) struct DispatcherConfig<St> {
+    pub(super) inflight: u16,
+    pub(super) inflight_size: usize,
+    pub(super) subscribe_timeout: Seconds,
+    pub(super) min_qos: QoS,
+    pub(super) limiter: RateLimiter,
+    pub(super) strict_acks: bool,
+    pub(super) max_lifetime: Seconds,
+    pub(super) on_unexpected_ack: Option<Rc<dyn Fn(&Session<St>, NonZeroU16, u8)>>,
+    pub(super) on_rejected_publish: Option<Rc<RejectSampler<codec::Publish>>>,
+    pub(super) ingress_topic: Option<TopicRewrite>,
+    pub(super) egress_topic: Option<TopicRewrite>,
+    pub(super) qos2_limit: Option<Qos2InflightLimit>,
+    pub(super) on_ping: Option<Rc<dyn Fn(&Session<St>, Instant)>>,
+    pub(super) on_idle_timeout: Option<Rc<dyn Fn(&Session<St>) -> IdleAction>>,
+    pub(super) on_disconnect: Option<Rc<dyn Fn(&Session<St>, DisconnectReason)>>,
+    pub(super) coalesce_subacks: Option<(Millis, usize)>,
+    pub(super) drain: Drain,
+}
+
 /// mqtt3 protocol dispatcher
 pub(super) fn factory<St, T, C, E>(
     publish: T,
     control: C,
-    inflight: u16,
-    inflight_size: usize,
+    config: DispatcherConfig<St>,
 ) -> impl ServiceFactory<
     DispatchItem<Rc<MqttShared>>,
     Session<St>,
@@ -35,9 +65,46 @@ where
     C: ServiceFactory<ControlMessage<E>, Session<St>, Response = ControlResult> + 'static,
     E: From<C::Error> + From<C::InitError> + From<T::Error> + From<T::InitError> + 'static,
 {
+    let DispatcherConfig {
+        inflight,
+        inflight_size,
+        subscribe_timeout,
+        min_qos,
+        limiter,
+        strict_acks,
+        max_lifetime,
+        on_unexpected_ack,
+        on_rejected_publish,
+        ingress_topic,
+        egress_topic,
+        qos2_limit,
+        on_ping,
+        on_idle_timeout,
+        on_disconnect,
+        coalesce_subacks,
+        drain,
+    } = config;
+
     fn_factory_with_config(move |cfg: Session<St>| {
+        // close connection after max lifetime
+        cfg.sink().close_after(max_lifetime);
+        cfg.sink().set_topic_rewrite(ingress_topic.clone(), egress_topic.clone());
+        if let Some(ref hook) = on_idle_timeout {
+            let hook = hook.clone();
+            let session = cfg.downgrade();
+            cfg.sink().set_idle_handler(Box::new(move || {
+                session.upgrade().map(|s| (*hook)(&s)).unwrap_or(IdleAction::Disconnect)
+            }));
+        }
+
         // create services
         let fut = join(publish.new_service(cfg.clone()), control.new_service(cfg.clone()));
+        let on_unexpected_ack = on_unexpected_ack.clone();
+        let on_rejected_publish = on_rejected_publish.clone();
+        let qos2_limit = qos2_limit.clone();
+        let on_ping = on_ping.clone();
+        let on_disconnect = on_disconnect.clone();
+        let drain = drain.clone();
 
         async move {
             let (publish, control) = fut.await;
@@ -58,7 +125,21 @@ where
                 crate::inflight::InFlightService::new(
                     inflight,
                     inflight_size,
-                    Dispatcher::<_, _, _, E>::new(cfg, publish, control),
+                    Dispatcher::<_, _, _, E>::new(
+                        cfg,
+                        publish,
+                        control,
+                        subscribe_timeout,
+                        min_qos,
+                        limiter,
+                    )
+                    .unexpected_ack(strict_acks, on_unexpected_ack)
+                    .rejected_publish(on_rejected_publish)
+                    .qos2_limit(qos2_limit)
+                    .on_ping(on_ping)
+                    .on_disconnect(on_disconnect)
+                    .coalesce_subacks(coalesce_subacks)
+                    .drain(&drain),
                 ),
             )
         }
@@ -78,16 +159,77 @@ impl crate::inflight::SizedRequest for DispatchItem<Rc<MqttShared>> {
 /// Mqtt protocol dispatcher
 pub(crate) struct Dispatcher<St, T, C: Service<ControlMessage<E>>, E> {
     session: Session<St>,
-    publish: T,
+    publish: Rc<T>,
     shutdown: RefCell<Option<Pin<Box<C::Future>>>>,
+    throttled: RefCell<Option<Sleep>>,
     inner: Rc<Inner<C>>,
+    subscribe_timeout: Seconds,
+    strict_acks: bool,
+    on_unexpected_ack: Option<Rc<dyn Fn(&Session<St>, NonZeroU16, u8)>>,
+    on_rejected_publish: Option<Rc<RejectSampler<codec::Publish>>>,
+    qos2_limit: Option<Qos2InflightLimit>,
+    on_ping: Option<Rc<dyn Fn(&Session<St>, Instant)>>,
+    on_disconnect: Option<Rc<dyn Fn(&Session<St>, DisconnectReason)>>,
+    _drain: Option<DrainGuard>,
     _t: PhantomData<(E,)>,
 }
 
 struct Inner<C> {
     control: C,
     sink: MqttSink,
+    min_qos: QoS,
+    limiter: RefCell<SlidingWindow>,
+    throttle: RefCell<TokenBucket>,
     inflight: RefCell<HashSet<NonZeroU16>>,
+    subacks: RefCell<Option<Rc<AckBatch<codec::Packet>>>>,
+    last_activity: Cell<Instant>,
+    reason: RefCell<Option<DisconnectReason>>,
+}
+
+impl<C> Inner<C> {
+    /// Queue SUBACK packet if writes are coalesced
+    fn suback(&self, pkt: Option<codec::Packet>) -> Option<codec::Packet> {
+        match (pkt, &*self.subacks.borrow()) {
+            (Some(pkt @ codec::Packet::SubscribeAck { .. }), Some(batch)) => {
+                batch.push(pkt);
+                None
+            }
+            (pkt, _) => pkt,
+        }
+    }
+
+    /// Record disconnect reason, first recorded reason wins
+    fn disconnect_reason(&self, reason: DisconnectReason) {
+        let mut cur = self.reason.borrow_mut();
+        if cur.is_none() {
+            *cur = Some(reason);
+        }
+    }
+
+    /// Disconnect reason, if connection is terminated without recorded reason
+    fn take_disconnect_reason(&self) -> DisconnectReason {
+        self.reason.borrow_mut().take().unwrap_or_else(|| {
+            if self.sink.is_closed_locally() {
+                DisconnectReason::ServerEvicted
+            } else {
+                DisconnectReason::Io
+            }
+        })
+    }
+}
+
+impl<C> DrainConnection for Inner<C> {
+    fn is_busy(&self) -> bool {
+        !self.inflight.borrow().is_empty() || self.sink.inflight_count() != 0
+    }
+
+    fn disconnect(&self) {
+        self.sink.close();
+    }
+
+    fn force_close(&self) {
+        self.sink.force_close();
+    }
 }
 
 impl<St, T, C, E> Dispatcher<St, T, C, E>
@@ -96,17 +238,140 @@ where
     T: Service<Publish, Response = ()>,
     C: Service<ControlMessage<E>, Response = ControlResult, Error = MqttError<E>>,
 {
-    pub(crate) fn new(session: Session<St>, publish: T, control: C) -> Self {
+    pub(crate) fn new(
+        session: Session<St>,
+        publish: T,
+        control: C,
+        subscribe_timeout: Seconds,
+        min_qos: QoS,
+        limiter: RateLimiter,
+    ) -> Self {
         let sink = session.sink().clone();
 
         Self {
             session,
-            publish,
+            publish: Rc::new(publish),
+            subscribe_timeout,
+            strict_acks: true,
+            on_unexpected_ack: None,
+            on_rejected_publish: None,
+            qos2_limit: None,
+            on_ping: None,
+            on_disconnect: None,
             shutdown: RefCell::new(None),
-            inner: Rc::new(Inner { sink, control, inflight: RefCell::new(HashSet::default()) }),
+            throttled: RefCell::new(None),
+            _drain: None,
+            inner: Rc::new(Inner {
+                sink,
+                control,
+                min_qos,
+                limiter: RefCell::new(SlidingWindow::new(limiter)),
+                throttle: RefCell::new(TokenBucket::new(limiter)),
+                inflight: RefCell::new(HashSet::default()),
+                subacks: RefCell::new(None),
+                last_activity: Cell::new(now()),
+                reason: RefCell::new(None),
+            }),
             _t: PhantomData,
         }
     }
+
+    /// Set unknown acks handling
+    pub(crate) fn unexpected_ack(
+        mut self,
+        strict: bool,
+        hook: Option<Rc<dyn Fn(&Session<St>, NonZeroU16, u8)>>,
+    ) -> Self {
+        self.strict_acks = strict;
+        self.on_unexpected_ack = hook;
+        self
+    }
+
+    /// Set rejected publish handler
+    pub(crate) fn rejected_publish(
+        mut self,
+        hook: Option<Rc<RejectSampler<codec::Publish>>>,
+    ) -> Self {
+        self.on_rejected_publish = hook;
+        self
+    }
+
+    /// Set global QoS 2 inflight limit
+    pub(crate) fn qos2_limit(mut self, limit: Option<Qos2InflightLimit>) -> Self {
+        self.qos2_limit = limit;
+        self
+    }
+
+    /// Set PINGREQ handler
+    pub(crate) fn on_ping(mut self, hook: Option<Rc<dyn Fn(&Session<St>, Instant)>>) -> Self {
+        self.on_ping = hook;
+        self
+    }
+
+    /// Set connection termination handler
+    pub(crate) fn on_disconnect(
+        mut self,
+        hook: Option<Rc<dyn Fn(&Session<St>, DisconnectReason)>>,
+    ) -> Self {
+        self.on_disconnect = hook;
+        self
+    }
+
+    /// Register connection for draining on server shutdown
+    pub(crate) fn drain(mut self, drain: &Drain) -> Self
+    where
+        C: 'static,
+    {
+        self._drain = drain.register(self.inner.clone());
+        self
+    }
+
+    /// Set SUBACK writes coalescing
+    pub(crate) fn coalesce_subacks(self, cfg: Option<(Millis, usize)>) -> Self {
+        let sink = self.inner.sink.clone();
+        *self.inner.subacks.borrow_mut() = cfg.map(|(max_delay, max_count)| {
+            AckBatch::new(max_delay, max_count, move |pkts| {
+                for pkt in pkts {
+                    sink.send(pkt);
+                }
+            })
+        });
+        self
+    }
+
+    fn publish_rejected(&self, publish: &codec::Publish, reason: RejectReason) {
+        if let Some(ref hook) = self.on_rejected_publish {
+            hook.rejected(publish, reason);
+        }
+    }
+
+    /// Handle ack of outbound publish, subscribe or unsubscribe packet
+    fn outbound_ack(
+        &self,
+        packet_id: NonZeroU16,
+        packet_type: u8,
+        ack: Ack,
+    ) -> Either<Ready<Option<codec::Packet>, MqttError<E>>, ControlResponse<C, E>> {
+        if !self.session.sink().is_inflight(packet_id.get()) {
+            log::trace!(
+                "{}: Unexpected ack packet {:#04X}: {:?}",
+                self.session.sink().connection_id(),
+                packet_type,
+                packet_id
+            );
+            if let Some(ref hook) = self.on_unexpected_ack {
+                (*hook)(&self.session, packet_id, packet_type);
+            }
+            if !self.strict_acks {
+                return Either::Left(Ready::Ok(None));
+            }
+        }
+        if let Err(e) = self.session.sink().pkt_ack(ack) {
+            Either::Right(ControlResponse::new(ControlMessage::proto_error(e), &self.inner))
+        } else {
+            Either::Left(Ready::Ok(None))
+        }
+    }
 }
 
 impl<St, T, C, E> Service<DispatchItem<Rc<MqttShared>>> for Dispatcher<St, T, C, E>
@@ -126,7 +391,32 @@ where
         let res1 = self.publish.poll_ready(cx).map_err(|e| MqttError::Service(e.into()))?;
         let res2 = self.inner.control.poll_ready(cx)?;
 
-        if res1.is_pending() || res2.is_pending() {
+        // pause reading while next publish packet exceeds rate limit,
+        // other packets are read until publish packet is received
+        let mut res3 = Poll::Ready(());
+        if self.inner.sink.is_publish_next() {
+            let delay = self.inner.throttle.borrow_mut().wait();
+            if let Some(throttled) = self.inner.throttle.borrow_mut().take_notify() {
+                ntex::rt::spawn(rate_limited(self.inner.clone(), throttled));
+            }
+            if !delay.is_zero() {
+                log::trace!(
+                    "{}: Inbound publish is throttled for {:?}",
+                    self.inner.sink.connection_id(),
+                    delay
+                );
+                let mut sleep = self.throttled.borrow_mut();
+                if let Some(ref sleep) = *sleep {
+                    sleep.reset(delay);
+                } else {
+                    *sleep = Some(Sleep::new(delay.into()));
+                }
+                let _ = sleep.as_ref().unwrap().poll_elapsed(cx);
+                res3 = Poll::Pending;
+            }
+        }
+
+        if res1.is_pending() || res2.is_pending() || res3.is_pending() {
             Poll::Pending
         } else {
             Poll::Ready(Ok(()))
@@ -136,9 +426,19 @@ where
     fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
         let mut shutdown = self.shutdown.borrow_mut();
         if !shutdown.is_some() {
+            if let Some(ref batch) = *self.inner.subacks.borrow() {
+                batch.flush();
+            }
+            if let Some(ref hook) = self.on_disconnect {
+                (*hook)(&self.session, self.inner.take_disconnect_reason());
+            }
             self.inner.sink.close();
-            *shutdown =
-                Some(Box::pin(self.inner.control.call(ControlMessage::closed(is_error))));
+            let msg = ControlMessage::closed(
+                is_error,
+                self.inner.sink.is_clean_disconnect(),
+                self.inner.sink.take_will(),
+            );
+            *shutdown = Some(Box::pin(self.inner.control.call(msg)));
         }
 
         let res0 = shutdown.as_mut().expect("guard above").as_mut().poll(cx);
@@ -152,95 +452,262 @@ where
     }
 
     fn call(&self, req: DispatchItem<Rc<MqttShared>>) -> Self::Future {
-        log::trace!("Dispatch v3 packet: {:#?}", req);
+        log::trace!("{}: Dispatch v3 packet: {:#?}", self.inner.sink.connection_id(), req);
+
+        // check packet types allowed for the connection
+        if let DispatchItem::Item(ref pkt) = req {
+            self.inner.last_activity.set(now());
+            let packet_type = pkt.packet_type();
+            if !self.inner.sink.is_packet_allowed(packet_type) {
+                log::trace!(
+                    "{}: Packet type is not allowed: {:#04X}",
+                    self.inner.sink.connection_id(),
+                    packet_type
+                );
+                return Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::proto_error(ProtocolError::PacketNotAllowed(packet_type)),
+                    &self.inner,
+                )));
+            }
+        }
 
         match req {
-            DispatchItem::Item(codec::Packet::Publish(publish)) => {
+            DispatchItem::Item(codec::Packet::Publish(mut publish)) => {
+                self.inner.sink.inbound_topic(&mut publish.topic);
                 let inner = self.inner.clone();
                 let packet_id = publish.packet_id;
 
-                // check for duplicated packet id
+                // check inbound publish rate
+                if !inner.limiter.borrow_mut().check() {
+                    log::trace!(
+                        "{}: Inbound publish rate limit exceeded",
+                        self.inner.sink.connection_id()
+                    );
+                    self.publish_rejected(&publish, RejectReason::RateLimitExceeded);
+                    return Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(ProtocolError::RateLimitExceeded),
+                        &self.inner,
+                    )));
+                }
+
+                // check for minimum qos
+                if publish.qos < inner.min_qos {
+                    log::trace!(
+                        "{}: Publish qos is lower than minimum: {:?}",
+                        self.inner.sink.connection_id(),
+                        publish.qos
+                    );
+                    self.publish_rejected(&publish, RejectReason::QosNotSupported);
+                    return Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(ProtocolError::QosNotSupported),
+                        &self.inner,
+                    )));
+                }
+
                 if let Some(pid) = packet_id {
+                    // check in-flight window
+                    let window = inner.sink.inbound_window();
+                    let exceeded = window != 0 && {
+                        let inflight = inner.inflight.borrow();
+                        inflight.len() >= window && !inflight.contains(&pid)
+                    };
+                    if exceeded {
+                        log::trace!(
+                            "{}: In-flight window is exceeded, packet id: {:?}",
+                            self.inner.sink.connection_id(),
+                            pid
+                        );
+                        self.publish_rejected(&publish, RejectReason::ReceiveMaximumExceeded);
+                        return Either::Right(Either::Right(ControlResponse::new(
+                            ControlMessage::proto_error(ProtocolError::ReceiveMaximumExceeded),
+                            &self.inner,
+                        )));
+                    }
+
+                    // check for duplicated packet id
                     if !inner.inflight.borrow_mut().insert(pid) {
-                        log::trace!("Duplicated packet id for publish packet: {:?}", pid);
+                        log::trace!(
+                            "{}: Duplicated packet id for publish packet: {:?}",
+                            self.inner.sink.connection_id(),
+                            pid
+                        );
                         return Either::Right(Either::Right(ControlResponse::new(
                             ControlMessage::proto_error(ProtocolError::ReceiveMaximumExceeded),
                             &self.inner,
                         )));
                     }
                 }
+                let rejected = self.on_rejected_publish.clone().map(|h| (h, publish.clone()));
+
+                // reading is paused until token is available, see `poll_throttle()`
+                inner.throttle.borrow_mut().acquire();
+
+                // delay qos2 publish until global limit allows it
+                let limit = match self.qos2_limit {
+                    Some(ref limit) if publish.qos == QoS::ExactlyOnce => Some(limit.clone()),
+                    _ => None,
+                };
+                let publish = Publish::new(publish);
+                let state = if let Some(limit) = limit {
+                    PublishResponseState::Qos2 {
+                        limit,
+                        service: self.publish.clone(),
+                        publish: Some(publish),
+                    }
+                } else {
+                    PublishResponseState::Publish { fut: self.publish.call(publish) }
+                };
                 Either::Left(PublishResponse {
                     packet_id,
                     inner,
-                    state: PublishResponseState::Publish {
-                        fut: self.publish.call(Publish::new(publish)),
-                    },
+                    rejected,
+                    permit: None,
+                    state,
                 })
             }
-            DispatchItem::Item(codec::Packet::PublishAck { packet_id }) => {
-                if let Err(e) = self.session.sink().pkt_ack(Ack::Publish(packet_id)) {
-                    Either::Right(Either::Right(ControlResponse::new(
-                        ControlMessage::proto_error(e),
-                        &self.inner,
-                    )))
-                } else {
-                    Either::Right(Either::Left(Ready::Ok(None)))
+            DispatchItem::Item(codec::Packet::PublishAck { packet_id }) => Either::Right(
+                self.outbound_ack(packet_id, packet_type::PUBACK, Ack::Publish(packet_id)),
+            ),
+            DispatchItem::Item(codec::Packet::PublishReceived { packet_id }) => Either::Right(
+                self.outbound_ack(packet_id, packet_type::PUBREC, Ack::Receive(packet_id)),
+            ),
+            DispatchItem::Item(codec::Packet::PublishComplete { packet_id }) => Either::Right(
+                self.outbound_ack(packet_id, packet_type::PUBCOMP, Ack::Complete(packet_id)),
+            ),
+            DispatchItem::Item(codec::Packet::SubscribeAck { packet_id, status }) => {
+                Either::Right(self.outbound_ack(
+                    packet_id,
+                    packet_type::SUBACK,
+                    Ack::Subscribe { packet_id, status },
+                ))
+            }
+            DispatchItem::Item(codec::Packet::UnsubscribeAck { packet_id }) => {
+                Either::Right(self.outbound_ack(
+                    packet_id,
+                    packet_type::UNSUBACK,
+                    Ack::Unsubscribe(packet_id),
+                ))
+            }
+            DispatchItem::Item(codec::Packet::PingRequest) => {
+                let at = self.inner.sink.ping_received();
+                if let Some(ref hook) = self.on_ping {
+                    (*hook)(&self.session, at);
                 }
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::ping(),
+                    &self.inner,
+                )))
             }
-            DispatchItem::Item(codec::Packet::PingRequest) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::ping(), &self.inner),
-            )),
-            DispatchItem::Item(codec::Packet::Subscribe { packet_id, topic_filters }) => {
+            DispatchItem::Item(codec::Packet::Subscribe { packet_id, mut topic_filters }) => {
                 if !self.inner.inflight.borrow_mut().insert(packet_id) {
-                    log::trace!("Duplicated packet id for unsubscribe packet: {:?}", packet_id);
+                    log::trace!(
+                        "{}: Duplicated packet id for unsubscribe packet: {:?}",
+                        self.inner.sink.connection_id(),
+                        packet_id
+                    );
                     return Either::Right(Either::Left(Ready::Err(MqttError::ServerError(
                         "Duplicated packet id for unsubscribe packet",
                     ))));
                 }
 
-                Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::subscribe(Subscribe::new(packet_id, topic_filters)),
-                    &self.inner,
-                )))
+                for (topic, _) in &mut topic_filters {
+                    self.inner.sink.inbound_topic(topic);
+                }
+
+                // ack with failure codes if control service does not complete in time
+                let timeout_pkt = codec::Packet::SubscribeAck {
+                    packet_id,
+                    status: vec![codec::SubscribeReturnCode::Failure; topic_filters.len()],
+                };
+
+                Either::Right(Either::Right(
+                    ControlResponse::new(
+                        ControlMessage::subscribe(Subscribe::new(packet_id, topic_filters)),
+                        &self.inner,
+                    )
+                    .timeout(
+                        self.subscribe_timeout,
+                        packet_id,
+                        timeout_pkt,
+                    ),
+                ))
             }
-            DispatchItem::Item(codec::Packet::Unsubscribe { packet_id, topic_filters }) => {
+            DispatchItem::Item(codec::Packet::Unsubscribe { packet_id, mut topic_filters }) => {
                 if !self.inner.inflight.borrow_mut().insert(packet_id) {
-                    log::trace!("Duplicated packet id for unsubscribe packet: {:?}", packet_id);
+                    log::trace!(
+                        "{}: Duplicated packet id for unsubscribe packet: {:?}",
+                        self.inner.sink.connection_id(),
+                        packet_id
+                    );
                     return Either::Right(Either::Left(Ready::Err(MqttError::ServerError(
                         "Duplicated packet id for unsubscribe packet",
                     ))));
                 }
 
+                for topic in &mut topic_filters {
+                    self.inner.sink.inbound_topic(topic);
+                }
+
                 Either::Right(Either::Right(ControlResponse::new(
                     ControlMessage::unsubscribe(Unsubscribe::new(packet_id, topic_filters)),
                     &self.inner,
                 )))
             }
-            DispatchItem::Item(codec::Packet::Disconnect) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::remote_disconnect(), &self.inner),
-            )),
-            DispatchItem::Item(_) => Either::Right(Either::Left(Ready::Ok(None))),
-            DispatchItem::EncoderError(err) => {
+            DispatchItem::Item(codec::Packet::Disconnect) => {
+                self.inner.sink.disconnect_received();
+                self.inner.disconnect_reason(DisconnectReason::Clean);
                 Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::Encode(err)),
+                    ControlMessage::remote_disconnect(),
                     &self.inner,
                 )))
             }
-            DispatchItem::KeepAliveTimeout => {
+            DispatchItem::Item(codec::Packet::Connect(_)) => {
+                log::trace!(
+                    "{}: Second CONNECT packet is received",
+                    self.inner.sink.connection_id()
+                );
                 Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::KeepAliveTimeout),
+                    ControlMessage::proto_error(ProtocolError::Unexpected(
+                        packet_type::CONNECT,
+                        "MQTT-3.1.0-2: Second CONNECT packet",
+                    )),
                     &self.inner,
                 )))
             }
-            DispatchItem::DecoderError(err) => {
+            DispatchItem::Item(codec::Packet::PingResponse) => {
+                self.inner.sink.ping_response();
+                Either::Right(Either::Left(Ready::Ok(None)))
+            }
+            DispatchItem::Item(_) => Either::Right(Either::Left(Ready::Ok(None))),
+            DispatchItem::EncoderError(err) => {
                 Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::Decode(err)),
+                    ControlMessage::proto_error(ProtocolError::Encode(err)),
                     &self.inner,
                 )))
             }
-            DispatchItem::Disconnect(err) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::peer_gone(err), &self.inner),
+            DispatchItem::KeepAliveTimeout => {
+                // idle handler extends idle period without calling control service
+                if let Some(timeout) = self.inner.sink.idle_timeout() {
+                    self.inner.sink.extend_keepalive(timeout);
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                } else {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::timeout(self.inner.last_activity.get().elapsed()),
+                        &self.inner,
+                    )))
+                }
+            }
+            DispatchItem::DecoderError(err) => Either::Right(Either::Right(
+                ControlResponse::new(ControlMessage::proto_error(err.into()), &self.inner),
             )),
+            DispatchItem::Disconnect(err) => {
+                // read and write timeouts are reported as protocol errors
+                let msg = match err.as_ref().and_then(ProtocolError::from_io_timeout) {
+                    Some(err) => ControlMessage::proto_error(err),
+                    None => ControlMessage::peer_gone(err),
+                };
+                Either::Right(Either::Right(ControlResponse::new(msg, &self.inner)))
+            }
             DispatchItem::WBackPressureEnabled | DispatchItem::WBackPressureDisabled => {
                 Either::Right(Either::Left(Ready::Ok(None)))
             }
@@ -255,12 +722,15 @@ pin_project_lite::pin_project! {
         state: PublishResponseState<T, C, E>,
         packet_id: Option<NonZeroU16>,
         inner: Rc<Inner<C>>,
+        rejected: Option<(Rc<RejectSampler<codec::Publish>>, codec::Publish)>,
+        permit: Option<Qos2Permit>,
     }
 }
 
 pin_project_lite::pin_project! {
     #[project = PublishResponseStateProject]
     enum PublishResponseState<T: Service<Publish>, C: Service<ControlMessage<E>>, E> {
+        Qos2 { limit: Qos2InflightLimit, service: Rc<T>, publish: Option<Publish> },
         Publish { #[pin] fut: T::Future },
         Control { #[pin] fut: ControlResponse<C, E> },
     }
@@ -278,9 +748,24 @@ where
         let mut this = self.as_mut().project();
 
         match this.state.as_mut().project() {
+            PublishResponseStateProject::Qos2 { limit, service, publish } => {
+                match limit.poll_acquire(cx) {
+                    Poll::Ready(permit) => {
+                        *this.permit = Some(permit);
+                        let fut = service.call(publish.take().expect("Publish is set"));
+                        this.state.set(PublishResponseState::Publish { fut });
+                        self.poll(cx)
+                    }
+                    Poll::Pending => Poll::Pending,
+                }
+            }
             PublishResponseStateProject::Publish { fut } => match fut.poll(cx) {
                 Poll::Ready(Ok(_)) => {
-                    log::trace!("Publish result for packet {:?} is ready", this.packet_id);
+                    log::trace!(
+                        "{}: Publish result for packet {:?} is ready",
+                        this.inner.sink.connection_id(),
+                        this.packet_id
+                    );
 
                     if let Some(packet_id) = this.packet_id {
                         this.inner.inflight.borrow_mut().remove(packet_id);
@@ -292,6 +777,9 @@ where
                     }
                 }
                 Poll::Ready(Err(e)) => {
+                    if let Some((hook, publish)) = this.rejected.take() {
+                        hook.rejected(&publish, RejectReason::Service);
+                    }
                     this.state.set(PublishResponseState::Control {
                         fut: ControlResponse::new(ControlMessage::error(e.into()), this.inner),
                     });
@@ -304,6 +792,24 @@ where
     }
 }
 
+/// Notify control service about throttled connection
+async fn rate_limited<C, E>(inner: Rc<Inner<C>>, throttled: Duration)
+where
+    C: Service<ControlMessage<E>, Response = ControlResult, Error = MqttError<E>>,
+{
+    match inner.control.call(ControlMessage::rate_limited(throttled)).await {
+        Ok(ControlResult { result: ControlResultKind::Disconnect }) => inner.sink.close(),
+        Ok(_) => (),
+        Err(_) => {
+            log::trace!(
+                "{}: Control service failed for rate limited connection",
+                inner.sink.connection_id()
+            );
+            inner.sink.close();
+        }
+    }
+}
+
 pin_project_lite::pin_project! {
     /// Control service response future
     pub(crate) struct ControlResponse<C: Service<ControlMessage<E>>, E>
@@ -312,6 +818,7 @@ pin_project_lite::pin_project! {
         fut: C::Future,
         inner: Rc<Inner<C>>,
         error: bool,
+        timeout: Option<(Deadline, NonZeroU16, codec::Packet)>,
         _t: PhantomData<E>,
     }
 }
@@ -322,12 +829,38 @@ where
 {
     #[allow(clippy::match_like_matches_macro)]
     fn new(pkt: ControlMessage<E>, inner: &Rc<Inner<C>>) -> Self {
+        match pkt {
+            ControlMessage::ProtocolError(ref e) => {
+                inner.sink.protocol_error(e.get_ref());
+                inner.disconnect_reason(DisconnectReason::Protocol(e.get_ref().clone()));
+            }
+            ControlMessage::Timeout(_) => {
+                inner.disconnect_reason(DisconnectReason::KeepaliveTimeout)
+            }
+            _ => (),
+        }
         let error = match pkt {
-            ControlMessage::Error(_) | ControlMessage::ProtocolError(_) => true,
+            ControlMessage::Error(_)
+            | ControlMessage::ProtocolError(_)
+            | ControlMessage::Timeout(_) => true,
             _ => false,
         };
 
-        Self { error, fut: inner.control.call(pkt), inner: inner.clone(), _t: PhantomData }
+        Self {
+            error,
+            fut: inner.control.call(pkt),
+            inner: inner.clone(),
+            timeout: None,
+            _t: PhantomData,
+        }
+    }
+
+    /// Respond with `pkt` if control service does not complete within timeout
+    fn timeout(mut self, timeout: Seconds, packet_id: NonZeroU16, pkt: codec::Packet) -> Self {
+        if !timeout.is_zero() {
+            self.timeout = Some((Deadline::new(timeout.into()), packet_id, pkt));
+        }
+        self
     }
 }
 
@@ -344,8 +877,25 @@ where
             Poll::Ready(Ok(item)) => {
                 let packet = match item.result {
                     ControlResultKind::Ping => Some(codec::Packet::PingResponse),
-                    ControlResultKind::Subscribe(res) => {
+                    ControlResultKind::Subscribe(mut res) => {
                         this.inner.inflight.borrow_mut().remove(&res.packet_id);
+
+                        // fail subscriptions with qos lower than minimum
+                        let min_qos = this.inner.min_qos;
+                        for (code, topic) in res.codes.iter_mut().zip(res.topics) {
+                            if let codec::SubscribeReturnCode::Success(qos) = code {
+                                if *qos < min_qos {
+                                    *code = codec::SubscribeReturnCode::Failure;
+                                } else {
+                                    this.inner.sink.lifecycle_event(
+                                        LifecycleEventKind::SubscriptionAdded {
+                                            topic,
+                                            qos: *qos,
+                                        },
+                                    );
+                                }
+                            }
+                        }
                         Some(codec::Packet::SubscribeAck {
                             status: res.codes,
                             packet_id: res.packet_id,
@@ -353,6 +903,11 @@ where
                     }
                     ControlResultKind::Unsubscribe(res) => {
                         this.inner.inflight.borrow_mut().remove(&res.packet_id);
+                        for topic in res.topics {
+                            this.inner.sink.lifecycle_event(
+                                LifecycleEventKind::SubscriptionRemoved { topic },
+                            );
+                        }
                         Some(codec::Packet::UnsubscribeAck { packet_id: res.packet_id })
                     }
                     ControlResultKind::Disconnect
@@ -361,9 +916,13 @@ where
                         this.inner.sink.close();
                         None
                     }
+                    ControlResultKind::Extend(timeout) => {
+                        this.inner.sink.extend_keepalive(timeout);
+                        None
+                    }
                     ControlResultKind::PublishAck(_) => unreachable!(),
                 };
-                Poll::Ready(Ok(packet))
+                Poll::Ready(Ok(this.inner.suback(packet)))
             }
             Poll::Ready(Err(err)) => {
                 // do not handle nested error
@@ -382,7 +941,21 @@ where
                     }
                 }
             }
-            Poll::Pending => Poll::Pending,
+            Poll::Pending => {
+                if let Some((ref deadline, packet_id, _)) = this.timeout {
+                    if deadline.poll_elapsed(cx).is_ready() {
+                        log::trace!(
+                            "{}: Control service timeout for packet: {:?}",
+                            this.inner.sink.connection_id(),
+                            packet_id
+                        );
+                        this.inner.inflight.borrow_mut().remove(packet_id);
+                        let pkt = this.timeout.take().map(|(_, _, pkt)| pkt);
+                        return Poll::Ready(Ok(this.inner.suback(pkt)));
+                    }
+                }
+                Poll::Pending
+            }
         }
     }
 }
@@ -37,7 +37,7 @@ impl From<Either<EncodeError, std::io::Error>> for ClientError {
 impl From<Either<DecodeError, std::io::Error>> for ClientError {
     fn from(err: Either<DecodeError, std::io::Error>) -> Self {
         match err {
-            Either::Left(err) => ClientError::Protocol(ProtocolError::Decode(err)),
+            Either::Left(err) => ClientError::Protocol(err.into()),
             Either::Right(err) => ClientError::Disconnected(Some(err)),
         }
     }
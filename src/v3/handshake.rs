@@ -1,21 +1,35 @@
-use std::{fmt, rc::Rc};
+use std::{fmt, io, rc::Rc};
 
+use ntex::codec::{Decoder, Encoder};
+use ntex::util::{Bytes, Either, Extensions};
 use ntex::{io::IoBoxed, time::Seconds};
 
 use super::codec as mqtt;
 use super::shared::MqttShared;
 use super::sink::MqttSink;
+use crate::selector::SelectContext;
+use crate::types::{ConnectSummary, PacketMask, MQTT_LEVEL_3};
 
 /// Connect message
 pub struct Handshake {
     io: IoBoxed,
     pkt: Box<mqtt::Connect>,
-    shared: Rc<MqttShared>,
+    pub(super) shared: Rc<MqttShared>,
+    raw: Bytes,
+    raw_client_id: Bytes,
+    pub(super) ctx: SelectContext,
+    pub(super) data: Extensions,
 }
 
 impl Handshake {
     pub(crate) fn new(pkt: Box<mqtt::Connect>, io: IoBoxed, shared: Rc<MqttShared>) -> Self {
-        Self { io, pkt, shared }
+        let raw = shared.codec.take_connect_bytes();
+        let raw_client_id = shared
+            .codec
+            .take_client_id_bytes()
+            .unwrap_or_else(|| pkt.client_id.as_bytes().clone());
+        let ctx = SelectContext::new(&io);
+        Self { io, pkt, shared, raw, raw_client_id, ctx, data: Extensions::new() }
     }
 
     pub fn packet(&self) -> &mqtt::Connect {
@@ -26,11 +40,92 @@ impl Handshake {
         &mut self.pkt
     }
 
+    /// Returns connect packet summary, it does not contain credentials
+    pub fn summary(&self) -> ConnectSummary {
+        ConnectSummary {
+            client_id: self.pkt.client_id.clone(),
+            protocol_level: MQTT_LEVEL_3,
+            clean: self.pkt.clean_session,
+            keep_alive: self.pkt.keep_alive,
+            has_will: self.pkt.last_will.is_some(),
+            has_username: self.pkt.username.is_some(),
+        }
+    }
+
+    /// Returns raw bytes of connect packet, fixed header is not included
+    ///
+    /// Raw bytes are kept only if it is enabled with `keep_connect_bytes()`
+    /// server option, otherwise empty slice is returned.
+    pub fn raw_connect_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// Returns raw bytes of client id
+    ///
+    /// Raw bytes differ from decoded client id if server accepts client ids
+    /// that are not valid utf-8, see `ClientIdEncoding::Lossy`.
+    pub fn raw_client_id(&self) -> &[u8] {
+        &self.raw_client_id
+    }
+
+    /// Connection id, see `Session::connection_id()`
+    pub fn connection_id(&self) -> u64 {
+        self.shared.id
+    }
+
+    /// Transport properties of connection
+    pub fn select_context(&self) -> &SelectContext {
+        &self.ctx
+    }
+
+    /// Returns data attached by selector's variant check
+    ///
+    /// Data is attached if variant check returns `Some(data)`, see
+    /// `Selector::variant()`.
+    pub fn data<T: 'static>(&self) -> Option<&T> {
+        self.data.get()
+    }
+
+    /// Take data attached by selector's variant check
+    pub fn take_data<T: 'static>(&mut self) -> Option<T> {
+        self.data.remove()
+    }
+
     #[inline]
     pub fn io(&self) -> &IoBoxed {
         &self.io
     }
 
+    /// Read frame of custom protocol from peer.
+    ///
+    /// Could be used for application level exchange before handshake gets
+    /// acked, for example challenge/response authentication. Codec must consume
+    /// whole frames, bytes left in read buffer are decoded as mqtt packets
+    /// once handshake is acked.
+    pub async fn recv_frame<U: Decoder>(
+        &self,
+        codec: &U,
+    ) -> Result<Option<U::Item>, Either<U::Error, io::Error>> {
+        self.io.recv(codec).await
+    }
+
+    /// Send frame of custom protocol to peer
+    pub async fn send_frame<U: Encoder>(
+        &self,
+        item: U::Item,
+        codec: &U,
+    ) -> Result<(), Either<U::Error, io::Error>> {
+        self.io.send(item, codec).await
+    }
+
+    /// Restrict packet types peer is allowed to send.
+    ///
+    /// Disallowed packet is handled as protocol error.
+    /// By default all packet types are allowed.
+    pub fn restrict_packets(&self, mask: PacketMask) {
+        self.shared.allowed_packets.set(mask);
+    }
+
     /// Returns mqtt server sink
     pub fn sink(&self) -> MqttSink {
         MqttSink::new(self.shared.clone())
@@ -38,7 +133,7 @@ impl Handshake {
 
     /// Ack handshake message and set state
     pub fn ack<St>(self, st: St, session_present: bool) -> HandshakeAck<St> {
-        let Handshake { io, shared, pkt } = self;
+        let Handshake { io, shared, pkt, .. } = self;
         // [MQTT-3.1.2-24].
         let keepalive = if pkt.keep_alive != 0 {
             (pkt.keep_alive >> 1).checked_add(pkt.keep_alive).unwrap_or(u16::MAX)
@@ -57,49 +152,48 @@ impl Handshake {
 
     /// Create connect ack object with `identifier rejected` return code
     pub fn identifier_rejected<St>(self) -> HandshakeAck<St> {
-        HandshakeAck {
-            io: self.io,
-            shared: self.shared,
-            session: None,
-            session_present: false,
-            keepalive: Seconds(30),
-            return_code: mqtt::ConnectAckReason::IdentifierRejected,
-        }
+        self.failed(mqtt::ConnectAckReason::IdentifierRejected)
     }
 
     /// Create connect ack object with `bad user name or password` return code
     pub fn bad_username_or_pwd<St>(self) -> HandshakeAck<St> {
-        HandshakeAck {
-            io: self.io,
-            shared: self.shared,
-            session: None,
-            session_present: false,
-            keepalive: Seconds(30),
-            return_code: mqtt::ConnectAckReason::BadUserNameOrPassword,
-        }
+        self.failed(mqtt::ConnectAckReason::BadUserNameOrPassword)
     }
 
     /// Create connect ack object with `not authorized` return code
     pub fn not_authorized<St>(self) -> HandshakeAck<St> {
-        HandshakeAck {
-            io: self.io,
-            shared: self.shared,
-            session: None,
-            session_present: false,
-            keepalive: Seconds(30),
-            return_code: mqtt::ConnectAckReason::NotAuthorized,
-        }
+        self.failed(mqtt::ConnectAckReason::NotAuthorized)
     }
 
     /// Create connect ack object with `service unavailable` return code
     pub fn service_unavailable<St>(self) -> HandshakeAck<St> {
+        self.failed(mqtt::ConnectAckReason::ServiceUnavailable)
+    }
+
+    /// Create connect ack object with provided return code
+    ///
+    /// Returns handshake back if `reason` is `ConnectionAccepted`, use `ack()`
+    /// to accept connection.
+    #[allow(clippy::result_large_err)]
+    pub fn ack_with_reason<St>(
+        self,
+        reason: mqtt::ConnectAckReason,
+    ) -> Result<HandshakeAck<St>, Self> {
+        if reason == mqtt::ConnectAckReason::ConnectionAccepted {
+            Err(self)
+        } else {
+            Ok(self.failed(reason))
+        }
+    }
+
+    fn failed<St>(self, reason: mqtt::ConnectAckReason) -> HandshakeAck<St> {
         HandshakeAck {
             io: self.io,
             shared: self.shared,
             session: None,
             session_present: false,
             keepalive: Seconds(30),
-            return_code: mqtt::ConnectAckReason::ServiceUnavailable,
+            return_code: reason,
         }
     }
 }
@@ -11,6 +11,7 @@ use crate::v3::codec;
 pub struct Publish {
     publish: codec::Publish,
     topic: Path<ByteString>,
+    segments: Vec<ByteString>,
 }
 
 #[derive(Debug)]
@@ -22,7 +23,7 @@ impl Publish {
     /// packet
     #[doc(hidden)]
     pub fn new(publish: codec::Publish) -> Self {
-        Self { topic: Path::new(publish.topic.clone()), publish }
+        Self { topic: Path::new(publish.topic.clone()), segments: Vec::new(), publish }
     }
 
     #[inline]
@@ -32,6 +33,8 @@ impl Publish {
     }
 
     #[inline]
+    /// Retain flag of publish packet, publisher asks server to store message
+    /// and deliver it to future subscribers.
     pub fn retain(&self) -> bool {
         self.publish.retain
     }
@@ -64,6 +67,22 @@ impl Publish {
         &mut self.topic
     }
 
+    #[inline]
+    /// topic level matched by wildcard of router's topic filter.
+    ///
+    /// Segments are indexed in order of wildcards in the filter,
+    /// multi-level wildcard matches all remaining levels.
+    pub fn segment(&self, idx: usize) -> Option<&str> {
+        self.segments.get(idx).map(|s| s.as_ref())
+    }
+
+    /// Store matched segments, ranges are relative to current topic
+    pub(super) fn set_segments(&mut self, segments: Vec<(usize, usize)>) {
+        let topic = self.topic.get_ref();
+        self.segments =
+            segments.into_iter().map(|(start, end)| topic.slice(start..end)).collect();
+    }
+
     #[inline]
     pub fn packet(&self) -> &codec::Publish {
         &self.publish
@@ -80,7 +99,15 @@ impl Publish {
         &self.publish.payload
     }
 
+    #[inline]
+    /// Mutable reference to the Application Message.
+    pub fn payload_mut(&mut self) -> &mut Bytes {
+        &mut self.publish.payload
+    }
+
     /// Replace packet'a payload with empty bytes, returns existing payload.
+    ///
+    /// Payload is not copied, subsequent calls return empty bytes.
     pub fn take_payload(&mut self) -> Bytes {
         mem::take(&mut self.publish.payload)
     }
@@ -1,5 +1,5 @@
 use std::task::{Context, Poll};
-use std::{future::Future, pin::Pin, rc::Rc};
+use std::{future::Future, pin::Pin, rc::Rc, str::FromStr};
 
 use ntex::router::{IntoPattern, RouterBuilder};
 use ntex::service::boxed::{self, BoxService, BoxServiceFactory};
@@ -7,6 +7,7 @@ use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
 
 use super::publish::Publish;
 use super::Session;
+use crate::topic::Topic;
 
 type Handler<S, E> = BoxServiceFactory<Session<S>, Publish, (), E, E>;
 type HandlerService<E> = BoxService<Publish, (), E>;
@@ -15,6 +16,7 @@ type HandlerService<E> = BoxService<Publish, (), E>;
 /// for building publish packet router instances for mqtt server.
 pub struct Router<S, Err> {
     router: RouterBuilder<usize>,
+    filters: Vec<(Topic, usize)>,
     handlers: Vec<Handler<S, Err>>,
     default: Handler<S, Err>,
 }
@@ -34,6 +36,7 @@ where
     {
         Router {
             router: ntex::router::Router::build(),
+            filters: Vec::new(),
             handlers: Vec::new(),
             default: boxed::factory(default_service.into_factory()),
         }
@@ -51,6 +54,25 @@ where
         self.handlers.push(boxed::factory(service.into_factory().map_init_err(Err::from)));
         self
     }
+
+    /// Configure mqtt resource for a topic filter.
+    ///
+    /// Filter could contain `+` and `#` wildcards, levels matched by wildcards
+    /// are available via `Publish::segment()`. Filters are checked in order
+    /// of registration if none of resources matches publish topic.
+    /// Panics if topic filter is not valid.
+    pub fn filter<F, U>(mut self, filter: &str, service: F) -> Self
+    where
+        F: IntoServiceFactory<U, Publish, Session<S>>,
+        U: ServiceFactory<Publish, Session<S>, Response = (), Error = Err> + 'static,
+        Err: From<U::InitError>,
+    {
+        let topic = Topic::from_str(filter)
+            .unwrap_or_else(|e| panic!("Invalid topic filter {:?}: {:?}", filter, e));
+        self.filters.push((topic, self.handlers.len()));
+        self.handlers.push(boxed::factory(service.into_factory().map_init_err(Err::from)));
+        self
+    }
 }
 
 impl<S, Err> IntoServiceFactory<RouterFactory<S, Err>, Publish, Session<S>> for Router<S, Err>
@@ -61,6 +83,7 @@ where
     fn into_factory(self) -> RouterFactory<S, Err> {
         RouterFactory {
             router: Rc::new(self.router.finish()),
+            filters: Rc::new(self.filters),
             handlers: self.handlers,
             default: self.default,
         }
@@ -69,6 +92,7 @@ where
 
 pub struct RouterFactory<S, Err> {
     router: Rc<ntex::router::Router<usize>>,
+    filters: Rc<Vec<(Topic, usize)>>,
     handlers: Vec<Handler<S, Err>>,
     default: Handler<S, Err>,
 }
@@ -89,6 +113,7 @@ where
             self.handlers.iter().map(|h| h.new_service(session.clone())).collect();
         let default_fut = self.default.new_service(session);
         let router = self.router.clone();
+        let filters = self.filters.clone();
 
         Box::pin(async move {
             let mut handlers = Vec::new();
@@ -96,13 +121,14 @@ where
                 handlers.push(handler.await?);
             }
 
-            Ok(RouterService { router, handlers, default: default_fut.await? })
+            Ok(RouterService { router, filters, handlers, default: default_fut.await? })
         })
     }
 }
 
 pub struct RouterService<Err> {
     router: Rc<ntex::router::Router<usize>>,
+    filters: Rc<Vec<(Topic, usize)>>,
     handlers: Vec<HandlerService<Err>>,
     default: HandlerService<Err>,
 }
@@ -133,9 +159,15 @@ impl<Err> Service<Publish> for RouterService<Err> {
 
     fn call(&self, mut req: Publish) -> Self::Future {
         if let Some((idx, _info)) = self.router.recognize(req.topic_mut()) {
-            self.handlers[*idx].call(req)
-        } else {
-            self.default.call(req)
+            return self.handlers[*idx].call(req);
+        }
+
+        for (topic, idx) in self.filters.iter() {
+            if let Some(segments) = topic.match_segments(req.publish_topic()) {
+                req.set_segments(segments);
+                return self.handlers[*idx].call(req);
+            }
         }
+        self.default.call(req)
     }
 }
@@ -1,20 +1,33 @@
-use std::task::{Context, Poll};
-use std::{fmt, future::Future, marker::PhantomData, pin::Pin, rc::Rc};
+use std::{
+    borrow::Cow, fmt, future::Future, marker::PhantomData, num::NonZeroU16, pin::Pin, rc::Rc,
+};
+use std::{task::Context, task::Poll, time::Duration, time::Instant};
 
 use ntex::io::{DispatchItem, IoBoxed};
 use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
-use ntex::time::{timeout_checked, Millis, Seconds};
-use ntex::util::{select, Either};
+use ntex::time::{timeout_checked, Deadline, Millis, Seconds};
+use ntex::util::{select, Either, PoolId};
 
-use crate::error::{MqttError, ProtocolError};
+use crate::ban::BanList;
+use crate::drain::Drain;
+use crate::error::{DecodeError, MqttError, ProtocolError};
+use crate::events::{LifecycleChannel, LifecycleEvents};
+use crate::limiter::{HandshakeLimit, Qos2InflightLimit, RateLimiter};
+use crate::reject::{RejectReason, RejectSampler};
+use crate::selector::Selected;
+use crate::session::{DisconnectReason, NegotiatedConfig};
+use crate::types::{ClientIdEncoding, CodecTiming, Direction, MaxSizeHandle};
+use crate::types::{IdleAction, PreConnackPublishPolicy, QoS, TopicRewrite, MQTT_LEVEL_3};
+use crate::types::{Metrics, MetricsHandle};
 use crate::{io::Dispatcher, service};
 
 use super::control::{ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
+use super::dispatcher::{factory, DispatcherConfig};
 use super::handshake::{Handshake, HandshakeAck};
 use super::selector::SelectItem;
-use super::shared::{MqttShared, MqttSinkPool};
-use super::{codec as mqtt, dispatcher::factory, MqttSink, Publish, Session};
+use super::shared::{MqttShared, MqttSinkPool, DEFAULT_INFLIGHT_WINDOW};
+use super::{codec as mqtt, MqttSink, Publish, Session};
 
 /// Mqtt v3.1.1 server
 ///
@@ -50,8 +63,38 @@ pub struct MqttServer<St, H, C, P> {
     max_size: u32,
     max_inflight: u16,
     max_inflight_size: usize,
+    ordered: bool,
     handshake_timeout: Seconds,
     disconnect_timeout: Seconds,
+    subscribe_timeout: Seconds,
+    min_qos: QoS,
+    keep_connect: bool,
+    lenient_protocol: bool,
+    client_id_encoding: ClientIdEncoding,
+    limiter: RateLimiter,
+    strict_acks: bool,
+    max_lifetime: Seconds,
+    on_unexpected_ack: Option<Rc<dyn Fn(&Session<St>, NonZeroU16, u8)>>,
+    on_rejected_publish: Option<Rc<RejectSampler<mqtt::Publish>>>,
+    ingress_topic: Option<TopicRewrite>,
+    egress_topic: Option<TopicRewrite>,
+    qos2_limit: Option<Qos2InflightLimit>,
+    on_ping: Option<Rc<dyn Fn(&Session<St>, Instant)>>,
+    on_idle_timeout: Option<Rc<dyn Fn(&Session<St>) -> IdleAction>>,
+    on_disconnect: Option<Rc<dyn Fn(&Session<St>, DisconnectReason)>>,
+    coalesce_subacks: Option<(Millis, usize)>,
+    events: Rc<LifecycleChannel>,
+    pub(super) handshakes: HandshakeLimit,
+    pub(super) drain: Drain,
+    pre_connack: PreConnackPublishPolicy,
+    max_size_handle: Option<MaxSizeHandle>,
+    ban_list: Option<Rc<dyn BanList>>,
+    max_write_buffer: usize,
+    pubcomp_timeout: Seconds,
+    inflight_window: Option<u16>,
+    codec_timing: Option<CodecTiming>,
+    metrics: Option<MetricsHandle>,
+    io_timeouts: (Seconds, Seconds),
     pub(super) pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
 }
@@ -75,8 +118,38 @@ where
             max_size: 0,
             max_inflight: 16,
             max_inflight_size: 65535,
+            ordered: false,
             handshake_timeout: Seconds::ZERO,
             disconnect_timeout: Seconds(3),
+            subscribe_timeout: Seconds::ZERO,
+            min_qos: QoS::AtMostOnce,
+            keep_connect: false,
+            lenient_protocol: false,
+            client_id_encoding: ClientIdEncoding::Strict,
+            limiter: RateLimiter::Disabled,
+            strict_acks: true,
+            max_lifetime: Seconds::ZERO,
+            on_unexpected_ack: None,
+            on_rejected_publish: None,
+            ingress_topic: None,
+            egress_topic: None,
+            qos2_limit: None,
+            on_ping: None,
+            on_idle_timeout: None,
+            on_disconnect: None,
+            coalesce_subacks: None,
+            events: Default::default(),
+            handshakes: HandshakeLimit::default(),
+            drain: Drain::default(),
+            pre_connack: PreConnackPublishPolicy::default(),
+            max_size_handle: None,
+            ban_list: None,
+            max_write_buffer: 0,
+            pubcomp_timeout: Seconds::ZERO,
+            inflight_window: None,
+            codec_timing: None,
+            metrics: None,
+            io_timeouts: (Seconds::ZERO, Seconds::ZERO),
             pool: Default::default(),
             _t: PhantomData,
         }
@@ -102,6 +175,119 @@ where
         self
     }
 
+    /// Set max number of concurrently running handshake service calls.
+    ///
+    /// Connections that received `connect` packet wait for a free slot
+    /// before handshake service gets called, waiting time counts towards
+    /// handshake timeout. If server is used as selector variant, selector's
+    /// limit is used instead.
+    ///
+    /// To disable limit set value to 0. By default limit is set to 256.
+    pub fn max_concurrent_handshakes(self, val: usize) -> Self {
+        self.handshakes.set_capacity(val);
+        self
+    }
+
+    /// Set shutdown grace period.
+    ///
+    /// Once shutdown starts, server rejects new connections and closes
+    /// idle connections.
+    /// Connections with inflight QoS 1 and QoS 2 exchanges are closed
+    /// as soon as exchanges complete, remaining connections get force
+    /// closed after grace period. `poll_shutdown()` of server service
+    /// returns `Poll::Pending` until all connections are closed or grace
+    /// period is elapsed. If server is used as selector variant,
+    /// selector's grace period is used instead.
+    ///
+    /// By default grace period is disabled.
+    pub fn shutdown_grace_period(self, period: Seconds) -> Self {
+        self.drain.set_grace_period(period);
+        self
+    }
+
+    /// Set handling of `publish` packets received before `connect-ack` is sent.
+    ///
+    /// Client could pipeline `publish` packet right after `connect` packet.
+    /// With `Buffer` policy such packets are processed after `connect-ack`,
+    /// with `Reject` policy connection is closed with protocol error.
+    ///
+    /// By default `Reject` policy is used.
+    pub fn pre_connack_publish_policy(mut self, policy: PreConnackPublishPolicy) -> Self {
+        self.pre_connack = policy;
+        self
+    }
+
+    /// Set callback for codec timing.
+    ///
+    /// Callback is called after each packet decode or encode with control
+    /// packet type (`1` for `connect` ... `15` for `auth`) and time spent
+    /// in codec. If server is used as selector variant, timing is reported
+    /// once variant accepts connection, `connect` packet is not reported.
+    ///
+    /// By default timing is not measured.
+    pub fn on_codec_timing<F>(mut self, f: F) -> Self
+    where
+        F: Fn(Direction, u8, Duration) + 'static,
+    {
+        self.codec_timing = Some(CodecTiming::new(f));
+        self
+    }
+
+    /// Set read timeout.
+    ///
+    /// Defines max time for receiving single packet, timer starts once first
+    /// bytes of the packet are received. During handshake timer starts once
+    /// connection is accepted, so partially received `connect` packet does not
+    /// wait for keep-alive negotiation. Connection is closed with
+    /// `ProtocolError::ReadTimeout` error. Keep-alive timer is not affected.
+    ///
+    /// By default read timeout is disabled, set `0` to disable timeout.
+    pub fn read_timeout(mut self, timeout: Seconds) -> Self {
+        self.io_timeouts.0 = timeout;
+        self
+    }
+
+    /// Set write timeout.
+    ///
+    /// Defines max time write buffer could stay not empty without write
+    /// progress, timer restarts every time peer accepts data. Connection is
+    /// closed with `ProtocolError::WriteTimeout` error.
+    ///
+    /// By default write timeout is disabled, set `0` to disable timeout.
+    pub fn write_timeout(mut self, timeout: Seconds) -> Self {
+        self.io_timeouts.1 = timeout;
+        self
+    }
+
+    /// Set memory pool.
+    ///
+    /// Use specified memory pool for read and write buffers of accepted
+    /// connections. Buffer sizes and memory limit are configured with pool id,
+    /// see `PoolId::set_read_params()`, `PoolId::set_write_params()` and
+    /// `PoolId::set_pool_size()`. Released buffers are cached for reuse only if
+    /// capacity does not exceed high watermark, buffers that grew because of
+    /// large payloads are freed. If server is used as selector
+    /// variant, selector's memory pool is used instead.
+    ///
+    /// By default listener's memory pool is used.
+    pub fn memory_pool(self, id: PoolId) -> Self {
+        self.pool.memory_pool.set(Some(id.pool_ref()));
+        self
+    }
+
+    /// Set connection metrics observer.
+    ///
+    /// Observer is notified about every decoded and encoded packet and about
+    /// protocol errors handled by dispatcher. If server is used as selector
+    /// variant, packets are reported once variant accepts connection, `connect`
+    /// packet is not reported.
+    ///
+    /// By default metrics are not collected.
+    pub fn metrics<M: Metrics + 'static>(mut self, metrics: M) -> Self {
+        self.metrics = Some(MetricsHandle::new(metrics));
+        self
+    }
+
     /// Set server connection disconnect timeout.
     ///
     /// Defines a timeout for disconnect connection. If a disconnect procedure does not complete
@@ -115,6 +301,275 @@ where
         self
     }
 
+    /// Set max connection lifetime.
+    ///
+    /// Defines max lifetime of the connection regardless of activity. If connection
+    /// lifetime is reached, the connection get closed.
+    ///
+    /// By default connection lifetime is not limited.
+    pub fn max_connection_lifetime(mut self, timeout: Seconds) -> Self {
+        self.max_lifetime = timeout;
+        self
+    }
+
+    /// Set subscribe timeout.
+    ///
+    /// Defines a timeout for control service to handle `Subscribe` packet. If control
+    /// service does not complete within this time, all topic filters get failed
+    /// with `Failure` return code.
+    ///
+    /// By default subscribe timeout is disabled.
+    pub fn subscribe_timeout(mut self, timeout: Seconds) -> Self {
+        self.subscribe_timeout = timeout;
+        self
+    }
+
+    /// Set minimum QoS for inbound publish packets.
+    ///
+    /// Publish packets with lower QoS are treated as protocol violation and
+    /// connection get closed. Granted QoS of subscriptions is checked as well,
+    /// topic filters with lower granted QoS get failed.
+    ///
+    /// By default QoS 0 is allowed.
+    pub fn min_inbound_qos(mut self, qos: QoS) -> Self {
+        self.min_qos = qos;
+        self
+    }
+
+    /// Set inbound publish rate limit with sliding window.
+    ///
+    /// Limits number of publish packets received within last `window` seconds
+    /// by `count`. If limit is exceeded connection get closed.
+    ///
+    /// By default inbound publish rate is not limited.
+    pub fn inbound_sliding_window_limit(mut self, count: u32, window: Seconds) -> Self {
+        self.limiter = RateLimiter::SlidingWindow { count, window };
+        self
+    }
+
+    /// Set inbound publish rate limit with token bucket.
+    ///
+    /// Limits rate of publish packets to `rate` packets per second with bursts
+    /// of `burst` packets. Once limit is exceeded, server stops reading from the
+    /// connection before next publish packet until token is available. Other packet
+    /// types that precede the publish packet are processed. Replaces sliding window limit.
+    ///
+    /// By default inbound publish rate is not limited.
+    pub fn publish_rate_limit(mut self, rate: u32, burst: u32) -> Self {
+        let notify = match self.limiter {
+            RateLimiter::TokenBucket { notify, .. } => notify,
+            _ => Seconds::ZERO,
+        };
+        self.limiter = RateLimiter::TokenBucket { rate, burst, notify };
+        self
+    }
+
+    /// Notify control service about throttled connections.
+    ///
+    /// Control service receives `ControlMessage::RateLimited` message once
+    /// connection is throttled by `publish_rate_limit()` continuously for
+    /// `period`, message is sent once per throttling period. Has no effect
+    /// if publish rate limit is not set.
+    ///
+    /// By default control service is not notified.
+    pub fn publish_rate_limit_notify(mut self, period: Seconds) -> Self {
+        if let RateLimiter::TokenBucket { rate, burst, .. } = self.limiter {
+            self.limiter = RateLimiter::TokenBucket { rate, burst, notify: period };
+        }
+        self
+    }
+
+    /// Returns active inbound publish rate limiter
+    pub fn rate_limiter(&self) -> RateLimiter {
+        self.limiter
+    }
+
+    /// Set handler for acks with unknown packet id.
+    ///
+    /// Handler is called with packet id and packet type of received ack packet
+    /// (PUBACK, PUBREC, PUBCOMP, SUBACK or UNSUBACK), if packet id does not
+    /// reference in-flight packet.
+    pub fn on_unexpected_ack<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Session<St>, NonZeroU16, u8) + 'static,
+    {
+        self.on_unexpected_ack = Some(Rc::new(f));
+        self
+    }
+
+    /// Set handler for rejected publish packets.
+    ///
+    /// Handler is called for every `sample_rate`-th publish packet rejected
+    /// by server checks or by publish service. If handler is set, publish
+    /// packet is cloned before it gets passed to publish service.
+    pub fn on_rejected_publish<F>(mut self, sample_rate: u32, f: F) -> Self
+    where
+        F: Fn(&mqtt::Publish, RejectReason) + 'static,
+    {
+        self.on_rejected_publish = Some(Rc::new(RejectSampler::new(sample_rate, f)));
+        self
+    }
+
+    /// Set topic rewrite for inbound packets.
+    ///
+    /// Hook is applied to topic of every received publish packet and to topic
+    /// filters of subscribe and unsubscribe packets before they get passed to
+    /// services, so `Publish::topic()` matches against rewritten topic.
+    pub fn ingress_topic_rewrite<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str) -> Cow<'_, str> + 'static,
+    {
+        self.ingress_topic = Some(TopicRewrite::new(f));
+        self
+    }
+
+    /// Set topic rewrite for outbound publish packets.
+    ///
+    /// Hook is applied to topic of every publish packet sent with connection's
+    /// sink after handshake is completed.
+    pub fn egress_topic_rewrite<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str) -> Cow<'_, str> + 'static,
+    {
+        self.egress_topic = Some(TopicRewrite::new(f));
+        self
+    }
+
+    /// Set limit of inbound QoS 2 publish packets processed concurrently by
+    /// all connections that share the limit.
+    ///
+    /// Server acknowledges QoS 2 publish with PUBACK once publish service
+    /// completes, PUBREC/PUBREL/PUBCOMP exchange is not used. So limit counts
+    /// publish service calls for QoS 2 packets that run concurrently, slot is
+    /// released when publish service call completes.
+    ///
+    /// If limit is reached, publish packet is delayed until a slot is released
+    /// and then passed to publish service.
+    /// Mqtt v5 server rejects such packets with PUBREC `Quota exceeded` instead.
+    pub fn max_global_qos2_inflight(mut self, limit: Qos2InflightLimit) -> Self {
+        self.qos2_limit = Some(limit);
+        self
+    }
+
+    /// Set handler for PINGREQ packets.
+    ///
+    /// Handler is called with the time packet is received, before control
+    /// service gets `Ping` message. Time of last PINGREQ packet is also
+    /// available via `Session::last_ping_at()`.
+    pub fn on_ping<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Session<St>, Instant) + 'static,
+    {
+        self.on_ping = Some(Rc::new(f));
+        self
+    }
+
+    /// Set handler for idle connections.
+    ///
+    /// Handler is called once keep-alive timeout is expired. `IdleAction::Extend`
+    /// keeps connection open and re-arms keep-alive timer, regular keep-alive timeout
+    /// is restored once a packet is received from the client. Handler is a shortcut
+    /// for `ControlMessage::Timeout` handling, with `IdleAction::Disconnect` timeout
+    /// message is sent to control service.
+    pub fn on_idle_timeout<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Session<St>) -> IdleAction + 'static,
+    {
+        self.on_idle_timeout = Some(Rc::new(f));
+        self
+    }
+
+    /// Set handler for connection termination.
+    ///
+    /// Handler is called exactly once per connection, once dispatcher is
+    /// stopped, with the reason of connection termination. Handler is not
+    /// called if handshake fails, as no session is created.
+    pub fn on_disconnect<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Session<St>, DisconnectReason) + 'static,
+    {
+        self.on_disconnect = Some(Rc::new(f));
+        self
+    }
+
+    /// Coalesce SUBACK packet writes.
+    ///
+    /// SUBACK packets are queued and written together once `max_count`
+    /// packets are queued or `max_delay` is elapsed since first queued packet.
+    /// Each SUBSCRIBE packet still gets its own SUBACK packet.
+    ///
+    /// By default SUBACK packets are written immediately.
+    pub fn coalesce_subacks(mut self, max_delay: Millis, max_count: usize) -> Self {
+        self.coalesce_subacks = Some((max_delay, max_count));
+        self
+    }
+
+    /// Set policy for acks with unknown packet id.
+    ///
+    /// If strict policy is enabled, ack with unknown packet id is treated
+    /// as protocol violation and connection get closed, otherwise such ack
+    /// is ignored.
+    ///
+    /// By default strict policy is enabled.
+    pub fn strict_acks(mut self, val: bool) -> Self {
+        self.strict_acks = val;
+        self
+    }
+
+    /// Keep raw bytes of `Connect` packet.
+    ///
+    /// Raw bytes are available via `Handshake::raw_connect_bytes()` method,
+    /// for example for verification of connect packet signature.
+    ///
+    /// By default raw bytes are not kept.
+    pub fn keep_connect_bytes(mut self, val: bool) -> Self {
+        self.keep_connect = val;
+        self
+    }
+
+    /// Get stream of connection lifecycle events.
+    ///
+    /// Stream emits events for all connections handled by this server
+    /// instance, events are not collected until first stream is created.
+    /// Server instance is created per worker thread, so each worker
+    /// has its own stream. See `LifecycleEvents` for overflow policy.
+    pub fn lifecycle_events(&self) -> LifecycleEvents {
+        self.events.receiver()
+    }
+
+    /// Accept case-insensitive protocol name in `Connect` packet.
+    ///
+    /// In strict mode connection with protocol name other than exact `MQTT`
+    /// is rejected with `UnacceptableProtocolVersion` connect-ack and
+    /// `DecodeError::InvalidProtocol` error. Lenient mode
+    /// also accepts names that differ only in case, i.e. `mqtt`.
+    ///
+    /// If server is used as selector variant, selector's setting is used
+    /// instead, see `Selector::lenient_protocol_name()`. Protocol selector of
+    /// `crate::MqttServer` always requires exact protocol name.
+    ///
+    /// By default strict mode is used.
+    pub fn lenient_protocol_name(mut self, val: bool) -> Self {
+        self.lenient_protocol = val;
+        self
+    }
+
+    /// Set decoding of client ids that are not valid utf-8.
+    ///
+    /// In strict mode connection is rejected with `IdentifierRejected`
+    /// connect-ack. Lossy mode replaces invalid sequences with `U+FFFD`,
+    /// raw bytes are available via `Handshake::raw_client_id()` method.
+    ///
+    /// If server is used as selector variant, selector's setting is used
+    /// instead, see `Selector::client_id_encoding()`. Protocol selector of
+    /// `crate::MqttServer` always uses strict mode.
+    ///
+    /// By default strict mode is used.
+    pub fn client_id_encoding(mut self, val: ClientIdEncoding) -> Self {
+        self.client_id_encoding = val;
+        self
+    }
+
     /// Set max inbound frame size.
     ///
     /// If max size is set to `0`, size is unlimited.
@@ -124,6 +579,63 @@ where
         self
     }
 
+    /// Set shared max inbound frame size.
+    ///
+    /// Codec reads max size from the handle for every packet, so changes
+    /// apply to new and existing connections. Handle overrides `max_size`.
+    pub fn max_size_handle(mut self, handle: MaxSizeHandle) -> Self {
+        self.max_size_handle = Some(handle);
+        self
+    }
+
+    /// Set ban list.
+    ///
+    /// Ban list is checked by client id and peer address before handshake
+    /// service is called, banned client gets `connect-ack` packet with
+    /// `not authorized` reason code. Clients could be added to the list with
+    /// `Session::quarantine()` method.
+    pub fn ban_list<B: BanList + 'static>(mut self, list: B) -> Self {
+        self.ban_list = Some(Rc::new(list));
+        self
+    }
+
+    /// Max size of outbound write buffer in bytes.
+    ///
+    /// If size of write buffer exceeds the limit, `MqttSink::ready()` and publishes
+    /// wait until buffer gets flushed, non-blocking `send_at_most_once()` fails
+    /// with `SendPacketError::WriteBufferFull` error. By default size of write
+    /// buffer is not limited.
+    pub fn max_write_buffer(mut self, size: usize) -> Self {
+        self.max_write_buffer = size;
+        self
+    }
+
+    /// Set PUBCOMP timeout for outbound QoS 2 publishes.
+    ///
+    /// Defines how long sink waits for PUBCOMP after sending PUBREL. PUBREL is
+    /// retransmitted once on timeout, if PUBCOMP is not received within second
+    /// timeout, connection is closed with `ProtocolError::PubcompTimeout` error.
+    ///
+    /// By default timeout is disabled, set `0` to disable timeout.
+    pub fn pubcomp_timeout(mut self, timeout: Seconds) -> Self {
+        self.pubcomp_timeout = timeout;
+        self
+    }
+
+    /// Max number of unacknowledged QoS 1 and QoS 2 packets in each direction.
+    ///
+    /// Inbound publish packet that exceeds the window is handled as protocol
+    /// error, outbound publishes wait for acknowledgements of in-flight packets.
+    /// Window is applied to selector's connections as well. By default outbound
+    /// window is set to 16 packets and inbound packets are not limited.
+    ///
+    /// Panics if `val` is `0`.
+    pub fn max_inflight(mut self, val: u16) -> Self {
+        assert!(val != 0, "In-flight window must be greater than 0");
+        self.inflight_window = Some(val);
+        self
+    }
+
     /// Number of in-flight concurrent messages.
     ///
     /// By default in-flight is set to 16 messages
@@ -140,6 +652,18 @@ where
         self
     }
 
+    /// Process inbound packets strictly one by one.
+    ///
+    /// Next packet is not passed to services until processing of previous
+    /// packet is completed, so publish service observes and completes publishes
+    /// in arrival order regardless of their qos. Publish service must not wait
+    /// for acknowledgement of outbound publishes of the same connection.
+    /// By default packets are processed concurrently.
+    pub fn ordered(mut self, val: bool) -> Self {
+        self.ordered = val;
+        self
+    }
+
     /// Service to handle control packets
     ///
     /// All control packets are processed sequentially, max number of buffered
@@ -158,14 +682,49 @@ where
             max_size: self.max_size,
             max_inflight: self.max_inflight,
             max_inflight_size: self.max_inflight_size,
+            ordered: self.ordered,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            subscribe_timeout: self.subscribe_timeout,
+            min_qos: self.min_qos,
+            keep_connect: self.keep_connect,
+            lenient_protocol: self.lenient_protocol,
+            client_id_encoding: self.client_id_encoding,
+            limiter: self.limiter,
+            strict_acks: self.strict_acks,
+            max_lifetime: self.max_lifetime,
+            on_unexpected_ack: self.on_unexpected_ack,
+            on_rejected_publish: self.on_rejected_publish,
+            ingress_topic: self.ingress_topic,
+            egress_topic: self.egress_topic,
+            qos2_limit: self.qos2_limit,
+            on_ping: self.on_ping,
+            on_idle_timeout: self.on_idle_timeout,
+            on_disconnect: self.on_disconnect,
+            coalesce_subacks: self.coalesce_subacks,
+            events: self.events,
+            handshakes: self.handshakes,
+            drain: self.drain,
+            pre_connack: self.pre_connack,
+            max_size_handle: self.max_size_handle,
+            ban_list: self.ban_list,
+            max_write_buffer: self.max_write_buffer,
+            pubcomp_timeout: self.pubcomp_timeout,
+            inflight_window: self.inflight_window,
+            codec_timing: self.codec_timing,
+            metrics: self.metrics,
+            io_timeouts: self.io_timeouts,
             pool: self.pool,
             _t: PhantomData,
         }
     }
 
     /// Set service to handle publish packets and create mqtt server factory
+    ///
+    /// PUBACK for QoS 1 publish is sent only after publish service future
+    /// completes successfully, so service could delay ack until message is
+    /// durably stored. If service fails, PUBACK is not sent and error is
+    /// passed to control service as `ControlMessage::Error`.
     pub fn publish<F, Srv>(self, publish: F) -> MqttServer<St, H, C, Srv>
     where
         F: IntoServiceFactory<Srv, Publish, Session<St>>,
@@ -179,13 +738,66 @@ where
             max_size: self.max_size,
             max_inflight: self.max_inflight,
             max_inflight_size: self.max_inflight_size,
+            ordered: self.ordered,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            subscribe_timeout: self.subscribe_timeout,
+            min_qos: self.min_qos,
+            keep_connect: self.keep_connect,
+            lenient_protocol: self.lenient_protocol,
+            client_id_encoding: self.client_id_encoding,
+            limiter: self.limiter,
+            strict_acks: self.strict_acks,
+            max_lifetime: self.max_lifetime,
+            on_unexpected_ack: self.on_unexpected_ack,
+            on_rejected_publish: self.on_rejected_publish,
+            ingress_topic: self.ingress_topic,
+            egress_topic: self.egress_topic,
+            qos2_limit: self.qos2_limit,
+            on_ping: self.on_ping,
+            on_idle_timeout: self.on_idle_timeout,
+            on_disconnect: self.on_disconnect,
+            coalesce_subacks: self.coalesce_subacks,
+            events: self.events,
+            handshakes: self.handshakes,
+            drain: self.drain,
+            pre_connack: self.pre_connack,
+            max_size_handle: self.max_size_handle,
+            ban_list: self.ban_list,
+            max_write_buffer: self.max_write_buffer,
+            pubcomp_timeout: self.pubcomp_timeout,
+            inflight_window: self.inflight_window,
+            codec_timing: self.codec_timing,
+            metrics: self.metrics,
+            io_timeouts: self.io_timeouts,
             pool: self.pool,
             _t: PhantomData,
         }
     }
 
+    /// Per-connection dispatcher settings
+    fn dispatcher_config(&self) -> DispatcherConfig<St> {
+        DispatcherConfig {
+            inflight: if self.ordered { 1 } else { self.max_inflight },
+            inflight_size: self.max_inflight_size,
+            subscribe_timeout: self.subscribe_timeout,
+            min_qos: self.min_qos,
+            limiter: self.limiter,
+            strict_acks: self.strict_acks,
+            max_lifetime: self.max_lifetime,
+            on_unexpected_ack: self.on_unexpected_ack.clone(),
+            on_rejected_publish: self.on_rejected_publish.clone(),
+            ingress_topic: self.ingress_topic.clone(),
+            egress_topic: self.egress_topic.clone(),
+            qos2_limit: self.qos2_limit.clone(),
+            on_ping: self.on_ping.clone(),
+            on_idle_timeout: self.on_idle_timeout.clone(),
+            on_disconnect: self.on_disconnect.clone(),
+            coalesce_subacks: self.coalesce_subacks,
+            drain: self.drain.clone(),
+        }
+    }
+
     /// Finish server configuration and create mqtt server factory
     pub fn finish(
         self,
@@ -206,15 +818,31 @@ where
         >,
         Rc<MqttShared>,
     > {
+        let config = self.dispatcher_config();
         service::MqttServer::new(
             HandshakeFactory {
                 factory: self.handshake,
                 max_size: self.max_size,
+                keep_connect: self.keep_connect,
+                lenient_protocol: self.lenient_protocol,
+                client_id_encoding: self.client_id_encoding,
+                events: self.events.clone(),
+                handshakes: self.handshakes,
+                drain: self.drain.clone(),
+                pre_connack: self.pre_connack,
+                max_size_handle: self.max_size_handle,
+                ban_list: self.ban_list,
+                max_write_buffer: self.max_write_buffer,
+                pubcomp_timeout: self.pubcomp_timeout,
+                inflight_window: self.inflight_window,
+                codec_timing: self.codec_timing,
+                metrics: self.metrics,
+                io_timeouts: self.io_timeouts,
                 handshake_timeout: self.handshake_timeout,
                 pool: self.pool.clone(),
                 _t: PhantomData,
             },
-            factory(self.publish, self.control, self.max_inflight, self.max_inflight_size),
+            factory(self.publish, self.control, config),
             self.disconnect_timeout,
         )
     }
@@ -223,6 +851,7 @@ where
     pub(crate) fn finish_selector<F, R>(
         self,
         check: F,
+        handshake_timeout: Option<Seconds>,
     ) -> impl ServiceFactory<
         SelectItem,
         Response = Either<SelectItem, ()>,
@@ -231,19 +860,28 @@ where
     >
     where
         F: Fn(&Handshake) -> R + 'static,
-        R: Future<Output = Result<bool, H::Error>> + 'static,
+        R: Future<Output = Result<Selected, H::Error>> + 'static,
     {
+        let config = self.dispatcher_config();
         ServerSelector {
             check: Rc::new(check),
+            handshake_timeout,
+            io_timeouts: self.io_timeouts,
             handshake: self.handshake,
-            handler: Rc::new(factory(
-                self.publish,
-                self.control,
-                self.max_inflight,
-                self.max_inflight_size,
-            )),
+            handler: Rc::new(factory(self.publish, self.control, config)),
             max_size: self.max_size,
+            handshakes: self.handshakes,
+            drain: self.drain,
+            pre_connack: self.pre_connack,
+            max_size_handle: self.max_size_handle,
+            ban_list: self.ban_list,
+            max_write_buffer: self.max_write_buffer,
+            pubcomp_timeout: self.pubcomp_timeout,
+            inflight_window: self.inflight_window,
             disconnect_timeout: self.disconnect_timeout,
+            events: self.events.clone(),
+            codec_timing: self.codec_timing,
+            metrics: self.metrics,
             _t: PhantomData,
         }
     }
@@ -252,6 +890,21 @@ where
 struct HandshakeFactory<St, H> {
     factory: H,
     max_size: u32,
+    keep_connect: bool,
+    lenient_protocol: bool,
+    client_id_encoding: ClientIdEncoding,
+    events: Rc<LifecycleChannel>,
+    handshakes: HandshakeLimit,
+    drain: Drain,
+    pre_connack: PreConnackPublishPolicy,
+    max_size_handle: Option<MaxSizeHandle>,
+    ban_list: Option<Rc<dyn BanList>>,
+    max_write_buffer: usize,
+    pubcomp_timeout: Seconds,
+    inflight_window: Option<u16>,
+    codec_timing: Option<CodecTiming>,
+    metrics: Option<MetricsHandle>,
+    io_timeouts: (Seconds, Seconds),
     handshake_timeout: Seconds,
     pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
@@ -259,6 +912,7 @@ struct HandshakeFactory<St, H> {
 
 impl<St, H> ServiceFactory<IoBoxed> for HandshakeFactory<St, H>
 where
+    St: 'static,
     H: ServiceFactory<Handshake, Response = HandshakeAck<St>> + 'static,
     H::Error: fmt::Debug,
 {
@@ -272,6 +926,21 @@ where
     fn new_service(&self, _: ()) -> Self::Future {
         let fut = self.factory.new_service(());
         let max_size = self.max_size;
+        let keep_connect = self.keep_connect;
+        let lenient_protocol = self.lenient_protocol;
+        let client_id_encoding = self.client_id_encoding;
+        let events = self.events.clone();
+        let handshakes = self.handshakes.clone();
+        let drain = self.drain.clone();
+        let pre_connack = self.pre_connack;
+        let max_size_handle = self.max_size_handle.clone();
+        let ban_list = self.ban_list.clone();
+        let max_write_buffer = self.max_write_buffer;
+        let pubcomp_timeout = self.pubcomp_timeout;
+        let inflight_window = self.inflight_window;
+        let codec_timing = self.codec_timing.clone();
+        let metrics = self.metrics.clone();
+        let io_timeouts = self.io_timeouts;
         let pool = self.pool.clone();
         let handshake_timeout = self.handshake_timeout;
 
@@ -279,6 +948,21 @@ where
             let service = fut.await?;
             Ok(HandshakeService {
                 max_size,
+                keep_connect,
+                lenient_protocol,
+                client_id_encoding,
+                events,
+                handshakes,
+                drain,
+                pre_connack,
+                max_size_handle,
+                ban_list,
+                max_write_buffer,
+                pubcomp_timeout,
+                inflight_window,
+                codec_timing,
+                metrics,
+                io_timeouts,
                 pool,
                 service: Rc::new(service),
                 handshake_timeout: handshake_timeout.into(),
@@ -291,6 +975,21 @@ where
 struct HandshakeService<St, H> {
     service: Rc<H>,
     max_size: u32,
+    keep_connect: bool,
+    lenient_protocol: bool,
+    client_id_encoding: ClientIdEncoding,
+    events: Rc<LifecycleChannel>,
+    handshakes: HandshakeLimit,
+    drain: Drain,
+    pre_connack: PreConnackPublishPolicy,
+    max_size_handle: Option<MaxSizeHandle>,
+    ban_list: Option<Rc<dyn BanList>>,
+    max_write_buffer: usize,
+    pubcomp_timeout: Seconds,
+    inflight_window: Option<u16>,
+    codec_timing: Option<CodecTiming>,
+    metrics: Option<MetricsHandle>,
+    io_timeouts: (Seconds, Seconds),
     pool: Rc<MqttSinkPool>,
     handshake_timeout: Millis,
     _t: PhantomData<St>,
@@ -298,6 +997,7 @@ struct HandshakeService<St, H> {
 
 impl<St, H> Service<IoBoxed> for HandshakeService<St, H>
 where
+    St: 'static,
     H: Service<Handshake, Response = HandshakeAck<St>> + 'static,
     H::Error: fmt::Debug,
 {
@@ -310,57 +1010,151 @@ where
     }
 
     fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
-        self.service.poll_shutdown(cx, is_error)
+        let ready1 = self.drain.poll_shutdown(cx).is_ready();
+        let ready2 = self.service.poll_shutdown(cx, is_error).is_ready();
+        if ready1 && ready2 {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
     }
 
     fn call(&self, io: IoBoxed) -> Self::Future {
-        log::trace!("Starting mqtt v3 handshake");
+        if self.drain.is_draining() {
+            log::trace!("Server is shutting down, connection is rejected");
+            io.close();
+            return Box::pin(async { Err(MqttError::ServerError("Server is shutting down")) });
+        }
+        self.pool.set_memory_pool(&io);
 
         let service = self.service.clone();
+        let handshakes = self.handshakes.clone();
+        let pre_connack = self.pre_connack;
         let shared = Rc::new(MqttShared::new(
             io.get_ref(),
-            mqtt::Codec::default().max_size(self.max_size),
-            16,
+            mqtt::Codec::default()
+                .max_size(self.max_size)
+                .keep_connect_bytes(self.keep_connect)
+                .lenient_protocol_name(self.lenient_protocol)
+                .client_id_encoding(self.client_id_encoding)
+                .codec_timing(self.codec_timing.clone())
+                .metrics(self.metrics.clone()),
+            self.inflight_window.unwrap_or(DEFAULT_INFLIGHT_WINDOW) as usize,
             self.pool.clone(),
         ));
+        if let Some(val) = self.inflight_window {
+            shared.inbound_window.set(val as usize);
+        }
+        shared.codec.set_max_size_handle(self.max_size_handle.clone());
+        *shared.ban_list.borrow_mut() = self.ban_list.clone();
+        shared.read_timeout.set(self.io_timeouts.0);
+        shared.write_timeout.set(self.io_timeouts.1);
+        shared.max_write_buffer.set(self.max_write_buffer);
+        shared.pubcomp_timeout.set(self.pubcomp_timeout);
+        let max_size = shared.codec.inbound_max_size();
         let handshake_timeout = self.handshake_timeout;
+        let events = self.events.clone();
+
+        let id = shared.id;
+        log::trace!("{}: Starting mqtt v3 handshake", id);
 
         let f = async move {
-            // read first packet
-            let packet = io
-                .recv(&shared.codec)
-                .await
-                .map_err(|err| {
-                    log::trace!("Error is received during mqtt handshake: {:?}", err);
-                    MqttError::from(err)
-                })?
-                .ok_or_else(|| {
-                    log::trace!("Server mqtt is disconnected during handshake");
-                    MqttError::Disconnected(None)
-                })?;
+            // read first packet, read timeout starts once connection is accepted
+            let packet = match timeout_checked(
+                shared.read_timeout.get(),
+                io.recv(&shared.codec),
+            )
+            .await
+            {
+                Ok(res) => res,
+                Err(_) => {
+                    log::trace!("{}: Connect packet is not received within read timeout", id);
+                    return Err(MqttError::Protocol(ProtocolError::ReadTimeout));
+                }
+            };
+            let packet = match packet {
+                Ok(Some(packet)) => packet,
+                Ok(None) => {
+                    log::trace!("{}: Server mqtt is disconnected during handshake", id);
+                    return Err(MqttError::Disconnected(None));
+                }
+                Err(Either::Left(DecodeError::InvalidClientId)) => {
+                    log::trace!("{}: Client identifier is not valid", id);
+                    let pkt = mqtt::Packet::ConnectAck {
+                        session_present: false,
+                        return_code: mqtt::ConnectAckReason::IdentifierRejected,
+                    };
+                    io.send(pkt, &shared.codec).await.map_err(MqttError::from)?;
+                    return Err(MqttError::Protocol(ProtocolError::Decode(
+                        DecodeError::InvalidClientId,
+                    )));
+                }
+                Err(Either::Left(
+                    err @ DecodeError::InvalidProtocol
+                    | err @ DecodeError::UnsupportedProtocolLevel,
+                )) => {
+                    log::trace!("{}: Protocol is not supported: {:?}", id, err);
+                    let pkt = mqtt::Packet::ConnectAck {
+                        session_present: false,
+                        return_code: mqtt::ConnectAckReason::UnacceptableProtocolVersion,
+                    };
+                    io.send(pkt, &shared.codec).await.map_err(MqttError::from)?;
+                    return Err(MqttError::Protocol(ProtocolError::Decode(err)));
+                }
+                Err(err) => {
+                    log::trace!("{}: Error is received during mqtt handshake: {:?}", id, err);
+                    return Err(MqttError::from(err));
+                }
+            };
 
             match packet {
                 mqtt::Packet::Connect(connect) => {
-                    // authenticate mqtt connection
-                    let ack = service
-                        .call(Handshake::new(connect, io, shared))
-                        .await
-                        .map_err(MqttError::Service)?;
+                    let client_id = connect.client_id.clone();
+                    let clean_start = connect.clean_session;
+                    *shared.will.borrow_mut() = connect.last_will.clone();
+
+                    let ack = if shared.is_banned(&client_id) {
+                        log::trace!("{}: Client is banned: {:?}", id, client_id);
+                        Handshake::new(connect, io, shared).not_authorized()
+                    } else {
+                        // authenticate mqtt connection
+                        let permit = handshakes.acquire().await;
+                        let ack = service
+                            .call(Handshake::new(connect, io, shared))
+                            .await
+                            .map_err(MqttError::Service)?;
+                        drop(permit);
+                        ack
+                    };
 
                     match ack.session {
                         Some(session) => {
+                            pre_connack.check(&ack.shared.io).map_err(MqttError::Protocol)?;
                             let pkt = mqtt::Packet::ConnectAck {
                                 session_present: ack.session_present,
                                 return_code: mqtt::ConnectAckReason::ConnectionAccepted,
                             };
 
-                            log::trace!("Sending success handshake ack: {:#?}", pkt);
+                            log::trace!("{}: Sending success handshake ack: {:#?}", id, pkt);
 
                             ack.io.send(pkt, &ack.shared.codec).await?;
+                            *ack.shared.events.borrow_mut() =
+                                events.connected(&ack.shared.io, client_id.clone());
                             Ok((
                                 ack.io,
                                 ack.shared.clone(),
-                                Session::new(session, MqttSink::new(ack.shared)),
+                                Session::new(
+                                    session,
+                                    MqttSink::new(ack.shared),
+                                    NegotiatedConfig {
+                                        protocol_level: MQTT_LEVEL_3,
+                                        client_id,
+                                        keepalive: ack.keepalive,
+                                        max_inbound_size: max_size,
+                                        clean_start,
+                                        ..Default::default()
+                                    },
+                                ),
                                 ack.keepalive,
                             ))
                         }
@@ -370,7 +1164,7 @@ where
                                 return_code: ack.return_code,
                             };
 
-                            log::trace!("Sending failed handshake ack: {:#?}", pkt);
+                            log::trace!("{}: Sending failed handshake ack: {:#?}", id, pkt);
                             ack.io.send(pkt, &ack.shared.codec).await?;
                             let _ = ack.io.shutdown().await;
 
@@ -379,7 +1173,11 @@ where
                     }
                 }
                 packet => {
-                    log::info!("MQTT-3.1.0-1: Expected CONNECT packet, received {:?}", packet);
+                    log::info!(
+                        "{}: MQTT-3.1.0-1: Expected CONNECT packet, received {:?}",
+                        id,
+                        packet
+                    );
                     Err(MqttError::Protocol(ProtocolError::Unexpected(
                         packet.packet_type(),
                         "MQTT-3.1.0-1: Expected CONNECT packet",
@@ -403,7 +1201,20 @@ pub(crate) struct ServerSelector<St, H, T, F, R> {
     handler: Rc<T>,
     disconnect_timeout: Seconds,
     check: Rc<F>,
+    handshake_timeout: Option<Seconds>,
+    io_timeouts: (Seconds, Seconds),
     max_size: u32,
+    handshakes: HandshakeLimit,
+    drain: Drain,
+    pre_connack: PreConnackPublishPolicy,
+    max_size_handle: Option<MaxSizeHandle>,
+    ban_list: Option<Rc<dyn BanList>>,
+    max_write_buffer: usize,
+    pubcomp_timeout: Seconds,
+    inflight_window: Option<u16>,
+    events: Rc<LifecycleChannel>,
+    codec_timing: Option<CodecTiming>,
+    metrics: Option<MetricsHandle>,
     _t: PhantomData<(St, R)>,
 }
 
@@ -411,7 +1222,7 @@ impl<St, H, T, F, R> ServiceFactory<SelectItem> for ServerSelector<St, H, T, F,
 where
     St: 'static,
     F: Fn(&Handshake) -> R + 'static,
-    R: Future<Output = Result<bool, H::Error>>,
+    R: Future<Output = Result<Selected, H::Error>>,
     H: ServiceFactory<Handshake, Response = HandshakeAck<St>> + 'static,
     H::Error: fmt::Debug,
     T: ServiceFactory<
@@ -433,7 +1244,20 @@ where
         let handler = self.handler.clone();
         let disconnect_timeout = self.disconnect_timeout;
         let check = self.check.clone();
+        let handshake_timeout = self.handshake_timeout;
+        let io_timeouts = self.io_timeouts;
         let max_size = self.max_size;
+        let handshakes = self.handshakes.clone();
+        let drain = self.drain.clone();
+        let pre_connack = self.pre_connack;
+        let max_size_handle = self.max_size_handle.clone();
+        let ban_list = self.ban_list.clone();
+        let max_write_buffer = self.max_write_buffer;
+        let pubcomp_timeout = self.pubcomp_timeout;
+        let inflight_window = self.inflight_window;
+        let events = self.events.clone();
+        let codec_timing = self.codec_timing.clone();
+        let metrics = self.metrics.clone();
 
         // create handshake service and then create service impl
         Box::pin(async move {
@@ -441,7 +1265,20 @@ where
                 handler,
                 disconnect_timeout,
                 check,
+                handshake_timeout,
+                io_timeouts,
                 max_size,
+                handshakes,
+                drain,
+                pre_connack,
+                max_size_handle,
+                ban_list,
+                max_write_buffer,
+                pubcomp_timeout,
+                inflight_window,
+                events,
+                codec_timing,
+                metrics,
                 handshake: Rc::new(fut.await?),
                 _t: PhantomData,
             })
@@ -451,10 +1288,23 @@ where
 
 pub(crate) struct ServerSelectorImpl<St, H, T, F, R> {
     check: Rc<F>,
+    handshake_timeout: Option<Seconds>,
+    io_timeouts: (Seconds, Seconds),
     handshake: Rc<H>,
     handler: Rc<T>,
     disconnect_timeout: Seconds,
     max_size: u32,
+    handshakes: HandshakeLimit,
+    drain: Drain,
+    pre_connack: PreConnackPublishPolicy,
+    max_size_handle: Option<MaxSizeHandle>,
+    ban_list: Option<Rc<dyn BanList>>,
+    max_write_buffer: usize,
+    pubcomp_timeout: Seconds,
+    inflight_window: Option<u16>,
+    events: Rc<LifecycleChannel>,
+    codec_timing: Option<CodecTiming>,
+    metrics: Option<MetricsHandle>,
     _t: PhantomData<(St, R)>,
 }
 
@@ -462,7 +1312,7 @@ impl<St, H, T, F, R> Service<SelectItem> for ServerSelectorImpl<St, H, T, F, R>
 where
     St: 'static,
     F: Fn(&Handshake) -> R + 'static,
-    R: Future<Output = Result<bool, H::Error>>,
+    R: Future<Output = Result<Selected, H::Error>>,
     H: Service<Handshake, Response = HandshakeAck<St>> + 'static,
     H::Error: fmt::Debug,
     T: ServiceFactory<
@@ -484,74 +1334,148 @@ where
 
     #[inline]
     fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
-        self.handshake.poll_shutdown(cx, is_error)
+        let ready1 = self.drain.poll_shutdown(cx).is_ready();
+        let ready2 = self.handshake.poll_shutdown(cx, is_error).is_ready();
+        if ready1 && ready2 {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
     }
 
     #[inline]
     fn call(&self, req: SelectItem) -> Self::Future {
-        log::trace!("Start connection handshake");
+        let id = req.0.shared.id;
+        log::trace!("{}: Start connection handshake", id);
 
         let check = self.check.clone();
+        let handshake_timeout = self.handshake_timeout;
+        let io_timeouts = self.io_timeouts;
         let handshake = self.handshake.clone();
         let handler = self.handler.clone();
         let timeout = self.disconnect_timeout;
-        let max_size = self.max_size;
+        let handshakes = self.handshakes.clone();
+        let pre_connack = self.pre_connack;
+        let max_size_handle = self.max_size_handle.clone();
+        let ban_list = self.ban_list.clone();
+        let max_write_buffer = self.max_write_buffer;
+        let pubcomp_timeout = self.pubcomp_timeout;
+        let inflight_window = self.inflight_window;
+        let events = self.events.clone();
+        let codec_timing = self.codec_timing.clone();
+        let metrics = self.metrics.clone();
+        let max_size = max_size_handle.as_ref().map_or(self.max_size, |h| h.get());
 
         Box::pin(async move {
-            let (hnd, mut delay) = req;
+            let (mut hnd, mut delay) = req;
 
             let result = match select((&*check)(&hnd), &mut delay).await {
                 Either::Left(res) => res,
                 Either::Right(_) => return Err(MqttError::HandshakeTimeout),
             };
 
-            if !result.map_err(MqttError::Service)? {
-                Ok(Either::Left((hnd, delay)))
-            } else {
-                // authenticate mqtt connection
-                let ack = match select(handshake.call(hnd), delay).await {
-                    Either::Left(res) => res.map_err(|e| {
-                        log::trace!("Connection handshake failed: {:?}", e);
-                        MqttError::Service(e)
-                    })?,
-                    Either::Right(_) => return Err(MqttError::HandshakeTimeout),
-                };
-
-                match ack.session {
-                    Some(session) => {
-                        let pkt = mqtt::Packet::ConnectAck {
-                            session_present: ack.session_present,
-                            return_code: mqtt::ConnectAckReason::ConnectionAccepted,
-                        };
-                        log::trace!(
-                            "Connection handshake succeeded, sending handshake ack: {:#?}",
-                            pkt
-                        );
-
-                        ack.shared.codec.set_max_size(max_size);
-                        ack.io.send(pkt, &ack.shared.codec).await.map_err(MqttError::from)?;
-
-                        let session = Session::new(session, MqttSink::new(ack.shared.clone()));
-                        let handler = handler.new_service(session).await?;
-                        log::trace!("Connection handler is created, starting dispatcher");
-
-                        Dispatcher::new(ack.io, ack.shared, handler)
-                            .keepalive_timeout(ack.keepalive)
-                            .disconnect_timeout(timeout)
-                            .await?;
-                        Ok(Either::Right(()))
+            match result.map_err(MqttError::Service)? {
+                None => Ok(Either::Left((hnd, delay))),
+                Some(data) => {
+                    hnd.data = data;
+                    // variant's own timeout replaces selector's one
+                    if let Some(timeout) = handshake_timeout {
+                        delay = Deadline::new(timeout.into());
                     }
-                    None => {
-                        let pkt = mqtt::Packet::ConnectAck {
-                            session_present: false,
-                            return_code: ack.return_code,
-                        };
+                    let clean_start = hnd.packet().clean_session;
+                    let client_id = hnd.packet().client_id.clone();
+                    *hnd.shared.will.borrow_mut() = hnd.packet().last_will.clone();
+                    // authenticate mqtt connection
+                    *hnd.shared.ban_list.borrow_mut() = ban_list;
+                    hnd.shared.read_timeout.set(io_timeouts.0);
+                    hnd.shared.write_timeout.set(io_timeouts.1);
+                    hnd.shared.max_write_buffer.set(max_write_buffer);
+                    hnd.shared.pubcomp_timeout.set(pubcomp_timeout);
+                    hnd.shared.codec.set_codec_timing(codec_timing);
+                    hnd.shared.codec.set_metrics(metrics);
+                    if let Some(val) = inflight_window {
+                        hnd.shared.cap.set(val as usize);
+                        hnd.shared.inbound_window.set(val as usize);
+                    }
+                    let fut = async move {
+                        if hnd.shared.is_banned(&hnd.packet().client_id) {
+                            log::trace!(
+                                "{}: Client is banned: {:?}",
+                                id,
+                                hnd.packet().client_id
+                            );
+                            return Ok(hnd.not_authorized());
+                        }
+                        let _permit = handshakes.acquire().await;
+                        handshake.call(hnd).await
+                    };
+                    let ack = match select(fut, delay).await {
+                        Either::Left(res) => res.map_err(|e| {
+                            log::trace!("{}: Connection handshake failed: {:?}", id, e);
+                            MqttError::Service(e)
+                        })?,
+                        Either::Right(_) => return Err(MqttError::HandshakeTimeout),
+                    };
+
+                    match ack.session {
+                        Some(session) => {
+                            pre_connack.check(&ack.shared.io).map_err(MqttError::Protocol)?;
+                            let pkt = mqtt::Packet::ConnectAck {
+                                session_present: ack.session_present,
+                                return_code: mqtt::ConnectAckReason::ConnectionAccepted,
+                            };
+                            log::trace!(
+                                "{}: Connection handshake succeeded, sending handshake ack: {:#?}",
+                                id,
+                                pkt
+                            );
 
-                        log::trace!("Sending failed handshake ack: {:#?}", pkt);
-                        ack.io.send(pkt, &ack.shared.codec).await?;
-                        let _ = ack.io.shutdown().await;
+                            ack.shared.codec.set_max_size(max_size);
+                            ack.shared.codec.set_max_size_handle(max_size_handle);
+                            ack.io
+                                .send(pkt, &ack.shared.codec)
+                                .await
+                                .map_err(MqttError::from)?;
+                            *ack.shared.events.borrow_mut() =
+                                events.connected(&ack.shared.io, client_id.clone());
 
-                        Err(MqttError::Disconnected(None))
+                            let negotiated = NegotiatedConfig {
+                                protocol_level: MQTT_LEVEL_3,
+                                client_id,
+                                keepalive: ack.keepalive,
+                                max_inbound_size: max_size,
+                                clean_start,
+                                ..Default::default()
+                            };
+                            let session = Session::new(
+                                session,
+                                MqttSink::new(ack.shared.clone()),
+                                negotiated,
+                            );
+                            let handler = handler.new_service(session).await?;
+                            log::trace!(
+                                "{}: Connection handler is created, starting dispatcher",
+                                id
+                            );
+
+                            Dispatcher::new(ack.io, ack.shared, handler)
+                                .keepalive_timeout(ack.keepalive)
+                                .disconnect_timeout(timeout)
+                                .await?;
+                            Ok(Either::Right(()))
+                        }
+                        None => {
+                            let pkt = mqtt::Packet::ConnectAck {
+                                session_present: false,
+                                return_code: ack.return_code,
+                            };
+
+                            log::trace!("{}: Sending failed handshake ack: {:#?}", id, pkt);
+                            ack.io.send(pkt, &ack.shared.codec).await?;
+                            let _ = ack.io.shutdown().await;
+
+                            Err(MqttError::Disconnected(None))
+                        }
                     }
                 }
             }
@@ -1,22 +1,45 @@
-use std::{cell::Cell, cell::RefCell, collections::VecDeque, num::NonZeroU16, rc::Rc};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+use std::{any::Any, cell::Cell, cell::RefCell, mem, num::NonZeroU16, rc::Rc};
 
 use ntex::channel::pool;
 use ntex::codec::{Decoder, Encoder};
-use ntex::io::IoRef;
-use ntex::util::{BytesMut, HashMap, PoolId, PoolRef};
+use ntex::io::{IoBoxed, IoRef};
+use ntex::time::Seconds;
+use ntex::util::{ByteString, BytesMut, HashMap, HashSet, PoolId, PoolRef};
 
-use crate::error::{DecodeError, EncodeError};
-use crate::{types::packet_type, v3::codec};
+use crate::ban::{peer_ip, BanList};
+use crate::error::{DecodeError, EncodeError, SendPacketError};
+use crate::events::{LifecycleEmitter, LifecycleEventKind};
+use crate::types::{packet_type, IdleAction, PacketMask, QoS, TopicRewrite};
+use crate::utils::{self, next_connection_id};
+use crate::{io::KeepAlive, v3::codec};
+
+/// Default number of unacknowledged QoS 1 and QoS 2 packets
+pub(super) const DEFAULT_INFLIGHT_WINDOW: u16 = 16;
 
 pub(super) enum Ack {
     Publish(NonZeroU16),
+    Receive(NonZeroU16),
+    Complete(NonZeroU16),
     Subscribe { packet_id: NonZeroU16, status: Vec<codec::SubscribeReturnCode> },
     Unsubscribe(NonZeroU16),
 }
 
+/// Receiver of in-flight packet acknowledgement
+pub(super) enum AckTx {
+    Channel(pool::Sender<Ack>),
+    Callback(AckCallback),
+}
+
+/// Delivery callback of QoS 1 publish, see `MqttSink::publish_with_callback()`
+pub(super) type AckCallback = Box<dyn FnOnce(Result<(), SendPacketError>)>;
+
 #[derive(Copy, Clone)]
 pub(super) enum AckType {
     Publish,
+    Receive,
+    Complete,
     Subscribe,
     Unsubscribe,
 }
@@ -24,7 +47,9 @@ pub(super) enum AckType {
 pub(super) struct MqttSinkPool {
     pub(super) queue: pool::Pool<Ack>,
     pub(super) waiters: pool::Pool<()>,
+    pub(super) pings: pool::Pool<Duration>,
     pub(super) pool: Cell<PoolRef>,
+    pub(super) memory_pool: Cell<Option<PoolRef>>,
 }
 
 impl Default for MqttSinkPool {
@@ -32,24 +57,66 @@ impl Default for MqttSinkPool {
         Self {
             queue: pool::new(),
             waiters: pool::new(),
+            pings: pool::new(),
             pool: Cell::new(PoolId::P5.pool_ref()),
+            memory_pool: Cell::new(None),
+        }
+    }
+}
+
+impl MqttSinkPool {
+    /// Use configured memory pool for server connection
+    pub(super) fn set_memory_pool(&self, io: &IoBoxed) {
+        if let Some(pool) = self.memory_pool.get() {
+            io.set_memory_pool(pool);
         }
     }
 }
 
 pub struct MqttShared {
+    pub(super) id: u64,
     pub(super) io: IoRef,
     pub(super) cap: Cell<usize>,
+    // inbound in-flight window, `0` means not limited
+    pub(super) inbound_window: Cell<usize>,
     queues: RefCell<MqttSharedQueues>,
     pub(super) inflight_idx: Cell<u16>,
+    // packet ids reserved by publish batch
+    reserved_ids: RefCell<HashSet<u16>>,
     pub(super) pool: Rc<MqttSinkPool>,
     pub(super) codec: codec::Codec,
+    pub(super) keepalive: Cell<Option<Seconds>>,
+    pub(super) keepalive_timeout: Cell<Option<Seconds>>,
+    pub(super) local_close: Cell<bool>,
+    // client side of connection
+    pub(super) client: Cell<bool>,
+    pub(super) read_timeout: Cell<Seconds>,
+    pub(super) write_timeout: Cell<Seconds>,
+    pub(super) on_idle: RefCell<Option<Box<dyn Fn() -> IdleAction>>>,
+    pub(super) allowed_packets: Cell<PacketMask>,
+    pub(super) events: RefCell<Option<LifecycleEmitter>>,
+    pub(super) last_ping: Cell<Option<Instant>>,
+    pub(super) subscriptions: RefCell<BTreeMap<ByteString, QoS>>,
+    pub(super) ban_list: RefCell<Option<Rc<dyn BanList>>>,
+    pub(super) topic_rewrite: RefCell<(Option<TopicRewrite>, Option<TopicRewrite>)>,
+    pub(super) will: RefCell<Option<codec::LastWill>>,
+    pub(super) clean_disconnect: Cell<bool>,
+    pub(super) max_write_buffer: Cell<usize>,
+    pub(super) pubcomp_timeout: Cell<Seconds>,
+    // eviction callback of the session, `Box<dyn FnOnce(St)>`
+    pub(super) evicted: RefCell<Option<Box<dyn Any>>>,
 }
 
 pub(super) struct MqttSharedQueues {
-    pub(super) inflight: HashMap<u16, (pool::Sender<Ack>, AckType)>,
+    pub(super) inflight: HashMap<u16, (AckTx, AckType)>,
     pub(super) inflight_order: VecDeque<u16>,
+    // released QoS 2 publishes, PUBCOMP order is independent of other acks
+    pub(super) release_order: VecDeque<u16>,
     pub(super) waiters: VecDeque<pool::Sender<()>>,
+    pub(super) idle_waiters: Vec<pool::Sender<()>>,
+    pub(super) ping_sent: Option<Instant>,
+    pub(super) ping_waiters: Vec<pool::Sender<Duration>>,
+    pub(super) write_waiters: Vec<pool::Sender<()>>,
 }
 
 impl MqttShared {
@@ -60,16 +127,88 @@ impl MqttShared {
         pool: Rc<MqttSinkPool>,
     ) -> Self {
         Self {
+            id: next_connection_id(),
             io,
             pool,
             codec,
+            keepalive: Cell::new(None),
+            keepalive_timeout: Cell::new(None),
+            local_close: Cell::new(false),
+            client: Cell::new(false),
+            read_timeout: Cell::new(Seconds::ZERO),
+            write_timeout: Cell::new(Seconds::ZERO),
+            on_idle: RefCell::new(None),
+            allowed_packets: Cell::new(PacketMask::all()),
+            events: RefCell::new(None),
+            last_ping: Cell::new(None),
+            subscriptions: RefCell::new(BTreeMap::new()),
+            ban_list: RefCell::new(None),
+            topic_rewrite: RefCell::new((None, None)),
+            will: RefCell::new(None),
+            clean_disconnect: Cell::new(false),
+            max_write_buffer: Cell::new(0),
+            pubcomp_timeout: Cell::new(Seconds::ZERO),
+            evicted: RefCell::new(None),
             cap: Cell::new(cap),
+            inbound_window: Cell::new(0),
             queues: RefCell::new(MqttSharedQueues {
                 inflight: HashMap::default(),
                 inflight_order: VecDeque::with_capacity(8),
+                release_order: VecDeque::new(),
                 waiters: VecDeque::new(),
+                idle_waiters: Vec::new(),
+                ping_sent: None,
+                ping_waiters: Vec::new(),
+                write_waiters: Vec::new(),
             }),
             inflight_idx: Cell::new(0),
+            reserved_ids: RefCell::new(HashSet::default()),
+        }
+    }
+
+    /// Mark connection as closed by this side of connection
+    pub(super) fn closed_locally(&self) {
+        if !self.io.is_closed() {
+            self.local_close.set(true);
+        }
+    }
+
+    /// Emit connection lifecycle event
+    pub(super) fn lifecycle_event(&self, kind: LifecycleEventKind) {
+        match kind {
+            LifecycleEventKind::SubscriptionAdded { ref topic, qos } => {
+                self.subscriptions.borrow_mut().insert(topic.clone(), qos);
+            }
+            LifecycleEventKind::SubscriptionRemoved { ref topic } => {
+                self.subscriptions.borrow_mut().remove(topic);
+            }
+            _ => (),
+        }
+        if let Some(ref emitter) = *self.events.borrow() {
+            emitter.emit(kind);
+        }
+    }
+
+    /// Check if client is in server's ban list
+    pub(super) fn is_banned(&self, client_id: &str) -> bool {
+        if let Some(ref bans) = *self.ban_list.borrow() {
+            bans.is_banned(client_id, peer_ip(&self.io))
+        } else {
+            false
+        }
+    }
+
+    /// Apply inbound topic rewrite
+    pub(super) fn inbound_topic(&self, topic: &mut ByteString) {
+        if let Some(ref f) = self.topic_rewrite.borrow().0 {
+            f.apply(topic)
+        }
+    }
+
+    /// Apply outbound topic rewrite
+    pub(super) fn outbound_topic(&self, topic: &mut ByteString) {
+        if let Some(ref f) = self.topic_rewrite.borrow().1 {
+            f.apply(topic)
         }
     }
 
@@ -78,21 +217,154 @@ impl MqttShared {
         f(&mut queues)
     }
 
+    /// Check if size of write buffer exceeds the limit
+    pub(super) fn is_write_buffer_full(&self) -> bool {
+        let max = self.max_write_buffer.get();
+        max != 0 && self.io.with_write_buf(|buf| buf.len()).unwrap_or(0) >= max
+    }
+
+    /// Wait until write buffer is flushed below the limit.
+    ///
+    /// Returns `None` if buffer has capacity, receiver fails if connection is closed.
+    pub(super) fn write_capacity(&self) -> Option<pool::Receiver<()>> {
+        if !self.is_write_buffer_full() {
+            return None;
+        }
+        let (tx, rx) = self.pool.waiters.channel();
+        self.with_queues(|q| q.write_waiters.push(tx));
+
+        // dispatcher waits for write buffer flush
+        self.io.wake();
+        Some(rx)
+    }
+
+    /// Remove in-flight packet that could not be encoded, returns ack receiver
+    pub(super) fn cancel_inflight(&self, idx: u16) -> Option<AckTx> {
+        self.with_queues(|queues| {
+            let (tx, _) = utils::cancel_inflight(
+                idx,
+                &mut queues.inflight,
+                &mut queues.inflight_order,
+                &mut queues.waiters,
+                &mut queues.idle_waiters,
+            )?;
+            queues.release_order.retain(|i| *i != idx);
+            Some(tx)
+        })
+    }
+
+    /// Drop all waiters, callbacks of in-flight publishes get `Disconnected` error
+    pub(super) fn clear_queues(&self) {
+        let callbacks: Vec<_> = self.with_queues(|q| {
+            q.waiters.clear();
+            q.idle_waiters.clear();
+            q.ping_waiters.clear();
+            q.write_waiters.clear();
+            q.inflight
+                .drain()
+                .filter_map(|(_, (tx, _))| match tx {
+                    AckTx::Callback(f) => Some(f),
+                    AckTx::Channel(_) => None,
+                })
+                .collect()
+        });
+        for f in callbacks {
+            f(Err(SendPacketError::Disconnected))
+        }
+    }
+
     pub(super) fn has_credit(&self) -> bool {
         self.cap.get() - self.queues.borrow().inflight.len() > 0
     }
 
+    /// Reserve block of sequential packet ids.
+    ///
+    /// Reserved ids are skipped by `next_id()` until they are taken
+    /// from `ReservedIds` or it is dropped.
+    pub(super) fn reserve_ids(self: &Rc<Self>, count: usize) -> ReservedIds {
+        let ids = (0..count)
+            .map(|_| {
+                let idx = self.next_id();
+                self.reserved_ids.borrow_mut().insert(idx);
+                idx
+            })
+            .collect();
+        ReservedIds { shared: self.clone(), ids }
+    }
+
     pub(super) fn next_id(&self) -> u16 {
-        let idx = self.inflight_idx.get() + 1;
-        if idx == u16::max_value() {
-            self.inflight_idx.set(0);
-            u16::max_value()
-        } else {
-            self.inflight_idx.set(idx);
-            idx
+        let reserved = self.reserved_ids.borrow();
+        loop {
+            let idx = self.inflight_idx.get() + 1;
+            let idx = if idx == u16::max_value() {
+                self.inflight_idx.set(0);
+                u16::max_value()
+            } else {
+                self.inflight_idx.set(idx);
+                idx
+            };
+            if !reserved.contains(&idx) || reserved.len() >= u16::max_value() as usize {
+                return idx;
+            }
+        }
+    }
+}
+
+/// Packet ids reserved by `MqttShared::reserve_ids()`, unused ids are released on drop
+pub(super) struct ReservedIds {
+    shared: Rc<MqttShared>,
+    ids: VecDeque<u16>,
+}
+
+impl ReservedIds {
+    /// Take next reserved packet id
+    pub(super) fn take(&mut self) -> Option<NonZeroU16> {
+        let idx = self.ids.pop_front()?;
+        self.shared.reserved_ids.borrow_mut().remove(&idx);
+        NonZeroU16::new(idx)
+    }
+}
+
+impl Drop for ReservedIds {
+    fn drop(&mut self) {
+        let mut reserved = self.shared.reserved_ids.borrow_mut();
+        for idx in self.ids.drain(..) {
+            reserved.remove(&idx);
         }
     }
 }
+
+impl KeepAlive for Rc<MqttShared> {
+    fn take_keepalive(&self) -> Option<Seconds> {
+        self.keepalive.take()
+    }
+
+    fn read_timeout(&self) -> Seconds {
+        self.read_timeout.get()
+    }
+
+    fn write_timeout(&self) -> Seconds {
+        self.write_timeout.get()
+    }
+
+    fn connection_id(&self) -> u64 {
+        self.id
+    }
+
+    fn has_write_waiters(&self) -> bool {
+        self.with_queues(|q| !q.write_waiters.is_empty())
+    }
+
+    fn wake_write_waiters(&self) {
+        let waiters = self.with_queues(|q| mem::take(&mut q.write_waiters));
+        if !self.io.is_closed() {
+            for tx in waiters {
+                let _ = tx.send(());
+            }
+        }
+    }
+}
+
 impl Encoder for MqttShared {
     type Item = codec::Packet;
     type Error = EncodeError;
@@ -117,6 +389,8 @@ impl Ack {
     pub(super) fn packet_type(&self) -> u8 {
         match self {
             Ack::Publish(_) => packet_type::PUBACK,
+            Ack::Receive(_) => packet_type::PUBREC,
+            Ack::Complete(_) => packet_type::PUBCOMP,
             Ack::Subscribe { .. } => packet_type::SUBACK,
             Ack::Unsubscribe(_) => packet_type::UNSUBACK,
         }
@@ -125,6 +399,8 @@ impl Ack {
     pub(super) fn packet_id(&self) -> u16 {
         match self {
             Ack::Publish(id) => id.get(),
+            Ack::Receive(id) => id.get(),
+            Ack::Complete(id) => id.get(),
             Ack::Subscribe { packet_id, .. } => packet_id.get(),
             Ack::Unsubscribe(id) => id.get(),
         }
@@ -141,6 +417,8 @@ impl Ack {
     pub(super) fn is_match(&self, tp: AckType) -> bool {
         match (self, tp) {
             (Ack::Publish(_), AckType::Publish) => true,
+            (Ack::Receive(_), AckType::Receive) => true,
+            (Ack::Complete(_), AckType::Complete) => true,
             (Ack::Subscribe { .. }, AckType::Subscribe) => true,
             (Ack::Unsubscribe(_), AckType::Unsubscribe) => true,
             (_, _) => false,
@@ -152,6 +430,8 @@ impl AckType {
     pub(super) fn name(&self) -> &'static str {
         match self {
             AckType::Publish => "PublishAck",
+            AckType::Receive => "PublishReceived",
+            AckType::Complete => "PublishComplete",
             AckType::Subscribe => "SubscribeAck",
             AckType::Unsubscribe => "UnsubscribeAck",
         }
@@ -1,9 +1,15 @@
 use std::future::{ready, Future};
-use std::{fmt, num::NonZeroU16, rc::Rc};
+use std::time::{Duration, Instant};
+use std::{collections::BTreeMap, fmt, io, num::NonZeroU16, rc::Rc};
 
-use ntex::util::{ByteString, Bytes, Either, Ready};
+use ntex::time::{sleep, Seconds};
+use ntex::util::{join_all, select, ByteString, Bytes, Either, Ready};
 
-use super::shared::{Ack, AckType, MqttShared};
+use crate::ban::peer_ip;
+use crate::events::LifecycleEventKind;
+use crate::types::{packet_type, IdleAction, PacketMask, QoS, TopicRewrite};
+
+use super::shared::{Ack, AckCallback, AckTx, AckType, MqttShared};
 use super::{codec, error::ProtocolError, error::SendPacketError};
 
 pub struct MqttSink(Rc<MqttShared>);
@@ -19,6 +25,11 @@ impl MqttSink {
         MqttSink(state)
     }
 
+    /// Connection id, see `Session::connection_id()`
+    pub fn connection_id(&self) -> u64 {
+        self.0.id
+    }
+
     /// Get client receive credit
     pub fn credit(&self) -> usize {
         self.0.cap.get() - self.0.with_queues(|q| q.inflight.len())
@@ -26,14 +37,178 @@ impl MqttSink {
 
     /// Get notification when packet could be send to the peer.
     ///
+    /// Waits for client receive credit and for write buffer capacity,
+    /// if max size of write buffer is configured.
+    ///
     /// Result indicates if connection is alive
     pub fn ready(&self) -> impl Future<Output = bool> {
+        if !self.0.io.is_closed() {
+            let write = self.0.write_capacity();
+            let credit = self.0.with_queues(|q| {
+                if q.inflight.len() >= self.0.cap.get() {
+                    let (tx, rx) = self.0.pool.waiters.channel();
+                    q.waiters.push_back(tx);
+                    return Some(rx);
+                }
+                None
+            });
+
+            if write.is_none() && credit.is_none() {
+                Either::Left(ready(true))
+            } else {
+                Either::Right(async move {
+                    if let Some(rx) = write {
+                        if rx.await.is_err() {
+                            return false;
+                        }
+                    }
+                    if let Some(rx) = credit {
+                        rx.await.is_ok()
+                    } else {
+                        true
+                    }
+                })
+            }
+        } else {
+            Either::Left(ready(false))
+        }
+    }
+
+    /// Set keep-alive timeout for the connection.
+    ///
+    /// Running keep-alive timer is restarted with new timeout.
+    /// To disable timeout set value to 0.
+    pub fn set_keepalive(&self, timeout: Seconds) {
+        self.0.io.start_keepalive_timer(timeout.into());
+        self.0.keepalive.set(Some(timeout));
+        self.0.keepalive_timeout.set(Some(timeout));
+    }
+
+    /// Set packet types peer is allowed to send.
+    ///
+    /// Disallowed packet is handled as protocol error.
+    pub fn set_allowed_packets(&self, mask: PacketMask) {
+        self.0.allowed_packets.set(mask);
+    }
+
+    /// Will message of the connection.
+    ///
+    /// Returns `None` if client did not set will or will is discarded.
+    pub fn will(&self) -> Option<codec::LastWill> {
+        self.0.will.borrow().clone()
+    }
+
+    /// Discard will message of the connection.
+    ///
+    /// `Closed` control message does not carry discarded will.
+    pub fn discard_will(&self) {
+        self.0.will.borrow_mut().take();
+    }
+
+    /// Take will message, will is not available after abnormal close
+    pub(super) fn take_will(&self) -> Option<codec::LastWill> {
+        self.0.will.borrow_mut().take()
+    }
+
+    /// DISCONNECT packet is received, connection is closed cleanly
+    pub(super) fn disconnect_received(&self) {
+        self.0.clean_disconnect.set(true);
+        // [MQTT-3.14.4-3] will message must be discarded
+        self.0.will.borrow_mut().take();
+    }
+
+    /// Report protocol error to connection metrics
+    pub(super) fn protocol_error(&self, err: &ProtocolError) {
+        self.0.codec.report_error(err);
+    }
+
+    /// Check if DISCONNECT packet is received
+    pub(super) fn is_clean_disconnect(&self) -> bool {
+        self.0.clean_disconnect.get()
+    }
+
+    /// Check if connection is closed by this side of connection
+    pub(super) fn is_closed_locally(&self) -> bool {
+        self.0.local_close.get()
+    }
+
+    /// Keep-alive timeout set with `set_keepalive()`
+    pub(crate) fn keepalive_timeout(&self) -> Option<Seconds> {
+        self.0.keepalive_timeout.get()
+    }
+
+    /// Time of last PINGREQ packet received from peer
+    pub(crate) fn last_ping_at(&self) -> Option<Instant> {
+        self.0.last_ping.get()
+    }
+
+    /// Topic filters confirmed by control service
+    pub(crate) fn subscriptions(&self) -> BTreeMap<ByteString, QoS> {
+        self.0.subscriptions.borrow().clone()
+    }
+
+    /// Ban client in server's ban list, returns `false` if ban list is not set
+    pub(crate) fn quarantine(&self, client_id: &str, duration: Seconds) -> bool {
+        if let Some(ref bans) = *self.0.ban_list.borrow() {
+            bans.ban(client_id, peer_ip(&self.0.io), duration);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Record received PINGREQ packet
+    pub(super) fn ping_received(&self) -> Instant {
+        let now = Instant::now();
+        self.0.last_ping.set(Some(now));
+        now
+    }
+
+    /// Check if peer is allowed to send packet type
+    pub(super) fn is_packet_allowed(&self, packet_type: u8) -> bool {
+        self.0.allowed_packets.get().is_allowed(packet_type)
+    }
+
+    /// Size of write buffer in bytes, not yet flushed to the peer
+    pub fn pending_write_bytes(&self) -> usize {
+        self.0.io.with_write_buf(|buf| buf.len()).unwrap_or(0)
+    }
+
+    /// Number of in-flight packets, not yet acknowledged by the peer
+    pub fn inflight_count(&self) -> usize {
+        self.0.with_queues(|q| q.inflight.len())
+    }
+
+    /// Check if publish packet is next in the read buffer
+    pub(super) fn is_publish_next(&self) -> bool {
+        self.0.io.with_read_buf(|buf| self.0.codec.is_publish_next(buf))
+    }
+
+    /// Max number of unacknowledged inbound packets, `0` means not limited
+    pub(super) fn inbound_window(&self) -> usize {
+        self.0.inbound_window.get()
+    }
+
+    /// Check if packet id is in-flight
+    pub(super) fn is_inflight(&self, id: u16) -> bool {
+        self.0.with_queues(|q| q.inflight.contains_key(&id))
+    }
+
+    /// Emit connection lifecycle event
+    pub(super) fn lifecycle_event(&self, kind: LifecycleEventKind) {
+        self.0.lifecycle_event(kind)
+    }
+
+    /// Get notification when all in-flight packets get acknowledged by the peer.
+    ///
+    /// Result indicates if connection is alive
+    pub fn on_idle(&self) -> impl Future<Output = bool> {
         if !self.0.io.is_closed() {
             self.0
                 .with_queues(|q| {
-                    if q.inflight.len() >= self.0.cap.get() {
+                    if !q.inflight.is_empty() {
                         let (tx, rx) = self.0.pool.waiters.channel();
-                        q.waiters.push_back(tx);
+                        q.idle_waiters.push(tx);
                         return Some(rx);
                     }
                     None
@@ -45,28 +220,140 @@ impl MqttSink {
         }
     }
 
+    /// Close connection when connection lifetime `timeout` is reached
+    pub(super) fn close_after(&self, timeout: Seconds) {
+        if timeout.is_zero() {
+            return;
+        }
+        let sink = self.clone();
+        let on_disconnect = self.0.io.on_disconnect();
+        ntex::rt::spawn(async move {
+            if let Either::Left(_) = select(sleep(timeout), on_disconnect).await {
+                log::trace!("{}: Max connection lifetime is reached, closing", sink.0.id);
+                sink.close();
+            }
+        });
+    }
+
+    /// Retransmit PUBREL if PUBCOMP is not received within timeout,
+    /// close connection on second timeout
+    fn pubcomp_timer(&self, packet_id: NonZeroU16) {
+        let timeout = self.0.pubcomp_timeout.get();
+        if timeout.is_zero() {
+            return;
+        }
+        let sink = self.clone();
+        let on_disconnect = self.0.io.on_disconnect();
+        ntex::rt::spawn(async move {
+            let released =
+                || sink.0.with_queues(|q| q.release_order.contains(&packet_id.get()));
+            let timer = async {
+                sleep(timeout).await;
+                if released() {
+                    log::trace!(
+                        "{}: PUBCOMP timeout, resend PUBREL {:?}",
+                        sink.0.id,
+                        packet_id
+                    );
+                    sink.send(codec::Packet::PublishRelease { packet_id });
+                    sleep(timeout).await;
+                    released()
+                } else {
+                    false
+                }
+            };
+            if let Either::Left(true) = select(timer, on_disconnect).await {
+                log::trace!("{}: PUBCOMP is not received, closing", sink.0.id);
+                sink.0.io.want_shutdown(Some(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    ProtocolError::PubcompTimeout,
+                )));
+            }
+        });
+    }
+
     /// Close mqtt connection
     pub fn close(&self) {
+        self.0.closed_locally();
         self.0.io.close();
-        self.0.with_queues(|q| {
-            q.inflight.clear();
-            q.waiters.clear();
-        });
+        self.0.clear_queues();
     }
 
     /// Force close mqtt connection. mqtt dispatcher does not wait for uncompleted
     /// responses, but it flushes buffers.
     pub fn force_close(&self) {
+        self.0.closed_locally();
         self.0.io.force_close();
+        self.0.clear_queues();
+    }
+
+    pub(super) fn send(&self, pkt: codec::Packet) {
+        let _ = self.0.io.encode(pkt, &self.0.codec);
+    }
+
+    /// Send keep-alive ping, ping is not sent if previous one is not answered yet
+    pub(super) fn keepalive_ping(&self) -> bool {
         self.0.with_queues(|q| {
-            q.inflight.clear();
-            q.waiters.clear();
+            if q.ping_sent.is_some() {
+                !self.0.io.is_closed()
+            } else if self.0.io.encode(codec::Packet::PingRequest, &self.0.codec).is_ok() {
+                q.ping_sent = Some(Instant::now());
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Send PINGREQ packet and wait for PINGRESP packet.
+    ///
+    /// Returns round-trip time of ping. Only one ping is in flight, concurrent
+    /// calls wait for the same response. Fails if connection is closed before
+    /// response is received. PINGREQ is sent by client only, on server side
+    /// `SendPacketError::NotAllowed` error is returned.
+    pub fn ping(&self) -> impl Future<Output = Result<Duration, SendPacketError>> {
+        if !self.0.client.get() {
+            return Either::Left(Ready::Err(SendPacketError::NotAllowed));
+        }
+        if self.0.io.is_closed() {
+            return Either::Left(Ready::Err(SendPacketError::Disconnected));
+        }
+
+        let rx = self.0.with_queues(|q| {
+            if q.ping_sent.is_none() {
+                self.0
+                    .io
+                    .encode(codec::Packet::PingRequest, &self.0.codec)
+                    .map_err(SendPacketError::Encode)?;
+                q.ping_sent = Some(Instant::now());
+            }
+            let (tx, rx) = self.0.pool.pings.channel();
+            q.ping_waiters.push(tx);
+            Ok(rx)
         });
+
+        match rx {
+            Ok(rx) => {
+                Either::Right(
+                    async move { rx.await.map_err(|_| SendPacketError::Disconnected) },
+                )
+            }
+            Err(e) => Either::Left(Ready::Err(e)),
+        }
     }
 
-    /// Send ping
-    pub(super) fn ping(&self) -> bool {
-        self.0.io.encode(codec::Packet::PingRequest, &self.0.codec).is_ok()
+    /// PINGRESP packet is received, notify ping waiters
+    pub(super) fn ping_response(&self) {
+        self.0.with_queues(|q| {
+            if let Some(sent) = q.ping_sent.take() {
+                let rtt = sent.elapsed();
+                for tx in q.ping_waiters.drain(..) {
+                    let _ = tx.send(rtt);
+                }
+            } else {
+                log::trace!("{}: Unexpected PINGRESP packet", self.0.id);
+            }
+        })
     }
 
     /// Create publish message builder
@@ -74,21 +361,121 @@ impl MqttSink {
     where
         ByteString: From<U>,
     {
-        PublishBuilder {
-            packet: codec::Publish {
-                payload,
-                dup: false,
-                retain: false,
-                topic: topic.into(),
-                qos: codec::QoS::AtMostOnce,
-                packet_id: None,
-            },
-            shared: self.0.clone(),
+        self.publish_pkt(codec::Publish::build(topic, payload))
+    }
+
+    /// Send publish packet with QoS 2.
+    ///
+    /// Future resolves once PUBCOMP is received from the peer.
+    pub fn publish_qos2<U>(
+        &self,
+        topic: U,
+        payload: Bytes,
+    ) -> impl Future<Output = Result<(), SendPacketError>>
+    where
+        ByteString: From<U>,
+    {
+        self.publish(topic, payload).send_exactly_once()
+    }
+
+    /// Send batch of publish packets with QoS 1.
+    ///
+    /// Contiguous block of packet ids is reserved for the batch when method is
+    /// called, reserved ids are not used by other packets until batch packets
+    /// are sent. Packets are written in batch order as long as client receive
+    /// credit allows, rest of the batch waits for credit and is sent in next chunk.
+    /// Future resolves when all packets are acked, results are returned in batch
+    /// order. If connection is lost, packets that are not acked resolve with
+    /// `Disconnected` error.
+    pub fn publish_batch<U>(
+        &self,
+        messages: Vec<(U, Bytes)>,
+    ) -> impl Future<Output = Vec<Result<(), SendPacketError>>>
+    where
+        ByteString: From<U>,
+    {
+        let shared = self.0.clone();
+        let mut ids = shared.reserve_ids(messages.len());
+        let packets: Vec<_> = messages
+            .into_iter()
+            .map(|(topic, payload)| {
+                let mut packet = codec::Publish::build(topic, payload);
+                packet.qos = codec::QoS::AtLeastOnce;
+                packet
+            })
+            .collect();
+
+        async move {
+            let mut results = Vec::with_capacity(packets.len());
+            for mut packet in packets {
+                if shared.io.is_closed() {
+                    results.push(Either::Left(Ready::Err(SendPacketError::Disconnected)));
+                    continue;
+                }
+
+                // wait until in-flight window has space for next chunk
+                if let Some(fut) = PublishBuilder::wait_capacity(&shared) {
+                    if let Err(err) = fut.await {
+                        results.push(Either::Left(Ready::Err(err)));
+                        continue;
+                    }
+                }
+
+                packet.packet_id = ids.take();
+                let (tx, rx) = shared.pool.queue.channel();
+                match PublishBuilder::send_inflight(packet, &shared, AckTx::Channel(tx)) {
+                    Ok(_) => results.push(Either::Right(async move {
+                        rx.await.map(|_| ()).map_err(|_| SendPacketError::Disconnected)
+                    })),
+                    Err((err, _)) => results.push(Either::Left(Ready::Err(err))),
+                }
+            }
+            join_all(results).await
+        }
+    }
+
+    /// Create publish message builder for prepared publish packet
+    ///
+    /// QoS level of the packet is overridden by the send method.
+    pub fn publish_pkt(&self, packet: codec::Publish) -> PublishBuilder {
+        PublishBuilder { packet, shared: self.0.clone() }
+    }
+
+    /// Send publish packet with QoS 1 and call `f` with delivery result.
+    ///
+    /// Callback is called once, when PUBACK is received from the peer, with
+    /// `SendPacketError::Disconnected` error if connection is closed before
+    /// acknowledgement, or with error if packet could not be sent. Task is
+    /// spawned only if publish waits for in-flight credit or write buffer capacity.
+    pub fn publish_with_callback<F>(&self, publish: codec::Publish, f: F)
+    where
+        F: FnOnce(Result<(), SendPacketError>) + 'static,
+    {
+        let mut packet = publish;
+        packet.qos = codec::QoS::AtLeastOnce;
+        let f: AckCallback = Box::new(f);
+
+        if self.0.io.is_closed() {
+            f(Err(SendPacketError::Disconnected))
+        } else if let Some(fut) = PublishBuilder::wait_capacity(&self.0) {
+            let shared = self.0.clone();
+            ntex::rt::spawn(async move {
+                match fut.await {
+                    Ok(_) => PublishBuilder::send_with_callback(packet, &shared, f),
+                    Err(e) => f(Err(e)),
+                }
+            });
+        } else {
+            PublishBuilder::send_with_callback(packet, &self.0, f)
         }
     }
 
     /// Create subscribe packet builder
     ///
+    /// Server could use it to subscribe to topics on connected peer, for example
+    /// for bridging. Subscribe resolves with return code for each requested filter,
+    /// publishes for subscribed topics are handled by publish service.
+    ///
     /// panics if id is 0
     pub fn subscribe(&self) -> SubscribeBuilder {
         SubscribeBuilder { id: 0, topic_filters: Vec::new(), shared: self.0.clone() }
@@ -99,24 +486,73 @@ impl MqttSink {
         UnsubscribeBuilder { id: 0, topic_filters: Vec::new(), shared: self.0.clone() }
     }
 
+    /// Set handler for expired keep-alive timer
+    pub(super) fn set_idle_handler(&self, f: Box<dyn Fn() -> IdleAction>) {
+        *self.0.on_idle.borrow_mut() = Some(f);
+    }
+
+    /// Keep-alive timer is expired, returns timeout if handler extends idle period
+    pub(super) fn idle_timeout(&self) -> Option<Seconds> {
+        match self.0.on_idle.borrow().as_ref().map(|f| f()) {
+            Some(IdleAction::Extend(timeout)) if !timeout.is_zero() => Some(timeout),
+            _ => None,
+        }
+    }
+
+    /// Re-arm keep-alive timer, regular timeout is restored once packet is received
+    pub(super) fn extend_keepalive(&self, timeout: Seconds) {
+        log::trace!("{}: Idle period is extended by {:?}", self.0.id, timeout);
+        self.0.io.start_keepalive_timer(timeout.into());
+    }
+
+    /// Set topic rewrite hooks
+    pub(super) fn set_topic_rewrite(
+        &self,
+        ingress: Option<TopicRewrite>,
+        egress: Option<TopicRewrite>,
+    ) {
+        *self.0.topic_rewrite.borrow_mut() = (ingress, egress);
+    }
+
+    /// Apply inbound topic rewrite
+    pub(super) fn inbound_topic(&self, topic: &mut ByteString) {
+        self.0.inbound_topic(topic)
+    }
+
     pub(super) fn pkt_ack(&self, pkt: Ack) -> Result<(), ProtocolError> {
+        if let Ack::Receive(packet_id) = pkt {
+            return self.pkt_received(packet_id);
+        }
+
         let result = self.0.with_queues(|queues| {
             // check ack order
-            if let Some(idx) = queues.inflight_order.pop_front() {
+            let order = if let Ack::Complete(_) = pkt {
+                &mut queues.release_order
+            } else {
+                &mut queues.inflight_order
+            };
+            if let Some(idx) = order.pop_front() {
                 if idx != pkt.packet_id() {
                     log::trace!(
-                    "MQTT protocol error, packet_id order does not match, expected {}, got: {}",
+                    "{}: MQTT protocol error, packet_id order does not match, expected {}, got: {}",
+                    self.0.id,
                     idx,
                     pkt.packet_id()
                 );
                     Err(ProtocolError::PacketIdMismatch)
                 } else {
                     // get publish ack channel
-                    log::trace!("Ack packet with id: {}", pkt.packet_id());
+                    log::trace!("{}: Ack packet with id: {}", self.0.id, pkt.packet_id());
                     let idx = pkt.packet_id();
                     if let Some((tx, tp)) = queues.inflight.remove(&idx) {
                         if pkt.is_match(tp) {
-                            let _ = tx.send(pkt);
+                            let callback = match tx {
+                                AckTx::Channel(tx) => {
+                                    let _ = tx.send(pkt);
+                                    None
+                                }
+                                AckTx::Callback(f) => Some(f),
+                            };
 
                             // wake up queued request (receive max limit)
                             while let Some(tx) = queues.waiters.pop_front() {
@@ -124,25 +560,109 @@ impl MqttSink {
                                     break;
                                 }
                             }
-                            Ok(())
+
+                            // notify idle waiters, all in-flight packets are acked
+                            if queues.inflight.is_empty() {
+                                for tx in queues.idle_waiters.drain(..) {
+                                    let _ = tx.send(());
+                                }
+                            }
+                            Ok(callback)
                         } else {
-                            log::trace!("MQTT protocol error, unexpected packet");
+                            log::trace!("{}: MQTT protocol error, unexpected packet", self.0.id);
                             Err(ProtocolError::Unexpected(pkt.packet_type(), tp.name()))
                         }
                     } else {
-                        log::error!("In-flight state inconsistency");
+                        log::error!("{}: In-flight state inconsistency", self.0.id);
                         Err(ProtocolError::PacketIdMismatch)
                     }
                 }
             } else {
-                log::trace!("Unexpected PublishAck packet: {:?}", pkt.packet_id());
+                log::trace!("{}: Unexpected PublishAck packet: {:?}", self.0.id, pkt.packet_id());
                 Err(ProtocolError::PacketIdMismatch)
             }
         });
-        result.map_err(|e| {
-            self.close();
-            e
-        })
+        match result {
+            Ok(callback) => {
+                // callback is called after queues are released, it could use sink
+                if let Some(f) = callback {
+                    f(Ok(()))
+                }
+                Ok(())
+            }
+            Err(e) => {
+                self.close();
+                Err(e)
+            }
+        }
+    }
+
+    /// Handle PUBREC of QoS 2 publish, in-flight slot is kept until PUBCOMP
+    fn pkt_received(&self, packet_id: NonZeroU16) -> Result<(), ProtocolError> {
+        let idx = packet_id.get();
+        let result = self.0.with_queues(|queues| {
+            let tp = match queues.inflight.get_mut(&idx) {
+                Some((_, tp)) => tp,
+                None => {
+                    log::trace!("{}: Unexpected PublishReceived packet: {:?}", self.0.id, idx);
+                    return Err(ProtocolError::PacketIdMismatch);
+                }
+            };
+            match *tp {
+                AckType::Receive => {
+                    // check ack order
+                    if queues.inflight_order.front() != Some(&idx) {
+                        log::trace!(
+                            "{}: MQTT protocol error, packet_id order does not match, got: {}",
+                            self.0.id,
+                            idx
+                        );
+                        return Err(ProtocolError::PacketIdMismatch);
+                    }
+                    *tp = AckType::Complete;
+                    queues.inflight_order.pop_front();
+                    queues.release_order.push_back(idx);
+                    Ok(true)
+                }
+                // duplicate PUBREC, release is already sent
+                AckType::Complete => Ok(false),
+                _ => {
+                    log::trace!("{}: MQTT protocol error, unexpected packet", self.0.id);
+                    Err(ProtocolError::Unexpected(packet_type::PUBREC, tp.name()))
+                }
+            }
+        });
+
+        match result {
+            Ok(true) => {
+                self.send(codec::Packet::PublishRelease { packet_id });
+                self.pubcomp_timer(packet_id);
+                Ok(())
+            }
+            Ok(false) => {
+                log::trace!(
+                    "{}: Duplicate PublishReceived packet is ignored: {:?}",
+                    self.0.id,
+                    idx
+                );
+                Ok(())
+            }
+            Err(e) => {
+                self.close();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl crate::session::SessionSink for MqttSink {
+    fn set_evicted<St: 'static>(&self, f: Box<dyn FnOnce(St)>) {
+        *self.0.evicted.borrow_mut() = Some(Box::new(f));
+    }
+
+    fn take_evicted<St: 'static>(&self) -> Option<Box<dyn FnOnce(St)>> {
+        let f = self.0.evicted.borrow_mut().take()?;
+        f.downcast::<Box<dyn FnOnce(St)>>().ok().map(|f| *f)
     }
 }
 
@@ -177,62 +697,189 @@ impl PublishBuilder {
         self
     }
 
+    /// Set retain flag
     pub fn retain(mut self) -> Self {
         self.packet.retain = true;
         self
     }
 
+    /// Set retain flag.
+    ///
+    /// Server must clear retain flag when it forwards publish to existing
+    /// subscriptions and set it when it sends retained message as a result
+    /// of new subscription (MQTT-3.3.1-9).
+    pub fn with_retain(mut self, val: bool) -> Self {
+        self.packet.retain = val;
+        self
+    }
+
     /// Send publish packet with QoS 0
+    ///
+    /// Publish does not wait for write buffer capacity, it fails with
+    /// `SendPacketError::WriteBufferFull` error if size of write buffer
+    /// exceeds the limit. Use `send_at_most_once_wait()` to wait for capacity.
     pub fn send_at_most_once(self) -> Result<(), SendPacketError> {
-        let packet = self.packet;
+        let mut packet = self.packet;
+        self.shared.outbound_topic(&mut packet.topic);
 
-        if !self.shared.io.is_closed() {
-            log::trace!("Publish (QoS-0) to {:?}", packet.topic);
+        if self.shared.is_write_buffer_full() {
+            log::trace!(
+                "{}: Write buffer is full, publish (QoS-0) is rejected",
+                self.shared.id
+            );
+            Err(SendPacketError::WriteBufferFull)
+        } else if !self.shared.io.is_closed() {
+            log::trace!("{}: Publish (QoS-0) to {:?}", self.shared.id, packet.topic);
             self.shared
                 .io
                 .encode(codec::Packet::Publish(packet), &self.shared.codec)
                 .map_err(SendPacketError::Encode)
                 .map(|_| ())
         } else {
-            log::error!("Mqtt sink is disconnected");
+            log::error!("{}: Mqtt sink is disconnected", self.shared.id);
             Err(SendPacketError::Disconnected)
         }
     }
 
-    #[allow(clippy::await_holding_refcell_ref)]
+    /// Send publish packet with QoS 0, waits for write buffer capacity
+    pub async fn send_at_most_once_wait(self) -> Result<(), SendPacketError> {
+        while !self.shared.io.is_closed() {
+            if let Some(rx) = self.shared.write_capacity() {
+                if rx.await.is_err() {
+                    return Err(SendPacketError::Disconnected);
+                }
+            } else {
+                break;
+            }
+        }
+        self.send_at_most_once()
+    }
+
     /// Send publish packet with QoS 1
     pub fn send_at_least_once(self) -> impl Future<Output = Result<(), SendPacketError>> {
+        self.send_with_ack(codec::QoS::AtLeastOnce)
+    }
+
+    /// Send publish packet with QoS 2
+    ///
+    /// Future resolves once PUBCOMP is received from the peer, packet id
+    /// stays reserved until then. If connection is lost before PUBCOMP,
+    /// `SendPacketError::Disconnected` error is returned.
+    pub fn send_exactly_once(self) -> impl Future<Output = Result<(), SendPacketError>> {
+        self.send_with_ack(codec::QoS::ExactlyOnce)
+    }
+
+    fn send_with_ack(
+        self,
+        qos: codec::QoS,
+    ) -> impl Future<Output = Result<(), SendPacketError>> {
         let shared = self.shared;
         let mut packet = self.packet;
-        packet.qos = codec::QoS::AtLeastOnce;
+        packet.qos = qos;
 
         if !shared.io.is_closed() {
-            // handle client receive maximum
-            if !shared.has_credit() {
-                let (tx, rx) = shared.pool.waiters.channel();
-                shared.with_queues(|q| q.waiters.push_back(tx));
-
+            if let Some(fut) = Self::wait_capacity(&shared) {
                 return Either::Left(Either::Right(async move {
-                    if rx.await.is_err() {
-                        return Err(SendPacketError::Disconnected);
-                    }
-                    Self::send_at_least_once_inner(packet, shared).await
+                    fut.await?;
+                    Self::send_with_ack_inner(packet, shared).await
                 }));
             }
-            Either::Right(Self::send_at_least_once_inner(packet, shared))
+            Either::Right(Self::send_with_ack_inner(packet, shared))
         } else {
             Either::Left(Either::Left(Ready::Err(SendPacketError::Disconnected)))
         }
     }
 
-    fn send_at_least_once_inner(
-        mut packet: codec::Publish,
+    /// Send publish packet with QoS which is the minimum of publish QoS
+    /// and subscription's granted QoS.
+    ///
+    /// Packet id is allocated only if effective QoS is greater than QoS 0,
+    /// QoS 0 publish waits for write buffer capacity. QoS 2 publish resolves
+    /// once PUBCOMP is received, same as `send_exactly_once()`.
+    pub fn send_at_min_qos(
+        mut self,
+        qos: codec::QoS,
+        granted: codec::QoS,
+    ) -> impl Future<Output = Result<(), SendPacketError>> {
+        let qos = qos.min(granted);
+        if qos == codec::QoS::AtMostOnce {
+            self.packet.packet_id = None;
+            Either::Left(self.send_at_most_once_wait())
+        } else {
+            Either::Right(self.send_with_ack(qos))
+        }
+    }
+
+    /// Wait for write buffer capacity and in-flight credit.
+    ///
+    /// Returns `None` if publish could be sent immediately.
+    #[allow(clippy::await_holding_refcell_ref)]
+    fn wait_capacity(
+        shared: &MqttShared,
+    ) -> Option<impl Future<Output = Result<(), SendPacketError>>> {
+        // handle write buffer limit
+        let write = shared.write_capacity();
+
+        // handle client receive maximum
+        let credit = if !shared.has_credit() {
+            let (tx, rx) = shared.pool.waiters.channel();
+            shared.with_queues(|q| q.waiters.push_back(tx));
+            Some(rx)
+        } else {
+            None
+        };
+
+        if write.is_none() && credit.is_none() {
+            return None;
+        }
+        Some(async move {
+            if let Some(rx) = write {
+                if rx.await.is_err() {
+                    return Err(SendPacketError::Disconnected);
+                }
+            }
+            if let Some(rx) = credit {
+                if rx.await.is_err() {
+                    return Err(SendPacketError::Disconnected);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn send_with_ack_inner(
+        packet: codec::Publish,
         shared: Rc<MqttShared>,
     ) -> impl Future<Output = Result<(), SendPacketError>> {
-        let rx = shared.with_queues(|queues| {
-            // publish ack channel
-            let (tx, rx) = shared.pool.queue.channel();
+        // publish ack channel
+        let (tx, rx) = shared.pool.queue.channel();
+
+        match Self::send_inflight(packet, &shared, AckTx::Channel(tx)) {
+            Ok(_) => Either::Right(async move {
+                rx.await.map(|_| ()).map_err(|_| SendPacketError::Disconnected)
+            }),
+            Err((e, _)) => Either::Left(Ready::Err(e)),
+        }
+    }
 
+    fn send_with_callback(packet: codec::Publish, shared: &MqttShared, f: AckCallback) {
+        if let Err((e, tx)) = Self::send_inflight(packet, shared, AckTx::Callback(f)) {
+            match tx {
+                Some(AckTx::Callback(f)) => f(Err(e)),
+                _ => log::error!("{}: In-flight state inconsistency", shared.id),
+            }
+        }
+    }
+
+    /// Register publish as in-flight packet and write it to the connection.
+    ///
+    /// Ack receiver is returned back if publish could not be sent.
+    fn send_inflight(
+        mut packet: codec::Publish,
+        shared: &MqttShared,
+        tx: AckTx,
+    ) -> Result<(), (SendPacketError, Option<AckTx>)> {
+        let tx = shared.with_queues(|queues| {
             // packet id
             let mut idx = packet.packet_id.map(|i| i.get()).unwrap_or(0);
             if idx == 0 {
@@ -240,26 +887,31 @@ impl PublishBuilder {
                 packet.packet_id = NonZeroU16::new(idx);
             }
             if queues.inflight.contains_key(&idx) {
-                return Err(SendPacketError::PacketIdInUse(idx));
+                return Err(tx);
             }
-            queues.inflight.insert(idx, (tx, AckType::Publish));
+            let tp = if packet.qos == codec::QoS::ExactlyOnce {
+                AckType::Receive
+            } else {
+                AckType::Publish
+            };
+            queues.inflight.insert(idx, (tx, tp));
             queues.inflight_order.push_back(idx);
-            Ok(rx)
+            Ok(())
         });
 
-        let rx = match rx {
-            Ok(rx) => rx,
-            Err(e) => return Either::Left(Ready::Err(e)),
-        };
+        let idx = packet.packet_id.map(|i| i.get()).unwrap_or(0);
+        if let Err(tx) = tx {
+            return Err((SendPacketError::PacketIdInUse(idx), Some(tx)));
+        }
 
-        log::trace!("Publish (QoS1) to {:#?}", packet);
+        shared.outbound_topic(&mut packet.topic);
+        log::trace!("{}: Publish ({:?}) to {:#?}", shared.id, packet.qos, packet);
 
-        match shared.io.encode(codec::Packet::Publish(packet), &shared.codec) {
-            Ok(_) => Either::Right(async move {
-                rx.await.map(|_| ()).map_err(|_| SendPacketError::Disconnected)
-            }),
-            Err(err) => Either::Left(Ready::Err(SendPacketError::Encode(err))),
-        }
+        shared
+            .io
+            .encode(codec::Packet::Publish(packet), &shared.codec)
+            .map(|_| ())
+            .map_err(|err| (SendPacketError::Encode(err), shared.cancel_inflight(idx)))
     }
 }
 
@@ -313,13 +965,18 @@ impl SubscribeBuilder {
                 if queues.inflight.contains_key(&idx) {
                     return Err(SendPacketError::PacketIdInUse(idx));
                 }
-                queues.inflight.insert(idx, (tx, AckType::Subscribe));
+                queues.inflight.insert(idx, (AckTx::Channel(tx), AckType::Subscribe));
                 queues.inflight_order.push_back(idx);
                 Ok(rx)
             })?;
 
             // send subscribe to client
-            log::trace!("Sending subscribe packet id: {} filters:{:?}", idx, filters);
+            log::trace!(
+                "{}: Sending subscribe packet id: {} filters:{:?}",
+                shared.id,
+                idx,
+                filters
+            );
 
             match shared.io.encode(
                 codec::Packet::Subscribe {
@@ -334,7 +991,10 @@ impl SubscribeBuilder {
                         .map_err(|_| SendPacketError::Disconnected)
                         .map(|pkt| pkt.subscribe())
                 }
-                Err(err) => Err(SendPacketError::Encode(err)),
+                Err(err) => {
+                    shared.cancel_inflight(idx);
+                    Err(SendPacketError::Encode(err))
+                }
             }
         } else {
             Err(SendPacketError::Disconnected)
@@ -392,13 +1052,18 @@ impl UnsubscribeBuilder {
                 if queues.inflight.contains_key(&idx) {
                     return Err(SendPacketError::PacketIdInUse(idx));
                 }
-                queues.inflight.insert(idx, (tx, AckType::Unsubscribe));
+                queues.inflight.insert(idx, (AckTx::Channel(tx), AckType::Unsubscribe));
                 queues.inflight_order.push_back(idx);
                 Ok(rx)
             })?;
 
             // send subscribe to client
-            log::trace!("Sending unsubscribe packet id: {} filters:{:?}", idx, filters);
+            log::trace!(
+                "{}: Sending unsubscribe packet id: {} filters:{:?}",
+                shared.id,
+                idx,
+                filters
+            );
 
             match shared.io.encode(
                 codec::Packet::Unsubscribe {
@@ -411,7 +1076,10 @@ impl UnsubscribeBuilder {
                     // wait ack from peer
                     rx.await.map_err(|_| SendPacketError::Disconnected).map(|_| ())
                 }
-                Err(err) => Err(SendPacketError::Encode(err)),
+                Err(err) => {
+                    shared.cancel_inflight(idx);
+                    Err(SendPacketError::Encode(err))
+                }
             }
         } else {
             Err(SendPacketError::Disconnected)
@@ -83,6 +83,14 @@ impl Client {
         &mut self.pkt
     }
 
+    #[inline]
+    /// Keep-alive timeout used for ping scheduling.
+    ///
+    /// Server keep-alive from `ConnectAck` packet overrides requested timeout.
+    pub fn effective_keepalive(&self) -> Seconds {
+        self.keepalive
+    }
+
     /// Configure mqtt resource for a specific topic
     pub fn resource<T, F, U, E>(self, address: T, service: F) -> ClientRouter<E, U::Error>
     where
@@ -313,7 +321,7 @@ async fn keepalive(sink: MqttSink, timeout: Seconds) {
     loop {
         sleep(keepalive).await;
 
-        if !sink.ping() {
+        if !sink.keepalive_ping() {
             // connection is closed
             log::debug!("mqtt client connection is closed, stopping keep-alive task");
             break;
@@ -233,6 +233,7 @@ where
                 })?;
 
             let shared = Rc::new(MqttShared::new(io.get_ref(), codec, 0, pool));
+            shared.client.set(true);
 
             match packet {
                 codec::Packet::ConnectAck(pkt) => {
@@ -248,6 +249,7 @@ where
                         shared
                             .cap
                             .set(pkt.receive_max.map(|v| v.get()).unwrap_or(65535) as usize);
+                        shared.set_topic_alias_max(pkt.topic_alias_max);
 
                         Ok(Client::new(
                             io,
@@ -48,7 +48,11 @@ impl<E> ControlMessage<E> {
     }
 
     pub fn disconnect(&self, pkt: codec::Disconnect) -> ControlResult {
-        ControlResult { packet: Some(codec::Packet::Disconnect(pkt)), disconnect: true }
+        ControlResult {
+            packet: Some(codec::Packet::Disconnect(pkt)),
+            disconnect: true,
+            extend: None,
+        }
     }
 }
 
@@ -67,7 +71,7 @@ impl Publish {
     }
 
     pub fn ack_qos0(self) -> ControlResult {
-        ControlResult { packet: None, disconnect: false }
+        ControlResult { packet: None, disconnect: false, extend: None }
     }
 
     pub fn ack(self, reason_code: codec::PublishAckReason) -> ControlResult {
@@ -81,6 +85,7 @@ impl Publish {
                 })
             }),
             disconnect: false,
+            extend: None,
         }
     }
 
@@ -100,6 +105,7 @@ impl Publish {
                 })
             }),
             disconnect: false,
+            extend: None,
         }
     }
 }
@@ -115,6 +121,6 @@ impl PeerGone {
 
     /// Ack PeerGone message
     pub fn ack(self) -> ControlResult {
-        ControlResult { packet: None, disconnect: true }
+        ControlResult { packet: None, disconnect: true, extend: None }
     }
 }
@@ -4,7 +4,8 @@ use std::{future::Future, marker::PhantomData, num::NonZeroU16, pin::Pin, rc::Rc
 
 use ntex::io::DispatchItem;
 use ntex::service::Service;
-use ntex::util::{buffer::BufferService, inflight::InFlightService, Either, HashSet, Ready};
+use ntex::util::{buffer::BufferService, inflight::InFlightService};
+use ntex::util::{ByteString, Either, HashMap, HashSet, Ready};
 
 use crate::error::{MqttError, ProtocolError};
 use crate::types::packet_type;
@@ -54,7 +55,7 @@ struct Inner<C> {
 
 struct PublishInfo {
     inflight: HashSet<NonZeroU16>,
-    aliases: HashSet<NonZeroU16>,
+    aliases: HashMap<NonZeroU16, ByteString>,
 }
 
 impl<T, C, E> Dispatcher<T, C, E>
@@ -78,7 +79,7 @@ where
                 control,
                 sink,
                 info: RefCell::new(PublishInfo {
-                    aliases: HashSet::default(),
+                    aliases: HashMap::default(),
                     inflight: HashSet::default(),
                 }),
             }),
@@ -133,7 +134,7 @@ where
         log::trace!("Dispatch packet: {:#?}", request);
 
         match request {
-            DispatchItem::Item(codec::Packet::Publish(publish)) => {
+            DispatchItem::Item(codec::Packet::Publish(mut publish)) => {
                 let info = self.inner.clone();
                 let packet_id = publish.packet_id;
 
@@ -173,7 +174,9 @@ where
                     if let Some(alias) = publish.properties.topic_alias {
                         // check existing topic
                         if publish.topic.is_empty() {
-                            if !inner.aliases.contains(&alias) {
+                            if let Some(topic) = inner.aliases.get(&alias) {
+                                publish.topic = topic.clone();
+                            } else {
                                 return Either::Right(Either::Right(ControlResponse::new(
                                     ControlMessage::proto_error(
                                         ProtocolError::UnknownTopicAlias,
@@ -190,7 +193,7 @@ where
                             }
 
                             // record new alias
-                            inner.aliases.insert(alias);
+                            inner.aliases.insert(alias, publish.topic.clone());
                         }
                     }
                 }
@@ -268,6 +271,7 @@ where
                 )))
             }
             DispatchItem::Item(codec::Packet::PingResponse) => {
+                self.inner.sink.ping_response();
                 Either::Right(Either::Left(Ready::Ok(None)))
             }
             DispatchItem::Item(pkt) => {
@@ -280,12 +284,9 @@ where
                     &self.inner,
                 )))
             }
-            DispatchItem::DecoderError(err) => {
-                Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::Decode(err)),
-                    &self.inner,
-                )))
-            }
+            DispatchItem::DecoderError(err) => Either::Right(Either::Right(
+                ControlResponse::new(ControlMessage::proto_error(err.into()), &self.inner),
+            )),
             DispatchItem::Disconnect(err) => Either::Right(Either::Right(
                 ControlResponse::new(ControlMessage::peer_gone(err), &self.inner),
             )),
@@ -1,12 +1,13 @@
-use std::cell::Cell;
+use std::{cell::Cell, cell::RefCell, time::Instant};
 
 use ntex::codec::{Decoder, Encoder};
-use ntex::util::{Buf, BytesMut};
+use ntex::util::{Buf, Bytes, BytesMut};
 
-use super::{decode::decode_packet, encode::EncodeLtd, Packet};
-use crate::error::{DecodeError, EncodeError};
-use crate::types::{FixedHeader, MAX_PACKET_SIZE};
-use crate::utils::decode_variable_length;
+use super::{decode::decode_packet, encode::EncodeLtd, Connect, Packet};
+use crate::error::{DecodeError, EncodeError, ProtocolError};
+use crate::types::{packet_type, ClientIdEncoding, CodecTiming, Direction, FixedHeader};
+use crate::types::{MetricsHandle, MAX_PACKET_SIZE};
+use crate::utils::{decode_remaining_length, is_complete_packet};
 
 #[derive(Debug)]
 pub struct Codec {
@@ -14,11 +15,17 @@ pub struct Codec {
     max_in_size: Cell<u32>,
     max_out_size: Cell<u32>,
     flags: Cell<CodecFlags>,
+    client_id_encoding: Cell<ClientIdEncoding>,
+    connect_bytes: RefCell<Option<Bytes>>,
+    client_id_bytes: RefCell<Option<Bytes>>,
+    timing: RefCell<Option<CodecTiming>>,
+    metrics: RefCell<Option<MetricsHandle>>,
 }
 
 bitflags::bitflags! {
     pub struct CodecFlags: u8 {
         const NO_PROBLEM_INFO = 0b0000_0001;
+        const KEEP_CONNECT    = 0b0000_0010;
     }
 }
 
@@ -36,6 +43,11 @@ impl Codec {
             max_in_size: Cell::new(0),
             max_out_size: Cell::new(0),
             flags: Cell::new(CodecFlags::empty()),
+            client_id_encoding: Cell::new(ClientIdEncoding::Strict),
+            connect_bytes: RefCell::new(None),
+            client_id_bytes: RefCell::new(None),
+            timing: RefCell::new(None),
+            metrics: RefCell::new(None),
         }
     }
 
@@ -69,6 +81,11 @@ impl Codec {
         self.max_in_size.set(size);
     }
 
+    /// Effective max inbound frame size
+    pub(crate) fn inbound_max_size(&self) -> u32 {
+        self.max_in_size.get()
+    }
+
     /// Set max outbound frame size.
     ///
     /// If max size is set to `0`, size is unlimited.
@@ -76,6 +93,131 @@ impl Codec {
     pub fn set_max_outbound_size(&self, size: u32) {
         self.max_out_size.set(size);
     }
+
+    /// Keep raw bytes of decoded `Connect` packet.
+    ///
+    /// By default raw bytes are not kept.
+    pub(crate) fn keep_connect_bytes(self, val: bool) -> Self {
+        let mut flags = self.flags.get();
+        flags.set(CodecFlags::KEEP_CONNECT, val);
+        self.flags.set(flags);
+        self
+    }
+
+    /// Set decoding of client ids that are not valid utf-8.
+    ///
+    /// By default such client ids are rejected.
+    pub(crate) fn client_id_encoding(self, val: ClientIdEncoding) -> Self {
+        self.client_id_encoding.set(val);
+        self
+    }
+
+    /// Report time spent in decoding and encoding of each packet.
+    pub(crate) fn codec_timing(self, timing: Option<CodecTiming>) -> Self {
+        self.set_codec_timing(timing);
+        self
+    }
+
+    /// Report time spent in decoding and encoding of each packet.
+    pub(crate) fn set_codec_timing(&self, timing: Option<CodecTiming>) {
+        *self.timing.borrow_mut() = timing;
+    }
+
+    /// Report decoded and encoded packets and protocol errors to metrics observer.
+    pub(crate) fn metrics(self, metrics: Option<MetricsHandle>) -> Self {
+        self.set_metrics(metrics);
+        self
+    }
+
+    /// Report decoded and encoded packets and protocol errors to metrics observer.
+    pub(crate) fn set_metrics(&self, metrics: Option<MetricsHandle>) {
+        *self.metrics.borrow_mut() = metrics;
+    }
+
+    /// Report protocol error to metrics observer
+    pub(crate) fn report_error(&self, err: &ProtocolError) {
+        if let Some(ref metrics) = *self.metrics.borrow() {
+            metrics.error(err);
+        }
+    }
+
+    /// Take raw bytes of decoded `Connect` packet, fixed header is not included
+    pub(crate) fn take_connect_bytes(&self) -> Bytes {
+        self.connect_bytes.borrow_mut().take().unwrap_or_default()
+    }
+
+    /// Take raw bytes of client id of decoded `Connect` packet
+    pub(crate) fn take_client_id_bytes(&self) -> Option<Bytes> {
+        self.client_id_bytes.borrow_mut().take()
+    }
+
+    /// Encode packet to the buffer.
+    ///
+    /// Packet that exceeds max outbound size is rejected with
+    /// `EncodeError::InvalidLength` error. Buffer is left unchanged
+    /// if packet could not be encoded.
+    pub fn encode_packet(&self, pkt: &Packet, dst: &mut BytesMut) -> Result<(), EncodeError> {
+        // handle [MQTT 3.1.2.11.7]
+        if self.flags.get().contains(CodecFlags::NO_PROBLEM_INFO) {
+            let mut pkt = pkt.clone();
+            strip_problem_info(&mut pkt);
+            self.encode_inner(&pkt, dst)
+        } else {
+            self.encode_inner(pkt, dst)
+        }
+    }
+
+    /// Check if next packet in the read buffer is publish packet
+    pub(crate) fn is_publish_next(&self, src: &[u8]) -> bool {
+        let first_byte = match self.state.get() {
+            DecodeState::Frame(fixed) => fixed.first_byte,
+            DecodeState::FrameHeader => match src.first() {
+                Some(b) => *b,
+                None => return false,
+            },
+        };
+        first_byte & 0xF0 == packet_type::PUBLISH_START
+    }
+
+    /// Decode packet from the buffer.
+    ///
+    /// Returns `Ok(None)` and leaves buffer unchanged if buffer does not contain
+    /// complete packet. Packet that exceeds max inbound size is rejected with
+    /// `DecodeError::MaxSizeExceeded` error before its payload is received.
+    pub fn decode_packet(&self, src: &mut BytesMut) -> Result<Option<Packet>, DecodeError> {
+        if let DecodeState::FrameHeader = self.state.get() {
+            if !is_complete_packet(src, self.inbound_max_size())? {
+                return Ok(None);
+            }
+        }
+        self.decode(src)
+    }
+
+    fn encode_inner(&self, item: &Packet, dst: &mut BytesMut) -> Result<(), EncodeError> {
+        let timing = self.timing.borrow();
+        let started = timing.as_ref().map(|t| (t, Instant::now(), dst.len()));
+        let max_out_size = self.max_out_size.get();
+        let max_size = if max_out_size != 0 { max_out_size } else { MAX_PACKET_SIZE };
+        let content_size = item.encoded_size(max_size);
+        if content_size > max_size as usize {
+            return Err(EncodeError::InvalidLength); // todo: separate error code
+        }
+        let pos = dst.len();
+        dst.reserve(content_size + 5);
+        // safe: max_size <= u32 max value
+        if let Err(err) = item.encode(dst, content_size as u32) {
+            // drop partially encoded packet
+            dst.truncate(pos);
+            return Err(err);
+        }
+        if let Some((timing, started, pos)) = started {
+            timing.report(Direction::Encode, dst[pos], started.elapsed());
+        }
+        if let Some(ref metrics) = *self.metrics.borrow() {
+            metrics.packet(Direction::Encode, dst[pos], dst.len() - pos);
+        }
+        Ok(())
+    }
 }
 
 impl Default for Codec {
@@ -97,24 +239,14 @@ impl Decoder for Codec {
                     }
                     let src_slice = src.as_ref();
                     let first_byte = src_slice[0];
-                    match decode_variable_length(&src_slice[1..])? {
+                    // remaining length is checked against max message size
+                    match decode_remaining_length(&src_slice[1..], self.inbound_max_size())? {
                         Some((remaining_length, consumed)) => {
-                            // check max message size
-                            let max_in_size = self.max_in_size.get();
-                            if max_in_size != 0 && max_in_size < remaining_length {
-                                log::debug!(
-                                    "MaxSizeExceeded max-size: {}, remaining: {}",
-                                    max_in_size,
-                                    remaining_length
-                                );
-                                return Err(DecodeError::MaxSizeExceeded);
-                            }
                             src.advance(consumed + 1);
                             self.state.set(DecodeState::Frame(FixedHeader {
                                 first_byte,
                                 remaining_length,
                             }));
-                            // todo: validate remaining_length against max frame size config
                             let remaining_length = remaining_length as usize;
                             if src.len() < remaining_length {
                                 // todo: subtract?
@@ -131,8 +263,30 @@ impl Decoder for Codec {
                     if src.len() < fixed.remaining_length as usize {
                         return Ok(None);
                     }
-                    let packet_buf = src.split_to(fixed.remaining_length as usize).freeze();
-                    let packet = decode_packet(packet_buf, fixed.first_byte)?;
+                    let mut packet_buf = src.split_to(fixed.remaining_length as usize).freeze();
+                    if fixed.first_byte == packet_type::CONNECT
+                        && self.flags.get().contains(CodecFlags::KEEP_CONNECT)
+                    {
+                        *self.connect_bytes.borrow_mut() = Some(packet_buf.clone());
+                    }
+                    let timing = self.timing.borrow();
+                    let started = timing.as_ref().map(|t| (t, Instant::now()));
+                    let packet = if fixed.first_byte == packet_type::CONNECT {
+                        let (pkt, raw_client_id) = Connect::decode_with(
+                            &mut packet_buf,
+                            self.client_id_encoding.get(),
+                        )?;
+                        *self.client_id_bytes.borrow_mut() = Some(raw_client_id);
+                        Packet::Connect(Box::new(pkt))
+                    } else {
+                        decode_packet(packet_buf, fixed.first_byte)?
+                    };
+                    if let Some((timing, started)) = started {
+                        timing.report(Direction::Decode, fixed.first_byte, started.elapsed());
+                    }
+                    if let Some(ref metrics) = *self.metrics.borrow() {
+                        metrics.decoded(fixed);
+                    }
                     self.state.set(DecodeState::FrameHeader);
                     src.reserve(5); // enough to fix 1 fixed header byte + 4 bytes max variable packet length
 
@@ -155,58 +309,93 @@ impl Encoder for Codec {
     fn encode(&self, mut item: Self::Item, dst: &mut BytesMut) -> Result<(), EncodeError> {
         // handle [MQTT 3.1.2.11.7]
         if self.flags.get().contains(CodecFlags::NO_PROBLEM_INFO) {
-            match item {
-                Packet::PublishAck(ref mut pkt) | Packet::PublishReceived(ref mut pkt) => {
-                    pkt.properties.clear();
-                    let _ = pkt.reason_string.take();
-                }
-                Packet::PublishRelease(ref mut pkt) | Packet::PublishComplete(ref mut pkt) => {
-                    pkt.properties.clear();
-                    let _ = pkt.reason_string.take();
-                }
-                Packet::Subscribe(ref mut pkt) => {
-                    pkt.user_properties.clear();
-                }
-                Packet::SubscribeAck(ref mut pkt) => {
-                    pkt.properties.clear();
-                    let _ = pkt.reason_string.take();
-                }
-                Packet::Unsubscribe(ref mut pkt) => {
-                    pkt.user_properties.clear();
-                }
-                Packet::UnsubscribeAck(ref mut pkt) => {
-                    pkt.properties.clear();
-                    let _ = pkt.reason_string.take();
-                }
-                Packet::Auth(ref mut pkt) => {
-                    pkt.user_properties.clear();
-                    let _ = pkt.reason_string.take();
-                }
-                _ => (),
-            }
+            strip_problem_info(&mut item);
         }
+        self.encode_inner(&item, dst)
+    }
+}
 
-        let max_out_size = self.max_out_size.get();
-        let max_size = if max_out_size != 0 { max_out_size } else { MAX_PACKET_SIZE };
-        let content_size = item.encoded_size(max_size);
-        if content_size > max_size as usize {
-            return Err(EncodeError::InvalidLength); // todo: separate error code
+/// Remove properties and reason strings from packet
+fn strip_problem_info(item: &mut Packet) {
+    match *item {
+        Packet::PublishAck(ref mut pkt) | Packet::PublishReceived(ref mut pkt) => {
+            pkt.properties.clear();
+            let _ = pkt.reason_string.take();
         }
-        dst.reserve(content_size + 5);
-        item.encode(dst, content_size as u32)?; // safe: max_size <= u32 max value
-        Ok(())
+        Packet::PublishRelease(ref mut pkt) | Packet::PublishComplete(ref mut pkt) => {
+            pkt.properties.clear();
+            let _ = pkt.reason_string.take();
+        }
+        Packet::Subscribe(ref mut pkt) => {
+            pkt.user_properties.clear();
+        }
+        Packet::SubscribeAck(ref mut pkt) => {
+            pkt.properties.clear();
+            let _ = pkt.reason_string.take();
+        }
+        Packet::Unsubscribe(ref mut pkt) => {
+            pkt.user_properties.clear();
+        }
+        Packet::UnsubscribeAck(ref mut pkt) => {
+            pkt.properties.clear();
+            let _ = pkt.reason_string.take();
+        }
+        Packet::Auth(ref mut pkt) => {
+            pkt.user_properties.clear();
+            let _ = pkt.reason_string.take();
+        }
+        _ => (),
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use ntex::util::ByteString;
+
     use super::*;
+    use crate::v5::codec;
 
     #[test]
     fn test_max_size() {
         let codec = Codec::new().max_inbound_size(5);
         let mut buf = BytesMut::new();
         buf.extend_from_slice(b"\0\x09");
-        assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
+        assert_eq!(
+            codec.decode(&mut buf),
+            Err(DecodeError::MaxSizeExceeded { size: 9, limit: 5 })
+        );
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let codec = Codec::new().max_inbound_size(16);
+        let pkt = Packet::PingRequest;
+
+        let mut buf = BytesMut::new();
+        codec.encode_packet(&pkt, &mut buf).unwrap();
+        codec.encode_packet(&pkt, &mut buf).unwrap();
+
+        let mut partial = BytesMut::from(&buf[..1]);
+        assert_eq!(codec.decode_packet(&mut partial), Ok(None));
+        assert_eq!(&partial[..], &buf[..1]);
+        assert_eq!(codec.decode_packet(&mut buf), Ok(Some(pkt.clone())));
+        assert_eq!(codec.decode_packet(&mut buf), Ok(Some(pkt)));
+        assert!(buf.is_empty());
+
+        // packet is rejected before payload is received
+        let mut buf = BytesMut::from(&b"\x30\x11\0"[..]);
+        assert_eq!(
+            codec.decode_packet(&mut buf),
+            Err(DecodeError::MaxSizeExceeded { size: 17, limit: 16 })
+        );
+
+        let codec = Codec::new().max_outbound_size(6);
+        let pkt = Packet::Disconnect(codec::Disconnect {
+            reason_string: Some(ByteString::from_static("too long reason")),
+            ..codec::Disconnect::default()
+        });
+        let mut buf = BytesMut::new();
+        assert_eq!(codec.encode_packet(&pkt, &mut buf), Err(EncodeError::InvalidLength));
+        assert!(buf.is_empty());
     }
 }
@@ -427,6 +427,73 @@ mod tests {
             }),
             b"\x30\x0e\x00\x05topic\x02\x0b\x01data",
         );
+
+        assert_encode_packet(
+            &Packet::Publish(
+                Publish::build("topic", Bytes::from_static(b"data")).retain(true).properties(
+                    |p| p.subscription_ids = Some(vec![NonZeroU32::new(1).unwrap()]),
+                ),
+            ),
+            b"\x31\x0e\x00\x05topic\x02\x0b\x01data",
+        );
+    }
+
+    #[test]
+    fn test_correlation_data_roundtrip() {
+        // correlation data is binary, it is not utf-8 validated
+        let pkt = Packet::Publish(Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtMostOnce,
+            topic: ByteString::from_static("topic"),
+            packet_id: None,
+            payload: Bytes::from_static(b"data"),
+            properties: PublishProperties {
+                correlation_data: Some(Bytes::from_static(b"\xff\xfe\x00\x80")),
+                ..Default::default()
+            },
+        });
+        let mut v = BytesMut::with_capacity(1024);
+        pkt.encode(&mut v, pkt.encoded_size(1024) as u32).unwrap();
+
+        let decoded =
+            super::super::decode::decode_packet(v.clone().freeze().split_off(2), v[0]);
+        assert_eq!(decoded, Ok(pkt));
+
+        let mut v2 = BytesMut::with_capacity(1024);
+        let decoded = decoded.unwrap();
+        decoded.encode(&mut v2, decoded.encoded_size(1024) as u32).unwrap();
+        assert_eq!(v, v2);
+    }
+
+    #[test]
+    fn test_will_properties_roundtrip() {
+        let pkt = Packet::Connect(Box::new(Connect {
+            client_id: ByteString::from_static("12345"),
+            last_will: Some(LastWill {
+                qos: QoS::AtLeastOnce,
+                retain: true,
+                topic: ByteString::from_static("topic"),
+                message: Bytes::from_static(b"message"),
+                will_delay_interval_sec: Some(10),
+                correlation_data: Some(Bytes::from_static(b"data")),
+                message_expiry_interval: NonZeroU32::new(60),
+                content_type: Some(ByteString::from_static("text")),
+                user_properties: vec![(
+                    ByteString::from_static("a"),
+                    ByteString::from_static("b"),
+                )],
+                is_utf8_payload: Some(true),
+                response_topic: Some(ByteString::from_static("response")),
+            }),
+            ..Default::default()
+        }));
+        let mut v = BytesMut::with_capacity(1024);
+        pkt.encode(&mut v, pkt.encoded_size(1024) as u32).unwrap();
+
+        let decoded =
+            super::super::decode::decode_packet(v.clone().freeze().split_off(2), v[0]);
+        assert_eq!(decoded, Ok(pkt));
     }
 
     #[test]
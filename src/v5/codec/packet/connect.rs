@@ -3,7 +3,7 @@ use std::convert::TryFrom;
 use std::num::{NonZeroU16, NonZeroU32};
 
 use crate::error::{DecodeError, EncodeError};
-use crate::types::{ConnectFlags, QoS, MQTT, MQTT_LEVEL_5, WILL_QOS_SHIFT};
+use crate::types::{ClientIdEncoding, ConnectFlags, QoS, MQTT, MQTT_LEVEL_5, WILL_QOS_SHIFT};
 use crate::utils::{self, Decode, Encode, Property};
 use crate::v5::codec::{encode::*, property_type as pt, UserProperties, UserProperty};
 
@@ -104,6 +104,14 @@ impl Connect {
     }
 
     pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
+        Self::decode_with(src, ClientIdEncoding::Strict).map(|(pkt, _)| pkt)
+    }
+
+    /// Decode packet, returns packet and raw bytes of client id
+    pub(crate) fn decode_with(
+        src: &mut Bytes,
+        encoding: ClientIdEncoding,
+    ) -> Result<(Self, Bytes), DecodeError> {
         ensure!(src.remaining() >= 10, DecodeError::InvalidLength);
         let len = src.get_u16();
 
@@ -143,7 +151,8 @@ impl Connect {
             }
         }
 
-        let client_id = ByteString::decode(src)?;
+        let raw_client_id = Bytes::decode(src)?;
+        let client_id = encoding.decode(&raw_client_id)?;
 
         ensure!(
             // todo: [MQTT-3.1.3-8]?
@@ -168,7 +177,7 @@ impl Connect {
             None
         };
 
-        Ok(Connect {
+        let pkt = Connect {
             clean_start: flags.contains(ConnectFlags::CLEAN_START),
             keep_alive,
 
@@ -186,7 +195,8 @@ impl Connect {
             last_will,
             username,
             password,
-        })
+        };
+        Ok((pkt, raw_client_id))
     }
 }
 
@@ -319,6 +329,13 @@ impl EncodeLtd for Connect {
         if let Some(will) = self.last_will.as_ref() {
             let prop_len = will.properties_len();
             utils::write_variable_length(prop_len as u32, buf); // safe: whole message size is checked for max already
+            encode_property(&will.will_delay_interval_sec, pt::WILL_DELAY_INT, buf)?;
+            encode_property(&will.correlation_data, pt::CORR_DATA, buf)?;
+            encode_property(&will.message_expiry_interval, pt::MSG_EXPIRY_INT, buf)?;
+            encode_property(&will.content_type, pt::CONTENT_TYPE, buf)?;
+            encode_property(&will.is_utf8_payload, pt::UTF8_PAYLOAD, buf)?;
+            encode_property(&will.response_topic, pt::RESP_TOPIC, buf)?;
+            will.user_properties.encode(buf)?;
 
             will.topic.encode(buf)?;
             will.message.encode(buf)?;
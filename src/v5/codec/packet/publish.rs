@@ -21,6 +21,45 @@ pub struct Publish {
     pub properties: PublishProperties,
 }
 
+impl Publish {
+    /// Create publish packet with QoS 0
+    pub fn build<U>(topic: U, payload: Bytes) -> Self
+    where
+        ByteString: From<U>,
+    {
+        Publish {
+            payload,
+            dup: false,
+            retain: false,
+            topic: topic.into(),
+            qos: QoS::AtMostOnce,
+            packet_id: None,
+            properties: PublishProperties::default(),
+        }
+    }
+
+    /// Set QoS level
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    /// Set retain flag
+    pub fn retain(mut self, val: bool) -> Self {
+        self.retain = val;
+        self
+    }
+
+    /// Set publish packet properties
+    pub fn properties<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut PublishProperties),
+    {
+        f(&mut self.properties);
+        self
+    }
+}
+
 impl fmt::Debug for Publish {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Publish")
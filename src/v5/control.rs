@@ -1,6 +1,6 @@
-use std::{io, marker::PhantomData};
+use std::{io, marker::PhantomData, time::Duration};
 
-use ntex::util::ByteString;
+use ntex::{time::Seconds, util::ByteString};
 
 use super::codec::{self, DisconnectReasonCode, QoS, UserProperties};
 use crate::error;
@@ -14,6 +14,9 @@ pub enum ControlMessage<E> {
     Ping(Ping),
     /// Disconnect packet from a client
     Disconnect(Disconnect),
+    /// Disconnect packet with `DisconnectWithWillMessage` reason code from a client,
+    /// will message must be published
+    WillPublish(WillPublish),
     /// Subscribe packet from a client
     Subscribe(Subscribe),
     /// Unsubscribe packet from a client
@@ -26,6 +29,10 @@ pub enum ControlMessage<E> {
     ProtocolError(ProtocolError),
     /// Peer is gone
     PeerGone(PeerGone),
+    /// Keep-alive timeout is expired
+    Timeout(Timeout),
+    /// Inbound publishes are throttled by rate limit
+    RateLimited(RateLimited),
 }
 
 /// Control message handling result
@@ -33,6 +40,7 @@ pub enum ControlMessage<E> {
 pub struct ControlResult {
     pub(crate) packet: Option<codec::Packet>,
     pub(crate) disconnect: bool,
+    pub(crate) extend: Option<Seconds>,
 }
 
 impl<E> ControlMessage<E> {
@@ -66,6 +74,10 @@ impl<E> ControlMessage<E> {
         ControlMessage::Disconnect(Disconnect(pkt))
     }
 
+    pub(super) fn will_publish(will: codec::LastWill, pkt: Option<codec::Disconnect>) -> Self {
+        ControlMessage::WillPublish(WillPublish { will, pkt })
+    }
+
     pub(super) fn closed(is_error: bool) -> Self {
         ControlMessage::Closed(Closed::new(is_error))
     }
@@ -82,6 +94,14 @@ impl<E> ControlMessage<E> {
         ControlMessage::ProtocolError(ProtocolError::new(err))
     }
 
+    pub(super) fn timeout(idle: Duration) -> Self {
+        ControlMessage::Timeout(Timeout { idle })
+    }
+
+    pub(super) fn rate_limited(throttled: Duration) -> Self {
+        ControlMessage::RateLimited(RateLimited { throttled })
+    }
+
     /// Disconnects the client by sending DISCONNECT packet
     /// with `NormalDisconnection` reason code.
     pub fn disconnect(&self) -> ControlResult {
@@ -92,13 +112,21 @@ impl<E> ControlMessage<E> {
             reason_string: None,
             user_properties: Default::default(),
         };
-        ControlResult { packet: Some(codec::Packet::Disconnect(pkt)), disconnect: true }
+        ControlResult {
+            packet: Some(codec::Packet::Disconnect(pkt)),
+            disconnect: true,
+            extend: None,
+        }
     }
 
     /// Disconnects the client by sending DISCONNECT packet
     /// with provided reason code.
     pub fn disconnect_with(&self, pkt: codec::Disconnect) -> ControlResult {
-        ControlResult { packet: Some(codec::Packet::Disconnect(pkt)), disconnect: true }
+        ControlResult {
+            packet: Some(codec::Packet::Disconnect(pkt)),
+            disconnect: true,
+            extend: None,
+        }
     }
 }
 
@@ -112,7 +140,11 @@ impl Auth {
     }
 
     pub fn ack(self, response: codec::Auth) -> ControlResult {
-        ControlResult { packet: Some(codec::Packet::Auth(response)), disconnect: false }
+        ControlResult {
+            packet: Some(codec::Packet::Auth(response)),
+            disconnect: false,
+            extend: None,
+        }
     }
 }
 
@@ -121,7 +153,11 @@ pub struct Ping;
 
 impl Ping {
     pub fn ack(self) -> ControlResult {
-        ControlResult { packet: Some(codec::Packet::PingResponse), disconnect: false }
+        ControlResult {
+            packet: Some(codec::Packet::PingResponse),
+            disconnect: false,
+            extend: None,
+        }
     }
 }
 
@@ -136,7 +172,118 @@ impl Disconnect {
 
     /// Ack disconnect message
     pub fn ack(self) -> ControlResult {
-        ControlResult { packet: None, disconnect: true }
+        ControlResult { packet: None, disconnect: true, extend: None }
+    }
+}
+
+/// Will publish message
+///
+/// Client disconnected with `DisconnectWithWillMessage` reason code or
+/// connection is closed without DISCONNECT packet, will message of
+/// the connection must be published.
+///
+/// For abnormal close, message is delivered after will delay interval
+/// or at session expiry, whichever comes first.
+#[derive(Debug)]
+pub struct WillPublish {
+    will: codec::LastWill,
+    pkt: Option<codec::Disconnect>,
+}
+
+impl WillPublish {
+    /// Returns reference to will message
+    pub fn will(&self) -> &codec::LastWill {
+        &self.will
+    }
+
+    /// Returns QoS of will message
+    pub fn qos(&self) -> QoS {
+        self.will.qos
+    }
+
+    /// Returns retain flag of will message
+    pub fn retain(&self) -> bool {
+        self.will.retain
+    }
+
+    /// Returns reference to disconnect packet
+    ///
+    /// Returns `None` if connection is closed without DISCONNECT packet
+    pub fn packet(&self) -> Option<&codec::Disconnect> {
+        self.pkt.as_ref()
+    }
+
+    /// Take will message
+    pub fn into_will(self) -> codec::LastWill {
+        self.will
+    }
+
+    /// Ack will publish message
+    pub fn ack(self) -> ControlResult {
+        ControlResult { packet: None, disconnect: true, extend: None }
+    }
+}
+
+/// Keep-alive timeout, no packets are received from the client
+///
+/// Connection is closed, unless idle period is extended with `Timeout::extend()`.
+#[derive(Debug)]
+pub struct Timeout {
+    idle: Duration,
+}
+
+impl Timeout {
+    #[inline]
+    /// Time elapsed since last packet received from the client
+    pub fn idle(&self) -> Duration {
+        self.idle
+    }
+
+    #[inline]
+    /// Ack timeout, return disconnect packet with `KeepAliveTimeout`
+    /// reason code and close connection.
+    pub fn ack(self) -> ControlResult {
+        let pkt = codec::Disconnect::new(DisconnectReasonCode::KeepAliveTimeout);
+        ControlResult {
+            packet: Some(codec::Packet::Disconnect(pkt)),
+            disconnect: true,
+            extend: None,
+        }
+    }
+
+    #[inline]
+    /// Keep connection open and re-arm keep-alive timer with specified timeout.
+    ///
+    /// Regular keep-alive timeout is restored once a packet is received from
+    /// the client. Zero timeout closes connection.
+    pub fn extend(self, timeout: Seconds) -> ControlResult {
+        if timeout.is_zero() {
+            self.ack()
+        } else {
+            ControlResult { packet: None, disconnect: false, extend: Some(timeout) }
+        }
+    }
+}
+
+/// Inbound publishes are throttled by `MqttServer::publish_rate_limit()`
+///
+/// Connection could be closed with `ControlMessage::disconnect_with()`.
+#[derive(Debug)]
+pub struct RateLimited {
+    throttled: Duration,
+}
+
+impl RateLimited {
+    #[inline]
+    /// Time elapsed since connection is throttled
+    pub fn throttled(&self) -> Duration {
+        self.throttled
+    }
+
+    #[inline]
+    /// Ack message and keep throttling connection
+    pub fn ack(self) -> ControlResult {
+        ControlResult { packet: None, disconnect: false, extend: None }
     }
 }
 
@@ -194,6 +341,7 @@ impl Subscribe {
         ControlResult {
             packet: Some(codec::Packet::SubscribeAck(self.result)),
             disconnect: false,
+            extend: None,
         }
     }
 
@@ -355,6 +503,7 @@ impl Unsubscribe {
         ControlResult {
             packet: Some(codec::Packet::UnsubscribeAck(self.result)),
             disconnect: false,
+            extend: None,
         }
     }
 
@@ -452,7 +601,7 @@ impl Closed {
     #[inline]
     /// convert packet to a result
     pub fn ack(self) -> ControlResult {
-        ControlResult { packet: None, disconnect: false }
+        ControlResult { packet: None, disconnect: false, extend: None }
     }
 }
 
@@ -511,7 +660,11 @@ impl<E> Error<E> {
     /// Ack service error, return disconnect packet and close connection.
     pub fn ack(mut self, reason: DisconnectReasonCode) -> ControlResult {
         self.pkt.reason_code = reason;
-        ControlResult { packet: Some(codec::Packet::Disconnect(self.pkt)), disconnect: true }
+        ControlResult {
+            packet: Some(codec::Packet::Disconnect(self.pkt)),
+            disconnect: true,
+            extend: None,
+        }
     }
 
     #[inline]
@@ -521,7 +674,11 @@ impl<E> Error<E> {
         F: FnOnce(E, codec::Disconnect) -> codec::Disconnect,
     {
         let pkt = f(self.err, self.pkt);
-        ControlResult { packet: Some(codec::Packet::Disconnect(pkt)), disconnect: true }
+        ControlResult {
+            packet: Some(codec::Packet::Disconnect(pkt)),
+            disconnect: true,
+            extend: None,
+        }
     }
 }
 
@@ -544,7 +701,7 @@ impl ProtocolError {
                     error::ProtocolError::Decode(error::DecodeError::InvalidLength) => {
                         DisconnectReasonCode::MalformedPacket
                     }
-                    error::ProtocolError::Decode(error::DecodeError::MaxSizeExceeded) => {
+                    error::ProtocolError::MaxSizeExceeded { .. } => {
                         DisconnectReasonCode::PacketTooLarge
                     }
                     error::ProtocolError::Unexpected(_, _) => {
@@ -556,9 +713,22 @@ impl ProtocolError {
                     error::ProtocolError::KeepAliveTimeout => {
                         DisconnectReasonCode::KeepAliveTimeout
                     }
-                    error::ProtocolError::UnknownTopicAlias => {
+                    error::ProtocolError::UnknownTopicAlias
+                    | error::ProtocolError::MaxTopicAlias => {
                         DisconnectReasonCode::TopicAliasInvalid
                     }
+                    error::ProtocolError::QosNotSupported => {
+                        DisconnectReasonCode::QosNotSupported
+                    }
+                    error::ProtocolError::RateLimitExceeded => {
+                        DisconnectReasonCode::MessageRateTooHigh
+                    }
+                    error::ProtocolError::RetainNotSupported => {
+                        DisconnectReasonCode::RetainNotSupported
+                    }
+                    error::ProtocolError::PacketNotAllowed(_) => {
+                        DisconnectReasonCode::ImplementationSpecificError
+                    }
                     error::ProtocolError::Encode(_) => {
                         DisconnectReasonCode::ImplementationSpecificError
                     }
@@ -609,7 +779,11 @@ impl ProtocolError {
     #[inline]
     /// Ack protocol error, return disconnect packet and close connection.
     pub fn ack(self) -> ControlResult {
-        ControlResult { packet: Some(codec::Packet::Disconnect(self.pkt)), disconnect: true }
+        ControlResult {
+            packet: Some(codec::Packet::Disconnect(self.pkt)),
+            disconnect: true,
+            extend: None,
+        }
     }
 
     #[inline]
@@ -619,6 +793,7 @@ impl ProtocolError {
             ControlResult {
                 packet: Some(codec::Packet::Disconnect(self.pkt)),
                 disconnect: true,
+                extend: None,
             },
             self.err,
         )
@@ -641,6 +816,6 @@ impl PeerGone {
 
     /// Ack PeerGone message
     pub fn ack(self) -> ControlResult {
-        ControlResult { packet: None, disconnect: true }
+        ControlResult { packet: None, disconnect: true, extend: None }
     }
 }
@@ -83,6 +83,9 @@ impl<S, E: fmt::Debug> Service<ControlMessage<E>> for DefaultControlService<S, E
         match pkt {
             ControlMessage::Ping(pkt) => Ready::Ok(pkt.ack()),
             ControlMessage::Disconnect(pkt) => Ready::Ok(pkt.ack()),
+            ControlMessage::WillPublish(pkt) => Ready::Ok(pkt.ack()),
+            ControlMessage::Timeout(pkt) => Ready::Ok(pkt.ack()),
+            ControlMessage::RateLimited(pkt) => Ready::Ok(pkt.ack()),
             _ => {
                 log::warn!("MQTT5 Control service is not configured, pkt: {:?}", pkt);
                 Ready::Ok(pkt.disconnect_with(super::codec::Disconnect::new(
@@ -1,14 +1,26 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::future::Future;
 use std::task::{Context, Poll};
-use std::{convert::TryFrom, future::Future, marker, num, pin::Pin, rc::Rc};
+use std::time::{Duration, Instant};
+use std::{convert::TryFrom, marker, mem, num, pin::Pin, rc::Rc};
 
 use ntex::io::DispatchItem;
 use ntex::service::{fn_factory_with_config, Service, ServiceFactory};
+use ntex::time::{now, sleep, Deadline, Millis, Seconds, Sleep};
 use ntex::util::{
-    buffer::BufferService, inflight::InFlightService, join, Either, HashSet, Ready,
+    buffer::BufferService, inflight::InFlightService, join, poll_fn, ByteString, Either,
+    HashMap, HashSet, Ready,
 };
 
+use crate::coalesce::AckBatch;
+use crate::drain::{Drain, DrainConnection, DrainGuard};
 use crate::error::{MqttError, ProtocolError};
+use crate::events::LifecycleEventKind;
+use crate::limiter::{Qos2InflightLimit, Qos2Permit, RateLimiter};
+use crate::limiter::{SlidingWindow, TokenBucket};
+use crate::reject::{RejectReason, RejectSampler};
+use crate::session::DisconnectReason;
+use crate::types::{packet_type, IdleAction, QoS, TopicRewrite};
 
 use super::control::{ControlMessage, ControlResult};
 use super::publish::{Publish, PublishAck};
@@ -16,11 +28,33 @@ use super::shared::{Ack, MqttShared};
 use super::sink::MqttSink;
 use super::{codec, codec::EncodeLtd, Session};
 
-/// mqtt3 protocol dispatcher
+/// Dispatcher settings, built by `MqttServer`
+pub(super) struct DispatcherConfig<St> {
+    pub(super) inflight_size: usize,
+    pub(super) ordered: bool,
+    pub(super) subscribe_timeout: Seconds,
+    pub(super) min_qos: QoS,
+    pub(super) limiter: RateLimiter,
+    pub(super) strict_acks: bool,
+    pub(super) max_lifetime: Seconds,
+    pub(super) on_unexpected_ack: Option<Rc<dyn Fn(&Session<St>, num::NonZeroU16, u8)>>,
+    pub(super) on_rejected_publish: Option<Rc<RejectSampler<codec::Publish>>>,
+    pub(super) ingress_topic: Option<TopicRewrite>,
+    pub(super) egress_topic: Option<TopicRewrite>,
+    pub(super) qos2_limit: Option<Qos2InflightLimit>,
+    pub(super) on_ping: Option<Rc<dyn Fn(&Session<St>, Instant)>>,
+    pub(super) on_idle_timeout: Option<Rc<dyn Fn(&Session<St>) -> IdleAction>>,
+    pub(super) on_disconnect: Option<Rc<dyn Fn(&Session<St>, DisconnectReason)>>,
+    pub(super) coalesce_subacks: Option<(Millis, usize)>,
+    pub(super) max_topic_cardinality: usize,
+    pub(super) drain: Drain,
+}
+
+/// mqtt5 protocol dispatcher
 pub(super) fn factory<St, T, C, E>(
     publish: T,
     control: C,
-    max_inflight_size: usize,
+    config: DispatcherConfig<St>,
 ) -> impl ServiceFactory<
     DispatchItem<Rc<MqttShared>>,
     Session<St>,
@@ -35,11 +69,57 @@ where
     C: ServiceFactory<ControlMessage<E>, Session<St>, Response = ControlResult> + 'static,
     PublishAck: TryFrom<T::Error, Error = E>,
 {
+    let DispatcherConfig {
+        inflight_size,
+        ordered,
+        subscribe_timeout,
+        min_qos,
+        limiter,
+        strict_acks,
+        max_lifetime,
+        on_unexpected_ack,
+        on_rejected_publish,
+        ingress_topic,
+        egress_topic,
+        qos2_limit,
+        on_ping,
+        on_idle_timeout,
+        on_disconnect,
+        coalesce_subacks,
+        max_topic_cardinality,
+        drain,
+    } = config;
+
     fn_factory_with_config(move |cfg: Session<St>| {
+        // close connection after max lifetime
+        cfg.sink().close_after(max_lifetime);
+        cfg.sink().set_topic_rewrite(ingress_topic.clone(), egress_topic.clone());
+        if let Some(ref hook) = on_idle_timeout {
+            let hook = hook.clone();
+            let session = cfg.downgrade();
+            cfg.sink().set_idle_handler(Box::new(move || {
+                session.upgrade().map(|s| (*hook)(&s)).unwrap_or(IdleAction::Disconnect)
+            }));
+        }
+
         // create services
         let fut = join(publish.new_service(cfg.clone()), control.new_service(cfg.clone()));
 
-        let (max_receive, max_topic_alias) = cfg.params();
+        let on_unexpected_ack = on_unexpected_ack.clone().map(|hook| {
+            let session = cfg.clone();
+            Rc::new(move |id, tp| (*hook)(&session, id, tp)) as Rc<dyn Fn(_, _)>
+        });
+        let on_rejected_publish = on_rejected_publish.clone();
+        let qos2_limit = qos2_limit.clone();
+        let on_ping = on_ping.clone().map(|hook| {
+            let session = cfg.clone();
+            Rc::new(move |at| (*hook)(&session, at)) as Rc<dyn Fn(_)>
+        });
+        let on_disconnect = on_disconnect.clone().map(|hook| {
+            let session = cfg.clone();
+            Rc::new(move |reason| (*hook)(&session, reason)) as Rc<dyn Fn(_)>
+        });
+        let drain = drain.clone();
 
         async move {
             let (publish, control) = fut.await;
@@ -56,15 +136,24 @@ where
             );
 
             Ok(crate::inflight::InFlightService::new(
-                0,
-                max_inflight_size,
+                if ordered { 1 } else { 0 },
+                inflight_size,
                 Dispatcher::<_, _, E>::new(
-                    cfg.sink().clone(),
-                    max_receive as usize,
-                    max_topic_alias,
+                    &cfg,
+                    subscribe_timeout,
+                    min_qos,
+                    limiter,
                     publish,
                     control,
-                ),
+                )
+                .unexpected_ack(strict_acks, on_unexpected_ack)
+                .rejected_publish(on_rejected_publish)
+                .qos2_limit(qos2_limit)
+                .on_ping(on_ping)
+                .on_disconnect(on_disconnect)
+                .coalesce_subacks(coalesce_subacks)
+                .max_topic_cardinality(max_topic_cardinality)
+                .drain(&drain),
             ))
         }
     })
@@ -84,22 +173,87 @@ impl crate::inflight::SizedRequest for DispatchItem<Rc<MqttShared>> {
 pub(crate) struct Dispatcher<T, C: Service<ControlMessage<E>>, E> {
     sink: MqttSink,
     publish: T,
-    shutdown: RefCell<Option<Pin<Box<C::Future>>>>,
+    shutdown: RefCell<Option<Pin<Box<dyn Future<Output = ()>>>>>,
+    throttled: RefCell<Option<Sleep>>,
     max_receive: usize,
     max_topic_alias: u16,
+    subscribe_timeout: Seconds,
+    strict_acks: bool,
+    on_unexpected_ack: Option<Rc<dyn Fn(num::NonZeroU16, u8)>>,
+    on_rejected_publish: Option<Rc<RejectSampler<codec::Publish>>>,
+    qos2_limit: Option<Qos2InflightLimit>,
+    on_ping: Option<Rc<dyn Fn(Instant)>>,
+    on_disconnect: Option<Rc<dyn Fn(DisconnectReason)>>,
+    max_topics: usize,
     inner: Rc<Inner<C>>,
+    _drain: Option<DrainGuard>,
     _t: marker::PhantomData<E>,
 }
 
 struct Inner<C> {
     control: C,
     sink: MqttSink,
+    min_qos: QoS,
+    limiter: RefCell<SlidingWindow>,
+    throttle: RefCell<TokenBucket>,
     info: RefCell<PublishInfo>,
+    subacks: RefCell<Option<Rc<AckBatch<codec::Packet>>>>,
+    last_activity: Cell<Instant>,
+    reason: RefCell<Option<DisconnectReason>>,
+}
+
+impl<C> Inner<C> {
+    /// Queue SUBACK packet if writes are coalesced
+    fn suback(&self, pkt: Option<codec::Packet>) -> Option<codec::Packet> {
+        match (pkt, &*self.subacks.borrow()) {
+            (Some(pkt @ codec::Packet::SubscribeAck(_)), Some(batch)) => {
+                batch.push(pkt);
+                None
+            }
+            (pkt, _) => pkt,
+        }
+    }
+
+    /// Record disconnect reason, first recorded reason wins
+    fn disconnect_reason(&self, reason: DisconnectReason) {
+        let mut cur = self.reason.borrow_mut();
+        if cur.is_none() {
+            *cur = Some(reason);
+        }
+    }
+
+    /// Disconnect reason, if connection is terminated without recorded reason
+    fn take_disconnect_reason(&self) -> DisconnectReason {
+        self.reason.borrow_mut().take().unwrap_or_else(|| {
+            if self.sink.is_closed_locally() {
+                DisconnectReason::ServerEvicted
+            } else {
+                DisconnectReason::Io
+            }
+        })
+    }
+}
+
+impl<C> DrainConnection for Inner<C> {
+    fn is_busy(&self) -> bool {
+        !self.info.borrow().inflight.is_empty() || self.sink.inflight_count() != 0
+    }
+
+    fn disconnect(&self) {
+        self.sink.close_with_reason(codec::Disconnect::new(
+            codec::DisconnectReasonCode::ServerShuttingDown,
+        ));
+    }
+
+    fn force_close(&self) {
+        self.sink.force_close();
+    }
 }
 
 struct PublishInfo {
     inflight: HashSet<num::NonZeroU16>,
-    aliases: HashSet<num::NonZeroU16>,
+    aliases: HashMap<num::NonZeroU16, ByteString>,
+    topics: HashSet<ByteString>,
 }
 
 impl<T, C, E> Dispatcher<T, C, E>
@@ -109,35 +263,176 @@ where
     PublishAck: TryFrom<T::Error, Error = E>,
     C: Service<ControlMessage<E>, Response = ControlResult, Error = MqttError<E>>,
 {
-    fn new(
-        sink: MqttSink,
-        max_receive: usize,
-        max_topic_alias: u16,
+    fn new<St>(
+        session: &Session<St>,
+        subscribe_timeout: Seconds,
+        min_qos: QoS,
+        limiter: RateLimiter,
         publish: T,
         control: C,
     ) -> Self {
+        let sink = session.sink().clone();
+        let (max_receive, max_topic_alias) = session.params();
         Self {
             publish,
-            max_receive,
+            max_receive: max_receive as usize,
             max_topic_alias,
+            subscribe_timeout,
+            strict_acks: true,
+            on_unexpected_ack: None,
+            on_rejected_publish: None,
+            qos2_limit: None,
+            on_ping: None,
+            on_disconnect: None,
+            max_topics: 0,
             sink: sink.clone(),
             shutdown: RefCell::new(None),
+            throttled: RefCell::new(None),
+            _drain: None,
             inner: Rc::new(Inner {
                 control,
                 sink,
+                min_qos,
+                limiter: RefCell::new(SlidingWindow::new(limiter)),
+                throttle: RefCell::new(TokenBucket::new(limiter)),
                 info: RefCell::new(PublishInfo {
-                    aliases: HashSet::default(),
+                    aliases: HashMap::default(),
                     inflight: HashSet::default(),
+                    topics: HashSet::default(),
                 }),
+                subacks: RefCell::new(None),
+                last_activity: Cell::new(now()),
+                reason: RefCell::new(None),
             }),
             _t: marker::PhantomData,
         }
     }
+
+    /// Set unknown acks handling
+    fn unexpected_ack(
+        mut self,
+        strict: bool,
+        hook: Option<Rc<dyn Fn(num::NonZeroU16, u8)>>,
+    ) -> Self {
+        self.strict_acks = strict;
+        self.on_unexpected_ack = hook;
+        self
+    }
+
+    /// Set rejected publish handler
+    fn rejected_publish(mut self, hook: Option<Rc<RejectSampler<codec::Publish>>>) -> Self {
+        self.on_rejected_publish = hook;
+        self
+    }
+
+    /// Set global QoS 2 inflight limit
+    fn qos2_limit(mut self, limit: Option<Qos2InflightLimit>) -> Self {
+        self.qos2_limit = limit;
+        self
+    }
+
+    /// Set PINGREQ handler
+    fn on_ping(mut self, hook: Option<Rc<dyn Fn(Instant)>>) -> Self {
+        self.on_ping = hook;
+        self
+    }
+
+    /// Set connection termination handler
+    fn on_disconnect(mut self, hook: Option<Rc<dyn Fn(DisconnectReason)>>) -> Self {
+        self.on_disconnect = hook;
+        self
+    }
+
+    /// Set SUBACK writes coalescing
+    fn coalesce_subacks(self, cfg: Option<(Millis, usize)>) -> Self {
+        let sink = self.inner.sink.clone();
+        *self.inner.subacks.borrow_mut() = cfg.map(|(max_delay, max_count)| {
+            AckBatch::new(max_delay, max_count, move |pkts| {
+                for pkt in pkts {
+                    sink.send(pkt);
+                }
+            })
+        });
+        self
+    }
+
+    /// Set max number of distinct publish topics
+    fn max_topic_cardinality(mut self, max: usize) -> Self {
+        self.max_topics = max;
+        self
+    }
+
+    /// Register connection for draining on server shutdown
+    fn drain(mut self, drain: &Drain) -> Self
+    where
+        C: 'static,
+    {
+        self._drain = drain.register(self.inner.clone());
+        self
+    }
+
+    fn publish_rejected(&self, publish: &codec::Publish, reason: RejectReason) {
+        if let Some(ref hook) = self.on_rejected_publish {
+            hook.rejected(publish, reason);
+        }
+    }
+
+    /// Handle ack of outbound publish, subscribe or unsubscribe packet
+    fn outbound_ack(
+        &self,
+        packet_id: num::NonZeroU16,
+        packet_type: u8,
+        ack: Ack,
+    ) -> Either<Ready<Option<codec::Packet>, MqttError<E>>, ControlResponse<C, E>> {
+        if !self.sink.is_inflight(packet_id.get()) {
+            self.report_unexpected_ack(packet_id, packet_type);
+            if !self.strict_acks {
+                return Either::Left(Ready::Ok(None));
+            }
+        }
+        if let Err(err) = self.sink.pkt_ack(ack) {
+            Either::Right(ControlResponse::new(ControlMessage::proto_error(err), &self.inner))
+        } else {
+            Either::Left(Ready::Ok(None))
+        }
+    }
+
+    /// Handle PUBREC or PUBCOMP packet, server does not send QoS 2 publishes
+    fn qos2_ack(
+        &self,
+        packet_id: num::NonZeroU16,
+        packet_type: u8,
+    ) -> Either<Ready<Option<codec::Packet>, MqttError<E>>, ControlResponse<C, E>> {
+        self.report_unexpected_ack(packet_id, packet_type);
+        if self.strict_acks {
+            Either::Right(ControlResponse::new(
+                ControlMessage::proto_error(ProtocolError::Unexpected(
+                    packet_type,
+                    "Outbound QoS 2 publish is not sent",
+                )),
+                &self.inner,
+            ))
+        } else {
+            Either::Left(Ready::Ok(None))
+        }
+    }
+
+    fn report_unexpected_ack(&self, packet_id: num::NonZeroU16, packet_type: u8) {
+        log::trace!(
+            "{}: Unexpected ack packet {:#04X}: {:?}",
+            self.sink.connection_id(),
+            packet_type,
+            packet_id
+        );
+        if let Some(ref hook) = self.on_unexpected_ack {
+            (*hook)(packet_id, packet_type);
+        }
+    }
 }
 
 impl<T, C, E> Service<DispatchItem<Rc<MqttShared>>> for Dispatcher<T, C, E>
 where
-    E: From<T::Error>,
+    E: From<T::Error> + 'static,
     T: Service<Publish, Response = PublishAck>,
     PublishAck: TryFrom<T::Error, Error = E>,
     C: Service<ControlMessage<E>, Response = ControlResult, Error = MqttError<E>> + 'static,
@@ -153,7 +448,32 @@ where
         let res1 = self.publish.poll_ready(cx).map_err(|e| MqttError::Service(e.into()))?;
         let res2 = self.inner.control.poll_ready(cx)?;
 
-        if res1.is_pending() || res2.is_pending() {
+        // pause reading while next publish packet exceeds rate limit,
+        // other packets are read until publish packet is received
+        let mut res3 = Poll::Ready(());
+        if self.inner.sink.is_publish_next() {
+            let delay = self.inner.throttle.borrow_mut().wait();
+            if let Some(throttled) = self.inner.throttle.borrow_mut().take_notify() {
+                ntex::rt::spawn(rate_limited(self.inner.clone(), throttled));
+            }
+            if !delay.is_zero() {
+                log::trace!(
+                    "{}: Inbound publish is throttled for {:?}",
+                    self.inner.sink.connection_id(),
+                    delay
+                );
+                let mut sleep = self.throttled.borrow_mut();
+                if let Some(ref sleep) = *sleep {
+                    sleep.reset(delay);
+                } else {
+                    *sleep = Some(Sleep::new(delay.into()));
+                }
+                let _ = sleep.as_ref().unwrap().poll_elapsed(cx);
+                res3 = Poll::Pending;
+            }
+        }
+
+        if res1.is_pending() || res2.is_pending() || res3.is_pending() {
             Poll::Pending
         } else {
             Poll::Ready(Ok(()))
@@ -163,9 +483,51 @@ where
     fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
         let mut shutdown = self.shutdown.borrow_mut();
         if !shutdown.is_some() {
+            if let Some(ref batch) = *self.inner.subacks.borrow() {
+                batch.flush();
+            }
+            if let Some(ref hook) = self.on_disconnect {
+                (*hook)(self.inner.take_disconnect_reason());
+            }
+
+            // will is still set if connection is closed without DISCONNECT packet
+            let will_delay = self.sink.will_delay();
             self.inner.sink.drop_sink();
-            *shutdown =
-                Some(Box::pin(self.inner.control.call(ControlMessage::closed(is_error))));
+
+            let inner = self.inner.clone();
+            *shutdown = Some(Box::pin(async move {
+                match will_delay {
+                    Some(0) => {
+                        if let Some(will) = inner.sink.take_will() {
+                            let msg = ControlMessage::will_publish(will, None);
+                            let _ = inner.control.call(msg).await;
+                        }
+                    }
+                    Some(delay) => {
+                        let inner = inner.clone();
+                        ntex::rt::spawn(async move {
+                            // timer is limited to u16 seconds, chain sleeps for longer delays
+                            let mut remaining = delay;
+                            while remaining > 0 {
+                                let secs = remaining.min(u32::from(u16::MAX));
+                                sleep(Seconds(secs as u16)).await;
+                                remaining -= secs;
+                            }
+
+                            // will could be discarded during delay
+                            if let Some(will) = inner.sink.take_will() {
+                                // dispatcher is gone, drive control service readiness
+                                if poll_fn(|cx| inner.control.poll_ready(cx)).await.is_ok() {
+                                    let msg = ControlMessage::will_publish(will, None);
+                                    let _ = inner.control.call(msg).await;
+                                }
+                            }
+                        });
+                    }
+                    None => (),
+                }
+                let _ = inner.control.call(ControlMessage::closed(is_error)).await;
+            }));
         }
 
         let res0 = shutdown.as_mut().expect("guard above").as_mut().poll(cx);
@@ -179,24 +541,115 @@ where
     }
 
     fn call(&self, request: DispatchItem<Rc<MqttShared>>) -> Self::Future {
-        log::trace!("Dispatch v5 packet: {:#?}", request);
+        log::trace!("{}: Dispatch v5 packet: {:#?}", self.inner.sink.connection_id(), request);
+
+        // check packet types allowed for the connection
+        if let DispatchItem::Item(ref pkt) = request {
+            self.inner.last_activity.set(now());
+            let packet_type = pkt.packet_type();
+            if !self.sink.is_packet_allowed(packet_type) {
+                log::trace!(
+                    "{}: Packet type is not allowed: {:#04X}",
+                    self.inner.sink.connection_id(),
+                    packet_type
+                );
+                return Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::proto_error(ProtocolError::PacketNotAllowed(packet_type)),
+                    &self.inner,
+                )));
+            }
+        }
 
         match request {
-            DispatchItem::Item(codec::Packet::Publish(publish)) => {
+            DispatchItem::Item(codec::Packet::Publish(mut publish)) => {
                 let info = self.inner.clone();
                 let packet_id = publish.packet_id;
 
+                // check inbound publish rate
+                if !info.limiter.borrow_mut().check() {
+                    log::trace!(
+                        "{}: Inbound publish rate limit exceeded",
+                        self.inner.sink.connection_id()
+                    );
+                    self.publish_rejected(&publish, RejectReason::RateLimitExceeded);
+                    return Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(ProtocolError::RateLimitExceeded),
+                        &self.inner,
+                    )));
+                }
+
+                // check for minimum qos
+                if publish.qos < info.min_qos {
+                    log::trace!(
+                        "{}: Publish qos is lower than minimum: {:?}",
+                        self.inner.sink.connection_id(),
+                        publish.qos
+                    );
+                    self.publish_rejected(&publish, RejectReason::QosNotSupported);
+                    return Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(ProtocolError::QosNotSupported),
+                        &self.inner,
+                    )));
+                }
+
+                // retain is not available, advertised in CONNACK
+                if publish.retain && !self.sink.is_retain_available() {
+                    log::trace!(
+                        "{}: Retained publish is received, retain is not available",
+                        self.inner.sink.connection_id()
+                    );
+                    self.publish_rejected(&publish, RejectReason::RetainNotSupported);
+                    return Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(ProtocolError::RetainNotSupported),
+                        &self.inner,
+                    )));
+                }
+
+                let mut permit = None;
                 {
                     let mut inner = info.info.borrow_mut();
 
+                    // check number of distinct topics, set size is bounded by limit
+                    let new_topic = self.max_topics != 0
+                        && !publish.topic.is_empty()
+                        && !inner.topics.contains(&publish.topic);
+                    if new_topic && inner.topics.len() >= self.max_topics {
+                        log::trace!(
+                            "{}: Publish topic cardinality exceeded: {}",
+                            self.inner.sink.connection_id(),
+                            publish.topic
+                        );
+                        self.publish_rejected(&publish, RejectReason::TopicCardinalityExceeded);
+                        let ack = |packet_id| codec::PublishAck {
+                            packet_id,
+                            reason_code: codec::PublishAckReason::QuotaExceeded,
+                            ..Default::default()
+                        };
+                        match (packet_id, publish.qos) {
+                            (Some(pid), QoS::ExactlyOnce) => {
+                                self.sink.send(codec::Packet::PublishReceived(ack(pid)))
+                            }
+                            (Some(pid), _) => {
+                                self.sink.send(codec::Packet::PublishAck(ack(pid)))
+                            }
+                            (None, _) => (),
+                        }
+                        return Either::Right(Either::Left(Ready::Ok(None)));
+                    }
+
                     if let Some(pid) = packet_id {
                         // check for receive maximum
                         if self.max_receive != 0 && inner.inflight.len() >= self.max_receive {
                             log::trace!(
-                                "Receive maximum exceeded: max: {} inflight: {}",
+                                "{}: Receive maximum exceeded: max: {} inflight: {}",
+                                self.inner.sink.connection_id(),
                                 self.max_receive,
                                 inner.inflight.len()
                             );
+                            self.publish_rejected(
+                                &publish,
+                                RejectReason::ReceiveMaximumExceeded,
+                            );
                             return Either::Right(Either::Right(ControlResponse::new(
                                 ControlMessage::proto_error(
                                     ProtocolError::ReceiveMaximumExceeded,
@@ -205,6 +658,31 @@ where
                             )));
                         }
 
+                        // check global qos2 limit
+                        if let Some(ref limit) = self.qos2_limit {
+                            if publish.qos == QoS::ExactlyOnce {
+                                permit = limit.try_acquire();
+                                if permit.is_none() {
+                                    log::trace!(
+                                        "{}: Global QoS 2 in-flight limit exceeded",
+                                        self.inner.sink.connection_id()
+                                    );
+                                    self.publish_rejected(
+                                        &publish,
+                                        RejectReason::QuotaExceeded,
+                                    );
+                                    self.sink.send(codec::Packet::PublishReceived(
+                                        codec::PublishAck {
+                                            packet_id: pid,
+                                            reason_code: codec::PublishAckReason::QuotaExceeded,
+                                            ..Default::default()
+                                        },
+                                    ));
+                                    return Either::Right(Either::Left(Ready::Ok(None)));
+                                }
+                            }
+                        }
+
                         // check for duplicated packet id
                         if !inner.inflight.insert(pid) {
                             self.sink.send(codec::Packet::PublishAck(codec::PublishAck {
@@ -216,11 +694,18 @@ where
                         }
                     }
 
+                    // rejected publish does not take topic slot
+                    if new_topic {
+                        inner.topics.insert(publish.topic.clone());
+                    }
+
                     // handle topic aliases
                     if let Some(alias) = publish.properties.topic_alias {
                         // check existing topic
                         if publish.topic.is_empty() {
-                            if !inner.aliases.contains(&alias) {
+                            if let Some(topic) = inner.aliases.get(&alias) {
+                                publish.topic = topic.clone();
+                            } else {
                                 return Either::Right(Either::Right(ControlResponse::new(
                                     ControlMessage::proto_error(
                                         ProtocolError::UnknownTopicAlias,
@@ -237,39 +722,81 @@ where
                             }
 
                             // record new alias
-                            inner.aliases.insert(alias);
+                            inner.aliases.insert(alias, publish.topic.clone());
                         }
                     }
                 }
 
+                self.sink.inbound_topic(&mut publish.topic);
+                publish.payload = self.sink.inbound_payload(mem::take(&mut publish.payload));
+                let rejected = self.on_rejected_publish.clone().map(|h| (h, publish.clone()));
+
+                // reading is paused until token is available, see `poll_ready()`
+                info.throttle.borrow_mut().acquire();
+
                 Either::Left(PublishResponse {
                     packet_id: packet_id.map(|v| v.get()).unwrap_or(0),
                     inner: info,
+                    rejected,
+                    _permit: permit,
                     state: PublishResponseState::Publish {
                         fut: self.publish.call(Publish::new(publish)),
                     },
                 })
             }
-            DispatchItem::Item(codec::Packet::PublishAck(packet)) => {
-                if let Err(err) = self.sink.pkt_ack(Ack::Publish(packet)) {
-                    Either::Right(Either::Right(ControlResponse::new(
-                        ControlMessage::proto_error(err),
-                        &self.inner,
-                    )))
-                } else {
-                    Either::Right(Either::Left(Ready::Ok(None)))
-                }
+            DispatchItem::Item(codec::Packet::PublishAck(packet)) => Either::Right(
+                self.outbound_ack(packet.packet_id, packet_type::PUBACK, Ack::Publish(packet)),
+            ),
+            DispatchItem::Item(codec::Packet::PublishReceived(packet)) => {
+                Either::Right(self.qos2_ack(packet.packet_id, packet_type::PUBREC))
+            }
+            DispatchItem::Item(codec::Packet::PublishComplete(packet)) => {
+                Either::Right(self.qos2_ack(packet.packet_id, packet_type::PUBCOMP))
+            }
+            DispatchItem::Item(codec::Packet::SubscribeAck(packet)) => {
+                Either::Right(self.outbound_ack(
+                    packet.packet_id,
+                    packet_type::SUBACK,
+                    Ack::Subscribe(packet),
+                ))
+            }
+            DispatchItem::Item(codec::Packet::UnsubscribeAck(packet)) => {
+                Either::Right(self.outbound_ack(
+                    packet.packet_id,
+                    packet_type::UNSUBACK,
+                    Ack::Unsubscribe(packet),
+                ))
             }
             DispatchItem::Item(codec::Packet::Auth(pkt)) => Either::Right(Either::Right(
                 ControlResponse::new(ControlMessage::auth(pkt), &self.inner),
             )),
-            DispatchItem::Item(codec::Packet::PingRequest) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::ping(), &self.inner),
-            )),
-            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::remote_disconnect(pkt), &self.inner),
-            )),
-            DispatchItem::Item(codec::Packet::Subscribe(pkt)) => {
+            DispatchItem::Item(codec::Packet::PingRequest) => {
+                let at = self.sink.ping_received();
+                if let Some(ref hook) = self.on_ping {
+                    (*hook)(at);
+                }
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::ping(),
+                    &self.inner,
+                )))
+            }
+            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => {
+                self.inner.disconnect_reason(DisconnectReason::Clean);
+                // [MQTT-3.14.4-3] will message is published only for
+                // `DisconnectWithWillMessage` reason code
+                let will = self.sink.take_will();
+                let msg = match will {
+                    Some(will)
+                        if pkt.reason_code
+                            == codec::DisconnectReasonCode::DisconnectWithWillMessage =>
+                    {
+                        ControlMessage::will_publish(will, Some(pkt))
+                    }
+                    _ => ControlMessage::remote_disconnect(pkt),
+                };
+                Either::Right(Either::Right(ControlResponse::new(msg, &self.inner)))
+            }
+            DispatchItem::Item(codec::Packet::Subscribe(mut pkt)) => {
                 // register inflight packet id
                 if !self.inner.info.borrow_mut().inflight.insert(pkt.packet_id) {
                     // duplicated packet id
@@ -285,13 +812,31 @@ where
                     }));
                     return Either::Right(Either::Left(Ready::Ok(None)));
                 }
+                for (topic, _) in &mut pkt.topic_filters {
+                    self.sink.inbound_topic(topic);
+                }
                 let id = pkt.packet_id;
+                let topics = pkt.topic_filters.iter().map(|(t, _)| t.clone()).collect();
+
+                // ack with failure codes if control service does not complete in time
+                let timeout_pkt = codec::Packet::SubscribeAck(codec::SubscribeAck {
+                    packet_id: id,
+                    status: vec![
+                        codec::SubscribeAckReason::UnspecifiedError;
+                        pkt.topic_filters.len()
+                    ],
+                    properties: codec::UserProperties::new(),
+                    reason_string: None,
+                });
+
                 Either::Right(Either::Right(
                     ControlResponse::new(ControlMessage::subscribe(pkt), &self.inner)
-                        .packet_id(id),
+                        .packet_id(id)
+                        .topics(topics)
+                        .timeout(self.subscribe_timeout, timeout_pkt),
                 ))
             }
-            DispatchItem::Item(codec::Packet::Unsubscribe(pkt)) => {
+            DispatchItem::Item(codec::Packet::Unsubscribe(mut pkt)) => {
                 // register inflight packet id
                 if !self.inner.info.borrow_mut().inflight.insert(pkt.packet_id) {
                     // duplicated packet id
@@ -307,34 +852,64 @@ where
                     }));
                     return Either::Right(Either::Left(Ready::Ok(None)));
                 }
+                for topic in &mut pkt.topic_filters {
+                    self.sink.inbound_topic(topic);
+                }
                 let id = pkt.packet_id;
+                let topics = pkt.topic_filters.clone();
                 Either::Right(Either::Right(
                     ControlResponse::new(ControlMessage::unsubscribe(pkt), &self.inner)
-                        .packet_id(id),
+                        .packet_id(id)
+                        .topics(topics),
                 ))
             }
-            DispatchItem::Item(_) => Either::Right(Either::Left(Ready::Ok(None))),
-            DispatchItem::EncoderError(err) => {
+            DispatchItem::Item(codec::Packet::Connect(_)) => {
+                log::trace!(
+                    "{}: Second CONNECT packet is received",
+                    self.inner.sink.connection_id()
+                );
                 Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::Encode(err)),
+                    ControlMessage::proto_error(ProtocolError::Unexpected(
+                        packet_type::CONNECT,
+                        "MQTT-3.1.0-2: Second CONNECT packet",
+                    )),
                     &self.inner,
                 )))
             }
-            DispatchItem::KeepAliveTimeout => {
-                Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::KeepAliveTimeout),
-                    &self.inner,
-                )))
+            DispatchItem::Item(codec::Packet::PingResponse) => {
+                self.inner.sink.ping_response();
+                Either::Right(Either::Left(Ready::Ok(None)))
             }
-            DispatchItem::DecoderError(err) => {
+            DispatchItem::Item(_) => Either::Right(Either::Left(Ready::Ok(None))),
+            DispatchItem::EncoderError(err) => {
                 Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::Decode(err)),
+                    ControlMessage::proto_error(ProtocolError::Encode(err)),
                     &self.inner,
                 )))
             }
-            DispatchItem::Disconnect(err) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::peer_gone(err), &self.inner),
+            DispatchItem::KeepAliveTimeout => {
+                // idle handler extends idle period without calling control service
+                if let Some(timeout) = self.sink.idle_timeout() {
+                    self.sink.extend_keepalive(timeout);
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                } else {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::timeout(self.inner.last_activity.get().elapsed()),
+                        &self.inner,
+                    )))
+                }
+            }
+            DispatchItem::DecoderError(err) => Either::Right(Either::Right(
+                ControlResponse::new(ControlMessage::proto_error(err.into()), &self.inner),
             )),
+            DispatchItem::Disconnect(err) => {
+                // read and write timeouts are reported as protocol errors
+                let msg = match err.as_ref().and_then(ProtocolError::from_io_timeout) {
+                    Some(err) => ControlMessage::proto_error(err),
+                    None => ControlMessage::peer_gone(err),
+                };
+                Either::Right(Either::Right(ControlResponse::new(msg, &self.inner)))
+            }
             DispatchItem::WBackPressureEnabled | DispatchItem::WBackPressureDisabled => {
                 Either::Right(Either::Left(Ready::Ok(None)))
             }
@@ -349,6 +924,8 @@ pin_project_lite::pin_project! {
         state: PublishResponseState<T, C, E>,
         packet_id: u16,
         inner: Rc<Inner<C>>,
+        rejected: Option<(Rc<RejectSampler<codec::Publish>>, codec::Publish)>,
+        _permit: Option<Qos2Permit>,
     }
 }
 
@@ -377,6 +954,9 @@ where
                 let ack = match fut.poll(cx) {
                     Poll::Ready(Ok(ack)) => ack,
                     Poll::Ready(Err(e)) => {
+                        if let Some((hook, publish)) = this.rejected.take() {
+                            hook.rejected(&publish, RejectReason::Service);
+                        }
                         if *this.packet_id != 0 {
                             match PublishAck::try_from(e) {
                                 Ok(ack) => ack,
@@ -402,7 +982,13 @@ where
                     }
                     Poll::Pending => return Poll::Pending,
                 };
+                if ack.reason_code as u8 >= 0x80 {
+                    if let Some((hook, publish)) = this.rejected.take() {
+                        hook.rejected(&publish, RejectReason::Service);
+                    }
+                }
                 if let Some(id) = num::NonZeroU16::new(*this.packet_id) {
+                    // release packet id before ack, peer may reuse it right after ack
                     this.inner.info.borrow_mut().inflight.remove(&id);
                     let ack = codec::PublishAck {
                         packet_id: id,
@@ -420,6 +1006,30 @@ where
     }
 }
 
+/// Notify control service about throttled connection
+async fn rate_limited<C, E>(inner: Rc<Inner<C>>, throttled: Duration)
+where
+    C: Service<ControlMessage<E>, Response = ControlResult, Error = MqttError<E>>,
+{
+    match inner.control.call(ControlMessage::rate_limited(throttled)).await {
+        Ok(result) => {
+            if let Some(pkt) = result.packet {
+                inner.sink.send(pkt)
+            }
+            if result.disconnect {
+                inner.sink.drop_sink();
+            }
+        }
+        Err(_) => {
+            log::trace!(
+                "{}: Control service failed for rate limited connection",
+                inner.sink.connection_id()
+            );
+            inner.sink.close();
+        }
+    }
+}
+
 pin_project_lite::pin_project! {
     /// Control service response future
     pub(crate) struct ControlResponse<C: Service<ControlMessage<E>>, E>
@@ -429,6 +1039,8 @@ pin_project_lite::pin_project! {
         inner: Rc<Inner<C>>,
         error: bool,
         packet_id: u16,
+        topics: Vec<ByteString>,
+        timeout: Option<(Deadline, codec::Packet)>,
         _t: marker::PhantomData<E>,
     }
 }
@@ -439,8 +1051,20 @@ where
 {
     #[allow(clippy::match_like_matches_macro)]
     fn new(pkt: ControlMessage<E>, inner: &Rc<Inner<C>>) -> Self {
+        match pkt {
+            ControlMessage::ProtocolError(ref e) => {
+                inner.sink.protocol_error(e.get_ref());
+                inner.disconnect_reason(DisconnectReason::Protocol(e.get_ref().clone()));
+            }
+            ControlMessage::Timeout(_) => {
+                inner.disconnect_reason(DisconnectReason::KeepaliveTimeout)
+            }
+            _ => (),
+        }
         let error = match pkt {
-            ControlMessage::Error(_) | ControlMessage::ProtocolError(_) => true,
+            ControlMessage::Error(_)
+            | ControlMessage::ProtocolError(_)
+            | ControlMessage::Timeout(_) => true,
             _ => false,
         };
 
@@ -449,6 +1073,8 @@ where
             fut: inner.control.call(pkt),
             inner: inner.clone(),
             packet_id: 0,
+            topics: Vec::new(),
+            timeout: None,
             _t: marker::PhantomData,
         }
     }
@@ -457,6 +1083,56 @@ where
         self.packet_id = id.get();
         self
     }
+
+    /// Topics of subscribe or unsubscribe packet, used for lifecycle events
+    fn topics(mut self, topics: Vec<ByteString>) -> Self {
+        self.topics = topics;
+        self
+    }
+
+    /// Respond with `pkt` if control service does not complete within timeout
+    fn timeout(mut self, timeout: Seconds, pkt: codec::Packet) -> Self {
+        if !timeout.is_zero() {
+            self.timeout = Some((Deadline::new(timeout.into()), pkt));
+        }
+        self
+    }
+}
+
+impl<C, E> ControlResponse<C, E>
+where
+    C: Service<ControlMessage<E>, Response = ControlResult, Error = MqttError<E>>,
+{
+    fn emit_lifecycle_events(self: Pin<&mut Self>, result: &ControlResult) {
+        let this = self.project();
+        let topics = mem::take(this.topics);
+
+        match result.packet {
+            Some(codec::Packet::SubscribeAck(ref ack)) => {
+                for (code, topic) in ack.status.iter().zip(topics) {
+                    let qos = match code {
+                        codec::SubscribeAckReason::GrantedQos0 => QoS::AtMostOnce,
+                        codec::SubscribeAckReason::GrantedQos1 => QoS::AtLeastOnce,
+                        codec::SubscribeAckReason::GrantedQos2 => QoS::ExactlyOnce,
+                        _ => continue,
+                    };
+                    this.inner
+                        .sink
+                        .lifecycle_event(LifecycleEventKind::SubscriptionAdded { topic, qos });
+                }
+            }
+            Some(codec::Packet::UnsubscribeAck(ref ack)) => {
+                for (code, topic) in ack.status.iter().zip(topics) {
+                    if *code == codec::UnsubscribeAckReason::Success {
+                        this.inner
+                            .sink
+                            .lifecycle_event(LifecycleEventKind::SubscriptionRemoved { topic });
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
 }
 
 impl<C, E> Future for ControlResponse<C, E>
@@ -469,10 +1145,26 @@ where
         let this = self.as_mut().project();
 
         let result = match this.fut.poll(cx) {
-            Poll::Ready(Ok(result)) => {
+            Poll::Ready(Ok(mut result)) => {
                 if let Some(id) = num::NonZeroU16::new(self.packet_id) {
                     self.inner.info.borrow_mut().inflight.remove(&id);
                 }
+
+                // fail subscriptions with qos lower than minimum
+                if let Some(codec::Packet::SubscribeAck(ref mut ack)) = result.packet {
+                    let min_qos = self.inner.min_qos;
+                    for code in ack.status.iter_mut() {
+                        let qos = match code {
+                            codec::SubscribeAckReason::GrantedQos0 => QoS::AtMostOnce,
+                            codec::SubscribeAckReason::GrantedQos1 => QoS::AtLeastOnce,
+                            _ => continue,
+                        };
+                        if qos < min_qos {
+                            *code = codec::SubscribeAckReason::UnspecifiedError;
+                        }
+                    }
+                }
+                self.as_mut().emit_lifecycle_events(&result);
                 result
             }
             Poll::Ready(Err(err)) => {
@@ -492,9 +1184,29 @@ where
                     }
                 };
             }
-            Poll::Pending => return Poll::Pending,
+            Poll::Pending => {
+                if let Some((ref deadline, _)) = this.timeout {
+                    if deadline.poll_elapsed(cx).is_ready() {
+                        log::trace!(
+                            "{}: Control service timeout for packet: {:?}",
+                            this.inner.sink.connection_id(),
+                            this.packet_id
+                        );
+                        if let Some(id) = num::NonZeroU16::new(*this.packet_id) {
+                            this.inner.info.borrow_mut().inflight.remove(&id);
+                        }
+                        let pkt = this.timeout.take().map(|(_, pkt)| pkt);
+                        return Poll::Ready(Ok(this.inner.suback(pkt)));
+                    }
+                }
+                return Poll::Pending;
+            }
         };
 
+        if let Some(timeout) = result.extend {
+            self.inner.sink.extend_keepalive(timeout);
+        }
+
         if self.error {
             if let Some(pkt) = result.packet {
                 self.inner.sink.send(pkt)
@@ -507,7 +1219,7 @@ where
             if result.disconnect {
                 self.inner.sink.drop_sink();
             }
-            Poll::Ready(Ok(result.packet))
+            Poll::Ready(Ok(self.inner.suback(result.packet)))
         }
     }
 }
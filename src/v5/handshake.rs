@@ -1,7 +1,12 @@
+use ntex::codec::{Decoder, Encoder};
 use ntex::io::IoBoxed;
-use std::{fmt, num::NonZeroU16, rc::Rc};
+use ntex::time::Seconds;
+use ntex::util::{ByteString, Bytes, Either, Extensions};
+use std::{fmt, io, num::NonZeroU16, rc::Rc};
 
 use super::{codec, shared::MqttShared, sink::MqttSink};
+use crate::selector::SelectContext;
+use crate::types::{ConnectSummary, PacketMask, MQTT_LEVEL_5};
 
 /// Handshake message
 pub struct Handshake {
@@ -11,6 +16,10 @@ pub struct Handshake {
     pub(super) max_size: u32,
     pub(super) max_receive: u16,
     pub(super) max_topic_alias: u16,
+    raw: Bytes,
+    raw_client_id: Bytes,
+    pub(super) ctx: SelectContext,
+    pub(super) data: Extensions,
 }
 
 impl Handshake {
@@ -22,7 +31,26 @@ impl Handshake {
         max_receive: u16,
         max_topic_alias: u16,
     ) -> Self {
-        Self { io, pkt, shared, max_size, max_receive, max_topic_alias }
+        let raw = shared.codec.take_connect_bytes();
+        let raw_client_id = shared
+            .codec
+            .take_client_id_bytes()
+            .unwrap_or_else(|| pkt.client_id.as_bytes().clone());
+        *shared.will.borrow_mut() = pkt.last_will.clone();
+        shared.session_expiry.set(pkt.session_expiry_interval_secs.unwrap_or(0));
+        let ctx = SelectContext::new(&io);
+        Self {
+            io,
+            pkt,
+            shared,
+            max_size,
+            max_receive,
+            max_topic_alias,
+            raw,
+            raw_client_id,
+            ctx,
+            data: Extensions::new(),
+        }
     }
 
     #[inline]
@@ -35,11 +63,142 @@ impl Handshake {
         &mut self.pkt
     }
 
+    /// Returns value of connect packet user property
+    pub fn user_property(&self, key: &str) -> Option<&ByteString> {
+        self.pkt.user_properties.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    /// Returns connect packet user properties
+    pub fn user_properties(&self) -> &codec::UserProperties {
+        &self.pkt.user_properties
+    }
+
+    /// Returns client's receive maximum.
+    ///
+    /// Max number of in-flight QoS 1 and QoS 2 publishes server could send
+    /// to client, `16` if client does not set it. Connect packet with receive
+    /// maximum `0` is rejected as malformed before handshake service is called.
+    pub fn receive_max(&self) -> u16 {
+        self.shared.cap.get() as u16
+    }
+
+    /// Limit number of in-flight publishes sent to client.
+    ///
+    /// Limit applies to connection's sink, values above client's receive
+    /// maximum are ignored. Panics if `max` is `0`.
+    pub fn clamp_receive_max(&self, max: u16) {
+        if max == 0 {
+            panic!("Receive maximum must be greater than 0")
+        }
+        self.shared.cap.set(self.shared.cap.get().min(max as usize));
+    }
+
+    /// Returns client's session expiry interval in seconds.
+    ///
+    /// Interval could be changed with `HandshakeAck::with_session_expiry()`.
+    pub fn session_expiry(&self) -> u32 {
+        self.pkt.session_expiry_interval_secs.unwrap_or(0)
+    }
+
+    /// Returns max packet size client is willing to accept.
+    ///
+    /// Larger packets are not sent by connection's sink.
+    pub fn max_packet_size(&self) -> Option<u32> {
+        self.pkt.max_packet_size.map(|v| v.get())
+    }
+
+    /// Returns max topic alias client is willing to accept
+    pub fn topic_alias_max(&self) -> u16 {
+        self.pkt.topic_alias_max
+    }
+
+    /// Returns connect packet summary, it does not contain credentials
+    pub fn summary(&self) -> ConnectSummary {
+        ConnectSummary {
+            client_id: self.pkt.client_id.clone(),
+            protocol_level: MQTT_LEVEL_5,
+            clean: self.pkt.clean_start,
+            keep_alive: self.pkt.keep_alive,
+            has_will: self.pkt.last_will.is_some(),
+            has_username: self.pkt.username.is_some(),
+        }
+    }
+
+    #[inline]
+    /// Returns raw bytes of connect packet, fixed header is not included
+    ///
+    /// Raw bytes are kept only if it is enabled with `keep_connect_bytes()`
+    /// server option, otherwise empty slice is returned.
+    pub fn raw_connect_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// Returns raw bytes of client id
+    ///
+    /// Raw bytes differ from decoded client id if server accepts client ids
+    /// that are not valid utf-8, see `ClientIdEncoding::Lossy`.
+    pub fn raw_client_id(&self) -> &[u8] {
+        &self.raw_client_id
+    }
+
+    /// Connection id, see `Session::connection_id()`
+    pub fn connection_id(&self) -> u64 {
+        self.shared.id
+    }
+
+    /// Transport properties of connection
+    pub fn select_context(&self) -> &SelectContext {
+        &self.ctx
+    }
+
+    /// Returns data attached by selector's variant check
+    ///
+    /// Data is attached if variant check returns `Some(data)`, see
+    /// `Selector::variant()`.
+    pub fn data<T: 'static>(&self) -> Option<&T> {
+        self.data.get()
+    }
+
+    /// Take data attached by selector's variant check
+    pub fn take_data<T: 'static>(&mut self) -> Option<T> {
+        self.data.remove()
+    }
+
     #[inline]
     pub fn io(&self) -> &IoBoxed {
         &self.io
     }
 
+    /// Read frame of custom protocol from peer.
+    ///
+    /// Could be used for application level exchange before handshake gets
+    /// acked, for example challenge/response authentication. Codec must consume
+    /// whole frames, bytes left in read buffer are decoded as mqtt packets
+    /// once handshake is acked.
+    pub async fn recv_frame<U: Decoder>(
+        &self,
+        codec: &U,
+    ) -> Result<Option<U::Item>, Either<U::Error, io::Error>> {
+        self.io.recv(codec).await
+    }
+
+    /// Send frame of custom protocol to peer
+    pub async fn send_frame<U: Encoder>(
+        &self,
+        item: U::Item,
+        codec: &U,
+    ) -> Result<(), Either<U::Error, io::Error>> {
+        self.io.send(item, codec).await
+    }
+
+    /// Restrict packet types peer is allowed to send.
+    ///
+    /// Disallowed packet is handled as protocol error.
+    /// By default all packet types are allowed.
+    pub fn restrict_packets(&self, mask: PacketMask) {
+        self.shared.allowed_packets.set(mask);
+    }
+
     #[inline]
     /// Returns mqtt server sink
     pub fn sink(&self) -> MqttSink {
@@ -83,6 +242,35 @@ impl Handshake {
         }
     }
 
+    /// Create handshake ack object with error, reason string and user properties.
+    ///
+    /// Reason string and user properties are sent within ConnectAck properties,
+    /// connection is closed after ConnectAck is sent.
+    pub fn fail<St>(
+        self,
+        reason_code: codec::ConnectAckReason,
+        reason_string: Option<ByteString>,
+        user_properties: codec::UserProperties,
+    ) -> HandshakeAck<St> {
+        self.fail_with(codec::ConnectAck {
+            reason_code,
+            reason_string,
+            user_properties,
+            ..codec::ConnectAck::default()
+        })
+    }
+
+    /// Create handshake ack object with `UseAnotherServer` reason code.
+    ///
+    /// `server_reference` is sent as `Server Reference` property of ConnectAck.
+    pub fn use_another_server<St>(self, server_reference: ByteString) -> HandshakeAck<St> {
+        self.fail_with(codec::ConnectAck {
+            reason_code: codec::ConnectAckReason::UseAnotherServer,
+            server_reference: Some(server_reference),
+            ..codec::ConnectAck::default()
+        })
+    }
+
     #[inline]
     /// Create handshake ack object with provided ConnectAck packet
     pub fn fail_with<St>(self, ack: codec::ConnectAck) -> HandshakeAck<St> {
@@ -126,6 +314,88 @@ impl<St> HandshakeAck<St> {
         self
     }
 
+    /// Set server keep-alive for the connection.
+    ///
+    /// Sets `server_keepalive_sec` property of `ConnectAck` packet, client must use
+    /// it instead of its own keep-alive. Connection is closed if no packets are
+    /// received within one and a half times of `timeout`. `0` disables keep-alive.
+    pub fn with_server_keepalive(mut self, timeout: Seconds) -> Self {
+        self.packet.server_keepalive_sec = Some(timeout.0);
+        self.keepalive = (timeout.0 >> 1).saturating_add(timeout.0);
+        self
+    }
+
+    /// Set client id assigned by server.
+    ///
+    /// Sets `assigned_client_id` property of `ConnectAck` packet, session
+    /// uses assigned id as client id.
+    pub fn with_assigned_client_id(mut self, client_id: ByteString) -> Self {
+        self.packet.assigned_client_id = Some(client_id);
+        self
+    }
+
+    /// Set max size of inbound packets.
+    ///
+    /// Overrides server's `max_size` for the connection. Panics if size is `0`.
+    pub fn with_max_packet_size(mut self, size: u32) -> Self {
+        if size == 0 {
+            panic!("Max packet size must be greater than 0")
+        }
+        self.packet.max_packet_size = Some(size);
+        self
+    }
+
+    /// Set receive maximum.
+    ///
+    /// Overrides server's `max_receive` for the connection, dispatcher enforces
+    /// value sent with ConnectAck packet. Panics if `max` is `0`.
+    pub fn with_receive_max(mut self, max: u16) -> Self {
+        self.packet.receive_max =
+            Some(NonZeroU16::new(max).expect("Receive maximum must be greater than 0"));
+        self
+    }
+
+    /// Set session expiry interval in seconds
+    pub fn with_session_expiry(mut self, secs: u32) -> Self {
+        self.packet.session_expiry_interval_secs = Some(secs);
+        self
+    }
+
+    /// Set human readable reason string of ConnectAck packet
+    pub fn with_reason_string(mut self, reason: ByteString) -> Self {
+        self.packet.reason_string = Some(reason);
+        self
+    }
+
+    /// Set server reference of ConnectAck packet.
+    ///
+    /// Could be used with `UseAnotherServer` and `ServerMoved` reason codes.
+    pub fn with_server_reference(mut self, reference: ByteString) -> Self {
+        self.packet.server_reference = Some(reference);
+        self
+    }
+
+    /// Add user property to ConnectAck packet
+    pub fn user_property(mut self, key: ByteString, value: ByteString) -> Self {
+        self.packet.user_properties.push((key, value));
+        self
+    }
+
+    /// Set payload transform for the connection.
+    ///
+    /// `inbound` is applied to payload of every received publish packet before
+    /// it is passed to publish service, `outbound` is applied to payload of every
+    /// publish packet sent with connection's sink. For example, negotiated
+    /// payload compression.
+    pub fn payload_transform<F1, F2>(self, inbound: F1, outbound: F2) -> Self
+    where
+        F1: Fn(Bytes) -> Bytes + 'static,
+        F2: Fn(Bytes) -> Bytes + 'static,
+    {
+        *self.shared.payload.borrow_mut() = Some((Box::new(inbound), Box::new(outbound)));
+        self
+    }
+
     /// Access to ConnectAck packet
     #[inline]
     pub fn with(mut self, f: impl FnOnce(&mut codec::ConnectAck)) -> Self {
@@ -20,9 +20,19 @@ pub use self::control::{ControlMessage, ControlResult};
 pub use self::handshake::{Handshake, HandshakeAck};
 pub use self::publish::{Publish, PublishAck};
 pub use self::router::Router;
-pub use self::selector::Selector;
+pub use self::selector::{IntoFallback, Selector, Variant};
 pub use self::server::MqttServer;
 pub use self::sink::{MqttSink, PublishBuilder, SubscribeBuilder, UnsubscribeBuilder};
 
+pub use crate::ban::{BanList, MemoryBanList};
+pub use crate::limiter::{Qos2InflightLimit, RateLimiter};
+pub use crate::registry::{Registration, SessionRegistry};
+pub use crate::reject::RejectReason;
+pub use crate::selector::{
+    IoFallback, PacketHeader, SelectContext, SelectorStats, SniffResult, VariantMatch,
+};
 pub use crate::topic::Topic;
-pub use crate::types::QoS;
+pub use crate::types::{
+    ClientIdEncoding, Direction, IdleAction, MaxSizeHandle, Metrics, PacketMask,
+    PreConnackPublishPolicy, QoS,
+};
@@ -11,6 +11,7 @@ use super::codec;
 pub struct Publish {
     publish: codec::Publish,
     topic: Path<ByteString>,
+    segments: Vec<ByteString>,
 }
 
 impl Publish {
@@ -18,7 +19,7 @@ impl Publish {
     /// packet
     #[doc(hidden)]
     pub fn new(publish: codec::Publish) -> Self {
-        Self { topic: Path::new(publish.topic.clone()), publish }
+        Self { topic: Path::new(publish.topic.clone()), segments: Vec::new(), publish }
     }
 
     #[inline]
@@ -28,6 +29,8 @@ impl Publish {
     }
 
     #[inline]
+    /// Retain flag of publish packet, publisher asks server to store message
+    /// and deliver it to future subscribers.
     pub fn retain(&self) -> bool {
         self.publish.retain
     }
@@ -60,6 +63,22 @@ impl Publish {
         &mut self.topic
     }
 
+    #[inline]
+    /// topic level matched by wildcard of router's topic filter.
+    ///
+    /// Segments are indexed in order of wildcards in the filter,
+    /// multi-level wildcard matches all remaining levels.
+    pub fn segment(&self, idx: usize) -> Option<&str> {
+        self.segments.get(idx).map(|s| s.as_ref())
+    }
+
+    /// Store matched segments, ranges are relative to current topic
+    pub(super) fn set_segments(&mut self, segments: Vec<(usize, usize)>) {
+        let topic = self.topic.get_ref();
+        self.segments =
+            segments.into_iter().map(|(start, end)| topic.slice(start..end)).collect();
+    }
+
     #[inline]
     pub fn packet(&self) -> &codec::Publish {
         &self.publish
@@ -70,13 +89,27 @@ impl Publish {
         &mut self.publish
     }
 
+    #[inline]
+    /// publish packet properties.
+    pub fn properties(&self) -> &codec::PublishProperties {
+        &self.publish.properties
+    }
+
     #[inline]
     /// the Application Message that is being published.
     pub fn payload(&self) -> &Bytes {
         &self.publish.payload
     }
 
+    #[inline]
+    /// Mutable reference to the Application Message.
+    pub fn payload_mut(&mut self) -> &mut Bytes {
+        &mut self.publish.payload
+    }
+
     /// Replace packet'a payload with empty bytes, returns existing payload.
+    ///
+    /// Payload is not copied, subsequent calls return empty bytes.
     pub fn take_payload(&mut self) -> Bytes {
         mem::take(&mut self.publish.payload)
     }
@@ -108,6 +141,10 @@ impl std::fmt::Debug for Publish {
 
 #[derive(Debug)]
 /// Publish ack
+///
+/// Publish service response, reason code, reason string and user properties
+/// are sent with PUBACK packet. Failure reason code rejects single message,
+/// connection stays open. Ack is ignored for QoS 0 publish.
 pub struct PublishAck {
     pub(crate) reason_code: codec::PublishAckReason,
     pub(crate) properties: codec::UserProperties,
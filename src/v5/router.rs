@@ -1,3 +1,4 @@
+use std::str::FromStr;
 use std::task::{Context, Poll};
 use std::{cell::Cell, cell::RefCell, future::Future, num::NonZeroU16, pin::Pin, rc::Rc};
 
@@ -9,14 +10,17 @@ use ntex::util::{ByteString, HashMap};
 
 use super::publish::{Publish, PublishAck};
 use super::Session;
+use crate::topic::Topic;
 
 type Handler<S, E> = BoxServiceFactory<Session<S>, Publish, PublishAck, E, E>;
 type HandlerService<E> = BoxService<Publish, PublishAck, E>;
+type Segments = Vec<(usize, usize)>;
 
 /// Router - structure that follows the builder pattern
 /// for building publish packet router instances for mqtt server.
 pub struct Router<S, Err> {
     router: RouterBuilder<usize>,
+    filters: Vec<(Topic, usize)>,
     handlers: Vec<Handler<S, Err>>,
     default: Handler<S, Err>,
 }
@@ -42,6 +46,7 @@ where
     {
         Router {
             router: ntex::router::Router::build(),
+            filters: Vec::new(),
             handlers: Vec::new(),
             default: boxed::factory(default_service.into_factory()),
         }
@@ -60,10 +65,30 @@ where
         self
     }
 
+    /// Configure mqtt resource for a topic filter.
+    ///
+    /// Filter could contain `+` and `#` wildcards, levels matched by wildcards
+    /// are available via `Publish::segment()`. Filters are checked in order
+    /// of registration if none of resources matches publish topic.
+    /// Panics if topic filter is not valid.
+    pub fn filter<F, U>(mut self, filter: &str, service: F) -> Self
+    where
+        F: IntoServiceFactory<U, Publish, Session<S>>,
+        U: ServiceFactory<Publish, Session<S>, Response = PublishAck, Error = Err> + 'static,
+        Err: From<U::InitError>,
+    {
+        let topic = Topic::from_str(filter)
+            .unwrap_or_else(|e| panic!("Invalid topic filter {:?}: {:?}", filter, e));
+        self.filters.push((topic, self.handlers.len()));
+        self.handlers.push(boxed::factory(service.into_factory().map_init_err(Err::from)));
+        self
+    }
+
     /// Finish router configuration and create router service factory
     pub fn finish(self) -> RouterFactory<S, Err> {
         RouterFactory {
             router: self.router.finish(),
+            filters: Rc::new(self.filters),
             handlers: Rc::new(self.handlers),
             default: self.default,
         }
@@ -82,6 +107,7 @@ where
 
 pub struct RouterFactory<S, Err> {
     router: ntex::router::Router<usize>,
+    filters: Rc<Vec<(Topic, usize)>>,
     handlers: Rc<Vec<Handler<S, Err>>>,
     default: Handler<S, Err>,
 }
@@ -99,6 +125,7 @@ where
 
     fn new_service(&self, session: Session<S>) -> Self::Future {
         let router = self.router.clone();
+        let filters = self.filters.clone();
         let factories = self.handlers.clone();
         let default_fut = self.default.new_service(session.clone());
 
@@ -108,6 +135,7 @@ where
 
             Ok(RouterService {
                 router,
+                filters,
                 default,
                 inner: Rc::new(Inner {
                     session,
@@ -125,6 +153,7 @@ where
 pub struct RouterService<S, Err> {
     inner: Rc<Inner<S, Err>>,
     router: ntex::router::Router<usize>,
+    filters: Rc<Vec<(Topic, usize)>>,
     default: HandlerService<Err>,
 }
 
@@ -132,12 +161,21 @@ struct Inner<S, Err> {
     session: Session<S>,
     handlers: RefCell<Vec<Option<HandlerService<Err>>>>,
     factories: Rc<Vec<Handler<S, Err>>>,
-    aliases: RefCell<HashMap<NonZeroU16, (usize, Path<ByteString>)>>,
+    aliases: RefCell<HashMap<NonZeroU16, (usize, Path<ByteString>, Segments)>>,
     waker: LocalWaker,
     creating: Cell<bool>,
 }
 
 impl<S: 'static, Err: 'static> RouterService<S, Err> {
+    fn recognize(&self, req: &mut Publish) -> Option<(usize, Segments)> {
+        if let Some((idx, _info)) = self.router.recognize(req.topic_mut()) {
+            return Some((*idx, Vec::new()));
+        }
+        self.filters.iter().find_map(|(topic, idx)| {
+            topic.match_segments(req.publish_topic()).map(|segments| (*idx, segments))
+        })
+    }
+
     fn create_handler(
         &self,
         idx: usize,
@@ -195,15 +233,19 @@ impl<S: 'static, Err: 'static> Service<Publish> for RouterService<S, Err> {
 
     fn call(&self, mut req: Publish) -> Self::Future {
         if !req.publish_topic().is_empty() {
-            if let Some((idx, _info)) = self.router.recognize(req.topic_mut()) {
+            if let Some((idx, segments)) = self.recognize(&mut req) {
                 // save info for topic alias
                 if let Some(alias) = req.packet().properties.topic_alias {
-                    self.inner.aliases.borrow_mut().insert(alias, (*idx, req.topic().clone()));
+                    self.inner
+                        .aliases
+                        .borrow_mut()
+                        .insert(alias, (idx, req.topic().clone(), segments.clone()));
                 }
-                if let Some(hnd) = &self.inner.handlers.borrow()[*idx] {
+                req.set_segments(segments);
+                if let Some(hnd) = &self.inner.handlers.borrow()[idx] {
                     return hnd.call(req);
                 } else {
-                    return self.create_handler(*idx, req);
+                    return self.create_handler(idx, req);
                 }
             }
         }
@@ -212,6 +254,7 @@ impl<S: 'static, Err: 'static> Service<Publish> for RouterService<S, Err> {
             let aliases = self.inner.aliases.borrow();
             if let Some(item) = aliases.get(alias) {
                 *req.topic_mut() = item.1.clone();
+                req.set_segments(item.2.clone());
                 if let Some(hnd) = &self.inner.handlers.borrow()[item.0] {
                     return hnd.call(req);
                 } else {
@@ -1,13 +1,18 @@
-use std::{
-    convert::TryFrom, fmt, future::Future, marker, pin::Pin, rc::Rc, task::Context, task::Poll,
-};
+use std::task::{Context, Poll};
+use std::{convert::TryFrom, fmt, future::Future, marker, net::SocketAddr, pin::Pin, rc::Rc};
 
 use ntex::io::{Filter, Io, IoBoxed};
 use ntex::service::{boxed, Service, ServiceFactory};
 use ntex::time::{Deadline, Millis, Seconds};
-use ntex::util::{select, Either};
+use ntex::util::{select, Either, Extensions, PoolId, Ready};
 
-use crate::error::{MqttError, ProtocolError};
+use crate::drain::Drain;
+use crate::error::{DecodeError, MqttError, ProtocolError};
+use crate::limiter::HandshakeLimit;
+use crate::selector::{count_match, peek_header, pre_connect, protocol_error, sniff};
+use crate::selector::{IoFallback, SelectorStats, SniffResult, VariantMatch};
+use crate::selector::{PacketHeader, PreConnectHook, ProtocolErrorHook};
+use crate::types::ClientIdEncoding;
 
 use super::control::{ControlMessage, ControlResult};
 use super::handshake::{Handshake, HandshakeAck};
@@ -22,26 +27,88 @@ type ServerFactory<Err, InitErr> =
 
 type Server<Err> = boxed::BoxService<SelectItem, Either<SelectItem, ()>, MqttError<Err>>;
 
+type FallbackFactory<Err, InitErr> = boxed::BoxServiceFactory<(), IoBoxed, (), Err, InitErr>;
+
+type Fallback<Err> = boxed::BoxService<IoBoxed, (), Err>;
+
+type SniffHook = Rc<dyn Fn(&[u8]) -> SniffResult>;
+
 /// Mqtt server selector
 ///
 /// Selector allows to choose different mqtt server impls depends on
 /// connectt packet.
 pub struct Selector<Err, InitErr> {
     servers: Vec<ServerFactory<Err, InitErr>>,
+    fallback: Option<ServerFactory<Err, InitErr>>,
     max_size: u32,
+    keep_connect: bool,
+    client_id_encoding: ClientIdEncoding,
     handshake_timeout: Millis,
+    initial_read_timeout: Millis,
+    pre_connect: Option<PreConnectHook<Err>>,
+    sniff: Option<SniffHook>,
+    io_fallback: Option<FallbackFactory<Err, InitErr>>,
+    handshakes: HandshakeLimit,
+    drain: Drain,
     pool: Rc<MqttSinkPool>,
+    stats: SelectorStats,
+    on_protocol_error: Option<ProtocolErrorHook>,
     _t: marker::PhantomData<(Err, InitErr)>,
 }
 
+/// Selector variant
+///
+/// Variant wraps server with settings that apply only when server is
+/// used by `Selector`.
+pub struct Variant<St, C, Cn, P> {
+    server: MqttServer<St, C, Cn, P>,
+    handshake_timeout: Option<Seconds>,
+}
+
+impl<St, C, Cn, P> Variant<St, C, Cn, P> {
+    /// Create variant for server
+    pub fn new(server: MqttServer<St, C, Cn, P>) -> Self {
+        Variant { server, handshake_timeout: None }
+    }
+
+    /// Set handshake timeout of variant.
+    ///
+    /// Timeout overrides selector's handshake timeout once variant accepts
+    /// connection, it is counted from the moment of acceptance. Zero timeout
+    /// disables handshake timeout for the variant.
+    ///
+    /// By default selector's handshake timeout is used.
+    pub fn handshake_timeout(mut self, timeout: Seconds) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+}
+
+impl<St, C, Cn, P> From<MqttServer<St, C, Cn, P>> for Variant<St, C, Cn, P> {
+    fn from(server: MqttServer<St, C, Cn, P>) -> Self {
+        Variant::new(server)
+    }
+}
+
 impl<Err, InitErr> Selector<Err, InitErr> {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Selector {
             servers: Vec::new(),
+            fallback: None,
             max_size: 0,
+            keep_connect: false,
+            client_id_encoding: ClientIdEncoding::Strict,
             handshake_timeout: Millis(10000),
+            initial_read_timeout: Millis::ZERO,
+            pre_connect: None,
+            sniff: None,
+            io_fallback: None,
+            handshakes: HandshakeLimit::default(),
+            drain: Drain::default(),
             pool: Default::default(),
+            stats: SelectorStats::default(),
+            on_protocol_error: None,
             _t: marker::PhantomData,
         }
     }
@@ -61,6 +128,107 @@ where
         self
     }
 
+    /// Set initial read timeout.
+    ///
+    /// Connection is closed if peer does not send any data within timeout
+    /// after connection is accepted. Short timeout reclaims idle connections
+    /// early, while handshake timeout could stay long enough for slow
+    /// handshake services. Timeout does not apply to connections passed
+    /// from protocol selecting `MqttServer`.
+    ///
+    /// By default initial read timeout is disabled.
+    pub fn initial_read_timeout(mut self, timeout: Seconds) -> Self {
+        self.initial_read_timeout = timeout.into();
+        self
+    }
+
+    /// Set hook that runs before first mqtt packet is read.
+    ///
+    /// Hook takes connection's io and could consume data that precedes
+    /// `connect` packet, i.e. PROXY protocol header added by load balancer.
+    /// Hook returns io back, bytes that are left in read buffer are decoded
+    /// as mqtt stream. Hook could also return remote address of the client,
+    /// it replaces transport's address in `SelectContext`. Connection is
+    /// closed if hook fails.
+    /// Hook's running time counts towards handshake timeout, hook does not
+    /// apply to connections passed from protocol selecting `MqttServer`.
+    pub fn pre_connect<F, R>(mut self, f: F) -> Self
+    where
+        F: Fn(IoBoxed) -> R + 'static,
+        R: Future<Output = Result<(IoBoxed, Option<SocketAddr>), Err>> + 'static,
+    {
+        self.pre_connect = Some(Rc::new(move |io| Box::pin(f(io))));
+        self
+    }
+
+    /// Set hook that inspects first bytes of connection.
+    ///
+    /// Hook is called with buffered bytes before `connect` packet is decoded.
+    /// It could continue with mqtt handshake, wait for more bytes, pass
+    /// connection to fallback service or close connection. Waiting time
+    /// counts towards handshake timeout. Hook does not apply to connections
+    /// passed from protocol selecting `MqttServer`.
+    pub fn sniff<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&[u8]) -> SniffResult + 'static,
+    {
+        self.sniff = Some(Rc::new(f));
+        self
+    }
+
+    /// Set hook for protocol errors of connection handshake.
+    ///
+    /// Hook is called with error, peer address and fixed header of the offending
+    /// packet before connection is closed, if first packet could not be decoded
+    /// or it is not `connect` packet.
+    pub fn on_protocol_error<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&ProtocolError, Option<SocketAddr>, &PacketHeader) + 'static,
+    {
+        self.on_protocol_error = Some(Rc::new(f));
+        self
+    }
+
+    /// Set max number of concurrently running handshake service calls.
+    ///
+    /// Limit is shared by all variants and overrides variant's own limit.
+    /// Connections wait for a free slot after variant is selected, waiting
+    /// time counts towards handshake timeout.
+    ///
+    /// To disable limit set value to 0. By default limit is set to 256.
+    pub fn max_concurrent_handshakes(self, val: usize) -> Self {
+        self.handshakes.set_capacity(val);
+        self
+    }
+
+    /// Set shutdown grace period.
+    ///
+    /// Grace period is shared by all variants and overrides variant's own
+    /// grace period. `poll_shutdown()` of selector service returns
+    /// `Poll::Pending` until connections of all variants are closed or
+    /// grace period is elapsed, see `MqttServer::shutdown_grace_period()`.
+    ///
+    /// By default grace period is disabled.
+    pub fn shutdown_grace_period(self, period: Seconds) -> Self {
+        self.drain.set_grace_period(period);
+        self
+    }
+
+    /// Set memory pool.
+    ///
+    /// Use specified memory pool for read and write buffers of accepted
+    /// connections. Buffer sizes and memory limit are configured with pool id,
+    /// see `PoolId::set_read_params()`, `PoolId::set_write_params()` and
+    /// `PoolId::set_pool_size()`. Released buffers are cached for reuse only if
+    /// capacity does not exceed high watermark, buffers that grew because of
+    /// large payloads are freed.
+    ///
+    /// By default listener's memory pool is used.
+    pub fn memory_pool(self, id: PoolId) -> Self {
+        self.pool.memory_pool.set(Some(id.pool_ref()));
+        self
+    }
+
     /// Set max inbound frame size.
     ///
     /// If max size is set to `0`, size is unlimited.
@@ -70,15 +238,48 @@ where
         self
     }
 
+    /// Keep raw bytes of `Connect` packet.
+    ///
+    /// Raw bytes are available via `Handshake::raw_connect_bytes()` method.
+    /// By default raw bytes are not kept.
+    pub fn keep_connect_bytes(mut self, val: bool) -> Self {
+        self.keep_connect = val;
+        self
+    }
+
+    /// Set decoding of client ids that are not valid utf-8.
+    ///
+    /// Setting is used for all variants and overrides variant's own setting,
+    /// see `MqttServer::client_id_encoding()`.
+    ///
+    /// By default strict mode is used.
+    pub fn client_id_encoding(mut self, val: ClientIdEncoding) -> Self {
+        self.client_id_encoding = val;
+        self
+    }
+
+    /// Get handle for variants statistics.
+    ///
+    /// Handle reports number of connections matched by each variant.
+    pub fn stats_handle(&self) -> SelectorStats {
+        self.stats.clone()
+    }
+
     /// Add server variant
-    pub fn variant<F, R, St, C, Cn, P>(
-        mut self,
-        check: F,
-        mut server: MqttServer<St, C, Cn, P>,
-    ) -> Self
+    ///
+    /// Variant is selected if check returns `true` or `Some(data)`, data is
+    /// available to handshake service via `Handshake::data()`. Check could be
+    /// used to load client's account or permissions, so handshake service does
+    /// not need to repeat lookup. Transport properties of connection are
+    /// available via `Handshake::select_context()`.
+    ///
+    /// Variant accepts `MqttServer` or `Variant` with variant's own settings.
+    pub fn variant<F, R, M, V, St, C, Cn, P>(mut self, check: F, variant: V) -> Self
     where
         F: Fn(&Handshake) -> R + 'static,
-        R: Future<Output = Result<bool, Err>> + 'static,
+        R: Future<Output = Result<M, Err>> + 'static,
+        M: VariantMatch,
+        V: Into<Variant<St, C, Cn, P>>,
         St: 'static,
         C: ServiceFactory<
                 Handshake,
@@ -98,29 +299,134 @@ where
         P::Error: fmt::Debug,
         PublishAck: TryFrom<P::Error, Error = C::Error>,
     {
+        let Variant { mut server, handshake_timeout } = variant.into();
         server.pool = self.pool.clone();
-        self.servers.push(boxed::factory(server.finish_selector(check)));
+        server.handshakes = self.handshakes.clone();
+        server.drain = self.drain.clone();
+        let (stats, idx) = (self.stats.clone(), self.stats.add_variant());
+        let check = move |hnd: &Handshake| {
+            let fut = check(hnd);
+            count_match(stats.clone(), idx, async move { Ok(fut.await?.into_selected()) })
+        };
+        self.servers.push(boxed::factory(server.finish_selector(check, handshake_timeout)));
+        self
+    }
+
+    /// Set fallback.
+    ///
+    /// `MqttServer` fallback handles connections that are not accepted by any
+    /// variant, for example to respond with `connect-ack` that rejects
+    /// connection. Fallback server is not counted in variants statistics.
+    ///
+    /// `IoFallback` service handles connections that sniff hook passes to
+    /// fallback, service receives io with all buffered bytes.
+    ///
+    /// Both kinds of fallback could be set, connections are closed if
+    /// corresponding fallback is not set.
+    pub fn fallback<T>(mut self, fallback: T) -> Self
+    where
+        T: IntoFallback<Err, InitErr>,
+    {
+        fallback.set_fallback(&mut self);
         self
     }
 }
 
+/// Fallback of selector, implemented for `MqttServer` and `IoFallback`
+///
+/// See `Selector::fallback()`.
+pub trait IntoFallback<Err, InitErr> {
+    #[doc(hidden)]
+    fn set_fallback(self, selector: &mut Selector<Err, InitErr>);
+}
+
+impl<Err, InitErr, St, C, Cn, P> IntoFallback<Err, InitErr> for MqttServer<St, C, Cn, P>
+where
+    Err: 'static,
+    InitErr: 'static,
+    St: 'static,
+    C: ServiceFactory<Handshake, Response = HandshakeAck<St>, Error = Err, InitError = InitErr>
+        + 'static,
+    C::Error: From<Cn::Error>
+        + From<Cn::InitError>
+        + From<P::Error>
+        + From<P::InitError>
+        + fmt::Debug,
+    Cn: ServiceFactory<ControlMessage<Err>, Session<St>, Response = ControlResult> + 'static,
+
+    P: ServiceFactory<Publish, Session<St>, Response = PublishAck> + 'static,
+    P::Error: fmt::Debug,
+    PublishAck: TryFrom<P::Error, Error = C::Error>,
+{
+    fn set_fallback(mut self, selector: &mut Selector<Err, InitErr>) {
+        self.pool = selector.pool.clone();
+        self.handshakes = selector.handshakes.clone();
+        self.drain = selector.drain.clone();
+        selector.fallback = Some(boxed::factory(
+            self.finish_selector(|_| Ready::Ok::<_, Err>(Some(Extensions::new())), None),
+        ));
+    }
+}
+
+impl<Err, InitErr, S> IntoFallback<Err, InitErr> for IoFallback<S>
+where
+    Err: 'static,
+    InitErr: 'static,
+    S: ServiceFactory<IoBoxed, Response = (), Error = Err, InitError = InitErr> + 'static,
+{
+    fn set_fallback(self, selector: &mut Selector<Err, InitErr>) {
+        selector.io_fallback = Some(boxed::factory(self.into_inner()));
+    }
+}
+
 impl<Err, InitErr> Selector<Err, InitErr>
 where
     Err: 'static,
     InitErr: 'static,
 {
     fn create_service(&self) -> impl Future<Output = Result<SelectorService<Err>, InitErr>> {
-        let futs: Vec<_> = self.servers.iter().map(|srv| srv.new_service(())).collect();
+        // fallback server is called after all variants
+        let futs: Vec<_> = self
+            .servers
+            .iter()
+            .chain(self.fallback.iter())
+            .map(|srv| srv.new_service(()))
+            .collect();
         let max_size = self.max_size;
+        let keep_connect = self.keep_connect;
+        let client_id_encoding = self.client_id_encoding;
         let handshake_timeout = self.handshake_timeout;
+        let initial_read_timeout = self.initial_read_timeout;
+        let pre_connect = self.pre_connect.clone();
+        let sniff = self.sniff.clone();
+        let fallback = self.io_fallback.as_ref().map(|f| f.new_service(()));
         let pool = self.pool.clone();
+        let on_protocol_error = self.on_protocol_error.clone();
+        let drain = self.drain.clone();
 
         async move {
             let mut servers = Vec::new();
             for fut in futs {
                 servers.push(fut.await?);
             }
-            Ok(SelectorService { max_size, handshake_timeout, pool, servers: Rc::new(servers) })
+            let io_fallback = match fallback {
+                Some(fut) => Some(Rc::new(fut.await?)),
+                None => None,
+            };
+            Ok(SelectorService {
+                max_size,
+                keep_connect,
+                client_id_encoding,
+                handshake_timeout,
+                initial_read_timeout,
+                pre_connect,
+                sniff,
+                io_fallback,
+                pool,
+                on_protocol_error,
+                drain,
+                servers: Rc::new(servers),
+            })
         }
     }
 }
@@ -177,8 +483,16 @@ where
 pub struct SelectorService<Err> {
     servers: Rc<Vec<Server<Err>>>,
     max_size: u32,
+    keep_connect: bool,
+    client_id_encoding: ClientIdEncoding,
     handshake_timeout: Millis,
+    initial_read_timeout: Millis,
+    pre_connect: Option<PreConnectHook<Err>>,
+    sniff: Option<SniffHook>,
+    io_fallback: Option<Rc<Fallback<Err>>>,
     pool: Rc<MqttSinkPool>,
+    on_protocol_error: Option<ProtocolErrorHook>,
+    drain: Drain,
 }
 
 impl<F, Err> Service<Io<F>> for SelectorService<Err>
@@ -220,6 +534,9 @@ where
         for srv in self.servers.iter() {
             ready &= srv.poll_ready(cx)?.is_ready();
         }
+        if let Some(ref fallback) = self.io_fallback {
+            ready &= fallback.poll_ready(cx).map_err(MqttError::Service)?.is_ready();
+        }
         if ready {
             Poll::Ready(Ok(()))
         } else {
@@ -229,10 +546,13 @@ where
 
     #[inline]
     fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
-        let mut ready = true;
+        let mut ready = self.drain.poll_shutdown(cx).is_ready();
         for srv in self.servers.iter() {
             ready &= srv.poll_shutdown(cx, is_error).is_ready()
         }
+        if let Some(ref fallback) = self.io_fallback {
+            ready &= fallback.poll_shutdown(cx, is_error).is_ready()
+        }
         if ready {
             Poll::Ready(())
         } else {
@@ -242,26 +562,90 @@ where
 
     #[inline]
     fn call(&self, io: IoBoxed) -> Self::Future {
+        if self.drain.is_draining() {
+            log::trace!("Server is shutting down, connection is rejected");
+            io.close();
+            return Box::pin(async { Err(MqttError::ServerError("Server is shutting down")) });
+        }
+        self.pool.set_memory_pool(&io);
         let servers = self.servers.clone();
+        let on_protocol_error = self.on_protocol_error.clone();
         let shared = Rc::new(MqttShared::new(
             io.get_ref(),
-            mqtt::Codec::default().max_inbound_size(self.max_size),
+            mqtt::Codec::default()
+                .max_inbound_size(self.max_size)
+                .keep_connect_bytes(self.keep_connect)
+                .client_id_encoding(self.client_id_encoding),
             0,
             self.pool.clone(),
         ));
+        let id = shared.id;
 
         let mut timeout = Deadline::new(self.handshake_timeout);
+        let initial_read_timeout = if self.handshake_timeout.is_zero() {
+            self.initial_read_timeout
+        } else {
+            self.initial_read_timeout.min(self.handshake_timeout)
+        };
+        let pre_connect_hook = self.pre_connect.clone();
+        let sniff_hook = self.sniff.clone();
+        let io_fallback = self.io_fallback.clone();
         Box::pin(async move {
+            // wait for first bytes of connect packet
+            if !initial_read_timeout.is_zero() && io.with_read_buf(|buf| buf.is_empty()) {
+                let mut initial = Deadline::new(initial_read_timeout);
+                if let Either::Left(_) = select(&mut initial, io.read_ready()).await {
+                    log::trace!("{}: Initial read timeout is elapsed", id);
+                    return Err(MqttError::HandshakeTimeout);
+                }
+            }
+
+            // consume data that precedes mqtt stream
+            let (io, peer_addr) = if let Some(ref hook) = pre_connect_hook {
+                match pre_connect(io, &mut timeout, hook).await {
+                    Ok(res) => res,
+                    Err(err) => {
+                        log::trace!("{}: Pre-connect hook failed", id);
+                        return Err(err);
+                    }
+                }
+            } else {
+                (io, None)
+            };
+
+            // inspect first bytes of connection
+            if let Some(ref hook) = sniff_hook {
+                match (sniff(&io, &mut timeout, &**hook).await?, io_fallback) {
+                    (SniffResult::Mqtt, _) => (),
+                    (SniffResult::Fallback, Some(fallback)) => {
+                        log::trace!("{}: Connection is passed to fallback service", id);
+                        return fallback.call(io).await.map_err(MqttError::Service);
+                    }
+                    _ => {
+                        log::trace!("{}: Connection is rejected by sniff hook", id);
+                        return Err(MqttError::ServerError("Connection is rejected"));
+                    }
+                }
+            }
+
             // read first packet
+            let mut header = None;
             let result = select(&mut timeout, async {
+                if on_protocol_error.is_some() {
+                    header = Some(peek_header(&io).await);
+                }
                 io.recv(&shared.codec)
                     .await
                     .map_err(|err| {
-                        log::trace!("Error is received during mqtt handshake: {:?}", err);
+                        log::trace!(
+                            "{}: Error is received during mqtt handshake: {:?}",
+                            id,
+                            err
+                        );
                         MqttError::from(err)
                     })?
                     .ok_or_else(|| {
-                        log::trace!("Server mqtt is disconnected during handshake");
+                        log::trace!("{}: Server mqtt is disconnected during handshake", id);
                         MqttError::Disconnected(None)
                     })
             })
@@ -270,21 +654,31 @@ where
             let packet = match result {
                 Either::Left(_) => Err(MqttError::HandshakeTimeout),
                 Either::Right(item) => item,
-            }?;
+            };
+            let packet = match packet {
+                Ok(packet) => packet,
+                Err(err) => {
+                    reject_connect(&io, &shared, &err).await;
+                    return Err(protocol_error(&on_protocol_error, &io, header.as_ref(), err));
+                }
+            };
 
             let connect = match packet {
                 mqtt::Packet::Connect(connect) => connect,
                 packet => {
-                    log::info!("MQTT-3.1.0-1: Expected CONNECT packet, received {}", 1);
-                    return Err(MqttError::Protocol(ProtocolError::Unexpected(
+                    log::info!("{}: MQTT-3.1.0-1: Expected CONNECT packet, received {}", id, 1);
+                    let err = MqttError::Protocol(ProtocolError::Unexpected(
                         packet.packet_type(),
                         "MQTT-3.1.0-1: Expected CONNECT packet",
-                    )));
+                    ));
+                    return Err(protocol_error(&on_protocol_error, &io, header.as_ref(), err));
                 }
             };
 
             // call servers
-            let mut item = (Handshake::new(connect, io, shared, 0, 0, 0), timeout);
+            let mut hnd = Handshake::new(connect, io, shared, 0, 0, 0);
+            hnd.ctx.set_peer_addr(peer_addr);
+            let mut item = (hnd, timeout);
             for srv in servers.iter() {
                 match srv.call(item).await? {
                     Either::Left(result) => {
@@ -293,7 +687,7 @@ where
                     Either::Right(_) => return Ok(()),
                 }
             }
-            log::error!("Cannot handle CONNECT packet {:?}", item.0);
+            log::error!("{}: Cannot handle CONNECT packet {:?}", id, item.0);
             Err(MqttError::ServerError("Cannot handle CONNECT packet"))
         })
     }
@@ -319,17 +713,32 @@ where
 
     #[inline]
     fn call(&self, (io, mut timeout): (IoBoxed, Deadline)) -> Self::Future {
+        if self.drain.is_draining() {
+            log::trace!("Server is shutting down, connection is rejected");
+            io.close();
+            return Box::pin(async { Err(MqttError::ServerError("Server is shutting down")) });
+        }
+        self.pool.set_memory_pool(&io);
         let servers = self.servers.clone();
+        let on_protocol_error = self.on_protocol_error.clone();
         let shared = Rc::new(MqttShared::new(
             io.get_ref(),
-            mqtt::Codec::default().max_inbound_size(self.max_size),
+            mqtt::Codec::default()
+                .max_inbound_size(self.max_size)
+                .keep_connect_bytes(self.keep_connect)
+                .client_id_encoding(self.client_id_encoding),
             0,
             self.pool.clone(),
         ));
+        let id = shared.id;
 
         Box::pin(async move {
             // read first packet
+            let mut header = None;
             let result = select(&mut timeout, async {
+                if on_protocol_error.is_some() {
+                    header = Some(peek_header(&io).await);
+                }
                 io.recv(&shared.codec)
                     .await
                     .map_err(|err| {
@@ -337,7 +746,7 @@ where
                         MqttError::from(err)
                     })?
                     .ok_or_else(|| {
-                        log::trace!("Server mqtt is disconnected during handshake");
+                        log::trace!("{}: Server mqtt is disconnected during handshake", id);
                         MqttError::Disconnected(None)
                     })
             })
@@ -346,16 +755,28 @@ where
             let packet = match result {
                 Either::Left(_) => Err(MqttError::HandshakeTimeout),
                 Either::Right(item) => item,
-            }?;
+            };
+            let packet = match packet {
+                Ok(packet) => packet,
+                Err(err) => {
+                    reject_connect(&io, &shared, &err).await;
+                    return Err(protocol_error(&on_protocol_error, &io, header.as_ref(), err));
+                }
+            };
 
             let connect = match packet {
                 mqtt::Packet::Connect(connect) => connect,
                 packet => {
-                    log::info!("MQTT-3.1.0-1: Expected CONNECT packet, received {:?}", packet);
-                    return Err(MqttError::Protocol(ProtocolError::Unexpected(
+                    log::info!(
+                        "{}: MQTT-3.1.0-1: Expected CONNECT packet, received {:?}",
+                        id,
+                        packet
+                    );
+                    let err = MqttError::Protocol(ProtocolError::Unexpected(
                         packet.packet_type(),
                         "MQTT-3.1.0-1: Expected CONNECT packet",
-                    )));
+                    ));
+                    return Err(protocol_error(&on_protocol_error, &io, header.as_ref(), err));
                 }
             };
 
@@ -369,8 +790,19 @@ where
                     Either::Right(_) => return Ok(()),
                 }
             }
-            log::error!("Cannot handle CONNECT packet {:?}", item.0);
+            log::error!("{}: Cannot handle CONNECT packet {:?}", id, item.0);
             Err(MqttError::ServerError("Cannot handle CONNECT packet"))
         })
     }
 }
+
+/// Send connect-ack for `connect` packet that is rejected by codec
+async fn reject_connect<E>(io: &IoBoxed, shared: &MqttShared, err: &MqttError<E>) {
+    if let MqttError::Protocol(ProtocolError::Decode(DecodeError::InvalidClientId)) = err {
+        let pkt = mqtt::Packet::ConnectAck(Box::new(mqtt::ConnectAck {
+            reason_code: mqtt::ConnectAckReason::ClientIdentifierNotValid,
+            ..Default::default()
+        }));
+        let _ = io.send(pkt, &shared.codec).await;
+    }
+}
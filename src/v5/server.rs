@@ -1,21 +1,33 @@
 use std::task::{Context, Poll};
-use std::{convert::TryFrom, fmt, future::Future, marker::PhantomData, pin::Pin, rc::Rc};
+use std::{borrow::Cow, pin::Pin, rc::Rc, time::Duration, time::Instant};
+use std::{convert::TryFrom, fmt, future::Future, marker::PhantomData, num::NonZeroU16};
 
 use ntex::io::{DispatchItem, IoBoxed};
 use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
-use ntex::time::{timeout_checked, Millis, Seconds};
-use ntex::util::{select, Either};
+use ntex::time::{timeout_checked, Deadline, Millis, Seconds};
+use ntex::util::{select, Either, PoolId};
 
-use crate::error::{MqttError, ProtocolError};
-use crate::{io::Dispatcher, service, types::QoS};
+use crate::ban::BanList;
+use crate::drain::Drain;
+use crate::error::{DecodeError, MqttError, ProtocolError};
+use crate::events::{LifecycleChannel, LifecycleEvents};
+use crate::limiter::{HandshakeLimit, Qos2InflightLimit, RateLimiter};
+use crate::reject::{RejectReason, RejectSampler};
+use crate::selector::Selected;
+use crate::session::{DisconnectReason, NegotiatedConfig};
+use crate::types::{ClientIdEncoding, CodecTiming, Direction, MaxSizeHandle};
+use crate::types::{IdleAction, PreConnackPublishPolicy, QoS, TopicRewrite, MQTT_LEVEL_5};
+use crate::types::{Metrics, MetricsHandle};
+use crate::{io::Dispatcher, service};
 
 use super::control::{ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
+use super::dispatcher::{factory, DispatcherConfig};
 use super::handshake::{Handshake, HandshakeAck};
 use super::publish::{Publish, PublishAck};
 use super::selector::SelectItem;
 use super::shared::{MqttShared, MqttSinkPool};
-use super::{codec as mqtt, dispatcher::factory, MqttSink, Session};
+use super::{codec as mqtt, MqttSink, Session};
 
 /// Mqtt Server
 pub struct MqttServer<St, C, Cn, P> {
@@ -26,9 +38,36 @@ pub struct MqttServer<St, C, Cn, P> {
     max_receive: u16,
     max_qos: Option<QoS>,
     max_inflight_size: usize,
+    ordered: bool,
     handshake_timeout: Seconds,
     disconnect_timeout: Seconds,
+    subscribe_timeout: Seconds,
+    min_qos: QoS,
     max_topic_alias: u16,
+    keep_connect: bool,
+    client_id_encoding: ClientIdEncoding,
+    limiter: RateLimiter,
+    strict_acks: bool,
+    max_lifetime: Seconds,
+    on_unexpected_ack: Option<Rc<dyn Fn(&Session<St>, NonZeroU16, u8)>>,
+    on_rejected_publish: Option<Rc<RejectSampler<mqtt::Publish>>>,
+    ingress_topic: Option<TopicRewrite>,
+    egress_topic: Option<TopicRewrite>,
+    qos2_limit: Option<Qos2InflightLimit>,
+    on_ping: Option<Rc<dyn Fn(&Session<St>, Instant)>>,
+    on_idle_timeout: Option<Rc<dyn Fn(&Session<St>) -> IdleAction>>,
+    on_disconnect: Option<Rc<dyn Fn(&Session<St>, DisconnectReason)>>,
+    coalesce_subacks: Option<(Millis, usize)>,
+    max_topic_cardinality: usize,
+    events: Rc<LifecycleChannel>,
+    pub(super) handshakes: HandshakeLimit,
+    pub(super) drain: Drain,
+    pre_connack: PreConnackPublishPolicy,
+    max_size_handle: Option<MaxSizeHandle>,
+    ban_list: Option<Rc<dyn BanList>>,
+    codec_timing: Option<CodecTiming>,
+    metrics: Option<MetricsHandle>,
+    io_timeouts: (Seconds, Seconds),
     pub(super) pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
 }
@@ -52,9 +91,36 @@ where
             max_receive: 15,
             max_qos: None,
             max_inflight_size: 65535,
+            ordered: false,
             handshake_timeout: Seconds::ZERO,
             disconnect_timeout: Seconds(3),
+            subscribe_timeout: Seconds::ZERO,
+            min_qos: QoS::AtMostOnce,
             max_topic_alias: 32,
+            keep_connect: false,
+            client_id_encoding: ClientIdEncoding::Strict,
+            limiter: RateLimiter::Disabled,
+            strict_acks: true,
+            max_lifetime: Seconds::ZERO,
+            on_unexpected_ack: None,
+            on_rejected_publish: None,
+            ingress_topic: None,
+            egress_topic: None,
+            qos2_limit: None,
+            on_ping: None,
+            on_idle_timeout: None,
+            on_disconnect: None,
+            coalesce_subacks: None,
+            max_topic_cardinality: 0,
+            events: Default::default(),
+            handshakes: HandshakeLimit::default(),
+            drain: Drain::default(),
+            pre_connack: PreConnackPublishPolicy::default(),
+            max_size_handle: None,
+            ban_list: None,
+            codec_timing: None,
+            metrics: None,
+            io_timeouts: (Seconds::ZERO, Seconds::ZERO),
             pool: Rc::new(MqttSinkPool::default()),
             _t: PhantomData,
         }
@@ -79,6 +145,119 @@ where
         self
     }
 
+    /// Set max number of concurrently running handshake service calls.
+    ///
+    /// Connections that received `connect` packet wait for a free slot
+    /// before handshake service gets called, waiting time counts towards
+    /// handshake timeout. If server is used as selector variant, selector's
+    /// limit is used instead.
+    ///
+    /// To disable limit set value to 0. By default limit is set to 256.
+    pub fn max_concurrent_handshakes(self, val: usize) -> Self {
+        self.handshakes.set_capacity(val);
+        self
+    }
+
+    /// Set shutdown grace period.
+    ///
+    /// Once shutdown starts, server rejects new connections and closes
+    /// idle connections with `ServerShuttingDown` disconnect packet.
+    /// Connections with inflight QoS 1 and QoS 2 exchanges are closed
+    /// as soon as exchanges complete, remaining connections get force
+    /// closed after grace period. `poll_shutdown()` of server service
+    /// returns `Poll::Pending` until all connections are closed or grace
+    /// period is elapsed. If server is used as selector variant,
+    /// selector's grace period is used instead.
+    ///
+    /// By default grace period is disabled.
+    pub fn shutdown_grace_period(self, period: Seconds) -> Self {
+        self.drain.set_grace_period(period);
+        self
+    }
+
+    /// Set handling of `publish` packets received before `connect-ack` is sent.
+    ///
+    /// Client could pipeline `publish` packet right after `connect` packet.
+    /// With `Buffer` policy such packets are processed after `connect-ack`,
+    /// with `Reject` policy connection is closed with protocol error.
+    ///
+    /// By default `Reject` policy is used.
+    pub fn pre_connack_publish_policy(mut self, policy: PreConnackPublishPolicy) -> Self {
+        self.pre_connack = policy;
+        self
+    }
+
+    /// Set callback for codec timing.
+    ///
+    /// Callback is called after each packet decode or encode with control
+    /// packet type (`1` for `connect` ... `15` for `auth`) and time spent
+    /// in codec. If server is used as selector variant, timing is reported
+    /// once variant accepts connection, `connect` packet is not reported.
+    ///
+    /// By default timing is not measured.
+    pub fn on_codec_timing<F>(mut self, f: F) -> Self
+    where
+        F: Fn(Direction, u8, Duration) + 'static,
+    {
+        self.codec_timing = Some(CodecTiming::new(f));
+        self
+    }
+
+    /// Set read timeout.
+    ///
+    /// Defines max time for receiving single packet, timer starts once first
+    /// bytes of the packet are received. During handshake timer starts once
+    /// connection is accepted, so partially received `connect` packet does not
+    /// wait for keep-alive negotiation. Connection is closed with
+    /// `ProtocolError::ReadTimeout` error. Keep-alive timer is not affected.
+    ///
+    /// By default read timeout is disabled, set `0` to disable timeout.
+    pub fn read_timeout(mut self, timeout: Seconds) -> Self {
+        self.io_timeouts.0 = timeout;
+        self
+    }
+
+    /// Set write timeout.
+    ///
+    /// Defines max time write buffer could stay not empty without write
+    /// progress, timer restarts every time peer accepts data. Connection is
+    /// closed with `ProtocolError::WriteTimeout` error.
+    ///
+    /// By default write timeout is disabled, set `0` to disable timeout.
+    pub fn write_timeout(mut self, timeout: Seconds) -> Self {
+        self.io_timeouts.1 = timeout;
+        self
+    }
+
+    /// Set memory pool.
+    ///
+    /// Use specified memory pool for read and write buffers of accepted
+    /// connections. Buffer sizes and memory limit are configured with pool id,
+    /// see `PoolId::set_read_params()`, `PoolId::set_write_params()` and
+    /// `PoolId::set_pool_size()`. Released buffers are cached for reuse only if
+    /// capacity does not exceed high watermark, buffers that grew because of
+    /// large payloads are freed. If server is used as selector
+    /// variant, selector's memory pool is used instead.
+    ///
+    /// By default listener's memory pool is used.
+    pub fn memory_pool(self, id: PoolId) -> Self {
+        self.pool.memory_pool.set(Some(id.pool_ref()));
+        self
+    }
+
+    /// Set connection metrics observer.
+    ///
+    /// Observer is notified about every decoded and encoded packet and about
+    /// protocol errors handled by dispatcher. If server is used as selector
+    /// variant, packets are reported once variant accepts connection, `connect`
+    /// packet is not reported.
+    ///
+    /// By default metrics are not collected.
+    pub fn metrics<M: Metrics + 'static>(mut self, metrics: M) -> Self {
+        self.metrics = Some(MetricsHandle::new(metrics));
+        self
+    }
+
     /// Set server connection disconnect timeout.
     ///
     /// Defines a timeout for disconnect connection. If a disconnect procedure does not complete
@@ -92,6 +271,272 @@ where
         self
     }
 
+    /// Set max connection lifetime.
+    ///
+    /// Defines max lifetime of the connection regardless of activity. If connection
+    /// lifetime is reached, server sends `Disconnect` packet with `MaximumConnectTime`
+    /// reason code and the connection get closed.
+    ///
+    /// By default connection lifetime is not limited.
+    pub fn max_connection_lifetime(mut self, timeout: Seconds) -> Self {
+        self.max_lifetime = timeout;
+        self
+    }
+
+    /// Set subscribe timeout.
+    ///
+    /// Defines a timeout for control service to handle `Subscribe` packet. If control
+    /// service does not complete within this time, all topic filters get failed
+    /// with `UnspecifiedError` reason code.
+    ///
+    /// By default subscribe timeout is disabled.
+    pub fn subscribe_timeout(mut self, timeout: Seconds) -> Self {
+        self.subscribe_timeout = timeout;
+        self
+    }
+
+    /// Set minimum QoS for inbound publish packets.
+    ///
+    /// Publish packets with lower QoS are treated as protocol violation and
+    /// connection get closed. Granted QoS of subscriptions is checked as well,
+    /// topic filters with lower granted QoS get failed.
+    ///
+    /// By default QoS 0 is allowed.
+    pub fn min_inbound_qos(mut self, qos: QoS) -> Self {
+        self.min_qos = qos;
+        self
+    }
+
+    /// Set inbound publish rate limit with sliding window.
+    ///
+    /// Limits number of publish packets received within last `window` seconds
+    /// by `count`. If limit is exceeded connection get closed.
+    ///
+    /// By default inbound publish rate is not limited.
+    pub fn inbound_sliding_window_limit(mut self, count: u32, window: Seconds) -> Self {
+        self.limiter = RateLimiter::SlidingWindow { count, window };
+        self
+    }
+
+    /// Set inbound publish rate limit with token bucket.
+    ///
+    /// Limits rate of publish packets to `rate` packets per second with bursts
+    /// of `burst` packets. Once limit is exceeded, server stops reading from the
+    /// connection before next publish packet until token is available. Other packet
+    /// types that precede the publish packet are processed. Replaces sliding window limit.
+    ///
+    /// By default inbound publish rate is not limited.
+    pub fn publish_rate_limit(mut self, rate: u32, burst: u32) -> Self {
+        let notify = match self.limiter {
+            RateLimiter::TokenBucket { notify, .. } => notify,
+            _ => Seconds::ZERO,
+        };
+        self.limiter = RateLimiter::TokenBucket { rate, burst, notify };
+        self
+    }
+
+    /// Notify control service about throttled connections.
+    ///
+    /// Control service receives `ControlMessage::RateLimited` message once
+    /// connection is throttled by `publish_rate_limit()` continuously for
+    /// `period`, message is sent once per throttling period. Has no effect
+    /// if publish rate limit is not set.
+    ///
+    /// By default control service is not notified.
+    pub fn publish_rate_limit_notify(mut self, period: Seconds) -> Self {
+        if let RateLimiter::TokenBucket { rate, burst, .. } = self.limiter {
+            self.limiter = RateLimiter::TokenBucket { rate, burst, notify: period };
+        }
+        self
+    }
+
+    /// Returns active inbound publish rate limiter
+    pub fn rate_limiter(&self) -> RateLimiter {
+        self.limiter
+    }
+
+    /// Set handler for acks with unknown packet id.
+    ///
+    /// Handler is called with packet id and packet type of received ack packet
+    /// (PUBACK, SUBACK or UNSUBACK), if packet id does not reference in-flight
+    /// packet. Server does not send QoS 2 publishes, so PUBREC and PUBCOMP
+    /// packets are always unexpected.
+    pub fn on_unexpected_ack<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Session<St>, NonZeroU16, u8) + 'static,
+    {
+        self.on_unexpected_ack = Some(Rc::new(f));
+        self
+    }
+
+    /// Set handler for rejected publish packets.
+    ///
+    /// Handler is called for every `sample_rate`-th publish packet rejected
+    /// by server checks or by publish service. If handler is set, publish
+    /// packet is cloned before it gets passed to publish service.
+    pub fn on_rejected_publish<F>(mut self, sample_rate: u32, f: F) -> Self
+    where
+        F: Fn(&mqtt::Publish, RejectReason) + 'static,
+    {
+        self.on_rejected_publish = Some(Rc::new(RejectSampler::new(sample_rate, f)));
+        self
+    }
+
+    /// Set topic rewrite for inbound packets.
+    ///
+    /// Hook is applied to topic of every received publish packet and to topic
+    /// filters of subscribe and unsubscribe packets before they get passed to
+    /// services, so `Publish::topic()` matches against rewritten topic.
+    pub fn ingress_topic_rewrite<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str) -> Cow<'_, str> + 'static,
+    {
+        self.ingress_topic = Some(TopicRewrite::new(f));
+        self
+    }
+
+    /// Set topic rewrite for outbound publish packets.
+    ///
+    /// Hook is applied to topic of every publish packet sent with connection's
+    /// sink after handshake is completed.
+    pub fn egress_topic_rewrite<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str) -> Cow<'_, str> + 'static,
+    {
+        self.egress_topic = Some(TopicRewrite::new(f));
+        self
+    }
+
+    /// Set limit of inbound QoS 2 publish packets processed concurrently by
+    /// all connections that share the limit.
+    ///
+    /// Server acknowledges QoS 2 publish with PUBACK once publish service
+    /// completes, PUBREC/PUBREL/PUBCOMP exchange is not used. So limit counts
+    /// publish service calls for QoS 2 packets that run concurrently, slot is
+    /// released when publish service call completes.
+    ///
+    /// If limit is reached, publish packet is rejected with PUBREC with `Quota
+    /// exceeded` reason code and is not passed to publish service. Mqtt v3 has
+    /// no reason codes, so v3 server delays such packets until a slot is released.
+    pub fn max_global_qos2_inflight(mut self, limit: Qos2InflightLimit) -> Self {
+        self.qos2_limit = Some(limit);
+        self
+    }
+
+    /// Set handler for PINGREQ packets.
+    ///
+    /// Handler is called with the time packet is received, before control
+    /// service gets `Ping` message. Time of last PINGREQ packet is also
+    /// available via `Session::last_ping_at()`.
+    pub fn on_ping<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Session<St>, Instant) + 'static,
+    {
+        self.on_ping = Some(Rc::new(f));
+        self
+    }
+
+    /// Set handler for idle connections.
+    ///
+    /// Handler is called once keep-alive timeout is expired. `IdleAction::Extend`
+    /// keeps connection open and re-arms keep-alive timer, regular keep-alive timeout
+    /// is restored once a packet is received from the client. Handler is a shortcut
+    /// for `ControlMessage::Timeout` handling, with `IdleAction::Disconnect` timeout
+    /// message is sent to control service.
+    pub fn on_idle_timeout<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Session<St>) -> IdleAction + 'static,
+    {
+        self.on_idle_timeout = Some(Rc::new(f));
+        self
+    }
+
+    /// Set handler for connection termination.
+    ///
+    /// Handler is called exactly once per connection, once dispatcher is
+    /// stopped, with the reason of connection termination. Handler is not
+    /// called if handshake fails, as no session is created.
+    pub fn on_disconnect<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Session<St>, DisconnectReason) + 'static,
+    {
+        self.on_disconnect = Some(Rc::new(f));
+        self
+    }
+
+    /// Coalesce SUBACK packet writes.
+    ///
+    /// SUBACK packets are queued and written together once `max_count`
+    /// packets are queued or `max_delay` is elapsed since first queued packet.
+    /// Each SUBSCRIBE packet still gets its own SUBACK packet.
+    ///
+    /// By default SUBACK packets are written immediately.
+    pub fn coalesce_subacks(mut self, max_delay: Millis, max_count: usize) -> Self {
+        self.coalesce_subacks = Some((max_delay, max_count));
+        self
+    }
+
+    /// Set max number of distinct topics connection could publish to.
+    ///
+    /// Publish to a topic that is not seen before is rejected with
+    /// `QuotaExceeded` reason once limit is reached, publishes to seen
+    /// topics are still processed. QoS 0 publishes to new topics are dropped.
+    ///
+    /// By default number of topics is not limited.
+    pub fn max_publish_topic_cardinality(mut self, max: usize) -> Self {
+        self.max_topic_cardinality = max;
+        self
+    }
+
+    /// Set policy for acks with unknown packet id.
+    ///
+    /// If strict policy is enabled, ack with unknown packet id is treated
+    /// as protocol violation and connection get closed, otherwise such ack
+    /// is ignored.
+    ///
+    /// By default strict policy is enabled.
+    pub fn strict_acks(mut self, val: bool) -> Self {
+        self.strict_acks = val;
+        self
+    }
+
+    /// Get stream of connection lifecycle events.
+    ///
+    /// Stream emits events for all connections handled by this server
+    /// instance, events are not collected until first stream is created.
+    /// Server instance is created per worker thread, so each worker
+    /// has its own stream. See `LifecycleEvents` for overflow policy.
+    pub fn lifecycle_events(&self) -> LifecycleEvents {
+        self.events.receiver()
+    }
+
+    /// Keep raw bytes of `Connect` packet.
+    ///
+    /// Raw bytes are available via `Handshake::raw_connect_bytes()` method,
+    /// for example for verification of connect packet signature.
+    ///
+    /// By default raw bytes are not kept.
+    pub fn keep_connect_bytes(mut self, val: bool) -> Self {
+        self.keep_connect = val;
+        self
+    }
+
+    /// Set decoding of client ids that are not valid utf-8.
+    ///
+    /// In strict mode connection is rejected with `ClientIdentifierNotValid`
+    /// connect-ack. Lossy mode replaces invalid sequences with `U+FFFD`,
+    /// raw bytes are available via `Handshake::raw_client_id()` method.
+    ///
+    /// If server is used as selector variant, selector's setting is used
+    /// instead, see `Selector::client_id_encoding()`. Protocol selector of
+    /// `crate::MqttServer` always uses strict mode.
+    ///
+    /// By default strict mode is used.
+    pub fn client_id_encoding(mut self, val: ClientIdEncoding) -> Self {
+        self.client_id_encoding = val;
+        self
+    }
+
     /// Set max inbound frame size.
     ///
     /// If max size is set to `0`, size is unlimited.
@@ -101,6 +546,27 @@ where
         self
     }
 
+    /// Set shared max inbound frame size.
+    ///
+    /// Handle overrides `max_size`. Max size is read from the handle when
+    /// connection is accepted and is advertised in `connect-ack` packet, so
+    /// changes apply to new connections only.
+    pub fn max_size_handle(mut self, handle: MaxSizeHandle) -> Self {
+        self.max_size_handle = Some(handle);
+        self
+    }
+
+    /// Set ban list.
+    ///
+    /// Ban list is checked by client id and peer address before handshake
+    /// service is called, banned client gets `connect-ack` packet with
+    /// `banned` reason code. Clients could be added to the list with
+    /// `Session::quarantine()` method.
+    pub fn ban_list<B: BanList + 'static>(mut self, list: B) -> Self {
+        self.ban_list = Some(Rc::new(list));
+        self
+    }
+
     /// Set `receive max`
     ///
     /// Number of in-flight publish packets. By default receive max is set to 15 packets.
@@ -134,6 +600,18 @@ where
         self
     }
 
+    /// Process inbound packets strictly one by one.
+    ///
+    /// Next packet is not passed to services until processing of previous
+    /// packet is completed, so publish service observes and completes publishes
+    /// in arrival order regardless of their qos. Publish service must not wait
+    /// for acknowledgement of outbound publishes of the same connection.
+    /// By default packets are processed concurrently.
+    pub fn ordered(mut self, val: bool) -> Self {
+        self.ordered = val;
+        self
+    }
+
     /// Service to handle control packets
     ///
     /// All control packets are processed sequentially, max number of buffered
@@ -154,14 +632,47 @@ where
             max_topic_alias: self.max_topic_alias,
             max_qos: self.max_qos,
             max_inflight_size: self.max_inflight_size,
+            ordered: self.ordered,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            subscribe_timeout: self.subscribe_timeout,
+            min_qos: self.min_qos,
+            keep_connect: self.keep_connect,
+            client_id_encoding: self.client_id_encoding,
+            limiter: self.limiter,
+            strict_acks: self.strict_acks,
+            max_lifetime: self.max_lifetime,
+            on_unexpected_ack: self.on_unexpected_ack,
+            on_rejected_publish: self.on_rejected_publish,
+            ingress_topic: self.ingress_topic,
+            egress_topic: self.egress_topic,
+            qos2_limit: self.qos2_limit,
+            on_ping: self.on_ping,
+            on_idle_timeout: self.on_idle_timeout,
+            on_disconnect: self.on_disconnect,
+            coalesce_subacks: self.coalesce_subacks,
+            max_topic_cardinality: self.max_topic_cardinality,
+            events: self.events,
+            handshakes: self.handshakes,
+            drain: self.drain,
+            pre_connack: self.pre_connack,
+            max_size_handle: self.max_size_handle,
+            ban_list: self.ban_list,
+            codec_timing: self.codec_timing,
+            metrics: self.metrics,
+            io_timeouts: self.io_timeouts,
             pool: self.pool,
             _t: PhantomData,
         }
     }
 
     /// Set service to handle publish packets and create mqtt server factory
+    ///
+    /// PUBACK for QoS 1 publish is sent only after publish service future
+    /// completes, with reason code of returned `PublishAck`. Service could
+    /// delay ack until message is durably stored or reply with failure
+    /// reason code. Service error is converted to `PublishAck`, if conversion
+    /// fails PUBACK is not sent and error is passed to control service.
     pub fn publish<F, Srv>(self, publish: F) -> MqttServer<St, C, Cn, Srv>
     where
         F: IntoServiceFactory<Srv, Publish, Session<St>>,
@@ -179,8 +690,35 @@ where
             max_topic_alias: self.max_topic_alias,
             max_qos: self.max_qos,
             max_inflight_size: self.max_inflight_size,
+            ordered: self.ordered,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            subscribe_timeout: self.subscribe_timeout,
+            min_qos: self.min_qos,
+            keep_connect: self.keep_connect,
+            client_id_encoding: self.client_id_encoding,
+            limiter: self.limiter,
+            strict_acks: self.strict_acks,
+            max_lifetime: self.max_lifetime,
+            on_unexpected_ack: self.on_unexpected_ack,
+            on_rejected_publish: self.on_rejected_publish,
+            ingress_topic: self.ingress_topic,
+            egress_topic: self.egress_topic,
+            qos2_limit: self.qos2_limit,
+            on_ping: self.on_ping,
+            on_idle_timeout: self.on_idle_timeout,
+            on_disconnect: self.on_disconnect,
+            coalesce_subacks: self.coalesce_subacks,
+            max_topic_cardinality: self.max_topic_cardinality,
+            events: self.events,
+            handshakes: self.handshakes,
+            drain: self.drain,
+            pre_connack: self.pre_connack,
+            max_size_handle: self.max_size_handle,
+            ban_list: self.ban_list,
+            codec_timing: self.codec_timing,
+            metrics: self.metrics,
+            io_timeouts: self.io_timeouts,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -202,6 +740,30 @@ where
     P::Error: fmt::Debug,
     PublishAck: TryFrom<P::Error, Error = C::Error>,
 {
+    /// Per-connection dispatcher settings
+    fn dispatcher_config(&self) -> DispatcherConfig<St> {
+        DispatcherConfig {
+            inflight_size: self.max_inflight_size,
+            ordered: self.ordered,
+            subscribe_timeout: self.subscribe_timeout,
+            min_qos: self.min_qos,
+            limiter: self.limiter,
+            strict_acks: self.strict_acks,
+            max_lifetime: self.max_lifetime,
+            on_unexpected_ack: self.on_unexpected_ack.clone(),
+            on_rejected_publish: self.on_rejected_publish.clone(),
+            ingress_topic: self.ingress_topic.clone(),
+            egress_topic: self.egress_topic.clone(),
+            qos2_limit: self.qos2_limit.clone(),
+            on_ping: self.on_ping.clone(),
+            on_idle_timeout: self.on_idle_timeout.clone(),
+            on_disconnect: self.on_disconnect.clone(),
+            coalesce_subacks: self.coalesce_subacks,
+            max_topic_cardinality: self.max_topic_cardinality,
+            drain: self.drain.clone(),
+        }
+    }
+
     /// Finish server configuration and create mqtt server factory
     pub fn finish(
         self,
@@ -222,6 +784,7 @@ where
         >,
         Rc<MqttShared>,
     > {
+        let config = self.dispatcher_config();
         service::MqttServer::new(
             HandshakeFactory {
                 factory: self.handshake,
@@ -229,11 +792,22 @@ where
                 max_receive: self.max_receive,
                 max_topic_alias: self.max_topic_alias,
                 max_qos: self.max_qos,
+                keep_connect: self.keep_connect,
+                client_id_encoding: self.client_id_encoding,
+                events: self.events,
+                handshakes: self.handshakes,
+                drain: self.drain.clone(),
+                pre_connack: self.pre_connack,
+                max_size_handle: self.max_size_handle,
+                ban_list: self.ban_list,
+                codec_timing: self.codec_timing,
+                metrics: self.metrics,
+                io_timeouts: self.io_timeouts,
                 handshake_timeout: self.handshake_timeout.into(),
                 pool: self.pool,
                 _t: PhantomData,
             },
-            factory(self.srv_publish, self.srv_control, self.max_inflight_size),
+            factory(self.srv_publish, self.srv_control, config),
             self.disconnect_timeout,
         )
     }
@@ -242,6 +816,7 @@ where
     pub(crate) fn finish_selector<F, R>(
         self,
         check: F,
+        handshake_timeout: Option<Seconds>,
     ) -> impl ServiceFactory<
         SelectItem,
         Response = Either<SelectItem, ()>,
@@ -250,21 +825,28 @@ where
     >
     where
         F: Fn(&Handshake) -> R + 'static,
-        R: Future<Output = Result<bool, C::Error>> + 'static,
+        R: Future<Output = Result<Selected, C::Error>> + 'static,
     {
+        let config = self.dispatcher_config();
         ServerSelector::<St, _, _, _, _> {
             check: Rc::new(check),
+            handshake_timeout,
+            io_timeouts: self.io_timeouts,
             connect: self.handshake,
-            handler: Rc::new(factory(
-                self.srv_publish,
-                self.srv_control,
-                self.max_inflight_size,
-            )),
+            handler: Rc::new(factory(self.srv_publish, self.srv_control, config)),
             max_size: self.max_size,
             max_receive: self.max_receive,
             max_topic_alias: self.max_topic_alias,
             max_qos: self.max_qos,
+            handshakes: self.handshakes,
+            drain: self.drain,
+            pre_connack: self.pre_connack,
+            max_size_handle: self.max_size_handle,
+            ban_list: self.ban_list,
             disconnect_timeout: self.disconnect_timeout,
+            events: self.events.clone(),
+            codec_timing: self.codec_timing,
+            metrics: self.metrics,
             _t: PhantomData,
         }
     }
@@ -276,6 +858,17 @@ struct HandshakeFactory<St, H> {
     max_receive: u16,
     max_topic_alias: u16,
     max_qos: Option<QoS>,
+    keep_connect: bool,
+    client_id_encoding: ClientIdEncoding,
+    events: Rc<LifecycleChannel>,
+    handshakes: HandshakeLimit,
+    drain: Drain,
+    pre_connack: PreConnackPublishPolicy,
+    max_size_handle: Option<MaxSizeHandle>,
+    ban_list: Option<Rc<dyn BanList>>,
+    codec_timing: Option<CodecTiming>,
+    metrics: Option<MetricsHandle>,
+    io_timeouts: (Seconds, Seconds),
     handshake_timeout: Millis,
     pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
@@ -283,6 +876,7 @@ struct HandshakeFactory<St, H> {
 
 impl<St, H> ServiceFactory<IoBoxed> for HandshakeFactory<St, H>
 where
+    St: 'static,
     H: ServiceFactory<Handshake, Response = HandshakeAck<St>> + 'static,
     H::Error: fmt::Debug,
 {
@@ -299,6 +893,17 @@ where
         let max_receive = self.max_receive;
         let max_topic_alias = self.max_topic_alias;
         let max_qos = self.max_qos;
+        let keep_connect = self.keep_connect;
+        let client_id_encoding = self.client_id_encoding;
+        let events = self.events.clone();
+        let handshakes = self.handshakes.clone();
+        let drain = self.drain.clone();
+        let pre_connack = self.pre_connack;
+        let max_size_handle = self.max_size_handle.clone();
+        let ban_list = self.ban_list.clone();
+        let codec_timing = self.codec_timing.clone();
+        let metrics = self.metrics.clone();
+        let io_timeouts = self.io_timeouts;
         let pool = self.pool.clone();
         let handshake_timeout = self.handshake_timeout;
 
@@ -309,6 +914,17 @@ where
                 max_receive,
                 max_topic_alias,
                 max_qos,
+                keep_connect,
+                client_id_encoding,
+                events,
+                handshakes,
+                drain,
+                pre_connack,
+                max_size_handle,
+                ban_list,
+                codec_timing,
+                metrics,
+                io_timeouts,
                 handshake_timeout,
                 pool,
                 service: Rc::new(service),
@@ -324,6 +940,17 @@ struct HandshakeService<St, H> {
     max_receive: u16,
     max_topic_alias: u16,
     max_qos: Option<QoS>,
+    keep_connect: bool,
+    client_id_encoding: ClientIdEncoding,
+    events: Rc<LifecycleChannel>,
+    handshakes: HandshakeLimit,
+    drain: Drain,
+    pre_connack: PreConnackPublishPolicy,
+    max_size_handle: Option<MaxSizeHandle>,
+    ban_list: Option<Rc<dyn BanList>>,
+    codec_timing: Option<CodecTiming>,
+    metrics: Option<MetricsHandle>,
+    io_timeouts: (Seconds, Seconds),
     handshake_timeout: Millis,
     pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
@@ -331,6 +958,7 @@ struct HandshakeService<St, H> {
 
 impl<St, H> Service<IoBoxed> for HandshakeService<St, H>
 where
+    St: 'static,
     H: Service<Handshake, Response = HandshakeAck<St>> + 'static,
     H::Error: fmt::Debug,
 {
@@ -343,35 +971,83 @@ where
     }
 
     fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
-        self.service.poll_shutdown(cx, is_error)
+        let ready1 = self.drain.poll_shutdown(cx).is_ready();
+        let ready2 = self.service.poll_shutdown(cx, is_error).is_ready();
+        if ready1 && ready2 {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
     }
 
     fn call(&self, io: IoBoxed) -> Self::Future {
-        log::trace!("Starting mqtt v5 handshake");
+        if self.drain.is_draining() {
+            log::trace!("Server is shutting down, connection is rejected");
+            io.close();
+            return Box::pin(async { Err(MqttError::ServerError("Server is shutting down")) });
+        }
+        self.pool.set_memory_pool(&io);
 
         let service = self.service.clone();
-        let codec = mqtt::Codec::default().max_inbound_size(self.max_size);
+        let handshakes = self.handshakes.clone();
+        let pre_connack = self.pre_connack;
+        let codec = mqtt::Codec::default()
+            .max_inbound_size(self.max_size_handle.as_ref().map_or(self.max_size, |h| h.get()))
+            .keep_connect_bytes(self.keep_connect)
+            .client_id_encoding(self.client_id_encoding)
+            .codec_timing(self.codec_timing.clone())
+            .metrics(self.metrics.clone());
         let shared = Rc::new(MqttShared::new(io.get_ref(), codec, 0, self.pool.clone()));
+        *shared.ban_list.borrow_mut() = self.ban_list.clone();
+        shared.read_timeout.set(self.io_timeouts.0);
+        shared.write_timeout.set(self.io_timeouts.1);
 
-        let max_size = self.max_size;
-        let mut max_receive = self.max_receive;
-        let mut max_topic_alias = self.max_topic_alias;
+        let max_size = shared.codec.inbound_max_size();
+        let max_receive = self.max_receive;
+        let max_topic_alias = self.max_topic_alias;
         let max_qos = self.max_qos;
         let handshake_timeout = self.handshake_timeout;
+        let events = self.events.clone();
+
+        let id = shared.id;
+        log::trace!("{}: Starting mqtt v5 handshake", id);
 
         let f = async move {
-            // read first packet
-            let packet = io
-                .recv(&shared.codec)
-                .await
-                .map_err(|err| {
-                    log::trace!("Error is received during mqtt handshake: {:?}", err);
-                    MqttError::from(err)
-                })?
-                .ok_or_else(|| {
-                    log::trace!("Server mqtt is disconnected during handshake");
-                    MqttError::Disconnected(None)
-                })?;
+            // read first packet, read timeout starts once connection is accepted
+            let packet = match timeout_checked(
+                shared.read_timeout.get(),
+                io.recv(&shared.codec),
+            )
+            .await
+            {
+                Ok(res) => res,
+                Err(_) => {
+                    log::trace!("{}: Connect packet is not received within read timeout", id);
+                    return Err(MqttError::Protocol(ProtocolError::ReadTimeout));
+                }
+            };
+            let packet = match packet {
+                Ok(Some(packet)) => packet,
+                Ok(None) => {
+                    log::trace!("{}: Server mqtt is disconnected during handshake", id);
+                    return Err(MqttError::Disconnected(None));
+                }
+                Err(Either::Left(DecodeError::InvalidClientId)) => {
+                    log::trace!("{}: Client identifier is not valid", id);
+                    let pkt = mqtt::Packet::ConnectAck(Box::new(mqtt::ConnectAck {
+                        reason_code: mqtt::ConnectAckReason::ClientIdentifierNotValid,
+                        ..Default::default()
+                    }));
+                    io.send(pkt, &shared.codec).await.map_err(MqttError::from)?;
+                    return Err(MqttError::Protocol(ProtocolError::Decode(
+                        DecodeError::InvalidClientId,
+                    )));
+                }
+                Err(err) => {
+                    log::trace!("{}: Error is received during mqtt handshake: {:?}", id, err);
+                    return Err(MqttError::from(err));
+                }
+            };
 
             match packet {
                 mqtt::Packet::Connect(connect) => {
@@ -380,38 +1056,46 @@ where
                         shared.codec.set_max_outbound_size(size.get());
                     }
                     shared.cap.set(connect.receive_max.map(|v| v.get()).unwrap_or(16) as usize);
+                    shared.set_topic_alias_max(connect.topic_alias_max);
 
                     let keep_alive = connect.keep_alive;
+                    let client_id = connect.client_id.clone();
+                    let clean_start = connect.clean_start;
+                    let max_outbound_size =
+                        connect.max_packet_size.map(|v| v.get()).unwrap_or(0);
 
-                    // authenticate mqtt connection
-                    let mut ack = service
-                        .call(Handshake::new(
-                            connect,
-                            io,
-                            shared,
-                            max_size,
-                            max_receive,
-                            max_topic_alias,
-                        ))
-                        .await
-                        .map_err(MqttError::Service)?;
+                    let hnd = Handshake::new(
+                        connect,
+                        io,
+                        shared,
+                        max_size,
+                        max_receive,
+                        max_topic_alias,
+                    );
+                    let mut ack = if hnd.shared.is_banned(&client_id) {
+                        log::trace!("{}: Client is banned: {:?}", id, client_id);
+                        hnd.failed(mqtt::ConnectAckReason::Banned)
+                    } else {
+                        // authenticate mqtt connection
+                        let permit = handshakes.acquire().await;
+                        let ack = service.call(hnd).await.map_err(MqttError::Service)?;
+                        drop(permit);
+                        ack
+                    };
 
                     match ack.session {
                         Some(session) => {
-                            log::trace!("Sending: {:#?}", ack.packet);
+                            pre_connack.check(&ack.shared.io).map_err(MqttError::Protocol)?;
+                            log::trace!("{}: Sending: {:#?}", id, ack.packet);
                             let shared = ack.shared;
 
-                            max_topic_alias = ack.packet.topic_alias_max;
-
                             if ack.packet.max_qos.is_none() {
                                 ack.packet.max_qos = max_qos;
                             }
+                            shared
+                                .retain_available
+                                .set(ack.packet.retain_available.unwrap_or(true));
 
-                            if let Some(num) = ack.packet.receive_max {
-                                max_receive = num.get();
-                            } else {
-                                max_receive = 0;
-                            }
                             if let Some(size) = ack.packet.max_packet_size {
                                 shared.codec.set_max_inbound_size(size);
                             }
@@ -421,27 +1105,44 @@ where
                                 ack.packet.server_keepalive_sec = Some(ack.keepalive as u16);
                             }
 
+                            let client_id =
+                                ack.packet.assigned_client_id.clone().unwrap_or(client_id);
+                            if let Some(expiry) = ack.packet.session_expiry_interval_secs {
+                                shared.session_expiry.set(expiry);
+                            }
+                            let negotiated = NegotiatedConfig {
+                                keepalive: Seconds(ack.keepalive),
+                                max_inbound_size: ack
+                                    .packet
+                                    .max_packet_size
+                                    .unwrap_or(max_size),
+                                max_outbound_size,
+                                clean_start,
+                                client_id: client_id.clone(),
+                                ..negotiated(&shared, &ack.packet)
+                            };
                             ack.io
                                 .send(
                                     mqtt::Packet::ConnectAck(Box::new(ack.packet)),
                                     &shared.codec,
                                 )
                                 .await?;
+                            *shared.events.borrow_mut() =
+                                events.connected(&shared.io, client_id);
 
                             Ok((
                                 ack.io,
                                 shared.clone(),
-                                Session::new_v5(
-                                    session,
-                                    MqttSink::new(shared),
-                                    max_receive,
-                                    max_topic_alias,
-                                ),
+                                Session::new(session, MqttSink::new(shared), negotiated),
                                 Seconds(ack.keepalive),
                             ))
                         }
                         None => {
-                            log::trace!("Failed to complete handshake: {:#?}", ack.packet);
+                            log::trace!(
+                                "{}: Failed to complete handshake: {:#?}",
+                                id,
+                                ack.packet
+                            );
 
                             ack.io
                                 .send(
@@ -455,7 +1156,7 @@ where
                     }
                 }
                 packet => {
-                    log::info!("MQTT-3.1.0-1: Expected CONNECT packet, received {}", 1);
+                    log::info!("{}: MQTT-3.1.0-1: Expected CONNECT packet, received {}", id, 1);
                     Err(MqttError::Protocol(ProtocolError::Unexpected(
                         packet.packet_type(),
                         "MQTT-3.1.0-1: Expected CONNECT packet",
@@ -478,11 +1179,21 @@ pub(crate) struct ServerSelector<St, C, T, F, R> {
     connect: C,
     handler: Rc<T>,
     check: Rc<F>,
+    handshake_timeout: Option<Seconds>,
+    io_timeouts: (Seconds, Seconds),
     max_size: u32,
     max_receive: u16,
     max_qos: Option<QoS>,
     disconnect_timeout: Seconds,
     max_topic_alias: u16,
+    handshakes: HandshakeLimit,
+    drain: Drain,
+    pre_connack: PreConnackPublishPolicy,
+    max_size_handle: Option<MaxSizeHandle>,
+    ban_list: Option<Rc<dyn BanList>>,
+    events: Rc<LifecycleChannel>,
+    codec_timing: Option<CodecTiming>,
+    metrics: Option<MetricsHandle>,
     _t: PhantomData<(St, R)>,
 }
 
@@ -490,7 +1201,7 @@ impl<St, C, T, F, R> ServiceFactory<SelectItem> for ServerSelector<St, C, T, F,
 where
     St: 'static,
     F: Fn(&Handshake) -> R + 'static,
-    R: Future<Output = Result<bool, C::Error>>,
+    R: Future<Output = Result<Selected, C::Error>>,
     C: ServiceFactory<Handshake, Response = HandshakeAck<St>> + 'static,
     C::Error: fmt::Debug,
     T: ServiceFactory<
@@ -511,22 +1222,42 @@ where
         let fut = self.connect.new_service(());
         let handler = self.handler.clone();
         let check = self.check.clone();
+        let handshake_timeout = self.handshake_timeout;
+        let io_timeouts = self.io_timeouts;
         let max_size = self.max_size;
         let max_receive = self.max_receive;
         let max_qos = self.max_qos;
         let max_topic_alias = self.max_topic_alias;
         let disconnect_timeout = self.disconnect_timeout;
+        let handshakes = self.handshakes.clone();
+        let drain = self.drain.clone();
+        let pre_connack = self.pre_connack;
+        let max_size_handle = self.max_size_handle.clone();
+        let ban_list = self.ban_list.clone();
+        let events = self.events.clone();
+        let codec_timing = self.codec_timing.clone();
+        let metrics = self.metrics.clone();
 
         // create connect service and then create service impl
         Box::pin(async move {
             Ok(ServerSelectorImpl {
                 handler,
                 check,
+                handshake_timeout,
+                io_timeouts,
                 max_size,
                 max_receive,
                 max_qos,
                 max_topic_alias,
                 disconnect_timeout,
+                handshakes,
+                drain,
+                pre_connack,
+                max_size_handle,
+                ban_list,
+                events,
+                codec_timing,
+                metrics,
                 connect: Rc::new(fut.await?),
                 _t: PhantomData,
             })
@@ -536,6 +1267,8 @@ where
 
 pub(crate) struct ServerSelectorImpl<St, C, T, F, R> {
     check: Rc<F>,
+    handshake_timeout: Option<Seconds>,
+    io_timeouts: (Seconds, Seconds),
     connect: Rc<C>,
     handler: Rc<T>,
     max_size: u32,
@@ -543,6 +1276,14 @@ pub(crate) struct ServerSelectorImpl<St, C, T, F, R> {
     max_qos: Option<QoS>,
     disconnect_timeout: Seconds,
     max_topic_alias: u16,
+    handshakes: HandshakeLimit,
+    drain: Drain,
+    pre_connack: PreConnackPublishPolicy,
+    max_size_handle: Option<MaxSizeHandle>,
+    ban_list: Option<Rc<dyn BanList>>,
+    events: Rc<LifecycleChannel>,
+    codec_timing: Option<CodecTiming>,
+    metrics: Option<MetricsHandle>,
     _t: PhantomData<(St, R)>,
 }
 
@@ -550,7 +1291,7 @@ impl<St, C, T, F, R> Service<SelectItem> for ServerSelectorImpl<St, C, T, F, R>
 where
     St: 'static,
     F: Fn(&Handshake) -> R + 'static,
-    R: Future<Output = Result<bool, C::Error>>,
+    R: Future<Output = Result<Selected, C::Error>>,
     C: Service<Handshake, Response = HandshakeAck<St>> + 'static,
     C::Error: fmt::Debug,
     T: ServiceFactory<
@@ -572,21 +1313,37 @@ where
 
     #[inline]
     fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
-        self.connect.poll_shutdown(cx, is_error)
+        let ready1 = self.drain.poll_shutdown(cx).is_ready();
+        let ready2 = self.connect.poll_shutdown(cx, is_error).is_ready();
+        if ready1 && ready2 {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
     }
 
     #[inline]
     fn call(&self, req: SelectItem) -> Self::Future {
-        log::trace!("Start connection handshake");
+        let id = req.0.shared.id;
+        log::trace!("{}: Start connection handshake", id);
 
         let check = self.check.clone();
+        let handshake_timeout = self.handshake_timeout;
+        let io_timeouts = self.io_timeouts;
         let connect = self.connect.clone();
         let handler = self.handler.clone();
         let timeout = self.disconnect_timeout;
         let max_qos = self.max_qos;
-        let max_size = self.max_size;
-        let mut max_receive = self.max_receive;
-        let mut max_topic_alias = self.max_topic_alias;
+        let max_receive = self.max_receive;
+        let max_topic_alias = self.max_topic_alias;
+        let handshakes = self.handshakes.clone();
+        let pre_connack = self.pre_connack;
+        let max_size_handle = self.max_size_handle.clone();
+        let ban_list = self.ban_list.clone();
+        let events = self.events.clone();
+        let codec_timing = self.codec_timing.clone();
+        let metrics = self.metrics.clone();
+        let max_size = max_size_handle.as_ref().map_or(self.max_size, |h| h.get());
 
         Box::pin(async move {
             let (mut hnd, mut delay) = req;
@@ -596,89 +1353,157 @@ where
                 Either::Right(_) => return Err(MqttError::HandshakeTimeout),
             };
 
-            if !result.map_err(MqttError::Service)? {
-                Ok(Either::Left((hnd, delay)))
-            } else {
-                // set max outbound (encoder) packet size
-                if let Some(size) = hnd.packet().max_packet_size {
-                    hnd.shared.codec.set_max_outbound_size(size.get());
-                }
-                hnd.shared
-                    .cap
-                    .set(hnd.packet().receive_max.map(|v| v.get()).unwrap_or(16) as usize);
-
-                let keep_alive = hnd.packet().keep_alive;
-                hnd.max_size = max_size;
-                hnd.max_receive = max_receive;
-                hnd.max_topic_alias = max_topic_alias;
-
-                // authenticate mqtt connection
-                let mut ack = match select(connect.call(hnd), &mut delay).await {
-                    Either::Left(res) => res.map_err(|e| {
-                        log::trace!("Connection handshake failed: {:?}", e);
-                        MqttError::Service(e)
-                    })?,
-                    Either::Right(_) => return Err(MqttError::HandshakeTimeout),
-                };
-
-                match ack.session {
-                    Some(session) => {
-                        log::trace!("Sending: {:#?}", ack.packet);
-                        let shared = ack.shared;
-
-                        max_topic_alias = ack.packet.topic_alias_max;
-
-                        if ack.packet.max_qos.is_none() {
-                            ack.packet.max_qos = max_qos;
-                        }
+            match result.map_err(MqttError::Service)? {
+                None => Ok(Either::Left((hnd, delay))),
+                Some(data) => {
+                    hnd.data = data;
+                    // variant's own timeout replaces selector's one
+                    if let Some(timeout) = handshake_timeout {
+                        delay = Deadline::new(timeout.into());
+                    }
+                    // set max outbound (encoder) packet size
+                    if let Some(size) = hnd.packet().max_packet_size {
+                        hnd.shared.codec.set_max_outbound_size(size.get());
+                    }
+                    hnd.shared
+                        .cap
+                        .set(hnd.packet().receive_max.map(|v| v.get()).unwrap_or(16) as usize);
+                    hnd.shared.set_topic_alias_max(hnd.packet().topic_alias_max);
 
-                        if let Some(num) = ack.packet.receive_max {
-                            max_receive = num.get();
-                        } else {
-                            max_receive = 0;
-                        }
-                        if let Some(size) = ack.packet.max_packet_size {
-                            shared.codec.set_max_inbound_size(size);
+                    let keep_alive = hnd.packet().keep_alive;
+                    let clean_start = hnd.packet().clean_start;
+                    let client_id = hnd.packet().client_id.clone();
+                    let max_outbound_size =
+                        hnd.packet().max_packet_size.map(|v| v.get()).unwrap_or(0);
+                    hnd.max_size = max_size;
+                    hnd.max_receive = max_receive;
+                    hnd.max_topic_alias = max_topic_alias;
+
+                    // authenticate mqtt connection
+                    *hnd.shared.ban_list.borrow_mut() = ban_list;
+                    hnd.shared.read_timeout.set(io_timeouts.0);
+                    hnd.shared.write_timeout.set(io_timeouts.1);
+                    hnd.shared.codec.set_codec_timing(codec_timing);
+                    hnd.shared.codec.set_metrics(metrics);
+                    let fut = async move {
+                        if hnd.shared.is_banned(&hnd.packet().client_id) {
+                            log::trace!(
+                                "{}: Client is banned: {:?}",
+                                id,
+                                hnd.packet().client_id
+                            );
+                            return Ok(hnd.failed(mqtt::ConnectAckReason::Banned));
                         }
-                        if ack.packet.server_keepalive_sec.is_none()
-                            && (keep_alive > ack.keepalive as u16)
-                        {
-                            ack.packet.server_keepalive_sec = Some(ack.keepalive as u16);
+                        let _permit = handshakes.acquire().await;
+                        connect.call(hnd).await
+                    };
+                    let mut ack = match select(fut, &mut delay).await {
+                        Either::Left(res) => res.map_err(|e| {
+                            log::trace!("{}: Connection handshake failed: {:?}", id, e);
+                            MqttError::Service(e)
+                        })?,
+                        Either::Right(_) => return Err(MqttError::HandshakeTimeout),
+                    };
+
+                    match ack.session {
+                        Some(session) => {
+                            pre_connack.check(&ack.shared.io).map_err(MqttError::Protocol)?;
+                            log::trace!("{}: Sending: {:#?}", id, ack.packet);
+                            let shared = ack.shared;
+
+                            if ack.packet.max_qos.is_none() {
+                                ack.packet.max_qos = max_qos;
+                            }
+                            shared
+                                .retain_available
+                                .set(ack.packet.retain_available.unwrap_or(true));
+
+                            if let Some(size) = ack.packet.max_packet_size {
+                                shared.codec.set_max_inbound_size(size);
+                            }
+                            if ack.packet.server_keepalive_sec.is_none()
+                                && (keep_alive > ack.keepalive as u16)
+                            {
+                                ack.packet.server_keepalive_sec = Some(ack.keepalive as u16);
+                            }
+
+                            if let Some(expiry) = ack.packet.session_expiry_interval_secs {
+                                shared.session_expiry.set(expiry);
+                            }
+                            let negotiated = NegotiatedConfig {
+                                keepalive: Seconds(ack.keepalive),
+                                max_inbound_size: ack
+                                    .packet
+                                    .max_packet_size
+                                    .unwrap_or(max_size),
+                                max_outbound_size,
+                                clean_start,
+                                client_id: ack
+                                    .packet
+                                    .assigned_client_id
+                                    .clone()
+                                    .unwrap_or(client_id),
+                                ..negotiated(&shared, &ack.packet)
+                            };
+
+                            ack.io
+                                .send(
+                                    mqtt::Packet::ConnectAck(Box::new(ack.packet)),
+                                    &shared.codec,
+                                )
+                                .await?;
+                            *shared.events.borrow_mut() =
+                                events.connected(&shared.io, negotiated.client_id.clone());
+
+                            let session = Session::new(
+                                session,
+                                MqttSink::new(shared.clone()),
+                                negotiated,
+                            );
+                            let handler = handler.new_service(session).await?;
+                            log::trace!(
+                                "{}: Connection handler is created, starting dispatcher",
+                                id
+                            );
+
+                            Dispatcher::new(ack.io, shared, handler)
+                                .keepalive_timeout(Seconds(ack.keepalive))
+                                .disconnect_timeout(timeout)
+                                .await?;
+                            Ok(Either::Right(()))
                         }
+                        None => {
+                            log::trace!(
+                                "{}: Failed to complete handshake: {:#?}",
+                                id,
+                                ack.packet
+                            );
 
-                        ack.io
-                            .send(mqtt::Packet::ConnectAck(Box::new(ack.packet)), &shared.codec)
-                            .await?;
-
-                        let session = Session::new_v5(
-                            session,
-                            MqttSink::new(shared.clone()),
-                            max_receive,
-                            max_topic_alias,
-                        );
-                        let handler = handler.new_service(session).await?;
-                        log::trace!("Connection handler is created, starting dispatcher");
-
-                        Dispatcher::new(ack.io, shared, handler)
-                            .keepalive_timeout(Seconds(ack.keepalive))
-                            .disconnect_timeout(timeout)
-                            .await?;
-                        Ok(Either::Right(()))
-                    }
-                    None => {
-                        log::trace!("Failed to complete handshake: {:#?}", ack.packet);
-
-                        ack.io
-                            .send(
-                                mqtt::Packet::ConnectAck(Box::new(ack.packet)),
-                                &ack.shared.codec,
-                            )
-                            .await?;
-                        let _ = ack.io.shutdown().await;
-                        Err(MqttError::Disconnected(None))
+                            ack.io
+                                .send(
+                                    mqtt::Packet::ConnectAck(Box::new(ack.packet)),
+                                    &ack.shared.codec,
+                                )
+                                .await?;
+                            let _ = ack.io.shutdown().await;
+                            Err(MqttError::Disconnected(None))
+                        }
                     }
                 }
             }
         })
     }
 }
+
+/// Negotiated parameters defined by `ConnectAck` packet
+fn negotiated(shared: &MqttShared, ack: &mqtt::ConnectAck) -> NegotiatedConfig {
+    NegotiatedConfig {
+        protocol_level: MQTT_LEVEL_5,
+        receive_max: ack.receive_max.map(|v| v.get()).unwrap_or(0),
+        peer_receive_max: shared.cap.get() as u16,
+        topic_alias_max: ack.topic_alias_max,
+        session_expiry: shared.session_expiry.get(),
+        max_qos: ack.max_qos,
+        ..Default::default()
+    }
+}
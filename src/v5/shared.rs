@@ -1,32 +1,77 @@
-use std::{cell::Cell, cell::RefCell, collections::VecDeque, rc::Rc};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+use std::{any::Any, cell::Cell, cell::RefCell, num::NonZeroU16, rc::Rc};
 
 use ntex::channel::pool;
 use ntex::codec::{Decoder, Encoder};
-use ntex::io::IoRef;
-use ntex::util::{BytesMut, HashMap, PoolId, PoolRef};
+use ntex::io::{IoBoxed, IoRef};
+use ntex::time::Seconds;
+use ntex::util::{ByteString, Bytes, BytesMut, HashMap, HashSet, PoolId, PoolRef};
 
 use super::codec;
-use crate::{error, types::packet_type};
+use crate::ban::{peer_ip, BanList};
+use crate::events::{LifecycleEmitter, LifecycleEventKind};
+use crate::types::{packet_type, IdleAction, PacketMask, QoS, TopicRewrite};
+use crate::utils::{self, next_connection_id};
+use crate::{error, io::KeepAlive};
+
+type PayloadFn = Box<dyn Fn(Bytes) -> Bytes>;
 
 pub struct MqttShared {
+    pub(super) id: u64,
     pub(super) io: IoRef,
     pub(super) cap: Cell<usize>,
     queues: RefCell<MqttSharedQueues>,
     pub(super) inflight_idx: Cell<u16>,
+    // packet ids reserved by publish batch
+    reserved_ids: RefCell<HashSet<u16>>,
     pub(super) pool: Rc<MqttSinkPool>,
     pub(super) codec: codec::Codec,
+    pub(super) keepalive: Cell<Option<Seconds>>,
+    pub(super) keepalive_timeout: Cell<Option<Seconds>>,
+    pub(super) local_close: Cell<bool>,
+    // client side of connection
+    pub(super) client: Cell<bool>,
+    pub(super) read_timeout: Cell<Seconds>,
+    pub(super) write_timeout: Cell<Seconds>,
+    pub(super) on_idle: RefCell<Option<Box<dyn Fn() -> IdleAction>>>,
+    pub(super) allowed_packets: Cell<PacketMask>,
+    pub(super) events: RefCell<Option<LifecycleEmitter>>,
+    pub(super) last_ping: Cell<Option<Instant>>,
+    pub(super) subscriptions: RefCell<BTreeMap<ByteString, QoS>>,
+    pub(super) ban_list: RefCell<Option<Rc<dyn BanList>>>,
+    pub(super) topic_rewrite: RefCell<(Option<TopicRewrite>, Option<TopicRewrite>)>,
+    pub(super) will: RefCell<Option<codec::LastWill>>,
+    pub(super) session_expiry: Cell<u32>,
+    pub(super) retain_available: Cell<bool>,
+    pub(super) payload: RefCell<Option<(PayloadFn, PayloadFn)>>,
+    pub(super) topic_aliases: RefCell<TopicAliases>,
+    // eviction callback of the session, `Box<dyn FnOnce(St)>`
+    pub(super) evicted: RefCell<Option<Box<dyn Any>>>,
+}
+
+/// Outbound topic aliases, limited by peer's topic alias maximum
+#[derive(Default)]
+pub(super) struct TopicAliases {
+    max: u16,
+    aliases: HashMap<ByteString, NonZeroU16>,
 }
 
 pub(super) struct MqttSharedQueues {
     pub(super) inflight: HashMap<u16, (pool::Sender<Ack>, AckType)>,
     pub(super) inflight_order: VecDeque<u16>,
     pub(super) waiters: VecDeque<pool::Sender<()>>,
+    pub(super) idle_waiters: Vec<pool::Sender<()>>,
+    pub(super) ping_sent: Option<Instant>,
+    pub(super) ping_waiters: Vec<pool::Sender<Duration>>,
 }
 
 pub(super) struct MqttSinkPool {
     pub(super) queue: pool::Pool<Ack>,
     pub(super) waiters: pool::Pool<()>,
+    pub(super) pings: pool::Pool<Duration>,
     pub(super) pool: Cell<PoolRef>,
+    pub(super) memory_pool: Cell<Option<PoolRef>>,
 }
 
 impl Default for MqttSinkPool {
@@ -34,7 +79,18 @@ impl Default for MqttSinkPool {
         Self {
             queue: pool::new(),
             waiters: pool::new(),
+            pings: pool::new(),
             pool: Cell::new(PoolId::P5.pool_ref()),
+            memory_pool: Cell::new(None),
+        }
+    }
+}
+
+impl MqttSinkPool {
+    /// Use configured memory pool for server connection
+    pub(super) fn set_memory_pool(&self, io: &IoBoxed) {
+        if let Some(pool) = self.memory_pool.get() {
+            io.set_memory_pool(pool);
         }
     }
 }
@@ -47,16 +103,63 @@ impl MqttShared {
         pool: Rc<MqttSinkPool>,
     ) -> Self {
         Self {
+            id: next_connection_id(),
             io,
             pool,
             codec,
+            keepalive: Cell::new(None),
+            keepalive_timeout: Cell::new(None),
+            local_close: Cell::new(false),
+            client: Cell::new(false),
+            read_timeout: Cell::new(Seconds::ZERO),
+            write_timeout: Cell::new(Seconds::ZERO),
+            on_idle: RefCell::new(None),
+            allowed_packets: Cell::new(PacketMask::all()),
+            events: RefCell::new(None),
+            last_ping: Cell::new(None),
+            subscriptions: RefCell::new(BTreeMap::new()),
+            ban_list: RefCell::new(None),
+            topic_rewrite: RefCell::new((None, None)),
+            will: RefCell::new(None),
+            session_expiry: Cell::new(0),
+            retain_available: Cell::new(true),
+            payload: RefCell::new(None),
+            topic_aliases: RefCell::new(TopicAliases::default()),
+            evicted: RefCell::new(None),
             cap: Cell::new(cap),
             queues: RefCell::new(MqttSharedQueues {
                 inflight: HashMap::default(),
                 inflight_order: VecDeque::with_capacity(8),
                 waiters: VecDeque::new(),
+                idle_waiters: Vec::new(),
+                ping_sent: None,
+                ping_waiters: Vec::new(),
             }),
             inflight_idx: Cell::new(0),
+            reserved_ids: RefCell::new(HashSet::default()),
+        }
+    }
+
+    /// Mark connection as closed by this side of connection
+    pub(super) fn closed_locally(&self) {
+        if !self.io.is_closed() {
+            self.local_close.set(true);
+        }
+    }
+
+    /// Emit connection lifecycle event
+    pub(super) fn lifecycle_event(&self, kind: LifecycleEventKind) {
+        match kind {
+            LifecycleEventKind::SubscriptionAdded { ref topic, qos } => {
+                self.subscriptions.borrow_mut().insert(topic.clone(), qos);
+            }
+            LifecycleEventKind::SubscriptionRemoved { ref topic } => {
+                self.subscriptions.borrow_mut().remove(topic);
+            }
+            _ => (),
+        }
+        if let Some(ref emitter) = *self.events.borrow() {
+            emitter.emit(kind);
         }
     }
 
@@ -65,23 +168,174 @@ impl MqttShared {
         f(&mut queues)
     }
 
+    /// Check if client is in server's ban list
+    pub(super) fn is_banned(&self, client_id: &str) -> bool {
+        if let Some(ref bans) = *self.ban_list.borrow() {
+            bans.is_banned(client_id, peer_ip(&self.io))
+        } else {
+            false
+        }
+    }
+
+    /// Apply inbound topic rewrite
+    pub(super) fn inbound_topic(&self, topic: &mut ByteString) {
+        if let Some(ref f) = self.topic_rewrite.borrow().0 {
+            f.apply(topic)
+        }
+    }
+
+    /// Apply outbound topic rewrite
+    pub(super) fn outbound_topic(&self, topic: &mut ByteString) {
+        if let Some(ref f) = self.topic_rewrite.borrow().1 {
+            f.apply(topic)
+        }
+    }
+
+    /// Set topic alias maximum of the peer, previously assigned aliases are dropped
+    pub(super) fn set_topic_alias_max(&self, max: u16) {
+        *self.topic_aliases.borrow_mut() = TopicAliases { max, aliases: HashMap::default() };
+    }
+
+    /// Assign topic alias to outbound publish.
+    ///
+    /// Topic is sent along with new alias, and is omitted once alias is assigned.
+    /// If all aliases are in use, publish is sent with full topic. New alias is
+    /// returned, it must be registered with `register_alias()` after publish is encoded.
+    pub(super) fn outbound_alias(
+        &self,
+        packet: &mut codec::Publish,
+    ) -> Option<(ByteString, NonZeroU16)> {
+        let aliases = self.topic_aliases.borrow();
+        if let Some(alias) = aliases.aliases.get(&packet.topic) {
+            packet.properties.topic_alias = Some(*alias);
+            packet.topic = ByteString::new();
+            None
+        } else if aliases.aliases.len() < aliases.max as usize {
+            let alias = NonZeroU16::new(aliases.aliases.len() as u16 + 1).unwrap();
+            packet.properties.topic_alias = Some(alias);
+            Some((packet.topic.clone(), alias))
+        } else {
+            None
+        }
+    }
+
+    /// Register topic alias, alias is known to the peer once publish is sent
+    pub(super) fn register_alias(&self, alias: Option<(ByteString, NonZeroU16)>) {
+        if let Some((topic, alias)) = alias {
+            self.topic_aliases.borrow_mut().aliases.insert(topic, alias);
+        }
+    }
+
+    /// Apply inbound payload transform
+    pub(super) fn inbound_payload(&self, payload: Bytes) -> Bytes {
+        if let Some((ref f, _)) = *self.payload.borrow() {
+            f(payload)
+        } else {
+            payload
+        }
+    }
+
+    /// Apply outbound payload transform
+    pub(super) fn outbound_payload(&self, payload: Bytes) -> Bytes {
+        if let Some((_, ref f)) = *self.payload.borrow() {
+            f(payload)
+        } else {
+            payload
+        }
+    }
+
+    /// Remove in-flight packet that could not be encoded
+    pub(super) fn cancel_inflight(&self, idx: u16) {
+        self.with_queues(|queues| {
+            utils::cancel_inflight(
+                idx,
+                &mut queues.inflight,
+                &mut queues.inflight_order,
+                &mut queues.waiters,
+                &mut queues.idle_waiters,
+            );
+        })
+    }
+
     pub(super) fn has_credit(&self) -> bool {
         self.cap.get() - self.queues.borrow().inflight.len() > 0
     }
 
+    /// Reserve block of sequential packet ids.
+    ///
+    /// Reserved ids are skipped by `next_id()` until they are taken
+    /// from `ReservedIds` or it is dropped.
+    pub(super) fn reserve_ids(self: &Rc<Self>, count: usize) -> ReservedIds {
+        let ids = (0..count)
+            .map(|_| {
+                let idx = self.next_id();
+                self.reserved_ids.borrow_mut().insert(idx);
+                idx
+            })
+            .collect();
+        ReservedIds { shared: self.clone(), ids }
+    }
+
     pub(super) fn next_id(&self) -> u16 {
-        let idx = self.inflight_idx.get() + 1;
-        self.inflight_idx.set(idx);
-        if idx == u16::max_value() {
-            self.inflight_idx.set(0);
-            u16::max_value()
-        } else {
-            self.inflight_idx.set(idx);
-            idx
+        let reserved = self.reserved_ids.borrow();
+        loop {
+            let idx = self.inflight_idx.get() + 1;
+            let idx = if idx == u16::max_value() {
+                self.inflight_idx.set(0);
+                u16::max_value()
+            } else {
+                self.inflight_idx.set(idx);
+                idx
+            };
+            if !reserved.contains(&idx) || reserved.len() >= u16::max_value() as usize {
+                return idx;
+            }
+        }
+    }
+}
+
+/// Packet ids reserved by `MqttShared::reserve_ids()`, unused ids are released on drop
+pub(super) struct ReservedIds {
+    shared: Rc<MqttShared>,
+    ids: VecDeque<u16>,
+}
+
+impl ReservedIds {
+    /// Take next reserved packet id
+    pub(super) fn take(&mut self) -> Option<NonZeroU16> {
+        let idx = self.ids.pop_front()?;
+        self.shared.reserved_ids.borrow_mut().remove(&idx);
+        NonZeroU16::new(idx)
+    }
+}
+
+impl Drop for ReservedIds {
+    fn drop(&mut self) {
+        let mut reserved = self.shared.reserved_ids.borrow_mut();
+        for idx in self.ids.drain(..) {
+            reserved.remove(&idx);
         }
     }
 }
 
+impl KeepAlive for Rc<MqttShared> {
+    fn take_keepalive(&self) -> Option<Seconds> {
+        self.keepalive.take()
+    }
+
+    fn read_timeout(&self) -> Seconds {
+        self.read_timeout.get()
+    }
+
+    fn write_timeout(&self) -> Seconds {
+        self.write_timeout.get()
+    }
+
+    fn connection_id(&self) -> u64 {
+        self.id
+    }
+}
+
 impl Encoder for MqttShared {
     type Item = codec::Packet;
     type Error = error::EncodeError;
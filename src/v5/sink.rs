@@ -1,12 +1,17 @@
+use std::collections::BTreeMap;
 use std::future::{ready, Future};
-use std::{fmt, num::NonZeroU16, num::NonZeroU32, rc::Rc};
+use std::time::{Duration, Instant};
+use std::{fmt, mem, num::NonZeroU16, num::NonZeroU32, rc::Rc};
 
-use ntex::util::{ByteString, Bytes, Either, Ready};
+use ntex::time::{sleep, Seconds};
+use ntex::util::{join_all, select, ByteString, Bytes, Either, Ready};
 
 use super::codec;
 use super::error::{ProtocolError, PublishQos1Error, SendPacketError};
 use super::shared::{Ack, AckType, MqttShared};
-use crate::types::QoS;
+use crate::ban::peer_ip;
+use crate::events::LifecycleEventKind;
+use crate::types::{IdleAction, PacketMask, QoS, TopicRewrite};
 
 pub struct MqttSink(Rc<MqttShared>);
 
@@ -21,6 +26,11 @@ impl MqttSink {
         MqttSink(state)
     }
 
+    /// Connection id, see `Session::connection_id()`
+    pub fn connection_id(&self) -> u64 {
+        self.0.id
+    }
+
     /// Check connection status
     pub fn is_open(&self) -> bool {
         !self.0.io.is_closed()
@@ -53,9 +63,137 @@ impl MqttSink {
         }
     }
 
+    /// Set keep-alive timeout for the connection.
+    ///
+    /// Running keep-alive timer is restarted with new timeout.
+    /// To disable timeout set value to 0.
+    pub fn set_keepalive(&self, timeout: Seconds) {
+        self.0.io.start_keepalive_timer(timeout.into());
+        self.0.keepalive.set(Some(timeout));
+        self.0.keepalive_timeout.set(Some(timeout));
+    }
+
+    /// Set packet types peer is allowed to send.
+    ///
+    /// Disallowed packet is handled as protocol error.
+    pub fn set_allowed_packets(&self, mask: PacketMask) {
+        self.0.allowed_packets.set(mask);
+    }
+
+    /// Check if connection is closed by this side of connection
+    pub(super) fn is_closed_locally(&self) -> bool {
+        self.0.local_close.get()
+    }
+
+    /// Keep-alive timeout set with `set_keepalive()`
+    pub(crate) fn keepalive_timeout(&self) -> Option<Seconds> {
+        self.0.keepalive_timeout.get()
+    }
+
+    /// Time of last PINGREQ packet received from peer
+    pub(crate) fn last_ping_at(&self) -> Option<Instant> {
+        self.0.last_ping.get()
+    }
+
+    /// Topic filters confirmed by control service
+    pub(crate) fn subscriptions(&self) -> BTreeMap<ByteString, QoS> {
+        self.0.subscriptions.borrow().clone()
+    }
+
+    /// Ban client in server's ban list, returns `false` if ban list is not set
+    pub(crate) fn quarantine(&self, client_id: &str, duration: Seconds) -> bool {
+        if let Some(ref bans) = *self.0.ban_list.borrow() {
+            bans.ban(client_id, peer_ip(&self.0.io), duration);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Report protocol error to connection metrics
+    pub(super) fn protocol_error(&self, err: &ProtocolError) {
+        self.0.codec.report_error(err);
+    }
+
+    /// Record received PINGREQ packet
+    pub(super) fn ping_received(&self) -> Instant {
+        let now = Instant::now();
+        self.0.last_ping.set(Some(now));
+        now
+    }
+
+    /// Check if peer is allowed to send packet type
+    pub(super) fn is_packet_allowed(&self, packet_type: u8) -> bool {
+        self.0.allowed_packets.get().is_allowed(packet_type)
+    }
+
+    /// Size of write buffer in bytes, not yet flushed to the peer
+    pub fn pending_write_bytes(&self) -> usize {
+        self.0.io.with_write_buf(|buf| buf.len()).unwrap_or(0)
+    }
+
+    /// Number of in-flight packets, not yet acknowledged by the peer
+    pub fn inflight_count(&self) -> usize {
+        self.0.with_queues(|q| q.inflight.len())
+    }
+
+    /// Emit connection lifecycle event
+    pub(super) fn lifecycle_event(&self, kind: LifecycleEventKind) {
+        self.0.lifecycle_event(kind)
+    }
+
+    /// Check if packet id is in-flight
+    pub(super) fn is_inflight(&self, id: u16) -> bool {
+        self.0.with_queues(|q| q.inflight.contains_key(&id))
+    }
+
+    /// Check if publish packet is next in the read buffer
+    pub(super) fn is_publish_next(&self) -> bool {
+        self.0.io.with_read_buf(|buf| self.0.codec.is_publish_next(buf))
+    }
+
+    /// Get notification when all in-flight packets get acknowledged by the peer.
+    ///
+    /// Result indicates if connection is alive
+    pub fn on_idle(&self) -> impl Future<Output = bool> {
+        if !self.0.io.is_closed() {
+            self.0
+                .with_queues(|q| {
+                    if !q.inflight.is_empty() {
+                        let (tx, rx) = self.0.pool.waiters.channel();
+                        q.idle_waiters.push(tx);
+                        return Some(rx);
+                    }
+                    None
+                })
+                .map(|rx| Either::Right(async move { rx.await.is_ok() }))
+                .unwrap_or_else(|| Either::Left(ready(true)))
+        } else {
+            Either::Left(ready(false))
+        }
+    }
+
+    /// Close connection when connection lifetime `timeout` is reached
+    pub(super) fn close_after(&self, timeout: Seconds) {
+        if timeout.is_zero() {
+            return;
+        }
+        let sink = self.clone();
+        let on_disconnect = self.0.io.on_disconnect();
+        ntex::rt::spawn(async move {
+            if let Either::Left(_) = select(sleep(timeout), on_disconnect).await {
+                log::trace!("{}: Max connection lifetime is reached, closing", sink.0.id);
+                sink.close_with_reason(codec::Disconnect::new(
+                    codec::DisconnectReasonCode::MaximumConnectTime,
+                ));
+            }
+        });
+    }
+
     /// Close mqtt connection with default Disconnect message
     pub fn close(&self) {
         if self.is_open() {
+            self.0.closed_locally();
             let _ = self
                 .0
                 .io
@@ -65,18 +203,43 @@ impl MqttSink {
         self.0.with_queues(|q| {
             q.inflight.clear();
             q.waiters.clear();
+            q.idle_waiters.clear();
+            q.ping_waiters.clear();
         });
     }
 
     /// Close mqtt connection
+    ///
+    /// Server must not set `session_expiry_interval_secs` of the disconnect
+    /// packet (MQTT-3.14.2-2), session expiry could be changed only with
+    /// `ConnectAck` packet during handshake.
+    ///
+    /// Disconnect packet is queued after already encoded packets and
+    /// gets flushed before io is shut down.
     pub fn close_with_reason(&self, pkt: codec::Disconnect) {
         if self.is_open() {
+            self.0.closed_locally();
             let _ = self.0.io.encode(codec::Packet::Disconnect(pkt), &self.0.codec);
             self.0.io.close();
         }
         self.0.with_queues(|q| {
             q.inflight.clear();
             q.waiters.clear();
+            q.idle_waiters.clear();
+            q.ping_waiters.clear();
+        });
+    }
+
+    /// Force close mqtt connection. mqtt dispatcher does not wait for uncompleted
+    /// responses, but it flushes buffers.
+    pub fn force_close(&self) {
+        self.0.closed_locally();
+        self.0.io.force_close();
+        self.0.with_queues(|q| {
+            q.inflight.clear();
+            q.waiters.clear();
+            q.idle_waiters.clear();
+            q.ping_waiters.clear();
         });
     }
 
@@ -84,15 +247,150 @@ impl MqttSink {
         let _ = self.0.io.encode(pkt, &self.0.codec);
     }
 
-    /// Send ping
-    pub(super) fn ping(&self) -> bool {
-        self.0.io.encode(codec::Packet::PingRequest, &self.0.codec).is_ok()
+    /// Send keep-alive ping, ping is not sent if previous one is not answered yet
+    pub(super) fn keepalive_ping(&self) -> bool {
+        self.0.with_queues(|q| {
+            if q.ping_sent.is_some() {
+                !self.0.io.is_closed()
+            } else if self.0.io.encode(codec::Packet::PingRequest, &self.0.codec).is_ok() {
+                q.ping_sent = Some(Instant::now());
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Send PINGREQ packet and wait for PINGRESP packet.
+    ///
+    /// Returns round-trip time of ping. Only one ping is in flight, concurrent
+    /// calls wait for the same response. Fails if connection is closed before
+    /// response is received. PINGREQ is sent by client only, on server side
+    /// `SendPacketError::NotAllowed` error is returned.
+    pub fn ping(&self) -> impl Future<Output = Result<Duration, SendPacketError>> {
+        if !self.0.client.get() {
+            return Either::Left(Ready::Err(SendPacketError::NotAllowed));
+        }
+        if self.0.io.is_closed() {
+            return Either::Left(Ready::Err(SendPacketError::Disconnected));
+        }
+
+        let rx = self.0.with_queues(|q| {
+            if q.ping_sent.is_none() {
+                self.0
+                    .io
+                    .encode(codec::Packet::PingRequest, &self.0.codec)
+                    .map_err(SendPacketError::Encode)?;
+                q.ping_sent = Some(Instant::now());
+            }
+            let (tx, rx) = self.0.pool.pings.channel();
+            q.ping_waiters.push(tx);
+            Ok(rx)
+        });
+
+        match rx {
+            Ok(rx) => {
+                Either::Right(
+                    async move { rx.await.map_err(|_| SendPacketError::Disconnected) },
+                )
+            }
+            Err(e) => Either::Left(Ready::Err(e)),
+        }
+    }
+
+    /// PINGRESP packet is received, notify ping waiters
+    pub(super) fn ping_response(&self) {
+        self.0.with_queues(|q| {
+            if let Some(sent) = q.ping_sent.take() {
+                let rtt = sent.elapsed();
+                for tx in q.ping_waiters.drain(..) {
+                    let _ = tx.send(rtt);
+                }
+            } else {
+                log::trace!("{}: Unexpected PINGRESP packet", self.0.id);
+            }
+        })
+    }
+
+    /// Take will message of the connection
+    pub(super) fn take_will(&self) -> Option<codec::LastWill> {
+        self.0.will.borrow_mut().take()
+    }
+
+    /// Delay of will message publish, `None` if connection has no will.
+    ///
+    /// Will is published at will delay interval or at session expiry,
+    /// whichever comes first.
+    pub(super) fn will_delay(&self) -> Option<u32> {
+        self.0.will.borrow().as_ref().map(|will| {
+            will.will_delay_interval_sec.unwrap_or(0).min(self.0.session_expiry.get())
+        })
+    }
+
+    /// Check if server advertised retain availability
+    pub(super) fn is_retain_available(&self) -> bool {
+        self.0.retain_available.get()
+    }
+
+    /// Will message of the connection.
+    ///
+    /// Returns `None` if client did not set will or will is discarded.
+    pub fn will(&self) -> Option<codec::LastWill> {
+        self.0.will.borrow().clone()
+    }
+
+    /// Discard will message of the connection.
+    ///
+    /// Will message that is pending for will delay interval is not published,
+    /// for example if client reconnects with the same client id.
+    pub fn discard_will(&self) {
+        self.0.will.borrow_mut().take();
+    }
+
+    /// Set handler for expired keep-alive timer
+    pub(super) fn set_idle_handler(&self, f: Box<dyn Fn() -> IdleAction>) {
+        *self.0.on_idle.borrow_mut() = Some(f);
+    }
+
+    /// Keep-alive timer is expired, returns timeout if handler extends idle period
+    pub(super) fn idle_timeout(&self) -> Option<Seconds> {
+        match self.0.on_idle.borrow().as_ref().map(|f| f()) {
+            Some(IdleAction::Extend(timeout)) if !timeout.is_zero() => Some(timeout),
+            _ => None,
+        }
+    }
+
+    /// Re-arm keep-alive timer, regular timeout is restored once packet is received
+    pub(super) fn extend_keepalive(&self, timeout: Seconds) {
+        log::trace!("{}: Idle period is extended by {:?}", self.0.id, timeout);
+        self.0.io.start_keepalive_timer(timeout.into());
+    }
+
+    /// Set topic rewrite hooks
+    pub(super) fn set_topic_rewrite(
+        &self,
+        ingress: Option<TopicRewrite>,
+        egress: Option<TopicRewrite>,
+    ) {
+        *self.0.topic_rewrite.borrow_mut() = (ingress, egress);
+    }
+
+    /// Apply inbound topic rewrite
+    pub(super) fn inbound_topic(&self, topic: &mut ByteString) {
+        self.0.inbound_topic(topic)
+    }
+
+    /// Apply inbound payload transform
+    pub(super) fn inbound_payload(&self, payload: Bytes) -> Bytes {
+        self.0.inbound_payload(payload)
     }
 
     /// Close mqtt connection, dont send disconnect message
     pub(super) fn drop_sink(&self) {
         self.0.with_queues(|q| {
             q.waiters.clear();
+            q.idle_waiters.clear();
+            q.ping_waiters.clear();
             q.inflight.clear();
         });
         self.0.io.close();
@@ -109,18 +407,19 @@ impl MqttSink {
 
                 if idx != pkt.packet_id() {
                     log::trace!(
-                        "MQTT protocol error, packet_id order does not match, expected {}, got: {}",
+                        "{}: MQTT protocol error, packet_id order does not match, expected {}, got: {}",
+                        self.0.id,
                         idx,
                         pkt.packet_id()
                     );
                 } else {
                     // get publish ack channel
-                    log::trace!("Ack packet with id: {}", pkt.packet_id());
+                    log::trace!("{}: Ack packet with id: {}", self.0.id, pkt.packet_id());
                     let idx = pkt.packet_id();
                     if let Some((tx, tp)) = queues.inflight.remove(&idx) {
                         // cleanup ack queue
                         if !pkt.is_match(tp) {
-                            log::trace!("MQTT protocol error, unexpeted packet");
+                            log::trace!("{}: MQTT protocol error, unexpeted packet", self.0.id);
                             return Err(ProtocolError::Unexpected(
                                 pkt.packet_type(),
                                 tp.name(),
@@ -134,13 +433,20 @@ impl MqttSink {
                                 break;
                             }
                         }
+
+                        // notify idle waiters, all in-flight packets are acked
+                        if queues.inflight.is_empty() {
+                            for tx in queues.idle_waiters.drain(..) {
+                                let _ = tx.send(());
+                            }
+                        }
                         return Ok(());
                     } else {
-                        log::error!("In-flight state inconsistency")
+                        log::error!("{}: In-flight state inconsistency", self.0.id)
                     }
                 }
             } else {
-                log::trace!("Unexpected PublishAck packet");
+                log::trace!("{}: Unexpected PublishAck packet", self.0.id);
             }
             return Err(ProtocolError::PacketIdMismatch);
         })
@@ -151,21 +457,77 @@ impl MqttSink {
     where
         ByteString: From<U>,
     {
-        PublishBuilder {
-            packet: codec::Publish {
-                payload,
-                dup: false,
-                retain: false,
-                topic: topic.into(),
-                qos: QoS::AtMostOnce,
-                packet_id: None,
-                properties: codec::PublishProperties::default(),
-            },
-            shared: self.0.clone(),
+        self.publish_pkt(codec::Publish::build(topic, payload))
+    }
+
+    /// Send batch of publish packets with QoS 1.
+    ///
+    /// Contiguous block of packet ids is reserved for the batch when method is
+    /// called, reserved ids are not used by other packets until batch packets
+    /// are sent. Packets are written in batch order as long as client receive
+    /// credit allows, rest of the batch waits for credit and is sent in next chunk.
+    /// Future resolves when all packets are acked, results are returned in batch
+    /// order. If connection is lost, packets that are not acked resolve with
+    /// `Disconnected` error.
+    pub fn publish_batch<U>(
+        &self,
+        messages: Vec<(U, Bytes)>,
+    ) -> impl Future<Output = Vec<Result<codec::PublishAck, PublishQos1Error>>>
+    where
+        ByteString: From<U>,
+    {
+        let shared = self.0.clone();
+        let mut ids = shared.reserve_ids(messages.len());
+        let packets: Vec<_> = messages
+            .into_iter()
+            .map(|(topic, payload)| {
+                let mut packet = codec::Publish::build(topic, payload);
+                packet.qos = QoS::AtLeastOnce;
+                packet
+            })
+            .collect();
+
+        async move {
+            let mut results = Vec::with_capacity(packets.len());
+            for mut packet in packets {
+                if shared.io.is_closed() {
+                    results.push(Either::Left(Ready::Err(PublishQos1Error::Disconnected)));
+                    continue;
+                }
+
+                // wait until in-flight window has space for next chunk
+                if !shared.has_credit() {
+                    let (tx, rx) = shared.pool.waiters.channel();
+                    shared.with_queues(|q| q.waiters.push_back(tx));
+                    if rx.await.is_err() {
+                        results.push(Either::Left(Ready::Err(PublishQos1Error::Disconnected)));
+                        continue;
+                    }
+                }
+
+                packet.packet_id = ids.take();
+                results.push(Either::Right(PublishBuilder::send_at_least_once_inner(
+                    packet,
+                    shared.clone(),
+                    false,
+                )));
+            }
+            join_all(results).await
         }
     }
 
+    /// Create publish message builder for prepared publish packet
+    ///
+    /// QoS level of the packet is overridden by the send method.
+    pub fn publish_pkt(&self, packet: codec::Publish) -> PublishBuilder {
+        PublishBuilder { packet, shared: self.0.clone(), topic_alias: false }
+    }
+
     /// Create subscribe packet builder
+    ///
+    /// Server could use it to subscribe to topics on connected peer, for example
+    /// for bridging. Subscribe resolves with reason code for each requested filter,
+    /// publishes for subscribed topics are handled by publish service.
     pub fn subscribe(&self, id: Option<NonZeroU32>) -> SubscribeBuilder {
         SubscribeBuilder {
             id: 0,
@@ -193,6 +555,17 @@ impl MqttSink {
     }
 }
 
+impl crate::session::SessionSink for MqttSink {
+    fn set_evicted<St: 'static>(&self, f: Box<dyn FnOnce(St)>) {
+        *self.0.evicted.borrow_mut() = Some(Box::new(f));
+    }
+
+    fn take_evicted<St: 'static>(&self) -> Option<Box<dyn FnOnce(St)>> {
+        let f = self.0.evicted.borrow_mut().take()?;
+        f.downcast::<Box<dyn FnOnce(St)>>().ok().map(|f| *f)
+    }
+}
+
 impl fmt::Debug for MqttSink {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt.debug_struct("MqttSink").finish()
@@ -202,6 +575,7 @@ impl fmt::Debug for MqttSink {
 pub struct PublishBuilder {
     shared: Rc<MqttShared>,
     packet: codec::Publish,
+    topic_alias: bool,
 }
 
 impl PublishBuilder {
@@ -230,6 +604,28 @@ impl PublishBuilder {
         self
     }
 
+    /// Set retain flag.
+    ///
+    /// Server sets retain flag when it sends retained message as a result of
+    /// new subscription, for publishes forwarded to existing subscriptions flag
+    /// depends on subscription's "retain as published" option (MQTT-3.3.1-12,
+    /// MQTT-3.3.1-13).
+    pub fn with_retain(mut self, val: bool) -> Self {
+        self.packet.retain = val;
+        self
+    }
+
+    /// Use topic alias for publish topic.
+    ///
+    /// Alias is assigned on first publish to the topic, following publishes
+    /// are sent with alias only. Number of aliases is limited by topic alias
+    /// maximum of the peer, once all aliases are assigned, publishes to other
+    /// topics are sent with full topic.
+    pub fn use_topic_alias(mut self) -> Self {
+        self.topic_alias = true;
+        self
+    }
+
     /// Set publish packet properties
     pub fn properties<F>(mut self, f: F) -> Self
     where
@@ -249,17 +645,22 @@ impl PublishBuilder {
 
     /// Send publish packet with QoS 0
     pub fn send_at_most_once(self) -> Result<(), SendPacketError> {
-        let packet = self.packet;
+        let mut packet = self.packet;
+        packet.payload = self.shared.outbound_payload(mem::take(&mut packet.payload));
+        self.shared.outbound_topic(&mut packet.topic);
+        let alias =
+            if self.topic_alias { self.shared.outbound_alias(&mut packet) } else { None };
 
         if !self.shared.io.is_closed() {
-            log::trace!("Publish (QoS-0) to {:?}", packet.topic);
+            log::trace!("{}: Publish (QoS-0) to {:?}", self.shared.id, packet.topic);
             self.shared
                 .io
                 .encode(codec::Packet::Publish(packet), &self.shared.codec)
-                .map_err(SendPacketError::Encode)
-                .map(|_| ())
+                .map_err(SendPacketError::Encode)?;
+            self.shared.register_alias(alias);
+            Ok(())
         } else {
-            log::error!("Mqtt sink is disconnected");
+            log::error!("{}: Mqtt sink is disconnected", self.shared.id);
             Err(SendPacketError::Disconnected)
         }
     }
@@ -269,6 +670,7 @@ impl PublishBuilder {
         self,
     ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>> {
         let shared = self.shared;
+        let topic_alias = self.topic_alias;
         let mut packet = self.packet;
         packet.qos = QoS::AtLeastOnce;
 
@@ -282,18 +684,43 @@ impl PublishBuilder {
                     if rx.await.is_err() {
                         return Err(PublishQos1Error::Disconnected);
                     }
-                    Self::send_at_least_once_inner(packet, shared).await
+                    Self::send_at_least_once_inner(packet, shared, topic_alias).await
                 }));
             }
-            Either::Right(Self::send_at_least_once_inner(packet, shared))
+            Either::Right(Self::send_at_least_once_inner(packet, shared, topic_alias))
         } else {
             Either::Left(Either::Left(Ready::Err(PublishQos1Error::Disconnected)))
         }
     }
 
+    /// Send publish packet with QoS which is the minimum of publish QoS
+    /// and subscription's granted QoS.
+    ///
+    /// Packet id is allocated only if effective QoS is greater than QoS 0,
+    /// in that case `PublishAck` packet is returned.
+    /// Sink does not support QoS 2 delivery, such packets are sent with QoS 1.
+    pub fn send_at_min_qos(
+        mut self,
+        qos: QoS,
+        granted: QoS,
+    ) -> impl Future<Output = Result<Option<codec::PublishAck>, PublishQos1Error>> {
+        if qos.min(granted) == QoS::AtMostOnce {
+            self.packet.packet_id = None;
+            let res = self.send_at_most_once().map(|_| None).map_err(|err| match err {
+                SendPacketError::Encode(err) => PublishQos1Error::Encode(err),
+                _ => PublishQos1Error::Disconnected,
+            });
+            Either::Left(Ready::from(res))
+        } else {
+            let fut = self.send_at_least_once();
+            Either::Right(async move { fut.await.map(Some) })
+        }
+    }
+
     fn send_at_least_once_inner(
         mut packet: codec::Publish,
         shared: Rc<MqttShared>,
+        topic_alias: bool,
     ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>> {
         // packet id
         let mut idx = packet.packet_id.map(|i| i.get()).unwrap_or(0);
@@ -320,10 +747,14 @@ impl PublishBuilder {
         };
 
         // send publish to client
-        log::trace!("Publish (QoS1) to {:#?}", packet);
+        packet.payload = shared.outbound_payload(mem::take(&mut packet.payload));
+        shared.outbound_topic(&mut packet.topic);
+        let alias = if topic_alias { shared.outbound_alias(&mut packet) } else { None };
+        log::trace!("{}: Publish (QoS1) to {:#?}", shared.id, packet);
 
         match shared.io.encode(codec::Packet::Publish(packet), &shared.codec) {
             Ok(_) => {
+                shared.register_alias(alias);
                 // wait ack from peer
                 Either::Right(async move {
                     rx.await.map_err(|_| PublishQos1Error::Disconnected).and_then(|pkt| {
@@ -335,7 +766,10 @@ impl PublishBuilder {
                     })
                 })
             }
-            Err(err) => Either::Left(Ready::Err(PublishQos1Error::Encode(err))),
+            Err(err) => {
+                shared.cancel_inflight(idx);
+                Either::Left(Ready::Err(PublishQos1Error::Encode(err)))
+            }
         }
     }
 }
@@ -407,7 +841,7 @@ impl SubscribeBuilder {
             })?;
 
             // send subscribe to client
-            log::trace!("Sending subscribe packet {:#?}", packet);
+            log::trace!("{}: Sending subscribe packet {:#?}", shared.id, packet);
 
             match shared.io.encode(codec::Packet::Subscribe(packet), &shared.codec) {
                 Ok(_) => {
@@ -416,7 +850,10 @@ impl SubscribeBuilder {
                         .map_err(|_| SendPacketError::Disconnected)
                         .map(|pkt| pkt.subscribe())
                 }
-                Err(err) => Err(SendPacketError::Encode(err)),
+                Err(err) => {
+                    shared.cancel_inflight(idx);
+                    Err(SendPacketError::Encode(err))
+                }
             }
         } else {
             Err(SendPacketError::Disconnected)
@@ -487,7 +924,7 @@ impl UnsubscribeBuilder {
             packet.packet_id = NonZeroU16::new(idx).unwrap();
 
             // send unsubscribe to client
-            log::trace!("Sending unsubscribe packet {:#?}", packet);
+            log::trace!("{}: Sending unsubscribe packet {:#?}", shared.id, packet);
 
             match shared.io.encode(codec::Packet::Unsubscribe(packet), &shared.codec) {
                 Ok(_) => {
@@ -496,7 +933,10 @@ impl UnsubscribeBuilder {
                         .map_err(|_| SendPacketError::Disconnected)
                         .map(|pkt| pkt.unsubscribe())
                 }
-                Err(err) => Err(SendPacketError::Encode(err)),
+                Err(err) => {
+                    shared.cancel_inflight(idx);
+                    Err(SendPacketError::Encode(err))
+                }
             }
         } else {
             Err(SendPacketError::Disconnected)
@@ -0,0 +1,229 @@
+//! MQTT over WebSocket transport
+//!
+//! Binary websocket frames are converted to continuous byte stream,
+//! so mqtt codec does not depend on websocket frame boundaries.
+use std::task::{Context, Poll};
+use std::{future::Future, io, pin::Pin, rc::Rc};
+
+use ntex::connect::{Connect, ConnectError};
+use ntex::http::{body::BodySize, h1, header, RequestHead, Response, Uri};
+use ntex::io::{Filter, Io, IoBoxed};
+use ntex::service::{Service, ServiceFactory};
+use ntex::time::{timeout_checked, Seconds};
+use ntex::util::Either;
+use ntex::ws;
+
+use crate::error::MqttError;
+
+/// WebSocket subprotocols supported by mqtt transport
+pub const PROTOCOLS: [&str; 2] = ["mqtt", "mqttv3.1"];
+
+/// Select mqtt subprotocol offered by websocket handshake request
+///
+/// Returns first offered protocol that is supported by mqtt transport.
+pub fn select_protocol(req: &RequestHead) -> Option<&'static str> {
+    req.headers()
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .filter_map(|hdr| hdr.to_str().ok())
+        .flat_map(|hdr| hdr.split(','))
+        .find_map(|proto| supported(proto.trim()))
+}
+
+fn supported(proto: &str) -> Option<&'static str> {
+    PROTOCOLS.iter().find(|p| p.eq_ignore_ascii_case(proto)).copied()
+}
+
+/// WebSocket transport for mqtt server
+///
+/// Server performs websocket handshake, negotiates mqtt subprotocol and
+/// passes websocket stream to the inner service, connection is rejected
+/// if client does not offer any of supported subprotocols.
+///
+/// ```rust,ignore
+/// ntex::server::Server::build()
+///     .bind("mqtt-ws", "127.0.0.1:8080", |_| {
+///         ws::WsServer::new(MqttServer::new().v3(v3_server).v5(v5_server))
+///     })?
+///     .run()
+///     .await
+/// ```
+pub struct WsServer<S> {
+    inner: S,
+    handshake_timeout: Seconds,
+}
+
+impl<S> WsServer<S> {
+    /// Create websocket transport for mqtt server service
+    pub fn new(inner: S) -> Self {
+        WsServer { inner, handshake_timeout: Seconds(5) }
+    }
+
+    /// Set websocket handshake timeout.
+    ///
+    /// Defines a timeout for reading websocket upgrade request.
+    /// By default timeout is 5 seconds, set `0` to disable timeout.
+    pub fn handshake_timeout(mut self, timeout: Seconds) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+}
+
+impl<F, S, E> ServiceFactory<Io<F>> for WsServer<S>
+where
+    F: Filter,
+    S: ServiceFactory<IoBoxed, Response = (), Error = MqttError<E>>,
+    S::Service: 'static,
+    S::InitError: 'static,
+    S::Future: 'static,
+    E: 'static,
+{
+    type Response = ();
+    type Error = MqttError<E>;
+    type InitError = S::InitError;
+    type Service = WsServerImpl<S::Service>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Service, S::InitError>>>>;
+
+    fn new_service(&self, _: ()) -> Self::Future {
+        let fut = self.inner.new_service(());
+        let handshake_timeout = self.handshake_timeout;
+
+        Box::pin(
+            async move { Ok(WsServerImpl { inner: Rc::new(fut.await?), handshake_timeout }) },
+        )
+    }
+}
+
+/// WebSocket transport service
+pub struct WsServerImpl<S> {
+    inner: Rc<S>,
+    handshake_timeout: Seconds,
+}
+
+impl<F, S, E> Service<Io<F>> for WsServerImpl<S>
+where
+    F: Filter,
+    S: Service<IoBoxed, Response = (), Error = MqttError<E>> + 'static,
+    E: 'static,
+{
+    type Response = ();
+    type Error = MqttError<E>;
+    type Future = Pin<Box<dyn Future<Output = Result<(), MqttError<E>>>>>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.inner.poll_shutdown(cx, is_error)
+    }
+
+    fn call(&self, io: Io<F>) -> Self::Future {
+        let inner = self.inner.clone();
+        let handshake_timeout = self.handshake_timeout;
+
+        Box::pin(async move {
+            let io = match timeout_checked(handshake_timeout, accept(io)).await {
+                Ok(res) => res?,
+                Err(_) => return Err(MqttError::HandshakeTimeout),
+            };
+            inner.call(IoBoxed::from(io)).await
+        })
+    }
+}
+
+/// Read websocket upgrade request and switch io to websocket transport
+async fn accept<F: Filter, E>(io: Io<F>) -> Result<Io<ws::WsTransport<F>>, MqttError<E>> {
+    let codec = h1::Codec::default();
+    let req = match io.recv(&codec).await {
+        Ok(Some((req, _))) => req,
+        Ok(_) => return Err(MqttError::Disconnected(None)),
+        Err(Either::Left(e)) => {
+            log::trace!("Cannot parse websocket handshake request: {:?}", e);
+            return Err(MqttError::ServerError("Invalid websocket handshake request"));
+        }
+        Err(Either::Right(e)) => return Err(MqttError::Disconnected(Some(e))),
+    };
+
+    let protocol =
+        ws::verify_handshake(req.head()).ok().and_then(|_| select_protocol(req.head()));
+    let protocol = if let Some(protocol) = protocol {
+        protocol
+    } else {
+        log::trace!("Reject websocket handshake request: {:?}", req.head());
+        let res = Response::BadRequest().finish();
+        let _ = io.send(h1::Message::Item((res.drop_body(), BodySize::Empty)), &codec).await;
+        io.close();
+        return Err(MqttError::ServerError("Websocket handshake is rejected"));
+    };
+
+    let res = ws::handshake_response(req.head())
+        .header(header::SEC_WEBSOCKET_PROTOCOL, protocol)
+        .finish();
+    io.send(h1::Message::Item((res.drop_body(), BodySize::None)), &codec)
+        .await
+        .map_err(|e| MqttError::Disconnected(Some(e.into_inner())))?;
+
+    io.add_filter(ws::WsTransportFactory::new(ws::Codec::default()))
+        .await
+        .map_err(|e| MqttError::Disconnected(Some(e)))
+}
+
+/// WebSocket connector for mqtt client
+///
+/// Connector upgrades connection to websocket, offers supported mqtt
+/// subprotocols and fails if server does not select one of them.
+///
+/// ```rust,ignore
+/// let client = v3::client::MqttConnector::new(Uri::from_static("ws://127.0.0.1:8080/mqtt"))
+///     .connector(ws::WsConnector::default())
+///     .client_id("user")
+///     .connect()
+///     .await?;
+/// ```
+#[derive(Copy, Clone, Debug, Default)]
+pub struct WsConnector;
+
+impl Service<Connect<Uri>> for WsConnector {
+    type Response = IoBoxed;
+    type Error = ConnectError;
+    type Future = Pin<Box<dyn Future<Output = Result<IoBoxed, ConnectError>>>>;
+
+    #[inline]
+    fn poll_ready(&self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&self, req: Connect<Uri>) -> Self::Future {
+        let mut builder = ws::WsClient::build(req.get_ref().clone());
+        if let Some(addr) = req.addrs().next() {
+            builder.address(addr);
+        }
+        builder.protocols(PROTOCOLS);
+
+        Box::pin(async move {
+            let client = builder
+                .finish()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let con = client.connect().await.map_err(|e| match e {
+                ws::error::WsClientError::Connect(e) => e,
+                e => ConnectError::Io(io::Error::new(io::ErrorKind::Other, e.to_string())),
+            })?;
+
+            let selected = con
+                .response()
+                .headers()
+                .get(header::SEC_WEBSOCKET_PROTOCOL)
+                .and_then(|hdr| hdr.to_str().ok())
+                .and_then(|proto| supported(proto.trim()));
+            if selected.is_none() {
+                return Err(ConnectError::Io(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Server did not select mqtt websocket subprotocol",
+                )));
+            }
+            Ok(IoBoxed::from(con.into_transport().await))
+        })
+    }
+}
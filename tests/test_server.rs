@@ -1,14 +1,21 @@
-use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
-use std::{num::NonZeroU16, time::Duration};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::{cell::Cell, cell::RefCell, num::NonZeroU16, rc::Rc, time::Duration};
 
-use ntex::service::{Service, ServiceFactory};
+use ntex::codec::{BytesCodec, Decoder, Encoder};
+use ntex::io::IoBoxed;
+use ntex::service::{fn_service, Service, ServiceFactory};
 use ntex::time::{sleep, Millis, Seconds};
-use ntex::util::{join_all, ByteString, Bytes, Ready};
+use ntex::util::{join_all, ByteString, Bytes, BytesMut, PoolId, Ready};
 use ntex::{server, service::pipeline_factory};
 
 use ntex_mqtt::v3::{
-    client, codec, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish, Session,
+    client, codec, ControlMessage, Direction, Handshake, HandshakeAck, IdleAction, IoFallback,
+    Metrics, MqttServer, MqttSink, PacketMask, PreConnackPublishPolicy, Publish,
+    Qos2InflightLimit, Registration, Router, Selector, Session, SessionRegistry, SniffResult,
+    Variant,
 };
+use ntex_mqtt::{error::SendPacketError, LifecycleEventKind};
 
 struct St;
 
@@ -41,6 +48,374 @@ async fn test_simple() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_memory_pool() -> std::io::Result<()> {
+    let pools = Arc::new(Mutex::new(Vec::new()));
+    let pools2 = pools.clone();
+
+    let srv = server::test_server(move || {
+        let pools = pools2.clone();
+        let pools3 = pools2.clone();
+        Selector::new()
+            .memory_pool(PoolId::P3)
+            .variant(
+                |hnd: &Handshake| Ready::Ok(hnd.packet().client_id == "selector"),
+                MqttServer::new(move |hnd: Handshake| {
+                    pools.lock().unwrap().push(hnd.io().memory_pool().id());
+                    Ready::Ok::<_, ()>(hnd.ack(St, false))
+                })
+                .publish(|_| Ready::Ok(())),
+            )
+            .variant(
+                |_| Ready::Ok(true),
+                MqttServer::new(move |hnd: Handshake| {
+                    pools3.lock().unwrap().push(hnd.io().memory_pool().id());
+                    Ready::Ok::<_, ()>(hnd.ack(St, false))
+                })
+                .memory_pool(PoolId::P4)
+                .publish(|_| Ready::Ok(())),
+            )
+    });
+
+    for client_id in ["selector", "variant"] {
+        let client = client::MqttConnector::new(srv.addr())
+            .client_id(client_id)
+            .connect()
+            .await
+            .unwrap();
+        client.sink().close();
+    }
+    // selector's memory pool is used for variants
+    assert_eq!(*pools.lock().unwrap(), vec![PoolId::P3, PoolId::P3]);
+
+    let pools2 = pools.clone();
+    let srv = server::test_server(move || {
+        let pools = pools2.clone();
+        MqttServer::new(move |hnd: Handshake| {
+            pools.lock().unwrap().push(hnd.io().memory_pool().id());
+            Ready::Ok::<_, ()>(hnd.ack(St, false))
+        })
+        .memory_pool(PoolId::P4)
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    client.sink().close();
+    assert_eq!(pools.lock().unwrap().pop(), Some(PoolId::P4));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_ping() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok(()))
+            .control(|msg| match msg {
+                ControlMessage::Ping(msg) => Ready::Ok::<_, ()>(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // concurrent pings wait for the same response
+    let res = join_all(vec![sink.ping(), sink.ping()]).await;
+    let rtt = *res[0].as_ref().unwrap();
+    assert_eq!(res[1].as_ref().unwrap(), &rtt);
+    assert!(sink.ping().await.is_ok());
+
+    sink.close();
+    assert!(sink.ping().await.is_err());
+
+    // connection is closed before PINGRESP is received
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok(()))
+            .control(|msg: ControlMessage<()>| Ready::Ok::<_, ()>(msg.disconnect()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    let res = ntex::time::timeout(Millis(1000), sink.ping()).await.unwrap();
+    assert!(res.is_err());
+
+    // server could not send PINGREQ
+    let not_allowed = Arc::new(AtomicBool::new(false));
+    let not_allowed2 = not_allowed.clone();
+    let srv = server::test_server(move || {
+        let not_allowed = not_allowed2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            let not_allowed = not_allowed.clone();
+            async move {
+                let res = packet.sink().ping().await;
+                not_allowed.store(matches!(res, Err(SendPacketError::NotAllowed)), Relaxed);
+                Ok::<_, ()>(packet.ack(St, false))
+            }
+        })
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+
+    let _client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    assert!(not_allowed.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_with_callback() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| async move {
+                // delay ack of publish to "slow" topic
+                if p.topic().path() == "slow" {
+                    sleep(Millis(5000)).await;
+                }
+                Ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let publish = |topic| codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::AtMostOnce,
+        topic: ByteString::from_static(topic),
+        packet_id: None,
+        payload: Bytes::new(),
+    };
+
+    let (tx, rx) = ntex::channel::oneshot::channel();
+    sink.publish_with_callback(publish("test"), move |res| {
+        let _ = tx.send(res);
+    });
+    assert!(rx.await.unwrap().is_ok());
+
+    // packet could not be encoded
+    let (tx, rx) = ntex::channel::oneshot::channel();
+    let mut packet = publish("test");
+    packet.topic = ByteString::from("t".repeat(70_000));
+    sink.publish_with_callback(packet, move |res| {
+        let _ = tx.send(res);
+    });
+    assert!(matches!(rx.await.unwrap(), Err(SendPacketError::Encode(_))));
+
+    // connection is lost before ack
+    let (tx, rx) = ntex::channel::oneshot::channel();
+    sink.publish_with_callback(publish("slow"), move |res| {
+        let _ = tx.send(res);
+    });
+    sleep(Millis(50)).await;
+    sink.close();
+    assert_eq!(rx.await.unwrap(), Err(SendPacketError::Disconnected));
+
+    // connection is closed
+    let (tx, rx) = ntex::channel::oneshot::channel();
+    sink.publish_with_callback(publish("test"), move |res| {
+        let _ = tx.send(res);
+    });
+    assert_eq!(rx.await.unwrap(), Err(SendPacketError::Disconnected));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_take_payload() -> std::io::Result<()> {
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let payloads2 = payloads.clone();
+
+    let srv = server::test_server(move || {
+        let payloads = payloads2.clone();
+        MqttServer::new(handshake)
+            .publish(move |mut p: Publish| {
+                p.payload_mut().truncate(4);
+                payloads.lock().unwrap().push(p.take_payload());
+                assert!(p.payload().is_empty());
+                assert!(p.take_payload().is_empty());
+                Ready::Ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::from_static(b"data-data"))
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+    assert_eq!(*payloads.lock().unwrap(), vec![Bytes::from_static(b"data")]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_batch() -> std::io::Result<()> {
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let results2 = results.clone();
+
+    let srv = server::test_server(move || {
+        let results = results2.clone();
+        MqttServer::new(move |hnd: Handshake| {
+            let sink = hnd.sink();
+            let results = results.clone();
+            ntex::rt::spawn(async move {
+                sleep(Millis(50)).await;
+                let batch = (1..5).map(|i| (format!("topic{}", i), Bytes::new())).collect();
+                let batch = sink.publish_batch(batch);
+
+                // publish does not take packet ids of the batch
+                let other = sink.publish(ByteString::from_static("other"), Bytes::new());
+                let other = other.send_at_least_once();
+                let res = batch.await;
+                let _ = other.await;
+                results.lock().unwrap().push(res);
+            });
+            Ready::Ok::<_, ()>(hnd.ack(St, false))
+        })
+        .max_inflight(2)
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+    let codec = codec::Codec::default();
+
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    async fn recv_publish(io: &IoBoxed) -> (NonZeroU16, ByteString) {
+        match io.recv(&codec::Codec::default()).await.unwrap().unwrap() {
+            codec::Packet::Publish(pkt) => (pkt.packet_id.unwrap(), pkt.topic),
+            pkt => panic!("Unexpected packet: {:?}", pkt),
+        }
+    }
+    let io = IoBoxed::from(io);
+
+    let id = |id| NonZeroU16::new(id).unwrap();
+
+    // batch is chunked by in-flight window
+    assert_eq!(recv_publish(&io).await, (id(5), ByteString::from_static("other")));
+    assert_eq!(recv_publish(&io).await, (id(1), ByteString::from_static("topic1")));
+    assert!(ntex::time::timeout(Millis(100), recv_publish(&io)).await.is_err());
+    io.send(codec::Packet::PublishAck { packet_id: id(5) }, &codec).await.unwrap();
+    assert_eq!(recv_publish(&io).await, (id(2), ByteString::from_static("topic2")));
+    io.send(codec::Packet::PublishAck { packet_id: id(1) }, &codec).await.unwrap();
+    assert_eq!(recv_publish(&io).await, (id(3), ByteString::from_static("topic3")));
+    io.send(codec::Packet::PublishAck { packet_id: id(2) }, &codec).await.unwrap();
+    io.send(codec::Packet::PublishAck { packet_id: id(3) }, &codec).await.unwrap();
+    assert_eq!(recv_publish(&io).await, (id(4), ByteString::from_static("topic4")));
+    io.send(codec::Packet::PublishAck { packet_id: id(4) }, &codec).await.unwrap();
+    sleep(Millis(50)).await;
+    assert_eq!(results.lock().unwrap().pop().unwrap(), vec![Ok(()), Ok(()), Ok(()), Ok(())]);
+
+    // connection is lost
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    let io = IoBoxed::from(io);
+    recv_publish(&io).await;
+    recv_publish(&io).await;
+    io.close();
+    sleep(Millis(100)).await;
+    let res = results.lock().unwrap().pop().unwrap();
+    assert_eq!(res.len(), 4);
+    assert!(res.iter().all(|r| r == &Err(SendPacketError::Disconnected)));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_concurrent_handshakes() -> std::io::Result<()> {
+    let active = Arc::new(AtomicUsize::new(0));
+    let max = Arc::new(AtomicUsize::new(0));
+    let (active2, max2) = (active.clone(), max.clone());
+
+    let srv = server::test_server(move || {
+        let (active, max) = (active2.clone(), max2.clone());
+        MqttServer::new(move |packet: Handshake| {
+            let (active, max) = (active.clone(), max.clone());
+            async move {
+                max.fetch_max(active.fetch_add(1, Relaxed) + 1, Relaxed);
+                sleep(Millis(50)).await;
+                active.fetch_sub(1, Relaxed);
+                Ok::<_, ()>(packet.ack(St, false))
+            }
+        })
+        .max_concurrent_handshakes(1)
+        .publish(|_t| Ready::Ok(()))
+        .finish()
+    });
+
+    let clients = join_all(
+        (0..3).map(|_| client::MqttConnector::new(srv.addr()).client_id("user").connect()),
+    )
+    .await;
+    for client in clients {
+        assert!(client.is_ok());
+    }
+    assert_eq!(max.load(Relaxed), 1);
+    Ok(())
+}
+
+async fn pipelined_publish(policy: PreConnackPublishPolicy) -> Option<codec::Packet> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|packet: Handshake| async move {
+            sleep(Millis(50)).await;
+            Ok::<_, ()>(packet.ack(St, false))
+        })
+        .pre_connack_publish_policy(policy)
+        .publish(|_t| Ready::Ok(()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.send(
+        codec::Packet::Publish(codec::Publish {
+            packet_id: NonZeroU16::new(1),
+            ..codec::Publish::build("test", Bytes::new()).qos(codec::QoS::AtLeastOnce)
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    match io.recv(&codec).await {
+        Ok(Some(codec::Packet::ConnectAck { .. })) => io.recv(&codec).await.unwrap(),
+        _ => None,
+    }
+}
+
+#[ntex::test]
+async fn test_pre_connack_publish() -> std::io::Result<()> {
+    let pkt = pipelined_publish(PreConnackPublishPolicy::Reject).await;
+    assert!(pkt.is_none());
+
+    let pkt = pipelined_publish(PreConnackPublishPolicy::Buffer).await;
+    assert_eq!(pkt, Some(codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() }));
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_connect_fail() -> std::io::Result<()> {
     // bad user name or password
@@ -95,324 +470,3513 @@ async fn test_connect_fail() -> std::io::Result<()> {
         assert_eq!(return_code, codec::ConnectAckReason::ServiceUnavailable);
     }
 
+    // custom return code
+    let srv = server::test_server(|| {
+        MqttServer::new(|conn: Handshake| {
+            Ready::Ok::<_, ()>(
+                conn.ack_with_reason::<St>(
+                    codec::ConnectAckReason::UnacceptableProtocolVersion,
+                )
+                .unwrap(),
+            )
+        })
+        .publish(|_t| Ready::Ok(()))
+        .finish()
+    });
+    let err =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.err().unwrap();
+    if let client::ClientError::Ack { session_present, return_code } = err {
+        assert!(!session_present);
+        assert_eq!(return_code, codec::ConnectAckReason::UnacceptableProtocolVersion);
+    } else {
+        panic!("Unexpected error: {:?}", err);
+    }
+
     Ok(())
 }
 
 #[ntex::test]
-async fn test_ping() -> std::io::Result<()> {
-    let ping = Arc::new(AtomicBool::new(false));
-    let ping2 = ping.clone();
-
-    let srv = server::test_server(move || {
-        let ping = ping2.clone();
-        MqttServer::new(handshake)
-            .publish(|_| Ready::Ok(()))
-            .control(move |msg| {
-                let ping = ping.clone();
-                match msg {
-                    ControlMessage::Ping(msg) => {
-                        ping.store(true, Relaxed);
-                        Ready::Ok(msg.ack())
-                    }
-                    _ => Ready::Ok(msg.disconnect()),
-                }
-            })
-            .finish()
+async fn test_initial_read_timeout() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        Selector::new()
+            .initial_read_timeout(Seconds(1))
+            .variant(|_| Ready::Ok(true), MqttServer::new(handshake).publish(|_| Ready::Ok(())))
     });
 
+    // idle connection is closed before handshake timeout
     let io = srv.connect().await.unwrap();
+    let start = std::time::Instant::now();
     let codec = codec::Codec::default();
-    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
-        .await
-        .unwrap();
-    io.recv(&codec).await.unwrap().unwrap();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    assert!(start.elapsed() < Duration::from_secs(5));
 
-    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    // slow connect packet is accepted
+    let io = srv.connect().await.unwrap();
+    sleep(Millis(200)).await;
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
     let pkt = io.recv(&codec).await.unwrap().unwrap();
-    assert_eq!(pkt, codec::Packet::PingResponse);
-    assert!(ping.load(Relaxed));
+    if let codec::Packet::ConnectAck { return_code, .. } = pkt {
+        assert_eq!(return_code, codec::ConnectAckReason::ConnectionAccepted);
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+
+    // disabled handshake timeout does not disable initial read timeout
+    let srv = server::test_server(|| {
+        Selector::new()
+            .handshake_timeout(Seconds::ZERO)
+            .initial_read_timeout(Seconds(1))
+            .variant(|_| Ready::Ok(true), MqttServer::new(handshake).publish(|_| Ready::Ok(())))
+    });
+    let io = srv.connect().await.unwrap();
+    let res = ntex::time::timeout(Millis(5000), io.recv(&codec)).await;
+    assert!(res.unwrap().unwrap().is_none());
 
     Ok(())
 }
 
 #[ntex::test]
-async fn test_ack_order() -> std::io::Result<()> {
-    let srv = server::test_server(move || {
-        MqttServer::new(handshake)
-            .publish(|_| async {
-                sleep(Duration::from_millis(100)).await;
-                Ok::<_, ()>(())
+async fn test_variant_handshake_timeout() -> std::io::Result<()> {
+    async fn slow_handshake(packet: Handshake) -> Result<HandshakeAck<St>, ()> {
+        sleep(Millis(1500)).await;
+        Ok(packet.ack(St, false))
+    }
+
+    let srv = server::test_server(|| {
+        Selector::new()
+            .handshake_timeout(Seconds(1))
+            .variant(
+                |hnd: &Handshake| Ready::Ok(hnd.packet().client_id == "slow"),
+                Variant::new(MqttServer::new(slow_handshake).publish(|_| Ready::Ok(())))
+                    .handshake_timeout(Seconds::ZERO),
+            )
+            .variant(
+                |_| Ready::Ok(true),
+                MqttServer::new(slow_handshake).publish(|_| Ready::Ok(())),
+            )
+    });
+    let codec = codec::Codec::default();
+
+    // variant without timeout
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("slow").into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::ConnectAck { return_code, .. } = pkt {
+        assert_eq!(return_code, codec::ConnectAckReason::ConnectionAccepted);
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+
+    // selector's timeout
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sniff() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        Selector::new()
+            .sniff(|buf| match buf {
+                [0x10, ..] => SniffResult::Mqtt,
+                b"PING" => SniffResult::Fallback,
+                [b'P', ..] if buf.len() < 4 => SniffResult::NeedMore,
+                _ => SniffResult::Reject,
             })
-            .control(move |msg| match msg {
-                ControlMessage::Subscribe(mut msg) => {
-                    for mut sub in &mut msg {
-                        assert_eq!(sub.qos(), codec::QoS::AtLeastOnce);
-                        sub.topic();
-                        sub.subscribe(codec::QoS::AtLeastOnce);
+            .fallback(IoFallback::new(fn_service(|io: IoBoxed| async move {
+                let data = io.recv(&BytesCodec).await.unwrap().unwrap();
+                assert_eq!(&data[..], b"PING");
+                io.send(Bytes::from_static(b"PONG"), &BytesCodec).await.unwrap();
+                Ok(())
+            })))
+            .variant(|_| Ready::Ok(true), MqttServer::new(handshake).publish(|_| Ready::Ok(())))
+    });
+
+    // mqtt connection
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::ConnectAck { return_code, .. } = pkt {
+        assert_eq!(return_code, codec::ConnectAckReason::ConnectionAccepted);
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+
+    // fallback receives buffered bytes
+    let io = srv.connect().await.unwrap();
+    io.send(Bytes::from_static(b"PI"), &BytesCodec).await.unwrap();
+    sleep(Millis(100)).await;
+    io.send(Bytes::from_static(b"NG"), &BytesCodec).await.unwrap();
+    let data = io.recv(&BytesCodec).await.unwrap().unwrap();
+    assert_eq!(&data[..], b"PONG");
+
+    // rejected connection
+    let io = srv.connect().await.unwrap();
+    io.send(Bytes::from_static(b"GET / HTTP/1.1\r\n"), &BytesCodec).await.unwrap();
+    assert!(io.recv(&BytesCodec).await.unwrap().is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_pre_connect() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        Selector::new()
+            .pre_connect(|io: IoBoxed| async move {
+                // PROXY protocol v1 header
+                loop {
+                    let line = io.with_read_buf(|buf| {
+                        let pos = buf.windows(2).position(|w| w == b"\r\n")?;
+                        Some(buf.split_to(pos + 2))
+                    });
+                    if let Some(line) = line {
+                        let line = std::str::from_utf8(&line).map_err(|_| ())?;
+                        let parts: Vec<_> = line.split_whitespace().collect();
+                        if parts.len() != 6 || parts[0] != "PROXY" {
+                            return Err(());
+                        }
+                        let addr = format!("{}:{}", parts[2], parts[4]);
+                        return Ok((io, Some(addr.parse().map_err(|_| ())?)));
+                    }
+                    if io.read_ready().await.map_err(|_| ())?.is_none() {
+                        return Err(());
                     }
-                    Ready::Ok(msg.ack())
                 }
-                _ => Ready::Ok(msg.disconnect()),
             })
-            .finish()
+            .variant(
+                |hnd: &Handshake| {
+                    let addr = "1.2.3.4:1000".parse().unwrap();
+                    Ready::Ok(hnd.select_context().peer_addr() == Some(addr))
+                },
+                MqttServer::new(handshake).publish(|_| Ready::Ok(())),
+            )
     });
 
+    // header and connect packet are received in one read
     let io = srv.connect().await.unwrap();
     let codec = codec::Codec::default();
-    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
-    let _ = io.recv(&codec).await.unwrap().unwrap();
+    let mut buf = BytesMut::from(&b"PROXY TCP4 1.2.3.4 5.6.7.8 1000 1883\r\n"[..]);
+    codec.encode(codec::Connect::default().client_id("user").into(), &mut buf).unwrap();
+    io.send(buf.freeze(), &BytesCodec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::ConnectAck { return_code, .. } = pkt {
+        assert_eq!(return_code, codec::ConnectAckReason::ConnectionAccepted);
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+
+    // connection with malformed header is closed
+    let io = srv.connect().await.unwrap();
+    io.send(Bytes::from_static(b"PROXY UNKNOWN\r\n"), &BytesCodec).await.unwrap();
+    assert!(io.recv(&BytesCodec).await.unwrap().is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_selector_stats() -> std::io::Result<()> {
+    let stats = Arc::new(Mutex::new(None));
+    let stats2 = stats.clone();
+
+    let srv = server::test_server(move || {
+        let selector = Selector::new()
+            .variant(
+                |hnd: &Handshake| Ready::Ok(hnd.packet().client_id == "a"),
+                MqttServer::new(handshake).publish(|_| Ready::Ok(())),
+            )
+            .variant(
+                |_| Ready::Ok(true),
+                MqttServer::new(handshake).publish(|_| Ready::Ok(())),
+            );
+        *stats2.lock().unwrap() = Some(selector.stats_handle());
+        selector
+    });
+
+    // match is counted while connection is open
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("a").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    let stats = stats.lock().unwrap().clone().unwrap();
+    assert_eq!(stats.counts(), vec![1, 0]);
+
+    let io2 = srv.connect().await.unwrap();
+    io2.send(codec::Connect::default().client_id("b").into(), &codec).await.unwrap();
+    io2.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(stats.get(0), Some(1));
+    assert_eq!(stats.get(1), Some(1));
+    assert_eq!(stats.get(2), None);
+    drop((io, io2));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_select_context() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        Selector::new()
+            .variant(
+                |hnd: &Handshake| Ready::Ok(hnd.select_context().is_tls()),
+                MqttServer::new(|hnd: Handshake| Ready::Ok(hnd.not_authorized::<St>()))
+                    .publish(|_| Ready::Ok(())),
+            )
+            .variant(
+                |hnd: &Handshake| {
+                    let addr = hnd.select_context().peer_addr();
+                    Ready::Ok(matches!(addr, Some(a) if a.ip().is_loopback()))
+                },
+                MqttServer::new(handshake).publish(|_| Ready::Ok(())),
+            )
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::ConnectAck { return_code, .. } = pkt {
+        assert_eq!(return_code, codec::ConnectAckReason::ConnectionAccepted);
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_selector_fallback() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        Selector::new()
+            .variant(
+                |hnd: &Handshake| Ready::Ok(hnd.packet().client_id == "user"),
+                MqttServer::new(handshake).publish(|_| Ready::Ok(())),
+            )
+            .fallback(
+                MqttServer::new(|hnd: Handshake| Ready::Ok(hnd.service_unavailable::<St>()))
+                    .publish(|_| Ready::Ok(())),
+            )
+    });
+
+    let codec = codec::Codec::default();
+    for (client_id, reason) in [
+        ("user", codec::ConnectAckReason::ConnectionAccepted),
+        ("other", codec::ConnectAckReason::ServiceUnavailable),
+    ] {
+        let io = srv.connect().await.unwrap();
+        io.send(codec::Connect::default().client_id(client_id).into(), &codec).await.unwrap();
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        if let codec::Packet::ConnectAck { return_code, .. } = pkt {
+            assert_eq!(return_code, reason);
+        } else {
+            panic!("Unexpected packet: {:?}", pkt);
+        }
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_selector_data() -> std::io::Result<()> {
+    #[derive(Debug, PartialEq)]
+    struct Account(&'static str);
+
+    let srv = server::test_server(|| {
+        Selector::new()
+            .variant(
+                |hnd: &Handshake| {
+                    let client_id = hnd.packet().client_id.clone();
+                    async move {
+                        // emulate account lookup
+                        sleep(Millis(10)).await;
+                        Ok::<_, ()>(if client_id == "user" {
+                            Some(Account("admin"))
+                        } else {
+                            None
+                        })
+                    }
+                },
+                MqttServer::new(|mut hnd: Handshake| {
+                    assert_eq!(hnd.data::<Account>(), Some(&Account("admin")));
+                    assert_eq!(hnd.data::<String>(), None);
+                    assert_eq!(hnd.take_data::<String>(), None);
+                    let account = hnd.take_data::<Account>();
+                    assert_eq!(hnd.data::<Account>(), None);
+                    Ready::Ok(if account == Some(Account("admin")) {
+                        hnd.ack(St, false)
+                    } else {
+                        hnd.not_authorized()
+                    })
+                })
+                .publish(|_| Ready::Ok(())),
+            )
+            .fallback(
+                MqttServer::new(|hnd: Handshake| {
+                    assert!(hnd.data::<Account>().is_none());
+                    Ready::Ok(hnd.service_unavailable::<St>())
+                })
+                .publish(|_| Ready::Ok(())),
+            )
+    });
+
+    let codec = codec::Codec::default();
+    for (client_id, reason) in [
+        ("user", codec::ConnectAckReason::ConnectionAccepted),
+        ("other", codec::ConnectAckReason::ServiceUnavailable),
+    ] {
+        let io = srv.connect().await.unwrap();
+        io.send(codec::Connect::default().client_id(client_id).into(), &codec).await.unwrap();
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        if let codec::Packet::ConnectAck { return_code, .. } = pkt {
+            assert_eq!(return_code, reason);
+        } else {
+            panic!("Unexpected packet: {:?}", pkt);
+        }
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_selector_protocol_error() -> std::io::Result<()> {
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let errors2 = errors.clone();
+
+    let srv = server::test_server(move || {
+        let errors = errors2.clone();
+        Selector::new()
+            .on_protocol_error(move |err, addr, hdr| {
+                errors.lock().unwrap().push((
+                    format!("{:?}", err),
+                    addr.is_some(),
+                    hdr.as_bytes().to_vec(),
+                    hdr.packet_len(),
+                ))
+            })
+            .variant(|_| Ready::Ok(true), MqttServer::new(handshake).publish(|_| Ready::Ok(())))
+    });
+
+    // ping request instead of connect packet
+    let io = srv.connect().await.unwrap();
+    io.send(Bytes::from_static(b"\xC0\x00"), &BytesCodec).await.unwrap();
+    assert!(io.recv(&BytesCodec).await.unwrap().is_none());
+
+    // malformed remaining length
+    let io = srv.connect().await.unwrap();
+    io.send(Bytes::from_static(b"\x10\xff\xff\xff\xff\x01"), &BytesCodec).await.unwrap();
+    assert!(io.recv(&BytesCodec).await.unwrap().is_none());
+
+    let errors = errors.lock().unwrap();
+    assert_eq!(errors.len(), 2);
+    assert!(errors[0].0.starts_with("Unexpected(192"));
+    assert!(errors[0].1);
+    assert_eq!(errors[0].2, b"\xC0\x00");
+    assert_eq!(errors[0].3, Some(2));
+    assert!(errors[1].0.starts_with("Decode(InvalidLength"));
+    assert_eq!(errors[1].2, b"\x10\xff\xff\xff\xff");
+    assert_eq!(errors[1].3, None);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_ping() -> std::io::Result<()> {
+    let ping = Arc::new(AtomicBool::new(false));
+    let ping2 = ping.clone();
+
+    let srv = server::test_server(move || {
+        let ping = ping2.clone();
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok(()))
+            .control(move |msg| {
+                let ping = ping.clone();
+                match msg {
+                    ControlMessage::Ping(msg) => {
+                        ping.store(true, Relaxed);
+                        Ready::Ok(msg.ack())
+                    }
+                    _ => Ready::Ok(msg.disconnect()),
+                }
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+    assert!(ping.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_on_ping() -> std::io::Result<()> {
+    let pings = Arc::new(AtomicUsize::new(0));
+    let pings2 = pings.clone();
+
+    let srv = server::test_server(move || {
+        let pings = pings2.clone();
+        MqttServer::new(handshake)
+            .on_ping(move |session: &Session<St>, at| {
+                assert_eq!(session.last_ping_at(), Some(at));
+                pings.fetch_add(1, Relaxed);
+            })
+            .publish(|_| Ready::Ok(()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    for _ in 0..2 {
+        io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        assert_eq!(pkt, codec::Packet::PingResponse);
+    }
+    assert_eq!(pings.load(Relaxed), 2);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_diagnostics() -> std::io::Result<()> {
+    let diag = Arc::new(Mutex::new(None));
+    let diag2 = diag.clone();
+
+    let srv = server::test_server(move || {
+        let diag = diag2.clone();
+        MqttServer::new(handshake)
+            .on_ping(move |session: &Session<St>, _| {
+                *diag.lock().unwrap() =
+                    Some(serde_json::to_value(session.diagnostics()).unwrap());
+            })
+            .publish(|_| Ready::Ok(()))
+            .control(|msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        sub.subscribe(codec::QoS::AtLeastOnce);
+                    }
+                    Ready::Ok::<_, ()>(msg.ack())
+                }
+                ControlMessage::Ping(msg) => Ready::Ok(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Packet::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(ByteString::from("topic"), codec::QoS::AtLeastOnce)],
+        },
+        &codec,
+    )
+    .await
+    .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    let diag = diag.lock().unwrap().take().unwrap();
+    assert_eq!(diag["negotiated"]["client_id"], "user");
+    assert_eq!(diag["negotiated"]["protocol_level"], 4);
+    assert_eq!(diag["negotiated"]["keepalive"], 16);
+    assert_eq!(diag["inflight"], 0);
+    assert_eq!(diag["last_ping_secs"], 0);
+    assert_eq!(diag["subscriptions"], serde_json::json!({"topic": "AtLeastOnce"}));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_connection_id() -> std::io::Result<()> {
+    let ids = Arc::new(Mutex::new(Vec::new()));
+    let ids2 = ids.clone();
+
+    let srv = server::test_server(move || {
+        let hnd_ids = ids2.clone();
+        let ping_ids = ids2.clone();
+        MqttServer::new(move |hnd: Handshake| {
+            hnd_ids.lock().unwrap().push(hnd.connection_id());
+            Ready::Ok::<_, ()>(hnd.ack(St, false))
+        })
+        .on_ping(move |session: &Session<St>, _| {
+            let id = session.diagnostics().connection_id;
+            assert_eq!(id, session.sink().connection_id());
+            ping_ids.lock().unwrap().push(session.connection_id());
+        })
+        .publish(|_| Ready::Ok(()))
+        .control(|msg| match msg {
+            ControlMessage::Ping(msg) => Ready::Ok::<_, ()>(msg.ack()),
+            _ => Ready::Ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    let codec = codec::Codec::default();
+    for _ in 0..2 {
+        let io = srv.connect().await.unwrap();
+        io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+        io.recv(&codec).await.unwrap().unwrap();
+        io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+        io.recv(&codec).await.unwrap().unwrap();
+    }
+
+    // handshake and session report the same id, ids are increasing
+    let ids = ids.lock().unwrap().clone();
+    assert_eq!(ids.len(), 4);
+    assert_eq!(ids[0], ids[1]);
+    assert_eq!(ids[2], ids[3]);
+    assert!(ids[0] < ids[2]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_set_keepalive() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok(()))
+            .control(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                Ready::Ok::<_, ()>(ntex::service::fn_service(move |msg| match msg {
+                    ControlMessage::Ping(msg) => {
+                        assert_eq!(session.keepalive(), Seconds(16));
+                        session.set_keepalive(Seconds(1));
+                        assert_eq!(session.keepalive(), Seconds(1));
+                        Ready::Ok(msg.ack())
+                    }
+                    _ => Ready::Ok(msg.disconnect()),
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+
+    // connection is closed after updated keep-alive timeout
+    let res = ntex::time::timeout(Millis(2500), io.recv(&codec)).await;
+    assert!(res.unwrap().unwrap().is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_on_idle_timeout() -> std::io::Result<()> {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let calls2 = calls.clone();
+
+    let srv = server::test_server(move || {
+        let calls = calls2.clone();
+        MqttServer::new(|con: Handshake| {
+            Ready::Ok::<_, ()>(con.ack(St, false).idle_timeout(Seconds(1)))
+        })
+        .publish(|_| Ready::Ok(()))
+        .on_idle_timeout(move |_: &Session<St>| {
+            if calls.fetch_add(1, Relaxed) == 0 {
+                IdleAction::Extend(Seconds(2))
+            } else {
+                IdleAction::Disconnect
+            }
+        })
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    // idle period is extended
+    let res = ntex::time::timeout(Millis(1800), io.recv(&codec)).await;
+    assert!(res.is_err());
+    assert_eq!(calls.load(Relaxed), 1);
+
+    // connection is closed after extended period
+    let res = ntex::time::timeout(Millis(3000), io.recv(&codec)).await;
+    assert!(res.unwrap().unwrap().is_none());
+    assert_eq!(calls.load(Relaxed), 2);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_timeout_extend() -> std::io::Result<()> {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let calls2 = calls.clone();
+
+    let srv = server::test_server(move || {
+        let calls = calls2.clone();
+        MqttServer::new(|con: Handshake| {
+            Ready::Ok::<_, ()>(con.ack(St, false).idle_timeout(Seconds(1)))
+        })
+        .publish(|_| Ready::Ok(()))
+        .control(move |msg| match msg {
+            ControlMessage::Timeout(msg) => {
+                if calls.fetch_add(1, Relaxed) == 0 {
+                    Ready::Ok::<_, ()>(msg.extend(Seconds(2)))
+                } else {
+                    Ready::Ok(msg.ack())
+                }
+            }
+            _ => Ready::Ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    // idle period is extended
+    let res = ntex::time::timeout(Millis(1800), io.recv(&codec)).await;
+    assert!(res.is_err());
+    assert_eq!(calls.load(Relaxed), 1);
+
+    // connection is closed after extended period
+    let res = ntex::time::timeout(Millis(3000), io.recv(&codec)).await;
+    assert!(res.unwrap().unwrap().is_none());
+    assert_eq!(calls.load(Relaxed), 2);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_connection_lifetime() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .max_connection_lifetime(Seconds(1))
+            .publish(|_| Ready::Ok(()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    // connection is active before lifetime expires
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+
+    // connection is closed after max lifetime
+    let pkt = ntex::time::timeout(Millis(2500), io.recv(&codec)).await;
+    assert!(pkt.unwrap().unwrap().is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_on_disconnect() -> std::io::Result<()> {
+    let reasons = Arc::new(Mutex::new(Vec::new()));
+    let reasons2 = reasons.clone();
+
+    let srv = server::test_server(move || {
+        let reasons = reasons2.clone();
+        MqttServer::new(|con: Handshake| {
+            let timeout = if con.packet().client_id == "idle" { 1 } else { 16 };
+            Ready::Ok::<_, ()>(con.ack(St, false).idle_timeout(Seconds(timeout)))
+        })
+        .publish(|_| Ready::Ok(()))
+        .control(ntex::service::fn_factory_with_config(|session: Session<St>| {
+            Ready::Ok::<_, ()>(ntex::service::fn_service(move |msg| match msg {
+                ControlMessage::Ping(msg) => {
+                    session.sink().close();
+                    Ready::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            }))
+        }))
+        .on_disconnect(move |_: &Session<St>, reason| {
+            reasons.lock().unwrap().push(format!("{:?}", reason));
+        })
+        .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let connect = |client_id: &'static str| {
+        let fut = srv.connect();
+        async move {
+            let codec = codec::Codec::default();
+            let io = fut.await.unwrap();
+            io.send(codec::Connect::default().client_id(client_id).into(), &codec)
+                .await
+                .unwrap();
+            io.recv(&codec).await.unwrap().unwrap();
+            io
+        }
+    };
+
+    // client sends DISCONNECT packet
+    let io = connect("clean").await;
+    io.send(codec::Packet::Disconnect, &codec).await.unwrap();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    sleep(Millis(100)).await;
+    assert_eq!(reasons.lock().unwrap().pop().unwrap(), "Clean");
+
+    // client drops connection
+    let io = connect("io").await;
+    io.close();
+    sleep(Millis(100)).await;
+    assert_eq!(reasons.lock().unwrap().pop().unwrap(), "Io");
+
+    // server closes connection
+    let io = connect("evicted").await;
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    sleep(Millis(100)).await;
+    assert_eq!(reasons.lock().unwrap().pop().unwrap(), "ServerEvicted");
+
+    // second CONNECT packet
+    let io = connect("proto").await;
+    io.send(codec::Connect::default().client_id("proto").into(), &codec).await.unwrap();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    sleep(Millis(100)).await;
+    assert!(reasons.lock().unwrap().pop().unwrap().starts_with("Protocol("));
+
+    // keep-alive timeout
+    let io = connect("idle").await;
+    let res = ntex::time::timeout(Millis(2500), io.recv(&codec)).await;
+    assert!(res.unwrap().unwrap().is_none());
+    sleep(Millis(100)).await;
+    assert_eq!(reasons.lock().unwrap().pop().unwrap(), "KeepaliveTimeout");
+    assert!(reasons.lock().unwrap().is_empty());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_retain() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                Ready::Ok::<_, ()>(fn_service(move |p: Publish| {
+                    // echo publish with the same retain flag
+                    session
+                        .sink()
+                        .publish(ByteString::from_static("out"), p.payload().clone())
+                        .with_retain(p.retain())
+                        .send_at_most_once()
+                        .unwrap();
+                    Ready::Ok::<_, ()>(())
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    for retain in [true, false] {
+        let publish = codec::Publish {
+            dup: false,
+            retain,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: None,
+            payload: Bytes::from_static(b"data"),
+        };
+        io.send(codec::Packet::Publish(publish), &codec).await.unwrap();
+        if let codec::Packet::Publish(pkt) = io.recv(&codec).await.unwrap().unwrap() {
+            assert_eq!(pkt.retain, retain);
+            assert_eq!(pkt.topic, "out");
+        } else {
+            panic!("Publish packet is expected");
+        }
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_rate_limit() -> std::io::Result<()> {
+    let throttled = Arc::new(Mutex::new(None));
+    let throttled2 = throttled.clone();
+    let acked = Arc::new(AtomicBool::new(false));
+    let acked2 = acked.clone();
+
+    let srv = server::test_server(move || {
+        let throttled = throttled2.clone();
+        let acked = acked2.clone();
+        MqttServer::new(handshake)
+            .publish_rate_limit(5, 2)
+            .publish_rate_limit_notify(Seconds(1))
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let acked = acked.clone();
+                Ready::Ok::<_, ()>(fn_service(move |p: Publish| {
+                    if p.topic().path() == "trigger" {
+                        let acked = acked.clone();
+                        let fut = session
+                            .sink()
+                            .publish(ByteString::from_static("out"), Bytes::new())
+                            .send_at_least_once();
+                        ntex::rt::spawn(async move {
+                            if fut.await.is_ok() {
+                                acked.store(true, Relaxed);
+                            }
+                        });
+                    }
+                    Ready::Ok::<_, ()>(())
+                }))
+            }))
+            .control(move |msg| match msg {
+                ControlMessage::RateLimited(ref m) => {
+                    *throttled.lock().unwrap() = Some(m.throttled());
+                    Ready::Ok::<_, ()>(msg.disconnect())
+                }
+                ControlMessage::Ping(msg) => Ready::Ok(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    let publish = |qos, packet_id| {
+        codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos,
+            topic: ByteString::from_static(if packet_id == 1 { "trigger" } else { "test" }),
+            packet_id: NonZeroU16::new(packet_id),
+            payload: Bytes::new(),
+        })
+    };
+    let ack = |packet_id| codec::Packet::PublishAck {
+        packet_id: NonZeroU16::new(packet_id).unwrap(),
+    };
+
+    // burst is handled immediately
+    for id in 1..=2 {
+        io.send(publish(codec::QoS::AtLeastOnce, id), &codec).await.unwrap();
+    }
+    let mut acks = Vec::new();
+    loop {
+        match io.recv(&codec).await.unwrap().unwrap() {
+            codec::Packet::Publish(pkt) => {
+                let packet_id = pkt.packet_id.unwrap();
+                io.send(codec::Packet::PublishAck { packet_id }, &codec).await.unwrap();
+                break;
+            }
+            pkt => acks.push(pkt),
+        }
+    }
+
+    // acks are not throttled
+    sleep(Millis(50)).await;
+    assert!(acked.load(Relaxed));
+    while acks.len() < 2 {
+        acks.push(io.recv(&codec).await.unwrap().unwrap());
+    }
+    assert_eq!(acks, vec![ack(1), ack(2)]);
+
+    // reading is paused until token is available
+    let start = std::time::Instant::now();
+    for id in 3..=4 {
+        io.send(publish(codec::QoS::AtLeastOnce, id), &codec).await.unwrap();
+    }
+    assert_eq!(io.recv(&codec).await.unwrap().unwrap(), ack(3));
+    assert_eq!(io.recv(&codec).await.unwrap().unwrap(), ack(4));
+    assert!(start.elapsed() >= Duration::from_millis(250));
+    assert!(throttled.lock().unwrap().is_none());
+
+    // control service disconnects throttled connection
+    for _ in 0..12 {
+        sleep(Millis(100)).await;
+        let _ = io.send(publish(codec::QoS::AtMostOnce, 0), &codec).await;
+    }
+    let res = ntex::time::timeout(Millis(2000), io.recv(&codec)).await;
+    assert!(res.unwrap().unwrap().is_none());
+    assert!(throttled.lock().unwrap().unwrap() >= Duration::from_secs(1));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_rate_limit_window() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .inflight(2)
+            .max_inflight(2)
+            .publish_rate_limit(2, 1)
+            .publish(|_| Ready::Ok::<_, ()>(()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    // throttled publishes stay in read buffer and do not exceed in-flight window
+    let start = std::time::Instant::now();
+    for id in 1..=4 {
+        let pkt = codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: NonZeroU16::new(id),
+            payload: Bytes::new(),
+        };
+        io.send(codec::Packet::Publish(pkt), &codec).await.unwrap();
+    }
+    for id in 1..=4 {
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        assert_eq!(pkt, codec::Packet::PublishAck { packet_id: NonZeroU16::new(id).unwrap() });
+    }
+    assert!(start.elapsed() >= Duration::from_millis(1400));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_keepalive_timeout_control() -> std::io::Result<()> {
+    let idle = Arc::new(Mutex::new(None));
+    let idle2 = idle.clone();
+
+    let srv = server::test_server(move || {
+        let idle = idle2.clone();
+        MqttServer::new(|con: Handshake| {
+            Ready::Ok::<_, ()>(con.ack(St, false).idle_timeout(Seconds(1)))
+        })
+        .publish(|_| Ready::Ok(()))
+        .control(move |msg| match msg {
+            ControlMessage::Timeout(msg) => {
+                *idle.lock().unwrap() = Some(msg.idle());
+                Ready::Ok::<_, ()>(msg.ack())
+            }
+            ControlMessage::Ping(msg) => Ready::Ok(msg.ack()),
+            _ => Ready::Ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    sleep(Millis(300)).await;
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    assert_eq!(io.recv(&codec).await.unwrap().unwrap(), codec::Packet::PingResponse);
+
+    let res = ntex::time::timeout(Millis(2500), io.recv(&codec)).await;
+    assert!(res.unwrap().unwrap().is_none());
+
+    let idle = idle.lock().unwrap().take().unwrap();
+    assert!(idle >= Duration::from_millis(900), "{:?}", idle);
+    assert!(idle < Duration::from_millis(2500), "{:?}", idle);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_read_timeout() -> std::io::Result<()> {
+    let timeouts = Arc::new(AtomicUsize::new(0));
+    let timeouts2 = timeouts.clone();
+
+    let srv = server::test_server(move || {
+        let timeouts = timeouts2.clone();
+        MqttServer::new(handshake)
+            .read_timeout(Seconds(1))
+            .write_timeout(Seconds(1))
+            .publish(|_| Ready::Ok(()))
+            .control(move |msg| match msg {
+                ControlMessage::ProtocolError(msg) => {
+                    if let ntex_mqtt::error::ProtocolError::ReadTimeout = msg.get_ref() {
+                        timeouts.fetch_add(1, Relaxed);
+                    }
+                    Ready::Ok::<_, ()>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+    let codec = codec::Codec::default();
+
+    // partial connect packet, keep-alive is not negotiated yet
+    let mut buf = BytesMut::new();
+    codec.encode(codec::Connect::default().client_id("user").into(), &mut buf).unwrap();
+    let io = srv.connect().await.unwrap();
+    io.send(buf.split_to(4).freeze(), &BytesCodec).await.unwrap();
+    let res = ntex::time::timeout(Millis(2500), io.recv(&BytesCodec)).await;
+    assert!(res.unwrap().unwrap().is_none());
+
+    // partial publish packet, keep-alive timeout is 16 seconds
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    let mut buf = BytesMut::new();
+    let publish = codec::Publish::build("test", Bytes::from_static(b"data"));
+    codec.encode(codec::Packet::Publish(publish), &mut buf).unwrap();
+    io.send(buf.split_to(4).freeze(), &BytesCodec).await.unwrap();
+    let res = ntex::time::timeout(Millis(2500), io.recv(&codec)).await;
+    assert!(res.unwrap().unwrap().is_none());
+    assert_eq!(timeouts.load(Relaxed), 1);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscribe_timeout() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .subscribe_timeout(Seconds(1))
+            .publish(|_| Ready::Ok(()))
+            .control(move |msg| async move {
+                match msg {
+                    ControlMessage::Subscribe(mut msg) => {
+                        let delay = if msg.iter_mut().any(|s| s.topic() == "stuck") {
+                            Millis(5000)
+                        } else {
+                            Millis(200)
+                        };
+                        sleep(delay).await;
+                        for mut sub in &mut msg {
+                            sub.subscribe(codec::QoS::AtLeastOnce);
+                        }
+                        Ok::<_, ()>(msg.ack())
+                    }
+                    _ => Ok(msg.disconnect()),
+                }
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    // suback is delayed until control service completes
+    io.send(
+        codec::Packet::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(ByteString::from("delayed"), codec::QoS::AtLeastOnce)],
+        },
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::SubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            status: vec![codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce)],
+        }
+    );
+
+    // control service does not complete in time
+    io.send(
+        codec::Packet::Subscribe {
+            packet_id: NonZeroU16::new(2).unwrap(),
+            topic_filters: vec![
+                (ByteString::from("stuck"), codec::QoS::AtLeastOnce),
+                (ByteString::from("other"), codec::QoS::AtMostOnce),
+            ],
+        },
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::SubscribeAck {
+            packet_id: NonZeroU16::new(2).unwrap(),
+            status: vec![
+                codec::SubscribeReturnCode::Failure,
+                codec::SubscribeReturnCode::Failure
+            ],
+        }
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_coalesce_subacks() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .coalesce_subacks(Millis(300), 3)
+            .publish(|_| Ready::Ok(()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        sub.subscribe(codec::QoS::AtLeastOnce);
+                    }
+                    Ready::Ok::<_, ()>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    let subscribe = |id| codec::Packet::Subscribe {
+        packet_id: NonZeroU16::new(id).unwrap(),
+        topic_filters: vec![(ByteString::from("topic"), codec::QoS::AtLeastOnce)],
+    };
+    let suback = |id| codec::Packet::SubscribeAck {
+        packet_id: NonZeroU16::new(id).unwrap(),
+        status: vec![codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce)],
+    };
+
+    // subacks are queued until batch is full
+    io.send(subscribe(1), &codec).await.unwrap();
+    io.send(subscribe(2), &codec).await.unwrap();
+    assert!(ntex::time::timeout(Millis(100), io.recv(&codec)).await.is_err());
+
+    io.send(subscribe(3), &codec).await.unwrap();
+    for id in 1..4 {
+        assert_eq!(io.recv(&codec).await.unwrap().unwrap(), suback(id));
+    }
+
+    // subacks are written after max delay
+    io.send(subscribe(4), &codec).await.unwrap();
+    let pkt = ntex::time::timeout(Millis(1000), io.recv(&codec)).await;
+    assert_eq!(pkt.unwrap().unwrap().unwrap(), suback(4));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_unexpected_ack() -> std::io::Result<()> {
+    let unexpected = Arc::new(Mutex::new(Vec::new()));
+    let unexpected2 = unexpected.clone();
+
+    let srv = server::test_server(move || {
+        let unexpected = unexpected2.clone();
+        MqttServer::new(handshake)
+            .strict_acks(false)
+            .on_unexpected_ack(move |_, packet_id, packet_type| {
+                unexpected.lock().unwrap().push((packet_id.get(), packet_type));
+            })
+            .publish(|_| Ready::Ok(()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    // acks with unknown packet id are ignored
+    io.send(codec::Packet::PublishAck { packet_id: NonZeroU16::new(5).unwrap() }, &codec)
+        .await
+        .unwrap();
+    io.send(codec::Packet::PublishReceived { packet_id: NonZeroU16::new(6).unwrap() }, &codec)
+        .await
+        .unwrap();
+    io.send(codec::Packet::PublishComplete { packet_id: NonZeroU16::new(7).unwrap() }, &codec)
+        .await
+        .unwrap();
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+    assert_eq!(*unexpected.lock().unwrap(), vec![(5, 0x40), (6, 0x50), (7, 0x70)]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_rejected_publish() -> std::io::Result<()> {
+    let rejected = Arc::new(Mutex::new(Vec::new()));
+    let rejected2 = rejected.clone();
+
+    let srv =
+        server::test_server(move || {
+            let rejected = rejected2.clone();
+            MqttServer::new(handshake)
+                .on_rejected_publish(1, move |pkt, reason| {
+                    rejected.lock().unwrap().push((pkt.topic.clone(), reason));
+                })
+                .publish(|p: Publish| {
+                    if p.topic().path() == "fail" {
+                        Ready::Err(())
+                    } else {
+                        Ready::Ok(())
+                    }
+                })
+                .finish()
+        });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    let mut pkt = codec::Publish::build(ByteString::from_static("ok"), Bytes::new())
+        .qos(codec::QoS::AtLeastOnce);
+    pkt.packet_id = NonZeroU16::new(1);
+    io.send(pkt.into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    let mut pkt = codec::Publish::build(ByteString::from_static("fail"), Bytes::new())
+        .qos(codec::QoS::AtLeastOnce);
+    pkt.packet_id = NonZeroU16::new(2);
+    io.send(pkt.into(), &codec).await.unwrap();
+    sleep(Millis(50)).await;
+
+    assert_eq!(
+        &*rejected.lock().unwrap(),
+        &[(ByteString::from_static("fail"), ntex_mqtt::v3::RejectReason::Service)]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_topic_rewrite() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .ingress_topic_rewrite(|topic| match topic.strip_prefix("s/") {
+                Some(rest) => format!("sensors/{}", rest).into(),
+                None => topic.into(),
+            })
+            .egress_topic_rewrite(|topic| match topic.strip_prefix("sensors/") {
+                Some(rest) => format!("s/{}", rest).into(),
+                None => topic.into(),
+            })
+            .publish(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                Ready::Ok(ntex::service::fn_service(move |p: Publish| {
+                    assert_eq!(p.topic().path(), "sensors/temp");
+                    session
+                        .sink()
+                        .publish(ByteString::from_static("sensors/ack"), p.payload().clone())
+                        .send_at_most_once()
+                        .unwrap();
+                    Ready::Ok(())
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Publish::build(ByteString::from_static("s/temp"), Bytes::from_static(b"1"))
+            .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Publish(codec::Publish::build(
+            ByteString::from_static("s/ack"),
+            Bytes::from_static(b"1")
+        ))
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_global_qos2_inflight() -> std::io::Result<()> {
+    let limit = Qos2InflightLimit::new(1);
+    let limit2 = limit.clone();
+    let concurrent = Arc::new(AtomicUsize::new(0));
+    let concurrent2 = concurrent.clone();
+
+    let srv = server::test_server(move || {
+        let concurrent = concurrent2.clone();
+        MqttServer::new(handshake)
+            .max_global_qos2_inflight(limit2.clone())
+            .publish(move |_: Publish| {
+                let concurrent = concurrent.clone();
+                async move {
+                    assert_eq!(concurrent.fetch_add(1, Relaxed), 0);
+                    sleep(Millis(50)).await;
+                    concurrent.fetch_sub(1, Relaxed);
+                    Ok::<_, ()>(())
+                }
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    // second publish is delayed until first one is processed
+    for id in 1..3 {
+        let mut pkt = codec::Publish::build(ByteString::from_static("test"), Bytes::new())
+            .qos(codec::QoS::ExactlyOnce);
+        pkt.packet_id = NonZeroU16::new(id);
+        io.send(pkt.into(), &codec).await.unwrap();
+    }
+    for id in 1..3 {
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        assert_eq!(pkt, codec::Packet::PublishAck { packet_id: NonZeroU16::new(id).unwrap() });
+    }
+    assert_eq!(limit.inflight(), 0);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_puback_after_publish_service() -> std::io::Result<()> {
+    let stored = Arc::new(AtomicBool::new(false));
+    let stored2 = stored.clone();
+
+    let srv = server::test_server(move || {
+        let stored = stored2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                let stored = stored.clone();
+                async move {
+                    sleep(Millis(100)).await;
+                    if p.topic().path() == "fail" {
+                        Err(())
+                    } else {
+                        stored.store(true, Relaxed);
+                        Ok(())
+                    }
+                }
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    let mut pkt = codec::Publish::build(ByteString::from_static("test"), Bytes::new())
+        .qos(codec::QoS::AtLeastOnce);
+    pkt.packet_id = NonZeroU16::new(1);
+    io.send(pkt.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() });
+    assert!(stored.load(Relaxed));
+
+    // failed publish is not acked
+    let mut pkt = codec::Publish::build(ByteString::from_static("fail"), Bytes::new())
+        .qos(codec::QoS::AtLeastOnce);
+    pkt.packet_id = NonZeroU16::new(2);
+    io.send(pkt.into(), &codec).await.unwrap();
+    assert!(io.recv(&codec).await.map(|pkt| pkt.is_none()).unwrap_or(true));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_lifecycle_events() -> std::io::Result<()> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+
+    let srv = server::test_server(move || {
+        let srv =
+            MqttServer::new(handshake).publish(|_| Ready::Ok(())).control(
+                move |msg| match msg {
+                    ControlMessage::Subscribe(mut msg) => {
+                        for mut sub in &mut msg {
+                            sub.confirm(codec::QoS::AtLeastOnce);
+                        }
+                        Ready::Ok(msg.ack())
+                    }
+                    ControlMessage::Unsubscribe(msg) => Ready::Ok(msg.ack()),
+                    _ => Ready::Ok(msg.disconnect()),
+                },
+            );
+
+        let mut stream = srv.lifecycle_events();
+        let events = events2.clone();
+        ntex::rt::spawn(async move {
+            while let Some(ev) = ntex::util::stream_recv(&mut stream).await {
+                assert_eq!(ev.client_id(), "user");
+                events.lock().unwrap().push(ev.kind().clone());
+            }
+        });
+        srv.finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Packet::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(ByteString::from("topic"), codec::QoS::AtLeastOnce)],
+        },
+        &codec,
+    )
+    .await
+    .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    io.send(
+        codec::Packet::Unsubscribe {
+            packet_id: NonZeroU16::new(2).unwrap(),
+            topic_filters: vec![ByteString::from("topic")],
+        },
+        &codec,
+    )
+    .await
+    .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    io.send(codec::Packet::Disconnect, &codec).await.unwrap();
+    sleep(Millis(150)).await;
+
+    assert_eq!(
+        &*events.lock().unwrap(),
+        &[
+            LifecycleEventKind::Connected,
+            LifecycleEventKind::SubscriptionAdded {
+                topic: ByteString::from("topic"),
+                qos: codec::QoS::AtLeastOnce
+            },
+            LifecycleEventKind::SubscriptionRemoved { topic: ByteString::from("topic") },
+            LifecycleEventKind::Disconnected,
+        ]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_allowed_packets() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok(()))
+            .control(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                // publish-only connection
+                session.set_allowed_packets(
+                    PacketMask::PUBLISH | PacketMask::PINGREQ | PacketMask::DISCONNECT,
+                );
+                Ready::Ok::<_, ()>(ntex::service::fn_service(
+                    |msg: ControlMessage<()>| match msg {
+                        ControlMessage::Ping(msg) => Ready::Ok::<_, ()>(msg.ack()),
+                        _ => Ready::Ok(msg.disconnect()),
+                    },
+                ))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+
+    io.send(
+        codec::Packet::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(ByteString::from("topic"), codec::QoS::AtLeastOnce)],
+        },
+        &codec,
+    )
+    .await
+    .unwrap();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_ack_order() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| async {
+                sleep(Duration::from_millis(100)).await;
+                Ok::<_, ()>(())
+            })
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        assert_eq!(sub.qos(), codec::QoS::AtLeastOnce);
+                        sub.topic();
+                        sub.subscribe(codec::QoS::AtLeastOnce);
+                    }
+                    Ready::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from("test"),
+            packet_id: Some(NonZeroU16::new(1).unwrap()),
+            payload: Bytes::new(),
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    io.send(
+        codec::Packet::Subscribe {
+            packet_id: NonZeroU16::new(2).unwrap(),
+            topic_filters: vec![(ByteString::from("topic1"), codec::QoS::AtLeastOnce)],
+        },
+        &codec,
+    )
+    .await
+    .unwrap();
+    io.send(
+        codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from("test"),
+            packet_id: Some(NonZeroU16::new(3).unwrap()),
+            payload: Bytes::new(),
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() });
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::SubscribeAck {
+            packet_id: NonZeroU16::new(2).unwrap(),
+            status: vec![codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce)],
+        }
+    );
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishAck { packet_id: NonZeroU16::new(3).unwrap() });
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_ack_order_sink() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| async {
+                sleep(Duration::from_millis(100)).await;
+                Ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let topic = ByteString::from_static("test");
+    let fut1 = sink.publish(topic.clone(), Bytes::from_static(b"pkt1")).send_at_least_once();
+    let fut2 = sink.publish(topic.clone(), Bytes::from_static(b"pkt2")).send_at_least_once();
+    let fut3 = sink.publish(topic.clone(), Bytes::from_static(b"pkt3")).send_at_least_once();
+
+    let res = join_all(vec![fut1, fut2, fut3]).await;
+    assert!(res[0].is_ok());
+    assert!(res[1].is_ok());
+    assert!(res[2].is_ok());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_disconnect() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                Ready::Ok(ntex::service::fn_service(move |_: Publish| {
+                    session.sink().force_close();
+                    async {
+                        sleep(Duration::from_millis(100)).await;
+                        Ok(())
+                    }
+                }))
+            }))
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_err());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_will_on_close() -> std::io::Result<()> {
+    let closed = Arc::new(Mutex::new(Vec::new()));
+    let closed2 = closed.clone();
+
+    let srv = server::test_server(move || {
+        let closed = closed2.clone();
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok(()))
+            .control(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let closed = closed.clone();
+                assert_eq!(session.will().unwrap().topic, "will");
+                Ready::Ok::<_, ()>(ntex::service::fn_service(move |msg| match msg {
+                    ControlMessage::Closed(mut msg) => {
+                        let will = msg.take_will().map(|will| will.topic);
+                        closed.lock().unwrap().push((msg.is_clean(), will));
+                        Ready::Ok(msg.ack())
+                    }
+                    ControlMessage::Disconnect(msg) => Ready::Ok(msg.ack()),
+                    _ => Ready::Ok(msg.disconnect()),
+                }))
+            }))
+            .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let connect = || {
+        let mut pkt = codec::Connect::default().client_id("user");
+        pkt.last_will = Some(codec::LastWill {
+            qos: codec::QoS::AtLeastOnce,
+            retain: false,
+            topic: ByteString::from_static("will"),
+            message: Bytes::from_static(b"gone"),
+        });
+        codec::Packet::from(pkt)
+    };
+
+    // abnormal close
+    let io = srv.connect().await.unwrap();
+    io.send(connect(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    io.close();
+    drop(io);
+    sleep(Millis(100)).await;
+    assert_eq!(*closed.lock().unwrap(), vec![(false, Some(ByteString::from_static("will")))]);
+
+    // clean disconnect discards will
+    let io = srv.connect().await.unwrap();
+    io.send(connect(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    io.send(codec::Packet::Disconnect, &codec).await.unwrap();
+    sleep(Millis(100)).await;
+    assert_eq!(closed.lock().unwrap()[1], (true, None));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_qos2() -> std::io::Result<()> {
+    let result = Arc::new(Mutex::new(Vec::new()));
+    let result2 = result.clone();
+
+    let srv = server::test_server(move || {
+        let result = result2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let result = result.clone();
+                Ready::Ok::<_, ()>(ntex::service::fn_service(move |_: Publish| {
+                    let result = result.clone();
+                    let fut = session.sink().publish_qos2("out", Bytes::new());
+                    ntex::rt::spawn(async move {
+                        let res = fut.await;
+                        result.lock().unwrap().push(res.is_ok());
+                    });
+                    Ready::Ok(())
+                }))
+            }))
+            .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let trigger = || {
+        codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static("trigger"),
+            packet_id: None,
+            payload: Bytes::new(),
+        })
+    };
+
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(trigger(), &codec).await.unwrap();
+    let packet_id = match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::Publish(pkt) => {
+            assert_eq!(pkt.qos, codec::QoS::ExactlyOnce);
+            pkt.packet_id.unwrap()
+        }
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    };
+
+    io.send(codec::Packet::PublishReceived { packet_id }, &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishRelease { packet_id });
+
+    // duplicate PUBREC is ignored
+    io.send(codec::Packet::PublishReceived { packet_id }, &codec).await.unwrap();
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+    assert!(result.lock().unwrap().is_empty());
+
+    io.send(codec::Packet::PublishComplete { packet_id }, &codec).await.unwrap();
+    sleep(Millis(50)).await;
+    assert_eq!(*result.lock().unwrap(), vec![true]);
+
+    // connection is dropped before PUBCOMP
+    io.send(trigger(), &codec).await.unwrap();
+    let packet_id = match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::Publish(pkt) => pkt.packet_id.unwrap(),
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    };
+    io.send(codec::Packet::PublishReceived { packet_id }, &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    io.close();
+    drop(io);
+    sleep(Millis(100)).await;
+    assert_eq!(*result.lock().unwrap(), vec![true, false]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_pubcomp_timeout() -> std::io::Result<()> {
+    let result = Arc::new(Mutex::new(Vec::new()));
+    let result2 = result.clone();
+    let timeouts = Arc::new(AtomicUsize::new(0));
+    let timeouts2 = timeouts.clone();
+
+    let srv = server::test_server(move || {
+        let result = result2.clone();
+        let timeouts = timeouts2.clone();
+        MqttServer::new(handshake)
+            .pubcomp_timeout(Seconds(1))
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let result = result.clone();
+                Ready::Ok::<_, ()>(ntex::service::fn_service(move |_: Publish| {
+                    let result = result.clone();
+                    let fut = session.sink().publish_qos2("out", Bytes::new());
+                    ntex::rt::spawn(async move {
+                        let res = fut.await;
+                        result.lock().unwrap().push(res.is_ok());
+                    });
+                    Ready::Ok(())
+                }))
+            }))
+            .control(move |msg| match msg {
+                ControlMessage::ProtocolError(msg) => {
+                    if let ntex_mqtt::error::ProtocolError::PubcompTimeout = msg.get_ref() {
+                        timeouts.fetch_add(1, Relaxed);
+                    }
+                    Ready::Ok::<_, ()>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static("trigger"),
+            packet_id: None,
+            payload: Bytes::new(),
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let packet_id = match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::Publish(pkt) => pkt.packet_id.unwrap(),
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    };
+
+    // client never sends PUBCOMP
+    io.send(codec::Packet::PublishReceived { packet_id }, &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishRelease { packet_id });
+
+    // PUBREL is retransmitted once, then connection is closed
+    let pkt = ntex::time::timeout(Millis(1500), io.recv(&codec)).await;
+    assert_eq!(pkt.unwrap().unwrap().unwrap(), codec::Packet::PublishRelease { packet_id });
+    let res = ntex::time::timeout(Millis(1500), io.recv(&codec)).await;
+    assert!(res.unwrap().unwrap().is_none());
+
+    sleep(Millis(100)).await;
+    assert_eq!(timeouts.load(Relaxed), 1);
+    assert_eq!(*result.lock().unwrap(), vec![false]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_qos2_mixed() -> std::io::Result<()> {
+    let result = Arc::new(Mutex::new(Vec::new()));
+    let result2 = result.clone();
+
+    let srv = server::test_server(move || {
+        let result = result2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let result = result.clone();
+                Ready::Ok::<_, ()>(ntex::service::fn_service(move |p: Publish| {
+                    let result = result.clone();
+                    let topic = p.topic().path().to_string();
+                    let sink = session.sink();
+                    if topic == "qos2" {
+                        let fut = sink.publish_qos2("out", Bytes::new());
+                        ntex::rt::spawn(async move {
+                            let res = fut.await;
+                            result.lock().unwrap().push((topic, res.is_ok()));
+                        });
+                    } else {
+                        let fut = sink
+                            .publish(ByteString::from_static("out"), Bytes::new())
+                            .send_at_least_once();
+                        ntex::rt::spawn(async move {
+                            let res = fut.await;
+                            result.lock().unwrap().push((topic, res.is_ok()));
+                        });
+                    }
+                    Ready::Ok(())
+                }))
+            }))
+            .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let trigger = |topic| {
+        codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static(topic),
+            packet_id: None,
+            payload: Bytes::new(),
+        })
+    };
+    let packet_id = |pkt| match pkt {
+        codec::Packet::Publish(pkt) => pkt.packet_id.unwrap(),
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    };
+
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(trigger("qos2"), &codec).await.unwrap();
+    let id1 = packet_id(io.recv(&codec).await.unwrap().unwrap());
+    io.send(trigger("qos1"), &codec).await.unwrap();
+    let id2 = packet_id(io.recv(&codec).await.unwrap().unwrap());
+
+    // PUBCOMP of qos2 publish is received before PUBACK of later qos1 publish
+    io.send(codec::Packet::PublishReceived { packet_id: id1 }, &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishRelease { packet_id: id1 });
+    io.send(codec::Packet::PublishComplete { packet_id: id1 }, &codec).await.unwrap();
+    io.send(codec::Packet::PublishAck { packet_id: id2 }, &codec).await.unwrap();
+    sleep(Millis(100)).await;
+    assert_eq!(
+        *result.lock().unwrap(),
+        vec![("qos2".to_string(), true), ("qos1".to_string(), true)]
+    );
+
+    // connection is still open
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_write_buffer() -> std::io::Result<()> {
+    let sent = Rc::new(Cell::new(0));
+    let waited = Rc::new(Cell::new(0));
+    let ready = Rc::new(Cell::new(None));
+    let (sent2, waited2, ready2) = (sent.clone(), waited.clone(), ready.clone());
+
+    let factory = MqttServer::new(handshake)
+        .max_write_buffer(128)
+        .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+            let (sent, waited, ready) = (sent2.clone(), waited2.clone(), ready2.clone());
+            Ready::Ok::<_, ()>(ntex::service::fn_service(move |_: Publish| {
+                let sink = session.sink().clone();
+                while sink
+                    .publish(ByteString::from_static("out"), Bytes::from(vec![0; 100]))
+                    .send_at_most_once()
+                    .is_ok()
+                {
+                    sent.set(sent.get() + 1);
+                }
+                let ready = ready.clone();
+                let waited = waited.clone();
+                ntex::rt::spawn(async move {
+                    for _ in 0..3 {
+                        sink.publish(ByteString::from_static("out"), Bytes::from(vec![0; 10]))
+                            .send_at_most_once_wait()
+                            .await
+                            .unwrap();
+                        waited.set(waited.get() + 1);
+                    }
+                    ready.set(Some(sink.ready().await))
+                });
+                Ready::Ok(())
+            }))
+        }))
+        .finish();
+    let srv = ServiceFactory::<IoBoxed>::new_service(&factory, ()).await.unwrap();
+
+    let (client, server) = ntex::testing::Io::create();
+    // only connect-ack fits into client buffer
+    client.remote_buffer_cap(4);
+    ntex::rt::spawn(async move {
+        let _ = srv.call(IoBoxed::from(ntex::io::Io::new(server))).await;
+    });
+
+    let codec = codec::Codec::default();
+    let mut buf = BytesMut::new();
+    codec.encode(codec::Connect::default().client_id("user").into(), &mut buf).unwrap();
+    client.write(buf.split());
+    sleep(Millis(20)).await;
+    assert_eq!(client.read_any().len(), 4);
+
+    codec
+        .encode(
+            codec::Packet::Publish(codec::Publish {
+                dup: false,
+                retain: false,
+                qos: codec::QoS::AtMostOnce,
+                topic: ByteString::from_static("trigger"),
+                packet_id: None,
+                payload: Bytes::new(),
+            }),
+            &mut buf,
+        )
+        .unwrap();
+    client.write(buf);
+    sleep(Millis(50)).await;
+
+    assert_eq!(sent.get(), 2);
+    assert_eq!(waited.get(), 0);
+    assert_eq!(ready.get(), None);
+
+    // write buffer is flushed
+    client.remote_buffer_cap(4096);
+    sleep(Millis(50)).await;
+    assert_eq!(waited.get(), 3);
+    assert_eq!(ready.get(), Some(true));
+    assert!(client.read_any().len() > 200);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handshake_custom_exchange() -> std::io::Result<()> {
+    let factory = MqttServer::new(|con: Handshake| async move {
+        con.send_frame(Bytes::from_static(b"challenge"), &BytesCodec).await.unwrap();
+        let response = con.recv_frame(&BytesCodec).await.unwrap().unwrap();
+        Ok::<_, ()>(con.ack(response.freeze(), false))
+    })
+    .publish(ntex::service::fn_factory_with_config(|session: Session<Bytes>| {
+        Ready::Ok::<_, ()>(fn_service(move |_: Publish| {
+            assert_eq!(session.state(), &Bytes::from_static(b"response"));
+            Ready::Ok::<_, ()>(())
+        }))
+    }))
+    .finish();
+    let srv = ServiceFactory::<IoBoxed>::new_service(&factory, ()).await.unwrap();
+
+    let (client, server) = ntex::testing::Io::create();
+    client.remote_buffer_cap(1024);
+    ntex::rt::spawn(async move {
+        let _ = srv.call(IoBoxed::from(ntex::io::Io::new(server))).await;
+    });
+
+    let codec = codec::Codec::default();
+    let mut buf = BytesMut::new();
+    codec.encode(codec::Connect::default().client_id("user").into(), &mut buf).unwrap();
+    client.write(buf.split());
+    sleep(Millis(20)).await;
+    assert_eq!(client.read_any(), Bytes::from_static(b"challenge"));
+
+    client.write(b"response");
+    sleep(Millis(20)).await;
+    let mut buf = BytesMut::from(&client.read_any()[..]);
+    assert_eq!(
+        codec.decode(&mut buf).unwrap().unwrap(),
+        codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::ConnectionAccepted
+        }
+    );
+
+    codec
+        .encode(
+            codec::Packet::Publish(codec::Publish {
+                dup: false,
+                retain: false,
+                qos: codec::QoS::AtLeastOnce,
+                topic: ByteString::from_static("test"),
+                packet_id: NonZeroU16::new(1),
+                payload: Bytes::new(),
+            }),
+            &mut buf,
+        )
+        .unwrap();
+    client.write(buf.split());
+    sleep(Millis(20)).await;
+    let mut buf = BytesMut::from(&client.read_any()[..]);
+    assert_eq!(
+        codec.decode(&mut buf).unwrap().unwrap(),
+        codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() }
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_inflight() -> std::io::Result<()> {
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let exceeded2 = exceeded.clone();
+
+    let srv = server::test_server(move || {
+        let exceeded = exceeded2.clone();
+        MqttServer::new(handshake)
+            .max_inflight(2)
+            .publish(|_| async {
+                sleep(Millis(500)).await;
+                Ok(())
+            })
+            .control(move |msg| match msg {
+                ControlMessage::ProtocolError(msg) => {
+                    if let ntex_mqtt::error::ProtocolError::ReceiveMaximumExceeded =
+                        msg.get_ref()
+                    {
+                        exceeded.store(true, Relaxed);
+                    }
+                    Ready::Ok::<_, ()>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    for id in 1..4 {
+        let pkt = codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: NonZeroU16::new(id),
+            payload: Bytes::new(),
+        };
+        io.send(codec::Packet::Publish(pkt), &codec).await.unwrap();
+    }
+
+    // third publish exceeds window
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    assert!(exceeded.load(Relaxed));
+
+    Ok(())
+}
+
+#[test]
+#[should_panic]
+fn test_max_inflight_zero() {
+    let _ = MqttServer::new(handshake).max_inflight(0);
+}
+
+#[ntex::test]
+async fn test_inbound_inflight_not_limited() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| async {
+                sleep(Millis(100)).await;
+                Ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    // more publishes than default outbound window
+    for id in 1..33 {
+        let pkt = codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: NonZeroU16::new(id),
+            payload: Bytes::new(),
+        };
+        io.send(codec::Packet::Publish(pkt), &codec).await.unwrap();
+    }
+
+    for id in 1..33 {
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        assert_eq!(pkt, codec::Packet::PublishAck { packet_id: NonZeroU16::new(id).unwrap() });
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_router_topic_filter() -> std::io::Result<()> {
+    let routed = Arc::new(Mutex::new(Vec::new()));
+    let routed2 = routed.clone();
+
+    let srv = server::test_server(move || {
+        let (r1, r2, r3, r4) =
+            (routed2.clone(), routed2.clone(), routed2.clone(), routed2.clone());
+        MqttServer::new(handshake)
+            .publish(
+                Router::new(move |p: Publish| {
+                    r1.lock().unwrap().push(format!("default:{}", p.publish_topic()));
+                    Ready::Ok(())
+                })
+                .resource("sensors/all/temp", move |_: Publish| {
+                    r2.lock().unwrap().push("resource".to_string());
+                    Ready::Ok(())
+                })
+                .filter("sensors/+/temp", move |mut p: Publish| {
+                    // segments are kept after topic is changed
+                    p.topic_mut().set(ByteString::from_static("t"));
+                    r3.lock().unwrap().push(format!("temp:{}", p.segment(0).unwrap()));
+                    Ready::Ok(())
+                })
+                .filter("logs/+/#", move |p: Publish| {
+                    let (s0, s1) = (p.segment(0).unwrap(), p.segment(1).unwrap());
+                    assert!(p.segment(2).is_none());
+                    r4.lock().unwrap().push(format!("logs:{}:{}", s0, s1));
+                    Ready::Ok(())
+                }),
+            )
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    for topic in [
+        "sensors/all/temp",
+        "sensors/kitchen/temp",
+        "sensors/kitchen/humidity",
+        "logs/app/error/db",
+        "logs/app",
+    ] {
+        let res =
+            sink.publish(ByteString::from(topic), Bytes::new()).send_at_least_once().await;
+        assert!(res.is_ok());
+    }
+    assert_eq!(
+        *routed.lock().unwrap(),
+        vec![
+            "resource".to_string(),
+            "temp:kitchen".to_string(),
+            "default:sensors/kitchen/humidity".to_string(),
+            "logs:app:error/db".to_string(),
+            "logs:app:".to_string(),
+        ]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_reconnecting_client() -> std::io::Result<()> {
+    let connects = Arc::new(AtomicUsize::new(0));
+    let connects2 = connects.clone();
+    let subscribed = Arc::new(Mutex::new(Vec::new()));
+    let subscribed2 = subscribed.clone();
+    let published = Arc::new(Mutex::new(Vec::new()));
+    let published2 = published.clone();
+
+    let srv = server::test_server(move || {
+        let connects = connects2.clone();
+        let subscribed = subscribed2.clone();
+        let published = published2.clone();
+        MqttServer::new(move |con: Handshake| {
+            connects.fetch_add(1, Relaxed);
+            Ready::Ok::<_, ()>(con.ack(St, false))
+        })
+        .publish(move |p: Publish| {
+            published.lock().unwrap().push(p.publish_topic().to_string());
+            // close connection
+            if p.publish_topic() == "drop" {
+                Ready::Err(())
+            } else {
+                Ready::Ok(())
+            }
+        })
+        .control(move |msg| match msg {
+            ControlMessage::Subscribe(mut msg) => {
+                for mut sub in &mut msg {
+                    subscribed.lock().unwrap().push(sub.topic().to_string());
+                    sub.subscribe(codec::QoS::AtLeastOnce);
+                }
+                Ready::Ok::<_, ()>(msg.ack())
+            }
+            _ => Ready::Ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    let addr = srv.addr();
+    let client = client::ReconnectingClient::new(move || {
+        client::MqttConnector::new(addr).client_id("user").connect()
+    })
+    .backoff(client::Backoff::new(Millis(10), Millis(50)))
+    .offline_policy(client::OfflinePolicy::Buffer(1));
+    let sink = client.sink();
+
+    // stored until connected
+    sink.subscribe(ByteString::from_static("a/#"), codec::QoS::AtLeastOnce).await.unwrap();
+    assert!(!sink.is_connected());
+    ntex::rt::spawn(client.start(|| {
+        fn_service(|msg: client::ControlMessage<()>| Ready::Ok::<_, ()>(msg.disconnect()))
+    }));
+
+    let wait_connected = |connected: bool| {
+        let sink = sink.clone();
+        async move {
+            for _ in 0..100 {
+                if sink.is_connected() == connected {
+                    return;
+                }
+                sleep(Millis(10)).await;
+            }
+            panic!("Timeout");
+        }
+    };
+    wait_connected(true).await;
+    assert_eq!(*subscribed.lock().unwrap(), vec!["a/#".to_string()]);
+
+    sink.publish(ByteString::from_static("drop"), Bytes::new(), codec::QoS::AtMostOnce)
+        .await
+        .unwrap();
+    wait_connected(false).await;
+
+    // publish is buffered and subscription is stored while disconnected
+    let buffered = sink.publish(
+        ByteString::from_static("buffered"),
+        Bytes::new(),
+        codec::QoS::AtLeastOnce,
+    );
+    let res = sink
+        .publish(ByteString::from_static("overflow"), Bytes::new(), codec::QoS::AtLeastOnce)
+        .await;
+    assert!(matches!(res, Err(SendPacketError::WriteBufferFull)));
+    sink.subscribe(ByteString::from_static("b"), codec::QoS::AtLeastOnce).await.unwrap();
+
+    assert!(buffered.await.is_ok());
+    assert!(sink.is_connected());
+    assert_eq!(connects.load(Relaxed), 2);
+    assert_eq!(
+        *subscribed.lock().unwrap(),
+        vec!["a/#".to_string(), "a/#".to_string(), "b".to_string()]
+    );
+    assert_eq!(*published.lock().unwrap(), vec!["drop".to_string(), "buffered".to_string()]);
+
+    sink.close();
+    let res = sink.publish(ByteString::from_static("t"), Bytes::new(), codec::QoS::AtMostOnce);
+    assert!(matches!(res.await, Err(SendPacketError::Disconnected)));
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_reconnecting_client_flap() -> std::io::Result<()> {
+    let connects = Arc::new(AtomicUsize::new(0));
+    let connects2 = connects.clone();
+    let subscribed = Arc::new(Mutex::new(Vec::new()));
+    let subscribed2 = subscribed.clone();
+
+    let srv = server::test_server(move || {
+        let connects = connects2.clone();
+        let subscribed = subscribed2.clone();
+        let connects3 = connects.clone();
+        MqttServer::new(move |con: Handshake| {
+            connects3.fetch_add(1, Relaxed);
+            Ready::Ok::<_, ()>(con.ack(St, false))
+        })
+        .publish(|_| Ready::Ok(()))
+        .control(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+            let num = connects.load(Relaxed);
+            let subscribed = subscribed.clone();
+            Ready::Ok::<_, ()>(fn_service(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        subscribed.lock().unwrap().push((num, sub.topic().to_string()));
+                        sub.subscribe(codec::QoS::AtLeastOnce);
+                    }
+                    // first connections are dropped before subscribe is acknowledged
+                    if num < 3 {
+                        session.sink().force_close();
+                    }
+                    Ready::Ok::<_, ()>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            }))
+        }))
+        .finish()
+    });
+
+    let addr = srv.addr();
+    let client = client::ReconnectingClient::new(move || {
+        client::MqttConnector::new(addr).client_id("user").connect()
+    })
+    .backoff(client::Backoff::new(Millis(5), Millis(10)));
+    let sink = client.sink();
+
+    sink.subscribe(ByteString::from_static("a"), codec::QoS::AtLeastOnce).await.unwrap();
+    sink.subscribe(ByteString::from_static("b"), codec::QoS::AtLeastOnce).await.unwrap();
+    ntex::rt::spawn(client.start(|| {
+        fn_service(|msg: client::ControlMessage<()>| Ready::Ok::<_, ()>(msg.disconnect()))
+    }));
+
+    for _ in 0..100 {
+        if sink.is_connected() && connects.load(Relaxed) == 3 {
+            break;
+        }
+        sleep(Millis(10)).await;
+    }
+    assert!(sink.is_connected());
+    sink.subscribe(ByteString::from_static("c"), codec::QoS::AtLeastOnce).await.unwrap();
+    assert_eq!(connects.load(Relaxed), 3);
+
+    // each connection subscribes every filter once
+    let subscribed = subscribed.lock().unwrap().clone();
+    for num in 1..3 {
+        let filters: Vec<_> =
+            subscribed.iter().filter(|(n, _)| *n == num).map(|(_, f)| f.as_str()).collect();
+        assert_eq!(filters, vec!["a", "b"]);
+    }
+    let filters: Vec<_> =
+        subscribed.iter().filter(|(n, _)| *n == 3).map(|(_, f)| f.as_str()).collect();
+    assert_eq!(filters, vec!["a", "b", "c"]);
+
+    sink.close();
+    Ok(())
+}
+
+#[test]
+#[should_panic]
+fn test_router_invalid_filter() {
+    let _ = Router::<St, ()>::new(|_: Publish| Ready::Ok(()))
+        .filter("sensors/#/temp", |_: Publish| Ready::Ok(()));
+}
+
+#[ntex::test]
+async fn test_handle_incoming() -> std::io::Result<()> {
+    let publish = Arc::new(AtomicBool::new(false));
+    let publish2 = publish.clone();
+    let disconnect = Arc::new(AtomicBool::new(false));
+    let disconnect2 = disconnect.clone();
+
+    let srv = server::test_server(move || {
+        let publish = publish2.clone();
+        let disconnect = disconnect2.clone();
+        MqttServer::new(handshake)
+            .pre_connack_publish_policy(PreConnackPublishPolicy::Buffer)
+            .publish(move |_| {
+                publish.store(true, Relaxed);
+                async {
+                    sleep(Duration::from_millis(100)).await;
+                    Ok(())
+                }
+            })
+            .control(move |msg| match msg {
+                ControlMessage::Disconnect(msg) => {
+                    disconnect.store(true, Relaxed);
+                    Ready::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.encode(codec::Connect::default().client_id("user").into(), &codec).unwrap();
+    io.encode(
+        codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from("test"),
+            packet_id: Some(NonZeroU16::new(3).unwrap()),
+            payload: Bytes::new(),
+        }
+        .into(),
+        &codec,
+    )
+    .unwrap();
+    io.encode(codec::Packet::Disconnect, &codec).unwrap();
+    io.flush(true).await.unwrap();
+    sleep(Millis(50)).await;
+    drop(io);
+    sleep(Millis(50)).await;
+
+    assert!(publish.load(Relaxed));
+    assert!(disconnect.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_large_publish() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake).publish(|_| Ready::Ok(())).finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.encode(codec::Connect::default().client_id("user").into(), &codec).unwrap();
+    let _ = io.recv(&codec).await;
+
+    let p = codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::AtLeastOnce,
+        topic: ByteString::from("test"),
+        packet_id: Some(NonZeroU16::new(3).unwrap()),
+        payload: Bytes::from(vec![b'*'; 270 * 1024]),
+    }
+    .into();
+    let res = io.send(p, &codec).await;
+    assert!(res.is_ok());
+    let result = io.recv(&codec).await;
+    assert!(result.is_ok());
+
+    Ok(())
+}
+
+fn ssl_acceptor() -> openssl::ssl::SslAcceptor {
+    use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+
+    // load ssl keys
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    builder.set_private_key_file("./tests/key.pem", SslFiletype::PEM).unwrap();
+    builder.set_certificate_chain_file("./tests/cert.pem").unwrap();
+    builder.build()
+}
+
+#[ntex::test]
+async fn test_large_publish_openssl() -> std::io::Result<()> {
+    use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+    env_logger::init();
+
+    let srv = server::test_server(move || {
+        pipeline_factory(server::openssl::Acceptor::new(ssl_acceptor()).map_err(|_| ()))
+            .and_then(
+                MqttServer::new(handshake)
+                    .publish(|_| Ready::Ok(()))
+                    .finish()
+                    .map_err(|_| ())
+                    .map_init_err(|_| ()),
+            )
+    });
+
+    let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+    builder.set_verify(SslVerifyMode::NONE);
+    let con = ntex::connect::openssl::Connector::new(builder.build());
+    let addr = format!("127.0.0.1:{}", srv.addr().port());
+    let io = con.call(addr.into()).await.unwrap();
+
+    let codec = codec::Codec::default();
+    io.encode(codec::Connect::default().client_id("user").into(), &codec).unwrap();
+    let _ = io.recv(&codec).await;
+
+    let p = codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::AtLeastOnce,
+        topic: ByteString::from("test"),
+        packet_id: Some(NonZeroU16::new(3).unwrap()),
+        payload: Bytes::from(vec![b'*'; 270 * 1024]),
+    }
+    .into();
+    let res = io.send(p, &codec).await;
+    assert!(res.is_ok());
+    let result = io.recv(&codec).await;
+    assert!(result.is_ok());
+
+    Ok(())
+}
+
+#[derive(Clone, Default)]
+struct Counters {
+    packets_in: Arc<Mutex<Vec<u8>>>,
+    packets_out: Arc<Mutex<Vec<u8>>>,
+    bytes_out: Arc<AtomicUsize>,
+}
+
+impl Metrics for Counters {
+    fn on_packet_in(&self, packet_type: u8) {
+        self.packets_in.lock().unwrap().push(packet_type);
+    }
+
+    fn on_packet_out(&self, packet_type: u8) {
+        self.packets_out.lock().unwrap().push(packet_type);
+    }
+
+    fn on_bytes(&self, dir: Direction, n: usize) {
+        if dir == Direction::Encode {
+            self.bytes_out.fetch_add(n, Relaxed);
+        }
+    }
+}
+
+#[ntex::test]
+async fn test_metrics() -> std::io::Result<()> {
+    let counters = Counters::default();
+    let metrics = counters.clone();
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok::<_, ()>(()))
+            .metrics(metrics.clone())
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sink.close();
+    sleep(Millis(100)).await;
+
+    assert_eq!(*counters.packets_in.lock().unwrap(), vec![1, 3]);
+    assert_eq!(*counters.packets_out.lock().unwrap(), vec![2, 4]);
+    // CONNACK and PUBACK, 4 bytes each
+    assert_eq!(counters.bytes_out.load(Relaxed), 8);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_selector_variant_settings() -> std::io::Result<()> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    let counters = Counters::default();
+    let metrics = counters.clone();
+
+    let srv = server::test_server(move || {
+        let srv =
+            MqttServer::new(handshake).publish(|_| Ready::Ok(())).metrics(metrics.clone());
+        let mut stream = srv.lifecycle_events();
+        let events = events2.clone();
+        ntex::rt::spawn(async move {
+            while let Some(ev) = ntex::util::stream_recv(&mut stream).await {
+                events.lock().unwrap().push(ev.kind().clone());
+            }
+        });
+        Selector::new().lenient_protocol_name(true).variant(|_| Ready::Ok(true), srv)
+    });
+    let strict = server::test_server(move || {
+        Selector::new()
+            .variant(|_| Ready::Ok(true), MqttServer::new(handshake).publish(|_| Ready::Ok(())))
+    });
+
+    let codec = codec::Codec::default();
+    let mut buf = BytesMut::new();
+    codec.encode(codec::Connect::default().client_id("user").into(), &mut buf).unwrap();
+    buf[4..8].copy_from_slice(b"mqtt");
+    let connect = buf.freeze();
+
+    // selector's lenient protocol name
+    let io = srv.connect().await.unwrap();
+    io.send(connect.clone(), &BytesCodec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(
+        pkt,
+        codec::Packet::ConnectAck {
+            return_code: codec::ConnectAckReason::ConnectionAccepted,
+            ..
+        }
+    ));
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    io.send(codec::Packet::Disconnect, &codec).await.unwrap();
+    sleep(Millis(150)).await;
+
+    // variant's events and metrics
+    assert_eq!(
+        &*events.lock().unwrap(),
+        &[LifecycleEventKind::Connected, LifecycleEventKind::Disconnected]
+    );
+    assert_eq!(*counters.packets_in.lock().unwrap(), vec![12, 14]);
+    assert_eq!(*counters.packets_out.lock().unwrap(), vec![2, 13]);
+
+    // strict selector rejects connection with connect-ack
+    let io = strict.connect().await.unwrap();
+    io.send(connect, &BytesCodec).await.unwrap();
+    assert_eq!(
+        io.recv(&codec).await.unwrap().unwrap(),
+        codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::UnacceptableProtocolVersion,
+        }
+    );
+    assert!(io.recv(&codec).await.unwrap().is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_subscribe() -> std::io::Result<()> {
+    let result = Rc::new(RefCell::new(None));
+    let publishes = Rc::new(Cell::new(0));
+    let (result2, publishes2) = (result.clone(), publishes.clone());
+
+    let factory = MqttServer::new(handshake)
+        .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+            let (result, publishes) = (result2.clone(), publishes2.clone());
+            Ready::Ok::<_, ()>(ntex::service::fn_service(move |p: Publish| {
+                publishes.set(publishes.get() + 1);
+                if p.topic().path() == "trigger" {
+                    let sink = session.sink().clone();
+                    let result = result.clone();
+                    ntex::rt::spawn(async move {
+                        let codes = sink
+                            .subscribe()
+                            .topic_filter(
+                                ByteString::from_static("up/#"),
+                                codec::QoS::AtLeastOnce,
+                            )
+                            .topic_filter(
+                                ByteString::from_static("denied"),
+                                codec::QoS::AtMostOnce,
+                            )
+                            .send()
+                            .await;
+                        let unsub = sink
+                            .unsubscribe()
+                            .topic_filter(ByteString::from_static("up/#"))
+                            .send()
+                            .await;
+                        *result.borrow_mut() = Some((codes, unsub));
+                    });
+                }
+                Ready::Ok(())
+            }))
+        }))
+        .finish();
+    let srv = ServiceFactory::<IoBoxed>::new_service(&factory, ()).await.unwrap();
+
+    let (client, server) = ntex::testing::Io::create();
+    client.remote_buffer_cap(1024);
+    ntex::rt::spawn(async move {
+        let _ = srv.call(IoBoxed::from(ntex::io::Io::new(server))).await;
+    });
+
+    let publish = |topic| {
+        codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static(topic),
+            packet_id: None,
+            payload: Bytes::new(),
+        })
+    };
+
+    let codec = codec::Codec::default();
+    let mut buf = BytesMut::new();
+    codec.encode(codec::Connect::default().client_id("user").into(), &mut buf).unwrap();
+    client.write(buf.split());
+    sleep(Millis(20)).await;
+    let mut buf = BytesMut::from(&client.read_any()[..]);
+    let pkt = codec.decode(&mut buf).unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::ConnectAck { .. }));
+
+    codec.encode(publish("trigger"), &mut buf).unwrap();
+    client.write(buf.split());
+    sleep(Millis(50)).await;
+
+    let mut buf = BytesMut::from(&client.read_any()[..]);
+    let packet_id = match codec.decode(&mut buf).unwrap().unwrap() {
+        codec::Packet::Subscribe { packet_id, topic_filters } => {
+            assert_eq!(topic_filters.len(), 2);
+            packet_id
+        }
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    };
+
+    // publish for subscribed topic is handled by publish service
+    codec.encode(publish("up/1"), &mut buf).unwrap();
+    codec
+        .encode(
+            codec::Packet::SubscribeAck {
+                packet_id,
+                status: vec![
+                    codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce),
+                    codec::SubscribeReturnCode::Failure,
+                ],
+            },
+            &mut buf,
+        )
+        .unwrap();
+    client.write(buf.split());
+    sleep(Millis(50)).await;
+    assert_eq!(publishes.get(), 2);
+
+    let mut buf = BytesMut::from(&client.read_any()[..]);
+    let packet_id = match codec.decode(&mut buf).unwrap().unwrap() {
+        codec::Packet::Unsubscribe { packet_id, .. } => packet_id,
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    };
+    codec.encode(codec::Packet::UnsubscribeAck { packet_id }, &mut buf).unwrap();
+    client.write(buf.split());
+    sleep(Millis(50)).await;
+
+    let (codes, unsub) = result.borrow_mut().take().unwrap();
+    assert_eq!(
+        codes.unwrap(),
+        vec![
+            codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce),
+            codec::SubscribeReturnCode::Failure
+        ]
+    );
+    assert!(unsub.is_ok());
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_encoder_error_recovery() -> std::io::Result<()> {
+    let delivered = Arc::new(AtomicBool::new(false));
+    let delivered2 = delivered.clone();
+
+    let srv = server::test_server(move || {
+        let delivered = delivered2.clone();
+        MqttServer::new(move |con: Handshake| {
+            let sink = con.sink();
+            let delivered = delivered.clone();
+            ntex::rt::spawn(async move {
+                // topic is too long, packet could not be encoded
+                let topic = ByteString::from("t".repeat(70_000));
+                let res = sink.publish(topic, Bytes::new()).send_at_least_once().await;
+                assert!(matches!(
+                    res,
+                    Err(SendPacketError::Encode(ntex_mqtt::error::EncodeError::InvalidLength))
+                ));
+
+                // in-flight slot is released, connection is still usable
+                let res = sink
+                    .publish(ByteString::from_static("test"), Bytes::new())
+                    .send_at_least_once()
+                    .await;
+                assert!(res.is_ok());
+                delivered.store(true, Relaxed);
+            });
+            Ready::Ok::<_, ()>(con.ack(St, false))
+        })
+        .max_inflight(1)
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let packet_id = if let codec::Packet::Publish(pkt) = pkt {
+        assert_eq!(pkt.topic, "test");
+        pkt.packet_id.unwrap()
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    };
+    io.send(codec::Packet::PublishAck { packet_id }, &codec).await.unwrap();
+
+    sleep(Millis(50)).await;
+    assert!(delivered.load(Relaxed));
+    Ok(())
+}
+
+struct Registered(#[allow(dead_code)] Registration<MqttSink>);
+
+#[ntex::test]
+async fn test_session_takeover() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        let registry = SessionRegistry::new();
+        MqttServer::new(move |con: Handshake| {
+            let (registration, prev) =
+                registry.register(con.packet().client_id.clone(), con.sink());
+            if let Some(prev) = prev {
+                prev.close();
+            }
+            Ready::Ok::<_, ()>(con.ack(Registered(registration), false))
+        })
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink1 = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink2 = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sleep(Millis(100)).await;
+
+    // previous connection is closed
+    assert!(!sink1.ready().await);
+    let res =
+        sink2.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    Ok(())
+}
+
+struct EvictedSession {
+    conn: usize,
+    _registration: Registration<MqttSink>,
+}
+
+#[ntex::test]
+async fn test_session_takeover_evicted() -> std::io::Result<()> {
+    let evicted = Arc::new(Mutex::new(Vec::new()));
+    let evicted2 = evicted.clone();
+    let conn = Arc::new(AtomicUsize::new(0));
+
+    let srv = server::test_server(move || {
+        let registry = SessionRegistry::new();
+        let evicted = evicted2.clone();
+        let conn = conn.clone();
+        MqttServer::new(move |con: Handshake| {
+            let (registration, prev) =
+                registry.register(con.packet().client_id.clone(), con.sink());
+            let evicted = evicted.clone();
+            registration.on_evicted(move |st: EvictedSession| {
+                evicted.lock().unwrap().push(st.conn);
+            });
+            if let Some(prev) = prev {
+                prev.close();
+            }
+            let conn = conn.fetch_add(1, Relaxed);
+            Ready::Ok::<_, ()>(
+                con.ack(EvictedSession { conn, _registration: registration }, false),
+            )
+        })
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    ntex::rt::spawn(client.start_default());
+    sleep(Millis(50)).await;
+    assert!(evicted.lock().unwrap().is_empty());
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sleep(Millis(100)).await;
+
+    // state of evicted session is handed back
+    assert_eq!(*evicted.lock().unwrap(), vec![0]);
+
+    // closed session is not evicted
+    sink.close();
+    sleep(Millis(100)).await;
+    assert_eq!(*evicted.lock().unwrap(), vec![0]);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_min_inbound_qos() -> std::io::Result<()> {
+    let errors = Arc::new(AtomicUsize::new(0));
+    let errors2 = errors.clone();
+
+    let srv = server::test_server(move || {
+        let errors = errors2.clone();
+        MqttServer::new(handshake)
+            .min_inbound_qos(codec::QoS::AtLeastOnce)
+            .publish(|_| Ready::Ok(()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        let qos = sub.qos();
+                        sub.subscribe(qos);
+                    }
+                    Ready::Ok::<_, ()>(msg.ack())
+                }
+                ControlMessage::ProtocolError(msg) => {
+                    if let ntex_mqtt::error::ProtocolError::QosNotSupported = msg.get_ref() {
+                        errors.fetch_add(1, Relaxed);
+                    }
+                    Ready::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
 
+    // subscription with lower granted qos is failed
     io.send(
-        codec::Publish {
+        codec::Packet::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![
+                (ByteString::from("qos0"), codec::QoS::AtMostOnce),
+                (ByteString::from("qos1"), codec::QoS::AtLeastOnce),
+            ],
+        },
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::SubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            status: vec![
+                codec::SubscribeReturnCode::Failure,
+                codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce),
+            ],
+        }
+    );
+
+    // publish with qos 1 is accepted
+    io.send(
+        codec::Packet::Publish(codec::Publish {
             dup: false,
             retain: false,
             qos: codec::QoS::AtLeastOnce,
-            topic: ByteString::from("test"),
-            packet_id: Some(NonZeroU16::new(1).unwrap()),
+            topic: ByteString::from_static("test"),
+            packet_id: NonZeroU16::new(1),
             payload: Bytes::new(),
-        }
-        .into(),
+        }),
         &codec,
     )
     .await
     .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() });
+
+    // publish with qos 0 closes connection
     io.send(
-        codec::Packet::Subscribe {
-            packet_id: NonZeroU16::new(2).unwrap(),
-            topic_filters: vec![(ByteString::from("topic1"), codec::QoS::AtLeastOnce)],
-        },
+        codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: None,
+            payload: Bytes::new(),
+        }),
         &codec,
     )
     .await
     .unwrap();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    assert_eq!(errors.load(Relaxed), 1);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_on_idle() -> std::io::Result<()> {
+    let counts = Arc::new(Mutex::new(Vec::new()));
+    let counts2 = counts.clone();
+
+    let srv = server::test_server(move || {
+        let counts = counts2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let counts = counts.clone();
+                Ready::Ok::<_, ()>(ntex::service::fn_service(move |_: Publish| {
+                    let counts = counts.clone();
+                    let sink = session.sink().clone();
+                    let fut = sink
+                        .publish(ByteString::from_static("out"), Bytes::new())
+                        .send_at_least_once();
+                    counts.lock().unwrap().push(sink.inflight_count());
+                    let idle = sink.on_idle();
+                    ntex::rt::spawn(fut);
+                    ntex::rt::spawn(async move {
+                        if idle.await {
+                            counts.lock().unwrap().push(sink.inflight_count());
+                        }
+                    });
+                    Ready::Ok(())
+                }))
+            }))
+            .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
     io.send(
-        codec::Publish {
+        codec::Packet::Publish(codec::Publish {
             dup: false,
             retain: false,
-            qos: codec::QoS::AtLeastOnce,
-            topic: ByteString::from("test"),
-            packet_id: Some(NonZeroU16::new(3).unwrap()),
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static("trigger"),
+            packet_id: None,
             payload: Bytes::new(),
-        }
-        .into(),
+        }),
         &codec,
     )
     .await
     .unwrap();
+    let packet_id = match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::Publish(pkt) => pkt.packet_id.unwrap(),
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    };
+    sleep(Millis(50)).await;
+    assert_eq!(*counts.lock().unwrap(), vec![1]);
 
-    let pkt = io.recv(&codec).await.unwrap().unwrap();
-    assert_eq!(pkt, codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() });
+    // in-flight publish is acked, sink is idle
+    io.send(codec::Packet::PublishAck { packet_id }, &codec).await.unwrap();
+    sleep(Millis(100)).await;
+    assert_eq!(*counts.lock().unwrap(), vec![1, 0]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_lenient_protocol_name() -> std::io::Result<()> {
+    let strict = server::test_server(move || {
+        MqttServer::new(handshake).publish(|_| Ready::Ok(())).finish()
+    });
+    let lenient = server::test_server(move || {
+        MqttServer::new(handshake)
+            .lenient_protocol_name(true)
+            .publish(|_| Ready::Ok(()))
+            .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let connect = |name: &[u8]| {
+        let mut buf = BytesMut::new();
+        codec.encode(codec::Connect::default().client_id("user").into(), &mut buf).unwrap();
+        // fixed header (2 bytes) and protocol name length (2 bytes)
+        buf[4..8].copy_from_slice(name);
+        buf.freeze()
+    };
 
+    let rejected = codec::Packet::ConnectAck {
+        session_present: false,
+        return_code: codec::ConnectAckReason::UnacceptableProtocolVersion,
+    };
+
+    // wrong case name is accepted only in lenient mode
+    let io = strict.connect().await.unwrap();
+    io.send(connect(b"mqtt"), &BytesCodec).await.unwrap();
+    assert_eq!(io.recv(&codec).await.unwrap().unwrap(), rejected);
+    assert!(io.recv(&codec).await.unwrap().is_none());
+
+    let io = lenient.connect().await.unwrap();
+    io.send(connect(b"mqtt"), &BytesCodec).await.unwrap();
     let pkt = io.recv(&codec).await.unwrap().unwrap();
     assert_eq!(
         pkt,
-        codec::Packet::SubscribeAck {
-            packet_id: NonZeroU16::new(2).unwrap(),
-            status: vec![codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce)],
+        codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::ConnectionAccepted,
         }
     );
 
-    let pkt = io.recv(&codec).await.unwrap().unwrap();
-    assert_eq!(pkt, codec::Packet::PublishAck { packet_id: NonZeroU16::new(3).unwrap() });
+    // garbage name is rejected in both modes
+    for srv in [&strict, &lenient].iter() {
+        let io = srv.connect().await.unwrap();
+        io.send(connect(b"MQXT"), &BytesCodec).await.unwrap();
+        assert_eq!(io.recv(&codec).await.unwrap().unwrap(), rejected);
+        assert!(io.recv(&codec).await.unwrap().is_none());
+    }
 
     Ok(())
 }
 
 #[ntex::test]
-async fn test_ack_order_sink() -> std::io::Result<()> {
+async fn test_publish_at_min_qos() -> std::io::Result<()> {
     let srv = server::test_server(move || {
         MqttServer::new(handshake)
-            .publish(|_| async {
-                sleep(Duration::from_millis(100)).await;
-                Ok::<_, ()>(())
-            })
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                Ready::Ok::<_, ()>(ntex::service::fn_service(move |p: Publish| {
+                    // subscription is granted with qos from publish topic
+                    let granted = match p.topic().path() {
+                        "qos0" => codec::QoS::AtMostOnce,
+                        "qos1" => codec::QoS::AtLeastOnce,
+                        _ => codec::QoS::ExactlyOnce,
+                    };
+                    let fut = session
+                        .sink()
+                        .publish(ByteString::from_static("out"), Bytes::new())
+                        .send_at_min_qos(codec::QoS::ExactlyOnce, granted);
+                    ntex::rt::spawn(fut);
+                    Ready::Ok(())
+                }))
+            }))
             .finish()
     });
 
-    // connect to server
-    let client =
-        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
-    let sink = client.sink();
+    let codec = codec::Codec::default();
+    let trigger = |topic| {
+        codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static(topic),
+            packet_id: None,
+            payload: Bytes::new(),
+        })
+    };
 
-    ntex::rt::spawn(client.start_default());
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
 
-    let topic = ByteString::from_static("test");
-    let fut1 = sink.publish(topic.clone(), Bytes::from_static(b"pkt1")).send_at_least_once();
-    let fut2 = sink.publish(topic.clone(), Bytes::from_static(b"pkt2")).send_at_least_once();
-    let fut3 = sink.publish(topic.clone(), Bytes::from_static(b"pkt3")).send_at_least_once();
+    io.send(trigger("qos0"), &codec).await.unwrap();
+    match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::Publish(pkt) => {
+            assert_eq!(pkt.qos, codec::QoS::AtMostOnce);
+            assert_eq!(pkt.packet_id, None);
+        }
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    }
 
-    let res = join_all(vec![fut1, fut2, fut3]).await;
-    assert!(res[0].is_ok());
-    assert!(res[1].is_ok());
-    assert!(res[2].is_ok());
+    io.send(trigger("qos1"), &codec).await.unwrap();
+    match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::Publish(pkt) => {
+            assert_eq!(pkt.qos, codec::QoS::AtLeastOnce);
+            let packet_id = pkt.packet_id.unwrap();
+            io.send(codec::Packet::PublishAck { packet_id }, &codec).await.unwrap();
+        }
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    }
+
+    io.send(trigger("qos2"), &codec).await.unwrap();
+    let packet_id = match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::Publish(pkt) => {
+            assert_eq!(pkt.qos, codec::QoS::ExactlyOnce);
+            pkt.packet_id.unwrap()
+        }
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    };
+    io.send(codec::Packet::PublishReceived { packet_id }, &codec).await.unwrap();
+    assert_eq!(
+        io.recv(&codec).await.unwrap().unwrap(),
+        codec::Packet::PublishRelease { packet_id }
+    );
 
     Ok(())
 }
 
 #[ntex::test]
-async fn test_disconnect() -> std::io::Result<()> {
-    let srv = server::test_server(|| {
-        MqttServer::new(handshake)
-            .publish(ntex::service::fn_factory_with_config(|session: Session<St>| {
-                Ready::Ok(ntex::service::fn_service(move |_: Publish| {
-                    session.sink().force_close();
-                    async {
-                        sleep(Duration::from_millis(100)).await;
-                        Ok(())
-                    }
-                }))
-            }))
-            .finish()
-    });
-
-    // connect to server
-    let client =
-        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+async fn test_handshake_summary() -> std::io::Result<()> {
+    let summary = Arc::new(Mutex::new(None));
+    let summary2 = summary.clone();
 
-    let sink = client.sink();
+    let srv = server::test_server(move || {
+        let summary = summary2.clone();
+        MqttServer::new(move |conn: Handshake| {
+            *summary.lock().unwrap() = Some(conn.summary().to_string());
+            Ready::Ok::<_, ()>(conn.ack(St, false))
+        })
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
 
-    ntex::rt::spawn(client.start_default());
+    let codec = codec::Codec::default();
+    let io = srv.connect().await.unwrap();
+    let connect = codec::Connect {
+        username: Some(ByteString::from_static("name")),
+        password: Some(Bytes::from_static(b"secret")),
+        ..codec::Connect::default().client_id("user")
+    };
+    io.send(connect.into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
 
-    let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
-    assert!(res.is_err());
+    let summary = summary.lock().unwrap().take().unwrap();
+    assert_eq!(
+        summary,
+        "client-id: \"user\", protocol-level: 4, clean: false, keep-alive: 0, will: false, username: <REDACTED>"
+    );
+    assert!(!summary.contains("secret"));
 
     Ok(())
 }
 
 #[ntex::test]
-async fn test_handle_incoming() -> std::io::Result<()> {
-    let publish = Arc::new(AtomicBool::new(false));
-    let publish2 = publish.clone();
-    let disconnect = Arc::new(AtomicBool::new(false));
-    let disconnect2 = disconnect.clone();
+async fn test_pending_write_bytes() -> std::io::Result<()> {
+    let sizes = Arc::new(Mutex::new(Vec::new()));
+    let sizes2 = sizes.clone();
 
     let srv = server::test_server(move || {
-        let publish = publish2.clone();
-        let disconnect = disconnect2.clone();
+        let sizes = sizes2.clone();
         MqttServer::new(handshake)
-            .publish(move |_| {
-                publish.store(true, Relaxed);
-                async {
-                    sleep(Duration::from_millis(100)).await;
-                    Ok(())
-                }
-            })
-            .control(move |msg| match msg {
-                ControlMessage::Disconnect(msg) => {
-                    disconnect.store(true, Relaxed);
-                    Ready::Ok(msg.ack())
-                }
-                _ => Ready::Ok(msg.disconnect()),
-            })
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let sizes = sizes.clone();
+                Ready::Ok::<_, ()>(ntex::service::fn_service(move |_: Publish| {
+                    let sink = session.sink().clone();
+                    let before = sink.pending_write_bytes();
+                    sink.publish(ByteString::from_static("out"), Bytes::from_static(b"data"))
+                        .send_at_most_once()
+                        .unwrap();
+                    sizes.lock().unwrap().push(sink.pending_write_bytes() - before);
+
+                    // write buffer is flushed
+                    let sizes = sizes.clone();
+                    ntex::rt::spawn(async move {
+                        sleep(Millis(50)).await;
+                        sizes.lock().unwrap().push(sink.pending_write_bytes());
+                    });
+                    Ready::Ok(())
+                }))
+            }))
             .finish()
     });
 
-    let io = srv.connect().await.unwrap();
     let codec = codec::Codec::default();
-    io.encode(codec::Connect::default().client_id("user").into(), &codec).unwrap();
-    io.encode(
-        codec::Publish {
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Packet::Publish(codec::Publish {
             dup: false,
             retain: false,
-            qos: codec::QoS::AtLeastOnce,
-            topic: ByteString::from("test"),
-            packet_id: Some(NonZeroU16::new(3).unwrap()),
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static("trigger"),
+            packet_id: None,
             payload: Bytes::new(),
-        }
-        .into(),
+        }),
         &codec,
     )
+    .await
     .unwrap();
-    io.encode(codec::Packet::Disconnect, &codec).unwrap();
-    io.flush(true).await.unwrap();
-    sleep(Millis(50)).await;
-    drop(io);
-    sleep(Millis(50)).await;
+    io.recv(&codec).await.unwrap().unwrap();
+    sleep(Millis(100)).await;
 
-    assert!(publish.load(Relaxed));
-    assert!(disconnect.load(Relaxed));
+    // fixed header (2 bytes), topic (5 bytes) and payload (4 bytes)
+    assert_eq!(*sizes.lock().unwrap(), vec![11, 0]);
 
     Ok(())
 }
 
 #[ntex::test]
-async fn test_large_publish() -> std::io::Result<()> {
+async fn test_publish_build() -> std::io::Result<()> {
     let srv = server::test_server(move || {
-        MqttServer::new(handshake).publish(|_| Ready::Ok(())).finish()
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                Ready::Ok::<_, ()>(ntex::service::fn_service(move |p: Publish| {
+                    let pkt = codec::Publish::build("out", p.payload().clone()).retain(true);
+                    session.sink().publish_pkt(pkt).send_at_most_once().unwrap();
+                    Ready::Ok(())
+                }))
+            }))
+            .finish()
     });
 
-    let io = srv.connect().await.unwrap();
     let codec = codec::Codec::default();
-    io.encode(codec::Connect::default().client_id("user").into(), &codec).unwrap();
-    let _ = io.recv(&codec).await;
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
 
-    let p = codec::Publish {
-        dup: false,
-        retain: false,
-        qos: codec::QoS::AtLeastOnce,
-        topic: ByteString::from("test"),
-        packet_id: Some(NonZeroU16::new(3).unwrap()),
-        payload: Bytes::from(vec![b'*'; 270 * 1024]),
-    }
-    .into();
-    let res = io.send(p, &codec).await;
-    assert!(res.is_ok());
-    let result = io.recv(&codec).await;
-    assert!(result.is_ok());
+    let mut pkt = codec::Publish::build("trigger", Bytes::from_static(b"data"))
+        .qos(codec::QoS::AtLeastOnce);
+    pkt.packet_id = NonZeroU16::new(1);
+    io.send(codec::Packet::Publish(pkt), &codec).await.unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: true,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static("out"),
+            packet_id: None,
+            payload: Bytes::from_static(b"data"),
+        })
+    );
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() });
 
     Ok(())
 }
 
-fn ssl_acceptor() -> openssl::ssl::SslAcceptor {
-    use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+#[ntex::test]
+async fn test_ack_with_reason() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|conn: Handshake| {
+            let reason = match conn.packet().client_id.as_ref() {
+                "banned" => codec::ConnectAckReason::IdentifierRejected,
+                "user" => codec::ConnectAckReason::ServiceUnavailable,
+                _ => codec::ConnectAckReason::ConnectionAccepted,
+            };
+            // accepted reason returns handshake back
+            Ready::Ok::<_, ()>(
+                conn.ack_with_reason::<St>(reason).unwrap_or_else(|conn| conn.ack(St, false)),
+            )
+        })
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
 
-    // load ssl keys
-    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-    builder.set_private_key_file("./tests/key.pem", SslFiletype::PEM).unwrap();
-    builder.set_certificate_chain_file("./tests/cert.pem").unwrap();
-    builder.build()
+    let codec = codec::Codec::default();
+    for (client_id, reason) in [
+        ("banned", codec::ConnectAckReason::IdentifierRejected),
+        ("user", codec::ConnectAckReason::ServiceUnavailable),
+    ]
+    .iter()
+    {
+        let io = srv.connect().await.unwrap();
+        io.send(codec::Connect::default().client_id(*client_id).into(), &codec).await.unwrap();
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        assert_eq!(
+            pkt,
+            codec::Packet::ConnectAck { session_present: false, return_code: *reason }
+        );
+        // connection is closed after connect-ack
+        assert!(io.recv(&codec).await.unwrap().is_none());
+    }
+
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("other").into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::ConnectionAccepted
+        }
+    );
+
+    Ok(())
 }
 
 #[ntex::test]
-async fn test_large_publish_openssl() -> std::io::Result<()> {
-    use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
-    env_logger::init();
+async fn test_session_keepalive() -> std::io::Result<()> {
+    let keepalive = Arc::new(Mutex::new(Vec::new()));
+    let keepalive2 = keepalive.clone();
 
     let srv = server::test_server(move || {
-        pipeline_factory(server::openssl::Acceptor::new(ssl_acceptor()).map_err(|_| ()))
-            .and_then(
-                MqttServer::new(handshake)
-                    .publish(|_| Ready::Ok(()))
-                    .finish()
-                    .map_err(|_| ())
-                    .map_init_err(|_| ()),
-            )
+        let keepalive = keepalive2.clone();
+        MqttServer::new(|conn: Handshake| {
+            Ready::Ok::<_, ()>(conn.ack(St, false).idle_timeout(Seconds(20)))
+        })
+        .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+            let keepalive = keepalive.clone();
+            keepalive.lock().unwrap().push(session.keepalive());
+            Ready::Ok::<_, ()>(ntex::service::fn_service(move |_: Publish| {
+                session.set_keepalive(Seconds(5));
+                keepalive.lock().unwrap().push(session.keepalive());
+                Ready::Ok(())
+            }))
+        }))
+        .finish()
     });
 
-    let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
-    builder.set_verify(SslVerifyMode::NONE);
-    let con = ntex::connect::openssl::Connector::new(builder.build());
-    let addr = format!("127.0.0.1:{}", srv.addr().port());
-    let io = con.call(addr.into()).await.unwrap();
-
     let codec = codec::Codec::default();
-    io.encode(codec::Connect::default().client_id("user").into(), &codec).unwrap();
-    let _ = io.recv(&codec).await;
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
 
-    let p = codec::Publish {
-        dup: false,
-        retain: false,
-        qos: codec::QoS::AtLeastOnce,
-        topic: ByteString::from("test"),
-        packet_id: Some(NonZeroU16::new(3).unwrap()),
-        payload: Bytes::from(vec![b'*'; 270 * 1024]),
-    }
-    .into();
-    let res = io.send(p, &codec).await;
-    assert!(res.is_ok());
-    let result = io.recv(&codec).await;
-    assert!(result.is_ok());
+    io.send(codec::Packet::Publish(codec::Publish::build("test", Bytes::new())), &codec)
+        .await
+        .unwrap();
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    // negotiated keep-alive, then keep-alive set by application
+    assert_eq!(*keepalive.lock().unwrap(), vec![Seconds(20), Seconds(5)]);
 
     Ok(())
 }
@@ -1,8 +1,11 @@
 use std::convert::TryFrom;
 
-use ntex::server;
-use ntex::util::{ByteString, Bytes, Ready};
+use ntex::codec::{Decoder, Encoder};
+use ntex::http::Uri;
+use ntex::util::{ByteString, Bytes, BytesMut, Ready};
+use ntex::{server, ws};
 
+use ntex_mqtt::ws::{WsConnector, WsServer};
 use ntex_mqtt::{v3, v5, MqttServer};
 
 struct St;
@@ -62,3 +65,100 @@ async fn test_simple() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_websocket() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        WsServer::new(
+            MqttServer::new()
+                .v3(v3::MqttServer::new(|con: v3::Handshake| {
+                    Ready::Ok::<_, TestError>(con.ack(St, false))
+                })
+                .publish(|_| Ready::Ok::<_, TestError>(())))
+                .v5(v5::MqttServer::new(|con: v5::Handshake| {
+                    Ready::Ok::<_, TestError>(con.ack(St))
+                })
+                .publish(|p: v5::Publish| Ready::Ok::<_, TestError>(p.ack()))),
+        )
+    });
+    let uri = Uri::try_from(format!("ws://{}/mqtt", srv.addr())).unwrap();
+
+    // v5 client over websocket
+    let client = v5::client::MqttConnector::new(uri.clone())
+        .connector(WsConnector)
+        .client_id("user")
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::from_static(&[0; 1024]))
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+    sink.close();
+
+    // v3 client over websocket
+    let client = v3::client::MqttConnector::new(uri.clone())
+        .connector(WsConnector)
+        .client_id("user")
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sink.close();
+
+    // mqtt packet split into several websocket frames
+    let con = ws::WsClient::build(uri.clone())
+        .address(srv.addr())
+        .protocols(["mqttv3.1"])
+        .finish()
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(con.response().headers().get("sec-websocket-protocol").unwrap(), "mqttv3.1");
+    let (io, codec, _) = con.into_inner();
+
+    let mut buf = BytesMut::new();
+    v3::codec::Codec::default()
+        .encode(
+            v3::codec::Packet::Connect(Box::new(
+                v3::codec::Connect::default().client_id(ByteString::from_static("user")),
+            )),
+            &mut buf,
+        )
+        .unwrap();
+    let tail = buf.split_off(3);
+    io.send(ws::Message::Binary(buf.freeze()), &codec).await.unwrap();
+    io.send(ws::Message::Binary(tail.freeze()), &codec).await.unwrap();
+
+    let frame = io.recv(&codec).await.unwrap().unwrap();
+    if let ws::Frame::Binary(data) = frame {
+        let pkt = v3::codec::Codec::default().decode(&mut BytesMut::from(&data[..])).unwrap();
+        assert_eq!(
+            pkt,
+            Some(v3::codec::Packet::ConnectAck {
+                session_present: false,
+                return_code: v3::codec::ConnectAckReason::ConnectionAccepted
+            })
+        );
+    } else {
+        panic!("Unexpected frame: {:?}", frame);
+    }
+
+    // mqtt subprotocol is required
+    let res = ws::WsClient::build(uri).address(srv.addr()).finish().unwrap().connect().await;
+    if let Err(ws::error::WsClientError::InvalidResponseStatus(status)) = res {
+        assert_eq!(status, 400);
+    } else {
+        panic!("Unexpected result");
+    }
+
+    Ok(())
+}
@@ -1,12 +1,18 @@
-use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
+use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc, Mutex};
+use std::{cell::Cell, cell::RefCell, rc::Rc};
 use std::{convert::TryFrom, num::NonZeroU16, time::Duration};
 
-use ntex::util::{ByteString, Bytes, Ready};
-use ntex::{server, service::fn_service, time::sleep};
+use ntex::codec::{BytesCodec, Decoder, Encoder};
+use ntex::io::{Io, IoBoxed};
+use ntex::server;
+use ntex::service::{fn_service, Service, ServiceFactory};
+use ntex::time::{sleep, Seconds};
+use ntex::util::{lazy, poll_fn, ByteString, Bytes, BytesMut, Ready};
 
 use ntex_mqtt::v5::{
-    client, codec, error, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish,
-    PublishAck, Session,
+    client, codec, error, ClientIdEncoding, ControlMessage, Handshake, HandshakeAck,
+    MaxSizeHandle, MemoryBanList, MqttServer, MqttSink, PacketMask, PreConnackPublishPolicy,
+    Publish, PublishAck, Qos2InflightLimit, Router, Session,
 };
 
 struct St;
@@ -68,6 +74,92 @@ async fn test_simple() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_publish_batch() -> std::io::Result<()> {
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let results2 = results.clone();
+
+    let srv = server::test_server(move || {
+        let results = results2.clone();
+        MqttServer::new(move |hnd: Handshake| {
+            let sink = hnd.sink();
+            let results = results.clone();
+            ntex::rt::spawn(async move {
+                sleep(Duration::from_millis(50)).await;
+                let batch = (1..5).map(|i| (format!("topic{}", i), Bytes::new())).collect();
+                let batch = sink.publish_batch(batch);
+
+                // publish does not take packet ids of the batch
+                let other = sink.publish(ByteString::from_static("other"), Bytes::new());
+                let other = other.send_at_least_once();
+                let res = batch.await;
+                let _ = other.await;
+                results.lock().unwrap().push(res);
+            });
+            Ready::Ok::<_, TestError>(hnd.ack(St))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+    let codec = codec::Codec::default();
+    let connect = || {
+        codec::Packet::Connect(Box::new(
+            codec::Connect::default().client_id("user").receive_max(2),
+        ))
+    };
+
+    let io = srv.connect().await.unwrap();
+    io.send(connect(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    async fn recv_publish(io: &IoBoxed) -> (NonZeroU16, ByteString) {
+        match io.recv(&codec::Codec::default()).await.unwrap().unwrap() {
+            codec::Packet::Publish(pkt) => (pkt.packet_id.unwrap(), pkt.topic),
+            pkt => panic!("Unexpected packet: {:?}", pkt),
+        }
+    }
+    let ack = |id| {
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(id).unwrap(),
+            ..Default::default()
+        })
+    };
+    let id = |id| NonZeroU16::new(id).unwrap();
+    let io = IoBoxed::from(io);
+
+    // batch is chunked by in-flight window
+    assert_eq!(recv_publish(&io).await, (id(5), ByteString::from_static("other")));
+    assert_eq!(recv_publish(&io).await, (id(1), ByteString::from_static("topic1")));
+    assert!(ntex::time::timeout(Duration::from_millis(100), recv_publish(&io)).await.is_err());
+    io.send(ack(5), &codec).await.unwrap();
+    assert_eq!(recv_publish(&io).await, (id(2), ByteString::from_static("topic2")));
+    io.send(ack(1), &codec).await.unwrap();
+    assert_eq!(recv_publish(&io).await, (id(3), ByteString::from_static("topic3")));
+    io.send(ack(2), &codec).await.unwrap();
+    io.send(ack(3), &codec).await.unwrap();
+    assert_eq!(recv_publish(&io).await, (id(4), ByteString::from_static("topic4")));
+    io.send(ack(4), &codec).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+    let res = results.lock().unwrap().pop().unwrap();
+    assert_eq!(res.len(), 4);
+    assert!(res.iter().all(|r| r.is_ok()));
+
+    // connection is lost
+    let io = srv.connect().await.unwrap();
+    io.send(connect(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    let io = IoBoxed::from(io);
+    recv_publish(&io).await;
+    recv_publish(&io).await;
+    io.close();
+    sleep(Duration::from_millis(100)).await;
+    let res = results.lock().unwrap().pop().unwrap();
+    assert_eq!(res.len(), 4);
+    assert!(res.iter().all(|r| matches!(r, Err(error::PublishQos1Error::Disconnected))));
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_handshake_failed() -> std::io::Result<()> {
     let srv = server::test_server(|| {
@@ -123,151 +215,186 @@ async fn test_disconnect() -> std::io::Result<()> {
 }
 
 #[ntex::test]
-async fn test_disconnect_with_reason() -> std::io::Result<()> {
-    let srv = server::test_server(|| {
+async fn test_ban_list() -> std::io::Result<()> {
+    let bans = MemoryBanList::new();
+    let bans2 = bans.clone();
+
+    let srv = server::test_server(move || {
         MqttServer::new(handshake)
+            .ban_list(bans2.clone())
             .publish(ntex::service::fn_factory_with_config(|session: Session<St>| {
                 Ready::Ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
-                    let pkt = codec::Disconnect {
-                        reason_code: codec::DisconnectReasonCode::ServerMoved,
-                        ..Default::default()
-                    };
-                    session.sink().close_with_reason(pkt);
-                    async move {
-                        sleep(Duration::from_millis(100)).await;
-                        Ok::<_, TestError>(p.ack())
-                    }
+                    assert!(session.quarantine(ntex::time::Seconds(60)));
+                    Ready::Ok::<_, TestError>(p.ack())
                 }))
             }))
             .finish()
     });
 
-    // connect to server
+    // client is quarantined by publish service
     let client =
         client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
-
     let sink = client.sink();
-
     ntex::rt::spawn(client.start_default());
-
     let res =
         sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
-    assert!(res.is_err());
+    assert!(res.is_ok());
+
+    // banned client is rejected
+    let res = client::MqttConnector::new(srv.addr()).client_id("user").connect().await;
+    if let Err(error::ClientError::Ack(ack)) = res {
+        assert_eq!(ack.reason_code, codec::ConnectAckReason::Banned);
+    } else {
+        panic!("Unexpected result: {:?}", res.map(|_| ()));
+    }
+
+    bans.unban("user");
+    assert!(client::MqttConnector::new(srv.addr()).client_id("user").connect().await.is_ok());
 
     Ok(())
 }
 
 #[ntex::test]
-async fn test_disconnect_after_control_error() -> std::io::Result<()> {
-    env_logger::init();
+async fn test_handshake_fail() -> std::io::Result<()> {
     let srv = server::test_server(|| {
-        MqttServer::new(handshake)
-            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
-            .control(move |msg| match msg {
-                ControlMessage::Subscribe(_) => Ready::Err(TestError),
-                _ => Ready::Ok(msg.disconnect()),
-            })
-            .finish()
+        MqttServer::new(|hnd: Handshake| async move {
+            if hnd.packet().client_id == "busy" {
+                Ok::<_, TestError>(
+                    hnd.use_another_server::<St>(ByteString::from_static("srv2")),
+                )
+            } else {
+                Ok(hnd.fail(
+                    codec::ConnectAckReason::QuotaExceeded,
+                    Some(ByteString::from_static("Too many connections")),
+                    vec![(ByteString::from_static("retry"), ByteString::from_static("60"))],
+                ))
+            }
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
     });
 
-    let io = srv.connect().await.unwrap();
     let codec = codec::Codec::default();
+    let io = srv.connect().await.unwrap();
     io.send(
         codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
         &codec,
     )
     .await
     .unwrap();
-    let _ = io.recv(&codec).await.unwrap().unwrap();
-
-    io.send(
-        codec::Subscribe {
-            id: None,
-            packet_id: NonZeroU16::new(2).unwrap(),
-            user_properties: Default::default(),
-            topic_filters: vec![(
-                ByteString::from("topic1"),
-                codec::SubscriptionOptions {
-                    qos: codec::QoS::AtLeastOnce,
-                    no_local: false,
-                    retain_as_published: false,
-                    retain_handling: codec::RetainHandling::AtSubscribe,
-                },
-            )],
-        }
-        .into(),
-        &codec,
-    )
-    .await
-    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::ConnectAck(ack) = pkt {
+        assert_eq!(ack.reason_code, codec::ConnectAckReason::QuotaExceeded);
+        assert_eq!(ack.reason_string.unwrap(), "Too many connections");
+        assert_eq!(ack.user_properties, vec![("retry".into(), "60".into())]);
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+    assert!(io.recv(&codec).await.unwrap().is_none());
 
-    let result = io.recv(&codec).await.unwrap().unwrap();
-    if let codec::Packet::Disconnect(_) = result {
+    let res = client::MqttConnector::new(srv.addr()).client_id("busy").connect().await;
+    if let Err(error::ClientError::Ack(ack)) = res {
+        assert_eq!(ack.reason_code, codec::ConnectAckReason::UseAnotherServer);
+        assert_eq!(ack.server_reference.unwrap(), "srv2");
     } else {
-        panic!();
+        panic!("Unexpected result: {:?}", res.map(|_| ()));
     }
+
     Ok(())
 }
 
 #[ntex::test]
-async fn test_ping() -> std::io::Result<()> {
-    let ping = Arc::new(AtomicBool::new(false));
-    let ping2 = ping.clone();
+async fn test_client_id_encoding() -> std::io::Result<()> {
+    let connect = Bytes::from_static(b"\x10\x10\x00\x04MQTT\x05\x02\x00\x3C\x00\x00\x03a\xffb");
 
-    let srv = server::test_server(move || {
-        let ping = ping2.clone();
+    // non utf-8 client id is rejected by default
+    let srv = server::test_server(|| {
         MqttServer::new(handshake)
             .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
-            .control(move |msg| {
-                let ping = ping.clone();
-                match msg {
-                    ControlMessage::Ping(msg) => {
-                        ping.store(true, Relaxed);
-                        Ready::Ok::<_, TestError>(msg.ack())
-                    }
-                    _ => Ready::Ok(msg.disconnect_with(codec::Disconnect::default())),
-                }
-            })
             .finish()
     });
+    let io = srv.connect().await.unwrap();
+    io.send(connect.clone(), &BytesCodec).await.unwrap();
+    let pkt = io.recv(&codec::Codec::default()).await.unwrap().unwrap();
+    if let codec::Packet::ConnectAck(ack) = pkt {
+        assert_eq!(ack.reason_code, codec::ConnectAckReason::ClientIdentifierNotValid);
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
 
+    let srv = server::test_server(|| {
+        MqttServer::new(|hnd: Handshake| async move {
+            assert_eq!(hnd.packet().client_id, "a\u{FFFD}b");
+            assert_eq!(hnd.raw_client_id(), b"a\xffb");
+            Ok::<_, TestError>(hnd.ack(St))
+        })
+        .client_id_encoding(ClientIdEncoding::Lossy)
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
     let io = srv.connect().await.unwrap();
-    let codec = codec::Codec::new();
-    io.send(
-        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
-        &codec,
-    )
-    .await
-    .unwrap();
-    let _ = io.recv(&codec).await.unwrap().unwrap();
+    io.send(connect, &BytesCodec).await.unwrap();
+    let pkt = io.recv(&codec::Codec::default()).await.unwrap().unwrap();
+    if let codec::Packet::ConnectAck(ack) = pkt {
+        assert_eq!(ack.reason_code, codec::ConnectAckReason::Success);
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
 
-    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
-    let pkt = io.recv(&codec).await.unwrap().unwrap();
-    assert_eq!(pkt, codec::Packet::PingResponse);
-    assert!(ping.load(Relaxed));
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_publish_topic_cardinality() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .max_publish_topic_cardinality(2)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    for topic in ["topic1", "topic2", "topic1"] {
+        let res =
+            sink.publish(ByteString::from(topic), Bytes::new()).send_at_least_once().await;
+        assert!(res.is_ok());
+    }
+
+    // new topic is rejected
+    let res = sink
+        .publish(ByteString::from_static("topic3"), Bytes::new())
+        .send_at_least_once()
+        .await;
+    if let Err(error::PublishQos1Error::Fail(ack)) = res {
+        assert_eq!(ack.reason_code, codec::PublishAckReason::QuotaExceeded);
+    } else {
+        panic!("Unexpected result: {:?}", res);
+    }
+
+    // seen topic is still accepted
+    let res = sink
+        .publish(ByteString::from_static("topic2"), Bytes::new())
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
 
     Ok(())
 }
 
 #[ntex::test]
-async fn test_ack_order() -> std::io::Result<()> {
-    let srv = server::test_server(move || {
+async fn test_topic_cardinality_rejected_publish() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
         MqttServer::new(handshake)
+            .max_publish_topic_cardinality(2)
+            .max_global_qos2_inflight(Qos2InflightLimit::new(1))
             .publish(|p: Publish| async move {
                 sleep(Duration::from_millis(100)).await;
                 Ok::<_, TestError>(p.ack())
             })
-            .control(move |msg| match msg {
-                ControlMessage::Subscribe(mut msg) => {
-                    for mut sub in &mut msg {
-                        sub.topic();
-                        sub.options();
-                        sub.subscribe(codec::QoS::AtLeastOnce);
-                    }
-                    Ready::Ok::<_, TestError>(msg.ack())
-                }
-                _ => Ready::Ok(msg.disconnect()),
-            })
             .finish()
     });
 
@@ -281,33 +408,93 @@ async fn test_ack_order() -> std::io::Result<()> {
     .unwrap();
     let _ = io.recv(&codec).await.unwrap().unwrap();
 
+    // publish rejected by global QoS 2 limit does not take topic slot
+    for (id, topic) in [(1, "topic1"), (2, "topic2")] {
+        io.send(
+            codec::Publish {
+                qos: codec::QoS::ExactlyOnce,
+                topic: ByteString::from_static(topic),
+                packet_id: NonZeroU16::new(id),
+                ..pkt_publish()
+            }
+            .into(),
+            &codec,
+        )
+        .await
+        .unwrap();
+    }
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishReceived(codec::PublishAck {
+            packet_id: NonZeroU16::new(2).unwrap(),
+            reason_code: codec::PublishAckReason::QuotaExceeded,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    );
+
     io.send(
-        codec::Publish { packet_id: Some(NonZeroU16::new(1).unwrap()), ..pkt_publish() }.into(),
+        codec::Publish {
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from_static("topic3"),
+            packet_id: NonZeroU16::new(3),
+            ..pkt_publish()
+        }
+        .into(),
         &codec,
     )
     .await
     .unwrap();
+    for id in [1, 3] {
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        assert_eq!(
+            pkt,
+            codec::Packet::PublishAck(codec::PublishAck {
+                packet_id: NonZeroU16::new(id).unwrap(),
+                reason_code: codec::PublishAckReason::Success,
+                properties: Default::default(),
+                reason_string: None,
+            })
+        );
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_retain_not_available() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake| {
+            Ready::Ok::<_, TestError>(
+                con.ack(St).with(|ack| ack.retain_available = Some(false)),
+            )
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .control(|msg: ControlMessage<TestError>| match msg {
+            ControlMessage::ProtocolError(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+            _ => Ready::Ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
     io.send(
-        codec::Subscribe {
-            id: None,
-            packet_id: NonZeroU16::new(2).unwrap(),
-            user_properties: Default::default(),
-            topic_filters: vec![(
-                ByteString::from("topic1"),
-                codec::SubscriptionOptions {
-                    qos: codec::QoS::AtLeastOnce,
-                    no_local: false,
-                    retain_as_published: false,
-                    retain_handling: codec::RetainHandling::AtSubscribe,
-                },
-            )],
-        }
-        .into(),
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
         &codec,
     )
     .await
     .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::ConnectAck(ack) = pkt {
+        assert_eq!(ack.retain_available, Some(false));
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
 
+    // not retained publish is accepted
+    io.send(pkt_publish().into(), &codec).await.unwrap();
     let pkt = io.recv(&codec).await.unwrap().unwrap();
     assert_eq!(
         pkt,
@@ -319,27 +506,34 @@ async fn test_ack_order() -> std::io::Result<()> {
         })
     );
 
+    io.send(codec::Publish { retain: true, ..pkt_publish() }.into(), &codec).await.unwrap();
     let pkt = io.recv(&codec).await.unwrap().unwrap();
-    assert_eq!(
-        pkt,
-        codec::Packet::SubscribeAck(codec::SubscribeAck {
-            packet_id: NonZeroU16::new(2).unwrap(),
-            properties: Default::default(),
-            reason_string: None,
-            status: vec![codec::SubscribeAckReason::GrantedQos1],
-        })
-    );
+    if let codec::Packet::Disconnect(pkt) = pkt {
+        assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::RetainNotSupported);
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+    assert!(io.recv(&codec).await.unwrap().is_none());
 
     Ok(())
 }
 
 #[ntex::test]
-async fn test_dups() {
+async fn test_max_size_exceeded() -> std::io::Result<()> {
+    let error = Arc::new(Mutex::new(None));
+    let error2 = error.clone();
+
     let srv = server::test_server(move || {
+        let error = error2.clone();
         MqttServer::new(handshake)
-            .publish(|p: Publish| async move {
-                sleep(Duration::from_millis(10000)).await;
-                Ok::<_, TestError>(p.ack())
+            .max_size(64)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg: ControlMessage<TestError>| match msg {
+                ControlMessage::ProtocolError(msg) => {
+                    *error.lock().unwrap() = Some(msg.get_ref().to_string());
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
             })
             .finish()
     });
@@ -347,221 +541,282 @@ async fn test_dups() {
     let io = srv.connect().await.unwrap();
     let codec = codec::Codec::default();
     io.send(
-        codec::Packet::Connect(Box::new(
-            codec::Connect::default().client_id("user").receive_max(2),
-        )),
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
         &codec,
     )
     .await
     .unwrap();
-    let _ = io.recv(&codec).await.unwrap().unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::ConnectAck(_)));
 
-    io.send(
-        codec::Publish { packet_id: Some(NonZeroU16::new(1).unwrap()), ..pkt_publish() }.into(),
-        &codec,
-    )
-    .await
-    .unwrap();
-
-    // send packet_id dup
-    io.send(
-        codec::Publish { packet_id: Some(NonZeroU16::new(1).unwrap()), ..pkt_publish() }.into(),
-        &codec,
-    )
-    .await
-    .unwrap();
-
-    // send subscribe dup
-    io.send(
-        codec::Subscribe {
-            id: None,
-            packet_id: NonZeroU16::new(1).unwrap(),
-            user_properties: Default::default(),
-            topic_filters: vec![(
-                ByteString::from("topic1"),
-                codec::SubscriptionOptions {
-                    qos: codec::QoS::AtLeastOnce,
-                    no_local: false,
-                    retain_as_published: false,
-                    retain_handling: codec::RetainHandling::AtSubscribe,
-                },
-            )],
-        }
-        .into(),
-        &codec,
-    )
-    .await
-    .unwrap();
-
-    // send unsubscribe dup
-    io.send(
-        codec::Unsubscribe {
-            packet_id: NonZeroU16::new(1).unwrap(),
-            user_properties: Default::default(),
-            topic_filters: vec![ByteString::from("topic1")],
-        }
-        .into(),
-        &codec,
-    )
-    .await
-    .unwrap();
-
-    // PublishAck
-    let pkt = io.recv(&codec).await.unwrap().unwrap();
-    assert_eq!(
-        pkt,
-        codec::Packet::PublishAck(codec::PublishAck {
-            packet_id: NonZeroU16::new(1).unwrap(),
-            reason_code: codec::PublishAckReason::PacketIdentifierInUse,
-            properties: Default::default(),
-            reason_string: None,
-        })
-    );
-
-    // SubscribeAck
+    // remaining length exceeds max size, payload is not sent
+    io.send(Bytes::from_static(b"\x30\x7f"), &BytesCodec).await.unwrap();
     let pkt = io.recv(&codec).await.unwrap().unwrap();
-    assert_eq!(
-        pkt,
-        codec::SubscribeAck {
-            packet_id: NonZeroU16::new(1).unwrap(),
-            properties: Default::default(),
-            reason_string: None,
-            status: vec![codec::SubscribeAckReason::PacketIdentifierInUse],
-        }
-        .into()
-    );
+    if let codec::Packet::Disconnect(pkt) = pkt {
+        assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::PacketTooLarge);
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    assert_eq!(error.lock().unwrap().take().unwrap(), "Packet size 127 exceeds max size 64");
 
-    // UnsubscribeAck
-    let pkt = io.recv(&codec).await.unwrap().unwrap();
-    assert_eq!(
-        pkt,
-        codec::UnsubscribeAck {
-            packet_id: NonZeroU16::new(1).unwrap(),
-            properties: Default::default(),
-            reason_string: None,
-            status: vec![codec::UnsubscribeAckReason::PacketIdentifierInUse],
-        }
-        .into()
-    );
+    Ok(())
 }
 
 #[ntex::test]
-async fn test_max_receive() {
+async fn test_read_timeout() -> std::io::Result<()> {
+    let error = Arc::new(Mutex::new(None));
+    let error2 = error.clone();
+
     let srv = server::test_server(move || {
+        let error = error2.clone();
         MqttServer::new(handshake)
-            .receive_max(1)
-            .max_qos(codec::QoS::AtLeastOnce)
-            .publish(|p: Publish| async move {
-                sleep(Duration::from_millis(10000)).await;
-                Ok::<_, TestError>(p.ack())
-            })
-            .control(move |msg| match msg {
-                ControlMessage::ProtocolError(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+            .read_timeout(Seconds(1))
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg: ControlMessage<TestError>| match msg {
+                ControlMessage::ProtocolError(msg) => {
+                    *error.lock().unwrap() = Some(msg.get_ref().to_string());
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
                 _ => Ready::Ok(msg.disconnect()),
             })
             .finish()
     });
-    let io = srv.connect().await.unwrap();
     let codec = codec::Codec::default();
 
+    // partial connect packet
+    let io = srv.connect().await.unwrap();
+    io.send(Bytes::from_static(b"\x10\x20\x00"), &BytesCodec).await.unwrap();
+    let res = ntex::time::timeout(Seconds(3), io.recv(&BytesCodec)).await;
+    assert!(res.unwrap().unwrap().is_none());
+
+    let io = srv.connect().await.unwrap();
     io.send(
         codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
         &codec,
     )
     .await
     .unwrap();
-    let ack = io.recv(&codec).await.unwrap().unwrap();
-    assert_eq!(
-        ack,
-        codec::Packet::ConnectAck(Box::new(codec::ConnectAck {
-            receive_max: Some(NonZeroU16::new(1).unwrap()),
-            max_qos: Some(codec::QoS::AtLeastOnce),
-            reason_code: codec::ConnectAckReason::Success,
-            topic_alias_max: 32,
-            ..Default::default()
-        }))
-    );
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::ConnectAck(_)));
 
-    io.send(
-        codec::Publish { packet_id: Some(NonZeroU16::new(1).unwrap()), ..pkt_publish() }.into(),
-        &codec,
-    )
-    .await
-    .unwrap();
-    io.send(
-        codec::Publish { packet_id: Some(NonZeroU16::new(2).unwrap()), ..pkt_publish() }.into(),
-        &codec,
-    )
-    .await
-    .unwrap();
+    // partial publish packet
+    io.send(Bytes::from_static(b"\x30\x0a\x00"), &BytesCodec).await.unwrap();
     let pkt = io.recv(&codec).await.unwrap().unwrap();
-    assert_eq!(
-        pkt,
-        codec::Packet::Disconnect(codec::Disconnect {
-            reason_code: codec::DisconnectReasonCode::ReceiveMaximumExceeded,
-            session_expiry_interval_secs: None,
-            server_reference: None,
-            reason_string: None,
-            user_properties: Default::default(),
-        })
-    );
+    if let codec::Packet::Disconnect(pkt) = pkt {
+        assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::ImplementationSpecificError);
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    assert_eq!(error.lock().unwrap().take().unwrap(), "Read timeout");
+
+    Ok(())
 }
 
 #[ntex::test]
-async fn test_keepalive() {
-    let ka = Arc::new(AtomicBool::new(false));
-    let ka2 = ka.clone();
+async fn test_negotiated_config() -> std::io::Result<()> {
+    let negotiated = Arc::new(Mutex::new(None));
+    let negotiated2 = negotiated.clone();
 
     let srv = server::test_server(move || {
-        let ka = ka2.clone();
-
-        MqttServer::new(|con: Handshake| async move { Ok(con.ack(St).keep_alive(1)) })
-            .publish(|p: Publish| async move { Ok::<_, TestError>(p.ack()) })
-            .control(move |msg| match msg {
-                ControlMessage::ProtocolError(msg) => {
-                    if let &error::ProtocolError::KeepAliveTimeout = msg.get_ref() {
-                        ka.store(true, Relaxed);
-                    }
-                    Ready::Ok::<_, TestError>(msg.ack())
-                }
-                _ => Ready::Ok(msg.disconnect()),
-            })
+        let negotiated = negotiated2.clone();
+        MqttServer::new(handshake)
+            .max_size(1024)
+            .receive_max(8)
+            .max_topic_alias(4)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                *negotiated.lock().unwrap() = Some(session.negotiated().clone());
+                Ready::Ok::<_, TestError>(fn_service(|p: Publish| {
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            }))
             .finish()
     });
 
-    // connect to server
     let client =
         client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
-
     let sink = client.sink();
-
     ntex::rt::spawn(client.start_default());
 
-    assert!(sink.is_open());
-    sleep(Duration::from_millis(2500)).await;
-    assert!(!sink.is_open());
-    assert!(ka.load(Relaxed));
+    let res =
+        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+
+    let cfg = negotiated.lock().unwrap().take().unwrap();
+    assert_eq!(cfg.protocol_level, 5);
+    assert_eq!(cfg.max_inbound_size, 1024);
+    assert_eq!(cfg.receive_max, 8);
+    assert_eq!(cfg.topic_alias_max, 4);
+    assert_eq!(cfg.max_qos, None);
+    Ok(())
 }
 
 #[ntex::test]
-async fn test_keepalive2() {
-    let ka = Arc::new(AtomicBool::new(false));
-    let ka2 = ka.clone();
+async fn test_handshake_connect_properties() -> std::io::Result<()> {
+    let negotiated = Arc::new(Mutex::new(None));
+    let negotiated2 = negotiated.clone();
 
     let srv = server::test_server(move || {
-        let ka = ka2.clone();
+        let negotiated = negotiated2.clone();
+        MqttServer::new(|hnd: Handshake| {
+            assert_eq!(hnd.receive_max(), 10);
+            assert_eq!(hnd.session_expiry(), 3600);
+            assert_eq!(hnd.max_packet_size(), Some(2048));
+            assert_eq!(hnd.user_properties(), &vec![("key".into(), "val".into())]);
+
+            // negotiate down
+            hnd.clamp_receive_max(4);
+            hnd.clamp_receive_max(100);
+            assert_eq!(hnd.receive_max(), 4);
+            let expiry = hnd.session_expiry().min(60);
+            Ready::Ok::<_, TestError>(
+                hnd.ack(St).with_receive_max(1).with_session_expiry(expiry),
+            )
+        })
+        .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+            *negotiated.lock().unwrap() = Some(session.negotiated().clone());
+            Ready::Ok::<_, TestError>(fn_service(|p: Publish| async move {
+                sleep(Duration::from_millis(100)).await;
+                Ok::<_, TestError>(p.ack())
+            }))
+        }))
+        .control(|msg| match msg {
+            ControlMessage::ProtocolError(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+            _ => Ready::Ok(msg.disconnect()),
+        })
+        .finish()
+    });
 
-        MqttServer::new(|con: Handshake| async move { Ok(con.ack(St).keep_alive(1)) })
-            .publish(|p: Publish| async move { Ok::<_, TestError>(p.ack()) })
-            .control(move |msg| match msg {
-                ControlMessage::ProtocolError(msg) => {
-                    if let &error::ProtocolError::KeepAliveTimeout = msg.get_ref() {
-                        ka.store(true, Relaxed);
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    let mut connect = codec::Connect::default().client_id("user").receive_max(10);
+    connect.session_expiry_interval_secs = Some(3600);
+    connect.max_packet_size = std::num::NonZeroU32::new(2048);
+    connect.user_properties.push(("key".into(), "val".into()));
+    io.send(codec::Packet::Connect(Box::new(connect)), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::ConnectAck(ack) = pkt {
+        assert_eq!(ack.receive_max, NonZeroU16::new(1));
+        assert_eq!(ack.session_expiry_interval_secs, Some(60));
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+
+    // receive maximum of ConnectAck is enforced
+    for id in 1..3 {
+        let publish = codec::Publish { packet_id: NonZeroU16::new(id), ..pkt_publish() };
+        io.send(publish.into(), &codec).await.unwrap();
+    }
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::Disconnect(pkt) = pkt {
+        assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::ReceiveMaximumExceeded);
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+    let cfg = negotiated.lock().unwrap().take().unwrap();
+    assert_eq!(cfg.receive_max, 1);
+    assert_eq!(cfg.peer_receive_max, 4);
+    assert_eq!(cfg.session_expiry, 60);
+
+    // receive maximum `0` is malformed
+    let mut buf = BytesMut::new();
+    let connect = codec::Connect::default().client_id("user").receive_max(5);
+    codec.encode(codec::Packet::Connect(Box::new(connect)), &mut buf).unwrap();
+    let pos = buf.windows(3).position(|w| w == b"\x21\x00\x05").unwrap();
+    buf[pos + 2] = 0;
+    let io = srv.connect().await.unwrap();
+    io.send(buf.freeze(), &BytesCodec).await.unwrap();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_shutdown_grace_period() {
+    let factory = MqttServer::new(handshake)
+        .shutdown_grace_period(Seconds(5))
+        .publish(|p: Publish| async move {
+            sleep(Duration::from_millis(200)).await;
+            Ok::<_, TestError>(p.ack())
+        })
+        .finish();
+    let srv = Rc::new(ServiceFactory::<IoBoxed>::new_service(&factory, ()).await.unwrap());
+
+    let (client, server) = ntex::testing::Io::create();
+    client.remote_buffer_cap(1024);
+    let srv2 = srv.clone();
+    ntex::rt::spawn(async move {
+        let _ = srv2.call(IoBoxed::from(Io::new(server))).await;
+    });
+
+    let codec = codec::Codec::default();
+    let mut buf = BytesMut::new();
+    codec
+        .encode(
+            codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+            &mut buf,
+        )
+        .unwrap();
+    client.write(buf.split());
+    sleep(Duration::from_millis(20)).await;
+    let mut buf = BytesMut::from(&client.read_any()[..]);
+    let pkt = codec.decode(&mut buf).unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::ConnectAck(_)));
+
+    codec.encode(pkt_publish().into(), &mut buf).unwrap();
+    client.write(buf.split());
+    sleep(Duration::from_millis(50)).await;
+
+    // publish is in progress
+    assert!(lazy(|cx| Service::<IoBoxed>::poll_shutdown(&*srv, cx, false)).await.is_pending());
+
+    // new connections are rejected
+    let (_, server) = ntex::testing::Io::create();
+    assert!(srv.call(IoBoxed::from(Io::new(server))).await.is_err());
+
+    let done = Rc::new(Cell::new(false));
+    let done2 = done.clone();
+    ntex::rt::spawn(async move {
+        poll_fn(|cx| Service::<IoBoxed>::poll_shutdown(&*srv, cx, false)).await;
+        done2.set(true);
+    });
+
+    // connection is disconnected once publish is acked
+    sleep(Duration::from_millis(300)).await;
+    let mut buf = BytesMut::from(&client.read_any()[..]);
+    let pkt = codec.decode(&mut buf).unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishAck(_)));
+    let pkt = codec.decode(&mut buf).unwrap().unwrap();
+    if let codec::Packet::Disconnect(pkt) = pkt {
+        assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::ServerShuttingDown);
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+    assert!(!done.get());
+
+    client.close().await;
+    sleep(Duration::from_millis(100)).await;
+    assert!(done.get());
+}
+
+#[ntex::test]
+async fn test_disconnect_with_reason() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                Ready::Ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                    let pkt = codec::Disconnect {
+                        reason_code: codec::DisconnectReasonCode::ServerMoved,
+                        ..Default::default()
+                    };
+                    session.sink().close_with_reason(pkt);
+                    async move {
+                        sleep(Duration::from_millis(100)).await;
+                        Ok::<_, TestError>(p.ack())
                     }
-                    Ready::Ok::<_, TestError>(msg.ack())
-                }
-                _ => Ready::Ok(msg.disconnect()),
-            })
+                }))
+            }))
             .finish()
     });
 
@@ -573,24 +828,1278 @@ async fn test_keepalive2() {
 
     ntex::rt::spawn(client.start_default());
 
-    assert!(sink.is_open());
-    let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
-    assert!(res.is_ok());
-    sleep(Duration::from_millis(500)).await;
     let res =
         sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
-    assert!(res.is_ok());
-    sleep(Duration::from_millis(2000)).await;
+    assert!(res.is_err());
 
-    assert!(!sink.is_open());
-    assert!(ka.load(Relaxed));
+    Ok(())
 }
 
 #[ntex::test]
-async fn test_sink_encoder_error_pub_qos1() {
-    let srv = server::test_server(move || {
-        MqttServer::new(|con: Handshake| async move {
+async fn test_disconnect_with_reason_flush() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                Ready::Ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                    let sink = session.sink();
+                    sink.publish(ByteString::from_static("evict"), Bytes::from_static(b"bye"))
+                        .send_at_most_once()
+                        .unwrap();
+                    sink.close_with_reason(codec::Disconnect {
+                        reason_code: codec::DisconnectReasonCode::QuotaExceeded,
+                        user_properties: vec![("k".into(), "v".into())],
+                        ..Default::default()
+                    });
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(pkt_publish().into(), &codec).await.unwrap();
+
+    // outbound publish is delivered before disconnect
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::Publish(pkt) = pkt {
+        assert_eq!(pkt.topic, "evict");
+        assert_eq!(pkt.payload, Bytes::from_static(b"bye"));
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::Disconnect(pkt) = pkt {
+        assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::QuotaExceeded);
+        assert_eq!(pkt.user_properties, vec![("k".into(), "v".into())]);
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+    assert!(io.recv(&codec).await.unwrap().is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_disconnect_after_control_error() -> std::io::Result<()> {
+    env_logger::init();
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(_) => Ready::Err(TestError),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Subscribe {
+            id: None,
+            packet_id: NonZeroU16::new(2).unwrap(),
+            user_properties: Default::default(),
+            topic_filters: vec![(
+                ByteString::from("topic1"),
+                codec::SubscriptionOptions {
+                    qos: codec::QoS::AtLeastOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: codec::RetainHandling::AtSubscribe,
+                },
+            )],
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    let result = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::Disconnect(_) = result {
+    } else {
+        panic!();
+    }
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handshake_ack_properties() -> std::io::Result<()> {
+    let client_id = Arc::new(Mutex::new(None));
+    let client_id2 = client_id.clone();
+
+    let srv = server::test_server(move || {
+        let client_id = client_id2.clone();
+        MqttServer::new(|con: Handshake| {
+            Ready::Ok::<_, TestError>(
+                con.ack(St)
+                    .with_assigned_client_id(ByteString::from_static("assigned"))
+                    .with_server_keepalive(ntex::time::Seconds(1))
+                    .with_max_packet_size(512)
+                    .with_session_expiry(120),
+            )
+        })
+        .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+            *client_id.lock().unwrap() =
+                Some((session.negotiated().client_id.clone(), session.keepalive()));
+            Ready::Ok::<_, TestError>(fn_service(|p: Publish| {
+                Ready::Ok::<_, TestError>(p.ack())
+            }))
+        }))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    let mut connect = codec::Connect::default().client_id("user");
+    connect.keep_alive = 60;
+    io.send(codec::Packet::Connect(Box::new(connect)), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::ConnectAck(ack) = pkt {
+        assert_eq!(ack.assigned_client_id, Some(ByteString::from_static("assigned")));
+        assert_eq!(ack.server_keepalive_sec, Some(1));
+        assert_eq!(ack.max_packet_size, Some(512));
+        assert_eq!(ack.session_expiry_interval_secs, Some(120));
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+
+    io.send(pkt_publish().into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    let (client_id, keepalive) = client_id.lock().unwrap().take().unwrap();
+    assert_eq!(client_id, "assigned");
+    assert_eq!(keepalive, ntex::time::Seconds(1));
+
+    // server keep-alive overrides idle timeout of the connection
+    let res = ntex::time::timeout(Duration::from_millis(2500), io.recv(&codec)).await;
+    if let Some(codec::Packet::Disconnect(pkt)) = res.unwrap().unwrap() {
+        assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::KeepAliveTimeout);
+    } else {
+        panic!("Disconnect packet is expected");
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_server_keepalive() -> std::io::Result<()> {
+    let ping = Arc::new(AtomicBool::new(false));
+    let ping2 = ping.clone();
+
+    let srv = server::test_server(move || {
+        let ping = ping2.clone();
+        MqttServer::new(|con: Handshake| {
+            Ready::Ok::<_, TestError>(
+                con.ack(St).with(|ack| ack.server_keepalive_sec = Some(1)),
+            )
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .control(move |msg| {
+            let ping = ping.clone();
+            match msg {
+                ControlMessage::Ping(msg) => {
+                    ping.store(true, Relaxed);
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect_with(codec::Disconnect::default())),
+            }
+        })
+        .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .keep_alive(ntex::time::Seconds(10))
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(client.packet().server_keepalive_sec, Some(1));
+    assert_eq!(client.effective_keepalive(), ntex::time::Seconds(1));
+
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // client pings server with server's keep-alive
+    sleep(Duration::from_millis(1500)).await;
+    assert!(sink.is_open());
+    assert!(ping.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_ping() -> std::io::Result<()> {
+    let ping = Arc::new(AtomicBool::new(false));
+    let ping2 = ping.clone();
+
+    let srv = server::test_server(move || {
+        let ping = ping2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| {
+                let ping = ping.clone();
+                match msg {
+                    ControlMessage::Ping(msg) => {
+                        ping.store(true, Relaxed);
+                        Ready::Ok::<_, TestError>(msg.ack())
+                    }
+                    _ => Ready::Ok(msg.disconnect_with(codec::Disconnect::default())),
+                }
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::new();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+    assert!(ping.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscribe_partial_failure() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        if sub.topic() == "forbidden" {
+                            sub.fail(codec::SubscribeAckReason::NotAuthorized);
+                        } else {
+                            sub.confirm(codec::QoS::AtLeastOnce);
+                        }
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    io.send(
+        codec::Subscribe {
+            id: None,
+            packet_id: NonZeroU16::new(1).unwrap(),
+            user_properties: Default::default(),
+            topic_filters: vec![
+                (ByteString::from("topic1"), opts.clone()),
+                (ByteString::from("forbidden"), opts),
+            ],
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::SubscribeAck(codec::SubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            properties: Default::default(),
+            reason_string: None,
+            status: vec![
+                codec::SubscribeAckReason::GrantedQos1,
+                codec::SubscribeAckReason::NotAuthorized
+            ],
+        })
+    );
+
+    // connection is still open
+    io.send(pkt_publish().into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_ordered_delivery() -> std::io::Result<()> {
+    let delivered = Arc::new(Mutex::new(Vec::new()));
+    let delivered2 = delivered.clone();
+
+    let srv = server::test_server(move || {
+        let delivered = delivered2.clone();
+        MqttServer::new(handshake)
+            .ordered(true)
+            .publish(move |p: Publish| {
+                let delivered = delivered.clone();
+                async move {
+                    let delay = match p.qos() {
+                        codec::QoS::AtMostOnce => 0,
+                        codec::QoS::AtLeastOnce => 100,
+                        codec::QoS::ExactlyOnce => 50,
+                    };
+                    sleep(Duration::from_millis(delay)).await;
+                    delivered.lock().unwrap().push(p.topic().path().to_string());
+                    Ok::<_, TestError>(p.ack())
+                }
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let qos = [
+        codec::QoS::AtLeastOnce,
+        codec::QoS::AtMostOnce,
+        codec::QoS::ExactlyOnce,
+        codec::QoS::AtMostOnce,
+        codec::QoS::AtLeastOnce,
+    ];
+    for (idx, qos) in qos.iter().enumerate() {
+        let packet_id =
+            if *qos == codec::QoS::AtMostOnce { None } else { NonZeroU16::new(idx as u16 + 1) };
+        io.send(
+            codec::Publish {
+                qos: *qos,
+                packet_id,
+                topic: ByteString::from(format!("{}", idx)),
+                ..pkt_publish()
+            }
+            .into(),
+            &codec,
+        )
+        .await
+        .unwrap();
+    }
+
+    for id in [1, 3, 5] {
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        if let codec::Packet::PublishAck(ack) = pkt {
+            assert_eq!(ack.packet_id.get(), id);
+        } else {
+            panic!("Unexpected packet: {:?}", pkt);
+        }
+    }
+    assert_eq!(&*delivered.lock().unwrap(), &["0", "1", "2", "3", "4"]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_global_qos2_inflight() -> std::io::Result<()> {
+    let limit = Qos2InflightLimit::new(1);
+    let limit2 = limit.clone();
+
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .max_global_qos2_inflight(limit2.clone())
+            .publish(|p: Publish| async move {
+                sleep(Duration::from_millis(100)).await;
+                Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    for id in 1..3 {
+        io.send(
+            codec::Publish {
+                qos: codec::QoS::ExactlyOnce,
+                packet_id: NonZeroU16::new(id),
+                ..pkt_publish()
+            }
+            .into(),
+            &codec,
+        )
+        .await
+        .unwrap();
+    }
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishReceived(codec::PublishAck {
+            packet_id: NonZeroU16::new(2).unwrap(),
+            reason_code: codec::PublishAckReason::QuotaExceeded,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    );
+    assert_eq!(limit.inflight(), 1);
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    );
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(limit.inflight(), 0);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_rate_limit() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish_rate_limit(2, 1)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    // throttled publishes stay in read buffer, reading is paused
+    let start = std::time::Instant::now();
+    for id in 1..=4 {
+        io.send(
+            codec::Publish {
+                qos: codec::QoS::AtLeastOnce,
+                packet_id: NonZeroU16::new(id),
+                ..pkt_publish()
+            }
+            .into(),
+            &codec,
+        )
+        .await
+        .unwrap();
+    }
+    for id in 1..=4 {
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        assert_eq!(
+            pkt,
+            codec::Packet::PublishAck(codec::PublishAck {
+                packet_id: NonZeroU16::new(id).unwrap(),
+                reason_code: codec::PublishAckReason::Success,
+                properties: Default::default(),
+                reason_string: None,
+            })
+        );
+    }
+    assert!(start.elapsed() >= Duration::from_millis(1400));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_ack_reason() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| {
+                if p.topic().path() == "denied" {
+                    Ready::Ok::<_, TestError>(
+                        PublishAck::new(codec::PublishAckReason::NotAuthorized)
+                            .reason("denied".into())
+                            .properties(|props| props.push(("key".into(), "val".into()))),
+                    )
+                } else {
+                    Ready::Ok(p.ack())
+                }
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    // qos0 publish is not acked
+    let publish =
+        codec::Publish { qos: codec::QoS::AtMostOnce, packet_id: None, ..pkt_publish() };
+    io.send(codec::Publish { topic: "denied".into(), ..publish }.into(), &codec).await.unwrap();
+
+    // rejected publish does not close connection
+    io.send(codec::Publish { topic: "denied".into(), ..pkt_publish() }.into(), &codec)
+        .await
+        .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::NotAuthorized,
+            properties: vec![("key".into(), "val".into())],
+            reason_string: Some("denied".into()),
+        })
+    );
+
+    io.send(pkt_publish().into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_ack_order() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| async move {
+                sleep(Duration::from_millis(100)).await;
+                Ok::<_, TestError>(p.ack())
+            })
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        sub.topic();
+                        sub.options();
+                        sub.subscribe(codec::QoS::AtLeastOnce);
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Publish { packet_id: Some(NonZeroU16::new(1).unwrap()), ..pkt_publish() }.into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    io.send(
+        codec::Subscribe {
+            id: None,
+            packet_id: NonZeroU16::new(2).unwrap(),
+            user_properties: Default::default(),
+            topic_filters: vec![(
+                ByteString::from("topic1"),
+                codec::SubscriptionOptions {
+                    qos: codec::QoS::AtLeastOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: codec::RetainHandling::AtSubscribe,
+                },
+            )],
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    );
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::SubscribeAck(codec::SubscribeAck {
+            packet_id: NonZeroU16::new(2).unwrap(),
+            properties: Default::default(),
+            reason_string: None,
+            status: vec![codec::SubscribeAckReason::GrantedQos1],
+        })
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_dups() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| async move {
+                sleep(Duration::from_millis(10000)).await;
+                Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(
+            codec::Connect::default().client_id("user").receive_max(2),
+        )),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Publish { packet_id: Some(NonZeroU16::new(1).unwrap()), ..pkt_publish() }.into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    // send packet_id dup
+    io.send(
+        codec::Publish { packet_id: Some(NonZeroU16::new(1).unwrap()), ..pkt_publish() }.into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    // send subscribe dup
+    io.send(
+        codec::Subscribe {
+            id: None,
+            packet_id: NonZeroU16::new(1).unwrap(),
+            user_properties: Default::default(),
+            topic_filters: vec![(
+                ByteString::from("topic1"),
+                codec::SubscriptionOptions {
+                    qos: codec::QoS::AtLeastOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: codec::RetainHandling::AtSubscribe,
+                },
+            )],
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    // send unsubscribe dup
+    io.send(
+        codec::Unsubscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            user_properties: Default::default(),
+            topic_filters: vec![ByteString::from("topic1")],
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    // PublishAck
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::PacketIdentifierInUse,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    );
+
+    // SubscribeAck
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::SubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            properties: Default::default(),
+            reason_string: None,
+            status: vec![codec::SubscribeAckReason::PacketIdentifierInUse],
+        }
+        .into()
+    );
+
+    // UnsubscribeAck
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::UnsubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            properties: Default::default(),
+            reason_string: None,
+            status: vec![codec::UnsubscribeAckReason::PacketIdentifierInUse],
+        }
+        .into()
+    );
+}
+
+#[ntex::test]
+async fn test_packet_id_reuse() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    // packet id is released before ack, immediate reuse is not a duplicate
+    for _ in 0..3 {
+        io.send(pkt_publish().into(), &codec).await.unwrap();
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        assert_eq!(
+            pkt,
+            codec::Packet::PublishAck(codec::PublishAck {
+                packet_id: NonZeroU16::new(1).unwrap(),
+                reason_code: codec::PublishAckReason::Success,
+                properties: Default::default(),
+                reason_string: None,
+            })
+        );
+    }
+}
+
+#[ntex::test]
+async fn test_restrict_packets() {
+    let srv = server::test_server(move || {
+        MqttServer::new(|packet: Handshake| async move {
+            packet.restrict_packets(PacketMask::PUBLISH | PacketMask::PINGREQ);
+            Ok::<_, TestError>(packet.ack(St))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .control(|msg| match msg {
+            ControlMessage::Ping(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+            ControlMessage::ProtocolError(msg) => Ready::Ok(msg.ack()),
+            _ => Ready::Ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+
+    io.send(
+        codec::Unsubscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            user_properties: Default::default(),
+            topic_filters: vec![ByteString::from("topic1")],
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::ImplementationSpecificError
+        ))
+    );
+}
+
+#[ntex::test]
+async fn test_max_receive() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .receive_max(1)
+            .max_qos(codec::QoS::AtLeastOnce)
+            .publish(|p: Publish| async move {
+                sleep(Duration::from_millis(10000)).await;
+                Ok::<_, TestError>(p.ack())
+            })
+            .control(move |msg| match msg {
+                ControlMessage::ProtocolError(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let ack = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        ack,
+        codec::Packet::ConnectAck(Box::new(codec::ConnectAck {
+            receive_max: Some(NonZeroU16::new(1).unwrap()),
+            max_qos: Some(codec::QoS::AtLeastOnce),
+            reason_code: codec::ConnectAckReason::Success,
+            topic_alias_max: 32,
+            ..Default::default()
+        }))
+    );
+
+    io.send(
+        codec::Publish { packet_id: Some(NonZeroU16::new(1).unwrap()), ..pkt_publish() }.into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    io.send(
+        codec::Publish { packet_id: Some(NonZeroU16::new(2).unwrap()), ..pkt_publish() }.into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::ReceiveMaximumExceeded,
+            session_expiry_interval_secs: None,
+            server_reference: None,
+            reason_string: None,
+            user_properties: Default::default(),
+        })
+    );
+}
+
+#[ntex::test]
+async fn test_router_topic_filter_alias() {
+    let routed = Arc::new(Mutex::new(Vec::new()));
+    let routed2 = routed.clone();
+
+    let srv = server::test_server(move || {
+        let routed = routed2.clone();
+        MqttServer::new(handshake)
+            .publish(
+                Router::new(
+                    fn_service(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+                        .map_init_err(|_| TestError),
+                )
+                .filter("sensors/+/temp", move |p: Publish| {
+                    routed.lock().unwrap().push(p.segment(0).unwrap().to_string());
+                    Ready::Ok::<_, TestError>(p.ack())
+                }),
+            )
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    // second publish is routed by topic alias
+    for (id, topic) in [(1, "sensors/kitchen/temp"), (2, "")] {
+        let mut pkt = codec::Publish {
+            topic: ByteString::from(topic),
+            packet_id: NonZeroU16::new(id),
+            ..pkt_publish()
+        };
+        pkt.properties.topic_alias = NonZeroU16::new(1);
+        io.send(pkt.into(), &codec).await.unwrap();
+
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        if let codec::Packet::PublishAck(ack) = pkt {
+            assert_eq!(ack.packet_id.get(), id);
+        } else {
+            panic!("Unexpected packet: {:?}", pkt);
+        }
+    }
+    assert_eq!(&*routed.lock().unwrap(), &["kitchen", "kitchen"]);
+}
+
+#[ntex::test]
+async fn test_second_connect() {
+    let violation = Arc::new(AtomicBool::new(false));
+    let violation2 = violation.clone();
+
+    let srv = server::test_server(move || {
+        let violation = violation2.clone();
+
+        MqttServer::new(|con: Handshake| async move { Ok(con.ack(St)) })
+            .publish(|p: Publish| async move { Ok::<_, TestError>(p.ack()) })
+            .control(move |msg| match msg {
+                ControlMessage::ProtocolError(msg) => {
+                    if let &error::ProtocolError::Unexpected(0x10, _) = msg.get_ref() {
+                        violation.store(true, Relaxed);
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    let connect =
+        || codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user")));
+    io.send(connect(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    // second connect packet closes connection with protocol error
+    io.send(connect(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::Disconnect(pkt) = pkt {
+        assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::ProtocolError);
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    assert!(violation.load(Relaxed));
+}
+
+#[ntex::test]
+async fn test_unexpected_qos2_ack() {
+    let unexpected = Arc::new(Mutex::new(Vec::new()));
+    let server = |strict| {
+        let unexpected = unexpected.clone();
+        server::test_server(move || {
+            let unexpected = unexpected.clone();
+            MqttServer::new(handshake)
+                .strict_acks(strict)
+                .on_unexpected_ack(move |_: &Session<St>, packet_id, packet_type| {
+                    unexpected.lock().unwrap().push((packet_id.get(), packet_type));
+                })
+                .publish(|p: Publish| async move { Ok::<_, TestError>(p.ack()) })
+                .finish()
+        })
+    };
+
+    let pubrec = |id| {
+        codec::Packet::PublishReceived(codec::PublishAck {
+            packet_id: NonZeroU16::new(id).unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    };
+    let pubcomp = |id| {
+        codec::Packet::PublishComplete(codec::PublishAck2 {
+            packet_id: NonZeroU16::new(id).unwrap(),
+            reason_code: codec::PublishAck2Reason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    };
+    let codec = codec::Codec::default();
+    let connect =
+        || codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user")));
+
+    // lenient policy, acks are ignored
+    let srv = server(false);
+    let io = srv.connect().await.unwrap();
+    io.send(connect(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    io.send(pubrec(1), &codec).await.unwrap();
+    io.send(pubcomp(2), &codec).await.unwrap();
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+    assert_eq!(*unexpected.lock().unwrap(), vec![(1, 0x50), (2, 0x70)]);
+
+    // strict policy, connection is closed with protocol error
+    let srv = server(true);
+    let io = srv.connect().await.unwrap();
+    io.send(connect(), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    io.send(pubcomp(3), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::Disconnect(_)));
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    assert_eq!(unexpected.lock().unwrap().last(), Some(&(3, 0x70)));
+}
+
+#[ntex::test]
+async fn test_keepalive() {
+    let ka = Arc::new(AtomicBool::new(false));
+    let ka2 = ka.clone();
+
+    let srv = server::test_server(move || {
+        let ka = ka2.clone();
+
+        MqttServer::new(|con: Handshake| async move { Ok(con.ack(St).keep_alive(1)) })
+            .publish(|p: Publish| async move { Ok::<_, TestError>(p.ack()) })
+            .control(move |msg| match msg {
+                ControlMessage::Timeout(msg) => {
+                    if msg.idle() >= Duration::from_millis(900) {
+                        ka.store(true, Relaxed);
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    assert!(sink.is_open());
+    sleep(Duration::from_millis(2500)).await;
+    assert!(!sink.is_open());
+    assert!(ka.load(Relaxed));
+}
+
+#[ntex::test]
+async fn test_keepalive2() {
+    let ka = Arc::new(AtomicBool::new(false));
+    let ka2 = ka.clone();
+
+    let srv = server::test_server(move || {
+        let ka = ka2.clone();
+
+        MqttServer::new(|con: Handshake| async move { Ok(con.ack(St).keep_alive(1)) })
+            .publish(|p: Publish| async move { Ok::<_, TestError>(p.ack()) })
+            .control(move |msg| match msg {
+                ControlMessage::Timeout(msg) => {
+                    if msg.idle() >= Duration::from_millis(900) {
+                        ka.store(true, Relaxed);
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    assert!(sink.is_open());
+    let res =
+        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sleep(Duration::from_millis(500)).await;
+    let res =
+        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sleep(Duration::from_millis(2000)).await;
+
+    assert!(!sink.is_open());
+    assert!(ka.load(Relaxed));
+}
+
+#[ntex::test]
+async fn test_keepalive_extend() {
+    let extended = Arc::new(AtomicBool::new(false));
+    let extended2 = extended.clone();
+
+    let srv = server::test_server(move || {
+        let extended = extended2.clone();
+
+        MqttServer::new(|con: Handshake| async move { Ok(con.ack(St).keep_alive(1)) })
+            .publish(|p: Publish| async move { Ok::<_, TestError>(p.ack()) })
+            .control(move |msg| match msg {
+                ControlMessage::Timeout(msg) => {
+                    if extended.swap(true, Relaxed) {
+                        Ready::Ok::<_, TestError>(msg.ack())
+                    } else {
+                        Ready::Ok(msg.extend(Seconds(2)))
+                    }
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sleep(Duration::from_millis(2500)).await;
+    assert!(sink.is_open());
+    assert!(extended.load(Relaxed));
+
+    sleep(Duration::from_millis(2500)).await;
+    assert!(!sink.is_open());
+}
+
+#[ntex::test]
+async fn test_sink_encoder_error_recovery() -> std::io::Result<()> {
+    let delivered = Arc::new(AtomicBool::new(false));
+    let delivered2 = delivered.clone();
+
+    let srv = server::test_server(move || {
+        let delivered = delivered2.clone();
+        MqttServer::new(move |con: Handshake| {
+            let sink = con.sink();
+            let delivered = delivered.clone();
+            ntex::rt::spawn(async move {
+                let res = sink
+                    .publish("test", Bytes::from_static(&[0; 64]))
+                    .send_at_least_once()
+                    .await;
+                assert_eq!(
+                    res,
+                    Err(error::PublishQos1Error::Encode(error::EncodeError::InvalidLength))
+                );
+
+                // connection is still usable
+                let res = sink.publish("test", Bytes::new()).send_at_least_once().await;
+                assert!(res.is_ok());
+                delivered.store(true, Relaxed);
+            });
+            Ready::Ok::<_, TestError>(con.ack(St))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    let mut connect = codec::Connect::default().client_id("user");
+    connect.max_packet_size = std::num::NonZeroU32::new(30);
+    io.send(codec::Packet::Connect(Box::new(connect)), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let packet_id = if let codec::Packet::Publish(pkt) = pkt {
+        assert!(pkt.payload.is_empty());
+        pkt.packet_id.unwrap()
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    };
+    io.send(
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id,
+            reason_code: codec::PublishAckReason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    sleep(Duration::from_millis(50)).await;
+    assert!(delivered.load(Relaxed));
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_encoder_error_pub_qos1() {
+    let srv = server::test_server(move || {
+        MqttServer::new(|con: Handshake| async move {
             let builder = con.sink().publish("test", Bytes::new()).properties(|props| {
                 props.user_properties.push((
                     "ssssssssssssssssssssssssssssssssssss".into(),
@@ -606,138 +2115,815 @@ async fn test_sink_encoder_error_pub_qos1() {
             });
             Ok(con.ack(St))
         })
-        .publish(|p: Publish| async move {
-            sleep(Duration::from_millis(50)).await;
-            Ok::<_, TestError>(p.ack())
+        .publish(|p: Publish| async move {
+            sleep(Duration::from_millis(50)).await;
+            Ok::<_, TestError>(p.ack())
+        })
+        .control(move |msg| match msg {
+            ControlMessage::ProtocolError(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+            _ => Ready::Ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    // connect to server
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .max_packet_size(30)
+        .connect()
+        .await
+        .unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+}
+
+#[ntex::test]
+async fn test_sink_encoder_error_pub_qos0() {
+    let srv = server::test_server(move || {
+        MqttServer::new(|con: Handshake| async move {
+            let builder = con.sink().publish("test", Bytes::new()).properties(|props| {
+                props.user_properties.push((
+                    "ssssssssssssssssssssssssssssssssssss".into(),
+                    "ssssssssssssssssssssssssssssssssssss".into(),
+                ));
+            });
+            let res = builder.send_at_most_once();
+            assert_eq!(
+                res,
+                Err(error::SendPacketError::Encode(error::EncodeError::InvalidLength))
+            );
+            Ok(con.ack(St))
+        })
+        .publish(|p: Publish| async move {
+            sleep(Duration::from_millis(50)).await;
+            Ok::<_, TestError>(p.ack())
+        })
+        .control(move |msg| match msg {
+            ControlMessage::ProtocolError(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+            _ => Ready::Ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    // connect to server
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .max_packet_size(30)
+        .connect()
+        .await
+        .unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+}
+
+#[ntex::test]
+async fn test_request_problem_info() {
+    let srv = server::test_server(move || {
+        MqttServer::new(|con: Handshake| async move { Ok(con.ack(St)) })
+            .publish(|p: Publish| async move {
+                Ok::<_, TestError>(
+                    p.ack()
+                        .properties(|props| {
+                            props.push((
+                                "ssssssssssssssssssssssssssssssssssss".into(),
+                                "ssssssssssssssssssssssssssssssssssss".into(),
+                            ))
+                        })
+                        .reason("TEST".into()),
+                )
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .max_packet_size(30)
+        .packet(|pkt| pkt.request_problem_info = false)
+        .connect()
+        .await
+        .unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish(ByteString::from_static("#"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    assert!(res.properties.is_empty());
+    assert!(res.reason_string.is_none());
+}
+
+#[ntex::test]
+async fn test_suback_with_reason() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    msg.iter_mut().for_each(|mut s| {
+                        s.fail(codec::SubscribeAckReason::ImplementationSpecificError)
+                    });
+                    Ready::Ok::<_, TestError>(msg.ack_reason("some reason".into()).ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::new();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Packet::Subscribe(codec::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(
+                "topic1".into(),
+                codec::SubscriptionOptions {
+                    qos: codec::QoS::AtLeastOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: codec::RetainHandling::AtSubscribe,
+                },
+            )],
+            id: None,
+            user_properties: codec::UserProperties::default(),
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::SubscribeAck(codec::SubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            status: vec![codec::SubscribeAckReason::ImplementationSpecificError],
+            properties: codec::UserProperties::default(),
+            reason_string: Some("some reason".into()),
+        })
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handle_incoming() -> std::io::Result<()> {
+    let publish = Arc::new(AtomicBool::new(false));
+    let publish2 = publish.clone();
+    let disconnect = Arc::new(AtomicBool::new(false));
+    let disconnect2 = disconnect.clone();
+
+    let srv = server::test_server(move || {
+        let publish = publish2.clone();
+        let disconnect = disconnect2.clone();
+        MqttServer::new(handshake)
+            .pre_connack_publish_policy(PreConnackPublishPolicy::Buffer)
+            .publish(move |p: Publish| {
+                publish.store(true, Relaxed);
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .control(move |msg| match msg {
+                ControlMessage::Disconnect(msg) => {
+                    disconnect.store(true, Relaxed);
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.encode(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .unwrap();
+    io.encode(pkt_publish().into(), &codec).unwrap();
+    io.encode(
+        codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::ReceiveMaximumExceeded,
+            session_expiry_interval_secs: None,
+            server_reference: None,
+            reason_string: None,
+            user_properties: Default::default(),
+        }),
+        &codec,
+    )
+    .unwrap();
+    io.flush(true).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+    drop(io);
+    sleep(Duration::from_millis(50)).await;
+
+    assert!(publish.load(Relaxed));
+    assert!(disconnect.load(Relaxed));
+
+    Ok(())
+}
+
+async fn disconnect_with_will(reason_code: codec::DisconnectReasonCode) -> (bool, bool) {
+    let will = Arc::new(AtomicBool::new(false));
+    let will2 = will.clone();
+    let disconnect = Arc::new(AtomicBool::new(false));
+    let disconnect2 = disconnect.clone();
+
+    let srv = server::test_server(move || {
+        let will = will2.clone();
+        let disconnect = disconnect2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::WillPublish(msg) => {
+                    assert_eq!(msg.will().topic, "will");
+                    will.store(true, Relaxed);
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::Disconnect(msg) => {
+                    disconnect.store(true, Relaxed);
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    let mut connect = codec::Connect::default().client_id("user");
+    connect.last_will = Some(codec::LastWill {
+        qos: codec::QoS::AtMostOnce,
+        retain: false,
+        topic: ByteString::from_static("will"),
+        message: Bytes::from_static(b"gone"),
+        will_delay_interval_sec: None,
+        correlation_data: None,
+        message_expiry_interval: None,
+        content_type: None,
+        user_properties: Default::default(),
+        is_utf8_payload: None,
+        response_topic: None,
+    });
+    io.encode(codec::Packet::Connect(Box::new(connect)), &codec).unwrap();
+    io.encode(codec::Packet::Disconnect(codec::Disconnect::new(reason_code)), &codec).unwrap();
+    io.flush(true).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+    drop(io);
+    sleep(Duration::from_millis(50)).await;
+
+    (will.load(Relaxed), disconnect.load(Relaxed))
+}
+
+#[ntex::test]
+async fn test_disconnect_with_will() -> std::io::Result<()> {
+    let (will, disconnect) =
+        disconnect_with_will(codec::DisconnectReasonCode::DisconnectWithWillMessage).await;
+    assert!(will);
+    assert!(!disconnect);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_disconnect_discards_will() -> std::io::Result<()> {
+    let (will, disconnect) =
+        disconnect_with_will(codec::DisconnectReasonCode::NormalDisconnection).await;
+    assert!(!will);
+    assert!(disconnect);
+    Ok(())
+}
+
+/// Drop connection without DISCONNECT packet, returns will flag
+async fn reset_with_will(
+    will_delay: Option<u32>,
+    session_expiry: Option<u32>,
+    reconnect: bool,
+) -> Arc<AtomicBool> {
+    let will = Arc::new(AtomicBool::new(false));
+    let will2 = will.clone();
+
+    let srv = server::test_server(move || {
+        let will = will2.clone();
+        let sinks: Rc<RefCell<Vec<MqttSink>>> = Default::default();
+        MqttServer::new(move |packet: Handshake| {
+            // reconnect discards pending will of previous connection
+            for sink in sinks.borrow_mut().drain(..) {
+                sink.discard_will();
+            }
+            sinks.borrow_mut().push(packet.sink());
+            Ready::Ok::<_, TestError>(packet.ack(St))
         })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
         .control(move |msg| match msg {
-            ControlMessage::ProtocolError(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+            ControlMessage::WillPublish(msg) => {
+                assert!(msg.packet().is_none());
+                assert_eq!(msg.qos(), codec::QoS::AtLeastOnce);
+                assert!(msg.retain());
+                will.store(true, Relaxed);
+                Ready::Ok::<_, TestError>(msg.ack())
+            }
             _ => Ready::Ok(msg.disconnect()),
         })
         .finish()
     });
 
-    // connect to server
-    let client = client::MqttConnector::new(srv.addr())
-        .client_id("user")
-        .max_packet_size(30)
-        .connect()
-        .await
+    let codec = codec::Codec::default();
+    let mut connect = codec::Connect::default().client_id("user");
+    connect.session_expiry_interval_secs = session_expiry;
+    connect.last_will = Some(codec::LastWill {
+        qos: codec::QoS::AtLeastOnce,
+        retain: true,
+        topic: ByteString::from_static("will"),
+        message: Bytes::from_static(b"gone"),
+        will_delay_interval_sec: will_delay,
+        correlation_data: None,
+        message_expiry_interval: None,
+        content_type: None,
+        user_properties: Default::default(),
+        is_utf8_payload: None,
+        response_topic: None,
+    });
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Packet::Connect(Box::new(connect.clone())), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    drop(io);
+    sleep(Duration::from_millis(100)).await;
+
+    if reconnect {
+        connect.last_will = None;
+        let io = srv.connect().await.unwrap();
+        io.send(codec::Packet::Connect(Box::new(connect)), &codec).await.unwrap();
+        let _ = io.recv(&codec).await.unwrap().unwrap();
+        ntex::rt::spawn(async move {
+            sleep(Duration::from_millis(3000)).await;
+            drop(io);
+        });
+    }
+
+    // keep server running until will delay expires
+    ntex::rt::spawn(async move {
+        sleep(Duration::from_millis(3000)).await;
+        drop(srv);
+    });
+    will
+}
+
+#[ntex::test]
+async fn test_will_on_transport_reset() -> std::io::Result<()> {
+    let will = reset_with_will(None, None, false).await;
+    assert!(will.load(Relaxed));
+
+    // session ends with connection
+    let will = reset_with_will(Some(10), None, false).await;
+    assert!(will.load(Relaxed));
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_will_delay_on_transport_reset() -> std::io::Result<()> {
+    // will delay is shorter than session expiry
+    let will = reset_with_will(Some(1), Some(10), false).await;
+    assert!(!will.load(Relaxed));
+    sleep(Duration::from_millis(1200)).await;
+    assert!(will.load(Relaxed));
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_will_session_expiry_on_transport_reset() -> std::io::Result<()> {
+    // session expires before will delay
+    let will = reset_with_will(Some(10), Some(1), false).await;
+    assert!(!will.load(Relaxed));
+    sleep(Duration::from_millis(1200)).await;
+    assert!(will.load(Relaxed));
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_will_reconnect_on_transport_reset() -> std::io::Result<()> {
+    // reconnect within will delay discards will
+    let will = reset_with_will(Some(1), Some(10), true).await;
+    sleep(Duration::from_millis(1200)).await;
+    assert!(!will.load(Relaxed));
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_payload_transform() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|hnd: Handshake| {
+            let enabled = hnd.user_property("compress").map(|v| v == "rev").unwrap_or(false);
+            let ack = hnd.ack(St);
+            if enabled {
+                Ready::Ok::<_, TestError>(
+                    ack.user_property("compress".into(), "rev".into()).payload_transform(
+                        |p| p.iter().rev().copied().collect::<Vec<_>>().into(),
+                        |p| p.iter().rev().copied().collect::<Vec<_>>().into(),
+                    ),
+                )
+            } else {
+                Ready::Ok(ack)
+            }
+        })
+        .publish(|p: Publish| {
+            assert_eq!(p.payload(), &Bytes::from_static(b"abc"));
+            Ready::Ok::<_, TestError>(p.ack())
+        })
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    let mut connect = codec::Connect::default().client_id("user");
+    connect.user_properties.push(("compress".into(), "rev".into()));
+    io.send(codec::Packet::Connect(Box::new(connect)), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::ConnectAck(ack) = pkt {
+        assert_eq!(ack.user_properties, vec![("compress".into(), "rev".into())]);
+    } else {
+        panic!("ConnectAck packet is expected")
+    }
+
+    let mut publish = pkt_publish();
+    publish.payload = Bytes::from_static(b"cba");
+    io.send(publish.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::PublishAck(ack) = pkt {
+        assert_eq!(ack.reason_code, codec::PublishAckReason::Success);
+    } else {
+        panic!("PublishAck packet is expected")
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_connection_lifetime() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .max_connection_lifetime(ntex::time::Seconds(1))
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    let pkt = ntex::time::timeout(ntex::time::Millis(2500), io.recv(&codec)).await;
+    assert_eq!(
+        pkt.unwrap().unwrap().unwrap(),
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::MaximumConnectTime
+        ))
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_on_disconnect() -> std::io::Result<()> {
+    let reasons = Arc::new(Mutex::new(Vec::new()));
+    let reasons2 = reasons.clone();
+
+    let srv = server::test_server(move || {
+        let reasons = reasons2.clone();
+        MqttServer::new(handshake)
+            .max_connection_lifetime(ntex::time::Seconds(1))
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .on_disconnect(move |_: &Session<St>, reason| {
+                reasons.lock().unwrap().push(format!("{:?}", reason));
+            })
+            .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let io = srv.connect().await.unwrap();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    io.send(
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::NormalDisconnection,
+        )),
+        &codec,
+    )
+    .await
+    .unwrap();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    sleep(ntex::time::Millis(100)).await;
+    assert_eq!(reasons.lock().unwrap().pop().unwrap(), "Clean");
+
+    // connection is closed after max lifetime
+    let io = srv.connect().await.unwrap();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    let pkt = ntex::time::timeout(ntex::time::Millis(2500), io.recv(&codec)).await;
+    assert!(pkt.unwrap().unwrap().is_some());
+    sleep(ntex::time::Millis(100)).await;
+    assert_eq!(reasons.lock().unwrap().pop().unwrap(), "ServerEvicted");
+    assert!(reasons.lock().unwrap().is_empty());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_subscribe() {
+    let result = Rc::new(RefCell::new(None));
+    let result2 = result.clone();
+
+    let factory = MqttServer::new(handshake)
+        .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+            let result = result2.clone();
+            Ready::Ok::<_, TestError>(fn_service(move |p: Publish| {
+                let sink = session.sink().clone();
+                let result = result.clone();
+                ntex::rt::spawn(async move {
+                    let opts = codec::SubscriptionOptions {
+                        qos: codec::QoS::AtLeastOnce,
+                        no_local: false,
+                        retain_as_published: false,
+                        retain_handling: codec::RetainHandling::AtSubscribe,
+                    };
+                    let ack = sink
+                        .subscribe(None)
+                        .topic_filter(ByteString::from_static("up/#"), opts.clone())
+                        .topic_filter(ByteString::from_static("denied"), opts)
+                        .send()
+                        .await;
+                    *result.borrow_mut() = Some(ack);
+                });
+                Ready::Ok::<_, TestError>(p.ack())
+            }))
+        }))
+        .finish();
+    let srv = ServiceFactory::<IoBoxed>::new_service(&factory, ()).await.unwrap();
+
+    let (client, server) = ntex::testing::Io::create();
+    client.remote_buffer_cap(1024);
+    ntex::rt::spawn(async move {
+        let _ = srv.call(IoBoxed::from(Io::new(server))).await;
+    });
+
+    let codec = codec::Codec::default();
+    let mut buf = BytesMut::new();
+    codec
+        .encode(
+            codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+            &mut buf,
+        )
         .unwrap();
+    client.write(buf.split());
+    sleep(Duration::from_millis(20)).await;
+    let mut buf = BytesMut::from(&client.read_any()[..]);
+    let pkt = codec.decode(&mut buf).unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::ConnectAck(_)));
+
+    codec.encode(pkt_publish().into(), &mut buf).unwrap();
+    client.write(buf.split());
+    sleep(Duration::from_millis(50)).await;
 
-    let sink = client.sink();
-
-    ntex::rt::spawn(client.start_default());
+    let mut buf = BytesMut::from(&client.read_any()[..]);
+    let mut subscribe = None;
+    while let Some(pkt) = codec.decode(&mut buf).unwrap() {
+        if let codec::Packet::Subscribe(pkt) = pkt {
+            subscribe = Some(pkt);
+        }
+    }
+    let subscribe = subscribe.unwrap();
+    assert_eq!(subscribe.topic_filters.len(), 2);
+
+    codec
+        .encode(
+            codec::Packet::SubscribeAck(codec::SubscribeAck {
+                packet_id: subscribe.packet_id,
+                properties: Vec::new(),
+                reason_string: None,
+                status: vec![
+                    codec::SubscribeAckReason::GrantedQos1,
+                    codec::SubscribeAckReason::NotAuthorized,
+                ],
+            }),
+            &mut buf,
+        )
+        .unwrap();
+    client.write(buf.split());
+    sleep(Duration::from_millis(50)).await;
 
-    let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
-    assert!(res.is_ok());
+    let ack = result.borrow_mut().take().unwrap().unwrap();
+    assert_eq!(
+        ack.status,
+        vec![codec::SubscribeAckReason::GrantedQos1, codec::SubscribeAckReason::NotAuthorized]
+    );
 }
 
 #[ntex::test]
-async fn test_sink_encoder_error_pub_qos0() {
+async fn test_topic_alias() {
+    let topics = Arc::new(Mutex::new(Vec::new()));
+    let topics2 = topics.clone();
+
     let srv = server::test_server(move || {
-        MqttServer::new(|con: Handshake| async move {
-            let builder = con.sink().publish("test", Bytes::new()).properties(|props| {
-                props.user_properties.push((
-                    "ssssssssssssssssssssssssssssssssssss".into(),
-                    "ssssssssssssssssssssssssssssssssssss".into(),
-                ));
-            });
-            let res = builder.send_at_most_once();
-            assert_eq!(
-                res,
-                Err(error::SendPacketError::Encode(error::EncodeError::InvalidLength))
-            );
-            Ok(con.ack(St))
-        })
-        .publish(|p: Publish| async move {
-            sleep(Duration::from_millis(50)).await;
-            Ok::<_, TestError>(p.ack())
-        })
-        .control(move |msg| match msg {
-            ControlMessage::ProtocolError(msg) => Ready::Ok::<_, TestError>(msg.ack()),
-            _ => Ready::Ok(msg.disconnect()),
-        })
-        .finish()
+        let topics = topics2.clone();
+        MqttServer::new(handshake)
+            .max_topic_alias(2)
+            .publish(move |p: Publish| {
+                topics
+                    .lock()
+                    .unwrap()
+                    .push((p.topic().path().to_string(), p.packet().properties.topic_alias));
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .control(|msg| match msg {
+                ControlMessage::ProtocolError(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
     });
 
-    // connect to server
-    let client = client::MqttConnector::new(srv.addr())
-        .client_id("user")
-        .max_packet_size(30)
-        .connect()
-        .await
-        .unwrap();
-
+    // aliases are assigned up to server's topic alias maximum
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
     let sink = client.sink();
-
     ntex::rt::spawn(client.start_default());
+    for topic in ["a", "a", "b", "c"] {
+        let res =
+            sink.publish(topic, Bytes::new()).use_topic_alias().send_at_least_once().await;
+        assert!(res.is_ok());
+    }
+    assert_eq!(
+        &*topics.lock().unwrap(),
+        &[
+            ("a".to_string(), NonZeroU16::new(1)),
+            ("a".to_string(), NonZeroU16::new(1)),
+            ("b".to_string(), NonZeroU16::new(2)),
+            ("c".to_string(), None)
+        ]
+    );
+    sink.close();
 
-    let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
-    assert!(res.is_ok());
+    // alias is greater than topic alias maximum
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    let mut pkt = pkt_publish();
+    pkt.properties.topic_alias = NonZeroU16::new(3);
+    io.send(pkt.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::TopicAliasInvalid,
+            ..
+        })
+    ));
 }
 
 #[ntex::test]
-async fn test_request_problem_info() {
+async fn test_topic_alias_encode_error() {
+    let topics = Arc::new(Mutex::new(Vec::new()));
+    let topics2 = topics.clone();
+
     let srv = server::test_server(move || {
-        MqttServer::new(|con: Handshake| async move { Ok(con.ack(St)) })
-            .publish(|p: Publish| async move {
-                Ok::<_, TestError>(
-                    p.ack()
-                        .properties(|props| {
-                            props.push((
-                                "ssssssssssssssssssssssssssssssssssss".into(),
-                                "ssssssssssssssssssssssssssssssssssss".into(),
-                            ))
-                        })
-                        .reason("TEST".into()),
-                )
+        let topics = topics2.clone();
+        MqttServer::new(handshake)
+            .max_size(64)
+            .max_topic_alias(2)
+            .publish(move |p: Publish| {
+                topics
+                    .lock()
+                    .unwrap()
+                    .push((p.topic().path().to_string(), p.packet().properties.topic_alias));
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .control(|msg| match msg {
+                ControlMessage::ProtocolError(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
             })
             .finish()
     });
 
-    // connect to server
-    let client = client::MqttConnector::new(srv.addr())
-        .client_id("user")
-        .max_packet_size(30)
-        .packet(|pkt| pkt.request_problem_info = false)
-        .connect()
-        .await
-        .unwrap();
-
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
     let sink = client.sink();
-
     ntex::rt::spawn(client.start_default());
 
+    // publish is too large, alias is not registered
     let res = sink
-        .publish(ByteString::from_static("#"), Bytes::new())
+        .publish("a", Bytes::from(vec![0; 128]))
+        .use_topic_alias()
         .send_at_least_once()
-        .await
-        .unwrap();
-    assert!(res.properties.is_empty());
-    assert!(res.reason_string.is_none());
+        .await;
+    assert!(matches!(res, Err(error::PublishQos1Error::Encode(_))));
+    let res =
+        sink.publish("a", Bytes::from(vec![0; 128])).use_topic_alias().send_at_most_once();
+    assert!(matches!(res, Err(error::SendPacketError::Encode(_))));
+
+    // topic is sent along with alias
+    for _ in 0..2 {
+        let res = sink.publish("a", Bytes::new()).use_topic_alias().send_at_least_once().await;
+        assert!(res.is_ok());
+    }
+    assert_eq!(
+        &*topics.lock().unwrap(),
+        &[("a".to_string(), NonZeroU16::new(1)), ("a".to_string(), NonZeroU16::new(1))]
+    );
 }
 
 #[ntex::test]
-async fn test_suback_with_reason() -> std::io::Result<()> {
+async fn test_max_size_handle() {
+    let handle = MaxSizeHandle::new(1024);
+    let handle2 = handle.clone();
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .max_size_handle(handle2.clone())
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // existing connection keeps advertised max size
+    handle.set(64);
+    let res = sink.publish("test", Bytes::from(vec![0; 512])).send_at_least_once().await;
+    assert!(res.is_ok());
+
+    // new connection uses current max size
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    let res = sink.publish("test", Bytes::from(vec![0; 512])).send_at_least_once().await;
+    assert!(matches!(res, Err(error::PublishQos1Error::Encode(_))));
+}
+
+#[ntex::test]
+async fn test_min_inbound_qos() -> std::io::Result<()> {
     let srv = server::test_server(move || {
         MqttServer::new(handshake)
+            .min_inbound_qos(codec::QoS::AtLeastOnce)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
             .control(move |msg| match msg {
                 ControlMessage::Subscribe(mut msg) => {
-                    msg.iter_mut().for_each(|mut s| {
-                        s.fail(codec::SubscribeAckReason::ImplementationSpecificError)
-                    });
-                    Ready::Ok::<_, TestError>(msg.ack_reason("some reason".into()).ack())
+                    for mut sub in &mut msg {
+                        let qos = sub.options().qos;
+                        sub.confirm(qos);
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
                 }
+                ControlMessage::ProtocolError(msg) => Ready::Ok(msg.ack()),
                 _ => Ready::Ok(msg.disconnect()),
             })
             .finish()
     });
 
     let io = srv.connect().await.unwrap();
-    let codec = codec::Codec::new();
+    let codec = codec::Codec::default();
     io.send(
         codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
         &codec,
@@ -746,21 +2932,24 @@ async fn test_suback_with_reason() -> std::io::Result<()> {
     .unwrap();
     let _ = io.recv(&codec).await.unwrap().unwrap();
 
+    // subscription with lower granted qos is failed
+    let opts = |qos| codec::SubscriptionOptions {
+        qos,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
     io.send(
-        codec::Packet::Subscribe(codec::Subscribe {
-            packet_id: NonZeroU16::new(1).unwrap(),
-            topic_filters: vec![(
-                "topic1".into(),
-                codec::SubscriptionOptions {
-                    qos: codec::QoS::AtLeastOnce,
-                    no_local: false,
-                    retain_as_published: false,
-                    retain_handling: codec::RetainHandling::AtSubscribe,
-                },
-            )],
+        codec::Subscribe {
             id: None,
-            user_properties: codec::UserProperties::default(),
-        }),
+            packet_id: NonZeroU16::new(1).unwrap(),
+            user_properties: Default::default(),
+            topic_filters: vec![
+                (ByteString::from("qos0"), opts(codec::QoS::AtMostOnce)),
+                (ByteString::from("qos1"), opts(codec::QoS::AtLeastOnce)),
+            ],
+        }
+        .into(),
         &codec,
     )
     .await
@@ -770,66 +2959,42 @@ async fn test_suback_with_reason() -> std::io::Result<()> {
         pkt,
         codec::Packet::SubscribeAck(codec::SubscribeAck {
             packet_id: NonZeroU16::new(1).unwrap(),
-            status: vec![codec::SubscribeAckReason::ImplementationSpecificError],
-            properties: codec::UserProperties::default(),
-            reason_string: Some("some reason".into()),
+            properties: Default::default(),
+            reason_string: None,
+            status: vec![
+                codec::SubscribeAckReason::UnspecifiedError,
+                codec::SubscribeAckReason::GrantedQos1
+            ],
         })
     );
 
-    Ok(())
-}
-
-#[ntex::test]
-async fn test_handle_incoming() -> std::io::Result<()> {
-    let publish = Arc::new(AtomicBool::new(false));
-    let publish2 = publish.clone();
-    let disconnect = Arc::new(AtomicBool::new(false));
-    let disconnect2 = disconnect.clone();
-
-    let srv = server::test_server(move || {
-        let publish = publish2.clone();
-        let disconnect = disconnect2.clone();
-        MqttServer::new(handshake)
-            .publish(move |p: Publish| {
-                publish.store(true, Relaxed);
-                Ready::Ok::<_, TestError>(p.ack())
-            })
-            .control(move |msg| match msg {
-                ControlMessage::Disconnect(msg) => {
-                    disconnect.store(true, Relaxed);
-                    Ready::Ok::<_, TestError>(msg.ack())
-                }
-                _ => Ready::Ok(msg.disconnect()),
-            })
-            .finish()
-    });
-
-    let io = srv.connect().await.unwrap();
-    let codec = codec::Codec::default();
-    io.encode(
-        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
-        &codec,
-    )
-    .unwrap();
-    io.encode(pkt_publish().into(), &codec).unwrap();
-    io.encode(
-        codec::Packet::Disconnect(codec::Disconnect {
-            reason_code: codec::DisconnectReasonCode::ReceiveMaximumExceeded,
-            session_expiry_interval_secs: None,
-            server_reference: None,
+    // publish with qos 1 is accepted
+    io.send(pkt_publish().into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: Default::default(),
             reason_string: None,
-            user_properties: Default::default(),
-        }),
+        })
+    );
+
+    // publish with qos 0 closes connection
+    io.send(
+        codec::Publish { qos: codec::QoS::AtMostOnce, packet_id: None, ..pkt_publish() }.into(),
         &codec,
     )
+    .await
     .unwrap();
-    io.flush(true).await.unwrap();
-    sleep(Duration::from_millis(50)).await;
-    drop(io);
-    sleep(Duration::from_millis(50)).await;
-
-    assert!(publish.load(Relaxed));
-    assert!(disconnect.load(Relaxed));
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::Disconnect(pkt) = pkt {
+        assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::QosNotSupported);
+    } else {
+        panic!("Unexpected packet: {:?}", pkt);
+    }
+    assert!(io.recv(&codec).await.unwrap().is_none());
 
     Ok(())
 }